@@ -1,8 +1,10 @@
 use criterion::{criterion_group, criterion_main, Criterion, black_box};
 
-criterion_group!(benches, insert);
+criterion_group!(benches, insert, bulk_insert);
 criterion_main!(benches);
 
+use std::path::Path;
+
 use tempdir::TempDir;
 
 use rej::{Db, Params, NodePage};
@@ -10,10 +12,7 @@ use rej::{Db, Params, NodePage};
 #[cfg(feature = "cipher")]
 use rej::Secret;
 
-fn insert(c: &mut Criterion) {
-    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
-    let path = dir.path().join("bench-insert");
-
+fn create(path: &Path) -> Db<NodePage> {
     #[cfg(feature = "cipher")]
     let seed = rand::random::<[u8; 32]>();
 
@@ -30,13 +29,21 @@ fn insert(c: &mut Criterion) {
     #[cfg(not(feature = "cipher"))]
     let create_params = Params::Create;
 
-    let db = Db::<NodePage>::new(&path, create_params).unwrap();
+    Db::<NodePage>::new(path, create_params).unwrap()
+}
+
+fn insert(c: &mut Criterion) {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("bench-insert");
+
+    let db = create(&path);
 
     // prepare
     let mut key = *b"preparation     preparation";
     for i in 0..=255u8 {
         key[24] = i;
-        db.entry(&key)
+        db.entry(key)
+            .unwrap()
             .vacant()
             .unwrap()
             .insert()
@@ -48,17 +55,84 @@ fn insert(c: &mut Criterion) {
     c.bench_function("insert", |b| {
         b.iter(|| {
             let key = *b"key key key asd asd asd     ";
-            db.entry(&key)
+            db.entry(key)
+                .unwrap()
                 .vacant()
                 .unwrap()
                 .insert()
                 .unwrap()
                 .write_at(0, &[0, 1])
                 .unwrap();
-            let value = db.entry(&key).occupied().unwrap().remove().unwrap();
+            let value = db.entry(key).unwrap().occupied().unwrap().remove().unwrap();
             db.sync().unwrap();
             black_box(value.read_to_vec(0, 2).unwrap());
-            black_box(db.stats());
+            black_box(db.stats_fast());
         })
     });
+
+    c.bench_function("scan keys", |b| {
+        b.iter(|| {
+            let mut it = db.entry(b"").unwrap().into_db_iter();
+            while let Some(key) = db.next_key(&mut it).unwrap() {
+                black_box(key);
+            }
+        })
+    });
+
+    let keys = (0..1000u16)
+        .map(|i| format!("lookup {i:04}").into_bytes())
+        .collect::<Vec<_>>();
+    for key in &keys {
+        db.entry(key).unwrap().vacant().unwrap().insert().unwrap();
+    }
+    let keys = keys.iter().map(Vec::as_slice).collect::<Vec<_>>();
+
+    c.bench_function("get 1000 sorted", |b| {
+        b.iter(|| {
+            for key in &keys {
+                black_box(db.get(key).unwrap());
+            }
+        })
+    });
+
+    c.bench_function("multi get 1000 sorted", |b| {
+        b.iter(|| black_box(db.multi_get(&keys).unwrap()))
+    });
+}
+
+fn bulk_insert(c: &mut Criterion) {
+    const N: u16 = 10_000;
+
+    let mut group = c.benchmark_group("bulk insert 10000");
+    group.sample_size(10);
+    for preallocate in [false, true] {
+        let name = if preallocate {
+            "preallocated"
+        } else {
+            "growing"
+        };
+        group.bench_function(name, |b| {
+            b.iter_with_large_drop(|| {
+                let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+                let db = create(&dir.path().join("bench-bulk"));
+                if preallocate {
+                    db.preallocate(u32::from(N) * 2).unwrap();
+                }
+                for i in 0..N {
+                    let key = format!("bulk {i:05}");
+                    db.entry(key.as_bytes())
+                        .unwrap()
+                        .vacant()
+                        .unwrap()
+                        .insert()
+                        .unwrap()
+                        .write_at(0, &i.to_le_bytes())
+                        .unwrap();
+                }
+                db.sync().unwrap();
+                (db, dir)
+            })
+        });
+    }
+    group.finish();
 }
@@ -0,0 +1,50 @@
+use tempdir::TempDir;
+
+use rej::{Db, Params, NodePage};
+
+#[cfg(feature = "cipher")]
+use rej::Secret;
+
+fn main() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("counter");
+
+    #[cfg(feature = "cipher")]
+    let seed = rand::random::<[u8; 32]>();
+
+    #[cfg(feature = "cipher")]
+    let params = Params::Create {
+        secret: Secret::Pw {
+            pw: "qwerty",
+            time: 1,
+            memory: 0x100,
+        },
+        seed: seed.as_slice(),
+    };
+
+    #[cfg(not(feature = "cipher"))]
+    let params = Params::Create;
+
+    let db = Db::<NodePage>::new(&path, params).unwrap();
+
+    // the counter is the first eight bytes of the value
+    let count = |plain: &[u8]| u64::from_le_bytes(plain[..8].try_into().unwrap());
+    for word in "the quick fox jumps over the lazy dog the end".split(' ') {
+        db.entry(word)
+            .unwrap()
+            .upsert_with(
+                || 1u64.to_le_bytes().to_vec(),
+                |plain| {
+                    let new = count(plain) + 1;
+                    plain[..8].clone_from_slice(&new.to_le_bytes());
+                },
+            )
+            .unwrap();
+    }
+
+    let value = db.get(b"the").unwrap().unwrap();
+    let data = value.read_to_vec(0, 8).unwrap();
+    println!("the {}", count(&data));
+
+    db.sync().unwrap();
+}
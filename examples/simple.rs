@@ -0,0 +1,49 @@
+use tempdir::TempDir;
+
+use rej::{Db, Params, NodePage};
+
+#[cfg(feature = "cipher")]
+use rej::Secret;
+
+fn main() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("simple");
+
+    #[cfg(feature = "cipher")]
+    let seed = rand::random::<[u8; 32]>();
+
+    #[cfg(feature = "cipher")]
+    let params = Params::Create {
+        secret: Secret::Pw {
+            pw: "qwerty",
+            time: 1,
+            memory: 0x100,
+        },
+        seed: seed.as_slice(),
+    };
+
+    #[cfg(not(feature = "cipher"))]
+    let params = Params::Create;
+
+    let db = Db::<NodePage>::new(&path, params).unwrap();
+
+    // write the value before taking the log lock
+    let value = db.allocate().unwrap();
+    value.write_at(0, b"world").unwrap();
+
+    // only the pointer is spliced into the tree
+    let value = db
+        .entry(b"hello")
+        .unwrap()
+        .vacant()
+        .unwrap()
+        .insert_value(value)
+        .unwrap();
+    drop(value);
+
+    let value = db.entry(b"hello").unwrap().occupied().unwrap().into_value();
+    let data = value.read_to_vec(0, 5).unwrap();
+    println!("hello {}", String::from_utf8_lossy(&data));
+
+    db.sync().unwrap();
+}
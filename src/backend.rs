@@ -0,0 +1,330 @@
+use std::{fs, io};
+
+#[cfg(feature = "async")]
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{mpsc, Arc, Mutex},
+    task::{Context, Poll, Waker},
+    thread,
+};
+
+#[cfg(feature = "async")]
+use super::runtime::PBox;
+
+use super::utils;
+
+pub trait SyncBackend
+where
+    Self: Sized,
+{
+    /// Writes each page at its offset, returns when all writes are done
+    fn write_pages<'a, I>(&mut self, file: &fs::File, pages: I) -> io::Result<()>
+    where
+        I: Iterator<Item = (u64, &'a [u8])>;
+}
+
+/// Writes by io_uring if it is there, otherwise one by one, the cache does not care
+pub enum Backend {
+    #[cfg(all(target_os = "linux", not(feature = "no-uring")))]
+    Uring(Box<Uring>),
+    Plain(Plain),
+}
+
+impl Backend {
+    /// io_uring of `depth` entries unless `uring` is off or the kernel refuses it
+    #[cfg_attr(
+        not(all(target_os = "linux", not(feature = "no-uring"))),
+        allow(unused_variables)
+    )]
+    pub fn new(depth: u32, uring: bool) -> Self {
+        #[cfg(all(target_os = "linux", not(feature = "no-uring")))]
+        if uring {
+            match Uring::new(depth) {
+                Ok(ring) => return Backend::Uring(Box::new(ring)),
+                Err(err) => log::warn!("io_uring is unavailable: {err}, fall back to plain writes"),
+            }
+        }
+
+        Backend::Plain(Plain)
+    }
+
+    pub fn is_uring(&self) -> bool {
+        !matches!(self, Backend::Plain(_))
+    }
+}
+
+impl SyncBackend for Backend {
+    fn write_pages<'a, I>(&mut self, file: &fs::File, pages: I) -> io::Result<()>
+    where
+        I: Iterator<Item = (u64, &'a [u8])>,
+    {
+        match self {
+            #[cfg(all(target_os = "linux", not(feature = "no-uring")))]
+            Backend::Uring(ring) => ring.write_pages(file, pages),
+            Backend::Plain(plain) => plain.write_pages(file, pages),
+        }
+    }
+}
+
+#[cfg(all(target_os = "linux", not(feature = "no-uring")))]
+pub struct Uring(io_uring::IoUring);
+
+#[cfg(all(target_os = "linux", not(feature = "no-uring")))]
+impl Uring {
+    // `depth` is a power of two, the ring may be smaller if the kernel refuses it
+    fn new(mut depth: u32) -> io::Result<Self> {
+        loop {
+            match io_uring::IoUring::new(depth) {
+                Ok(ring) => return Ok(Self(ring)),
+                Err(err) if depth > 1 => {
+                    log::warn!("failed to create a ring of {depth} entries: {err}, try less");
+                    depth /= 2;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    // takes every completion, returns the first error
+    fn complete(&mut self) -> io::Result<()> {
+        let mut res = Ok(());
+        while let Some(cqe) = self.0.completion().next() {
+            if cqe.result() < 0 && res.is_ok() {
+                res = Err(io::Error::from_raw_os_error(-cqe.result()));
+            }
+        }
+        res
+    }
+}
+
+#[cfg(all(target_os = "linux", not(feature = "no-uring")))]
+impl SyncBackend for Uring {
+    fn write_pages<'a, I>(&mut self, file: &fs::File, pages: I) -> io::Result<()>
+    where
+        I: Iterator<Item = (u64, &'a [u8])>,
+    {
+        use io_uring::{opcode, types};
+        use std::os::unix::io::AsRawFd;
+
+        let fd = file.as_raw_fd();
+
+        for (offset, page) in pages {
+            let op = opcode::Write::new(types::Fd(fd), page.as_ptr(), page.len() as u32)
+                .offset(offset)
+                .build()
+                .user_data(offset);
+
+            while unsafe { self.0.submission().push(&op).is_err() } {
+                let l = self.0.submission().len();
+                self.0.submit_and_wait(l)?;
+                self.0.completion().sync();
+                self.complete()?;
+            }
+        }
+
+        let l = self.0.submission().len();
+        if l == 0 {
+            return Ok(());
+        }
+
+        self.0.submit_and_wait(l)?;
+        self.complete()
+    }
+}
+
+pub struct Plain;
+
+impl SyncBackend for Plain {
+    fn write_pages<'a, I>(&mut self, file: &fs::File, pages: I) -> io::Result<()>
+    where
+        I: Iterator<Item = (u64, &'a [u8])>,
+    {
+        for (offset, page) in pages {
+            utils::write_at(file, page, offset)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads pages on a thread of its own, the caller awaits them instead of blocking.
+/// The thread is done once the reader is dropped and every read in flight completes.
+#[cfg(feature = "async")]
+pub struct AsyncReader {
+    requests: Option<mpsc::Sender<ReadRequest>>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+#[cfg(feature = "async")]
+struct ReadRequest {
+    offset: u64,
+    page: PBox,
+    slot: Arc<ReadSlot>,
+}
+
+#[cfg(feature = "async")]
+#[derive(Default)]
+struct ReadSlot(Mutex<(Option<io::Result<PBox>>, Option<Waker>)>);
+
+#[cfg(feature = "async")]
+impl ReadSlot {
+    fn complete(&self, res: io::Result<PBox>) {
+        let mut state = self.0.lock().expect("poisoned");
+        state.0 = Some(res);
+        let waker = state.1.take();
+        drop(state);
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// The page read by `AsyncReader::read`
+#[cfg(feature = "async")]
+pub struct ReadFuture(Arc<ReadSlot>);
+
+#[cfg(feature = "async")]
+impl Future for ReadFuture {
+    type Output = io::Result<PBox>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.0 .0.lock().expect("poisoned");
+        match state.0.take() {
+            Some(res) => Poll::Ready(res),
+            None => {
+                state.1 = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncReader {
+    /// Reads by io_uring if `uring`, unless the kernel refuses it
+    pub fn spawn(file: fs::File, uring: bool) -> io::Result<Self> {
+        let (requests, rx) = mpsc::channel();
+        let handle = thread::Builder::new()
+            .name("rej-reader".to_owned())
+            .spawn(move || Self::run(file, rx, uring))?;
+
+        Ok(AsyncReader {
+            requests: Some(requests),
+            handle: Some(handle),
+        })
+    }
+
+    /// Reads the page at `offset` into `page`
+    pub fn read(&self, offset: u64, page: PBox) -> ReadFuture {
+        let slot = Arc::new(ReadSlot::default());
+        let request = ReadRequest {
+            offset,
+            page,
+            slot: slot.clone(),
+        };
+        let requests = self.requests.as_ref().expect("must be running");
+        if let Err(mpsc::SendError(request)) = requests.send(request) {
+            let err = io::Error::new(io::ErrorKind::BrokenPipe, "the reader is stopped");
+            request.slot.complete(Err(err));
+        }
+
+        ReadFuture(slot)
+    }
+
+    #[cfg_attr(
+        not(all(target_os = "linux", not(feature = "no-uring"))),
+        allow(unused_variables)
+    )]
+    fn run(file: fs::File, requests: mpsc::Receiver<ReadRequest>, uring: bool) {
+        #[cfg(all(target_os = "linux", not(feature = "no-uring")))]
+        if uring {
+            match io_uring::IoUring::new(Self::ENTRIES) {
+                Ok(ring) => return Self::run_uring(ring, file, requests),
+                Err(err) => log::warn!("io_uring is unavailable: {err}, fall back to plain reads"),
+            }
+        }
+
+        for mut request in requests {
+            let res = utils::read_at(&file, &mut *request.page, request.offset);
+            request.slot.complete(res.map(|()| request.page));
+        }
+    }
+
+    #[cfg(all(target_os = "linux", not(feature = "no-uring")))]
+    const ENTRIES: u32 = 64;
+
+    #[cfg(all(target_os = "linux", not(feature = "no-uring")))]
+    fn run_uring(
+        mut ring: io_uring::IoUring,
+        file: fs::File,
+        requests: mpsc::Receiver<ReadRequest>,
+    ) {
+        use std::{collections::BTreeMap, iter, mem, os::unix::io::AsRawFd};
+
+        use io_uring::{opcode, types};
+
+        use super::page::PAGE_SIZE;
+
+        let fd = types::Fd(file.as_raw_fd());
+        let mut in_flight = BTreeMap::new();
+        let mut id = 0u64;
+        loop {
+            // block for a request only if nothing is in flight
+            let next = if in_flight.is_empty() {
+                match requests.recv() {
+                    Ok(request) => Some(request),
+                    Err(mpsc::RecvError) => break,
+                }
+            } else {
+                None
+            };
+            let more = iter::from_fn(|| requests.try_recv().ok());
+            let room = Self::ENTRIES as usize - in_flight.len();
+            for mut request in next.into_iter().chain(more).take(room) {
+                let op = opcode::Read::new(fd, request.page.as_mut_ptr(), PAGE_SIZE as u32)
+                    .offset(request.offset)
+                    .build()
+                    .user_data(id);
+                // the page stays in place while it is in flight
+                if let Err(err) = unsafe { ring.submission().push(&op) } {
+                    request.slot.complete(Err(io::Error::other(err)));
+                    continue;
+                }
+                in_flight.insert(id, request);
+                id = id.wrapping_add(1);
+            }
+
+            if let Err(err) = ring.submit_and_wait(1) {
+                log::error!("failed to submit reads: {err}");
+                for (_, request) in mem::take(&mut in_flight) {
+                    request.slot.complete(Err(err.kind().into()));
+                }
+                continue;
+            }
+            for cqe in ring.completion() {
+                let Some(request) = in_flight.remove(&cqe.user_data()) else {
+                    continue;
+                };
+                let res = match cqe.result() {
+                    n if n < 0 => Err(io::Error::from_raw_os_error(-n)),
+                    n if n as u64 != PAGE_SIZE => Err(io::ErrorKind::UnexpectedEof.into()),
+                    _ => Ok(request.page),
+                };
+                request.slot.complete(res);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl Drop for AsyncReader {
+    fn drop(&mut self) {
+        drop(self.requests.take());
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                log::error!("the reader thread panicked");
+            }
+        }
+    }
+}
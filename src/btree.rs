@@ -1,16 +1,52 @@
+use std::{
+    cmp::Ordering,
+    io::{self, Write},
+};
+
+use thiserror::Error;
+
 use super::{
     page::{PagePtr, RawPtr},
     runtime::{PlainData, Free, AbstractIo},
     file::FileIo,
     value::MetadataPage,
-    node::{Node, R},
+    node::{Node, R, Inline, INLINE_PTR, INLINE_ZERO},
 };
 
+/// What the leaf holds for a key
+#[derive(Clone, Copy)]
+pub enum Cell {
+    Empty,
+    Page(PagePtr<MetadataPage>),
+    Inline(Inline),
+}
+
+impl Cell {
+    fn ptr<N>(self) -> Option<PagePtr<N>> {
+        match self {
+            Cell::Empty => None,
+            Cell::Page(ptr) => Some(ptr.cast()),
+            Cell::Inline(_) => PagePtr::from_raw_number(INLINE_PTR),
+        }
+    }
+
+    fn inline(self) -> Inline {
+        match self {
+            Cell::Inline(inline) => inline,
+            _ => INLINE_ZERO,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct EntryInner<N> {
     stack: Vec<Level<N>>,
     leaf: Level<N>,
+    // keys of the leaf, read at once when iterating
+    keys: Option<Vec<Vec<u8>>>,
 }
 
+#[derive(Clone)]
 struct Level<N> {
     ptr: PagePtr<N>,
     node: N,
@@ -21,22 +57,177 @@ impl<N> EntryInner<N>
 where
     N: Copy + PlainData + Node,
 {
-    pub fn new(view: &FileIo, root: PagePtr<N>, key: &[u8]) -> (Self, bool) {
-        let mut stack = Vec::with_capacity(6);
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(depth = tracing::field::Empty))
+    )]
+    pub fn new(view: &FileIo, root: PagePtr<N>, key: &[u8]) -> io::Result<(Self, bool)> {
+        let res = Self::descend(view, root, key, Vec::with_capacity(6));
+        #[cfg(feature = "tracing")]
+        if let Ok((this, _)) = &res {
+            tracing::Span::current().record("depth", this.stack.len() + 1);
+        }
+
+        res
+    }
+
+    /// Positions the iterator at the first key that is not less than `key`,
+    /// reuses the stack of the old position. On error the iterator is exhausted.
+    pub fn seek(
+        it: &mut Option<Self>,
+        view: &FileIo,
+        root: PagePtr<N>,
+        key: &[u8],
+    ) -> io::Result<()> {
+        let mut stack = it.take().map(|this| this.stack).unwrap_or_default();
+        stack.clear();
+        let (this, _) = Self::descend(view, root, key, stack)?;
+        let past_leaf = !this.has_value();
+        *it = Some(this);
+        // the position may be past the end of the leaf
+        if past_leaf {
+            Self::next(it, view)?;
+        }
+
+        Ok(())
+    }
+
+    /// Positions at `key` like `new`, the nodes of the old position are reused
+    /// down to the level where the paths split, so sorted keys read each node once
+    pub fn redescend(self, view: &FileIo, key: &[u8]) -> io::Result<(Self, bool)> {
+        let EntryInner {
+            mut stack, leaf, ..
+        } = self;
+        for depth in 0..stack.len() {
+            let level = &mut stack[depth];
+            let idx = level.node.search(view, key)?.unwrap_or_else(|idx| idx);
+            if idx != level.idx {
+                level.idx = idx;
+                let ptr = child(&level.node, level.ptr, idx)?;
+                stack.truncate(depth + 1);
+                return Self::descend(view, ptr, key, stack);
+            }
+        }
+        let pos = leaf.node.search(view, key)?;
+        let leaf = Level {
+            idx: pos.unwrap_or_else(|idx| idx),
+            ..leaf
+        };
+        let this = EntryInner {
+            stack,
+            leaf,
+            keys: None,
+        };
+
+        Ok((this, pos.is_ok()))
+    }
+
+    /// Positions at `key` like `new`, climbs from the leaf only as far as
+    /// the separators of the old path require and descends from there,
+    /// so a key near the old position reads few or no nodes
+    pub fn reposition(mut self, view: &FileIo, key: &[u8]) -> io::Result<(Self, bool)> {
+        // between the keys of the leaf read while iterating, it is searched in memory
+        if let Some(keys) = &self.keys {
+            let cmp = |k: Option<&Vec<u8>>| k.map(|k| view.compare(k, key));
+            if cmp(keys.first()).is_some_and(Ordering::is_le)
+                && cmp(keys.last()).is_some_and(Ordering::is_ge)
+            {
+                let pos = keys.binary_search_by(|k| view.compare(k, key));
+                self.leaf.idx = pos.unwrap_or_else(|idx| idx);
+                return Ok((self, pos.is_ok()));
+            }
+        }
+        // the deepest node of the path whose range holds the key, the root holds any key
+        let mut depth = self.stack.len();
+        while depth > 0 && !self.holds(view, depth, key)? {
+            depth -= 1;
+        }
+        if depth == self.stack.len() {
+            let pos = self.leaf.node.search(view, key)?;
+            self.leaf.idx = pos.unwrap_or_else(|idx| idx);
+            return Ok((self, pos.is_ok()));
+        }
+        let mut stack = self.stack;
+        stack.truncate(depth + 1);
+        let level = &mut stack[depth];
+        level.idx = level.node.search(view, key)?.unwrap_or_else(|idx| idx);
+        let ptr = child(&level.node, level.ptr, level.idx)?;
+        Self::descend(view, ptr, key, stack)
+    }
+
+    // the nearest separators above the node at `depth` bound its range
+    fn holds(&self, view: &FileIo, depth: usize, key: &[u8]) -> io::Result<bool> {
+        let above = &self.stack[..depth];
+        if let Some(level) = above.iter().rev().find(|level| level.idx > 0) {
+            let lower = level.node.read_key(view, level.idx - 1)?;
+            if view.compare(key, &lower).is_le() {
+                return Ok(false);
+            }
+        }
+        let upper = above
+            .iter()
+            .rev()
+            .find(|level| level.idx + 1 < level.node.len());
+        if let Some(level) = upper {
+            let upper = level.node.read_key(view, level.idx)?;
+            if view.compare(key, &upper).is_gt() {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn descend(
+        view: &FileIo,
+        root: PagePtr<N>,
+        key: &[u8],
+        mut stack: Vec<Level<N>>,
+    ) -> io::Result<(Self, bool)> {
         let mut ptr = root;
 
         loop {
-            let node = view.read(ptr);
+            let node = read_node(view, ptr)?;
             if node.is_leaf() {
-                let pos = node.search(view, key);
+                let pos = node.search(view, key)?;
                 let occupied = pos.is_ok();
                 let idx = pos.unwrap_or_else(|idx| idx);
                 let leaf = Level { ptr, node, idx };
-                return (EntryInner { stack, leaf }, occupied);
+                return Ok((
+                    EntryInner {
+                        stack,
+                        leaf,
+                        keys: None,
+                    },
+                    occupied,
+                ));
             } else {
-                let idx = node.search(view, key).unwrap_or_else(|idx| idx);
+                let idx = node.search(view, key)?.unwrap_or_else(|idx| idx);
                 stack.push(Level { ptr, node, idx });
-                ptr = node.child(idx).unwrap_or_else(|| panic!("{idx}"));
+                ptr = child(&node, ptr, idx)?;
+            }
+        }
+    }
+
+    /// Positioned at the smallest key, `None` if the tree is empty
+    pub fn first(view: &FileIo, root: PagePtr<N>) -> io::Result<Option<Self>> {
+        let mut stack = Vec::with_capacity(6);
+        let mut ptr = root;
+
+        loop {
+            let node = read_node(view, ptr)?;
+            let idx = 0;
+            if node.is_leaf() {
+                let leaf = Level { ptr, node, idx };
+                let this = EntryInner {
+                    stack,
+                    leaf,
+                    keys: None,
+                };
+                return Ok(this.has_value().then_some(this));
+            } else {
+                stack.push(Level { ptr, node, idx });
+                ptr = child(&node, ptr, idx)?;
             }
         }
     }
@@ -45,79 +236,243 @@ where
         self.leaf.idx < self.leaf.node.len()
     }
 
-    pub fn next(it: &mut Option<Self>, view: &impl AbstractIo) {
+    /// Estimates the number of keys from `self` up to `end`, both positioned in the same tree.
+    /// Exact if both are in the same leaf, otherwise every subtree between them
+    /// is assumed to be as full as the nodes of both paths at its level.
+    pub fn approximate_distance(&self, end: &Self) -> u64 {
+        let height = self.stack.len();
+        let split = self
+            .stack
+            .iter()
+            .zip(&end.stack)
+            .position(|(a, b)| a.idx != b.idx);
+        let Some(split) = split else {
+            return end.leaf.idx.saturating_sub(self.leaf.idx) as u64;
+        };
+
+        let fanout = |left: &Level<N>, right: &Level<N>| {
+            ((left.node.len() + right.node.len()) as u64 / 2).max(1)
+        };
+        // going up, the size of the subtree of a child of the node at `depth`
+        let mut subtree = fanout(&self.leaf, &end.leaf);
+        let mut count = (self.leaf.node.len() - self.leaf.idx + end.leaf.idx) as u64;
+        for depth in (split + 1..height).rev() {
+            let left = &self.stack[depth];
+            let right = &end.stack[depth];
+            let children = (left.node.len() - left.idx - 1 + right.idx) as u64;
+            count += children * subtree;
+            subtree = subtree.saturating_mul(fanout(left, right));
+        }
+        let between = end.stack[split]
+            .idx
+            .saturating_sub(self.stack[split].idx + 1);
+        count += between as u64 * subtree;
+
+        count
+    }
+
+    /// On error the position stays the same
+    pub fn next(it: &mut Option<Self>, view: &FileIo) -> io::Result<()> {
         let Some(this) = it else {
-            return;
+            return Ok(());
         };
 
         if this.leaf.idx + 1 < this.leaf.node.len() {
             this.leaf.idx += 1;
         } else {
-            while let Some(mut current) = this.stack.pop() {
+            let mut stack = this.stack.clone();
+            while let Some(mut current) = stack.pop() {
                 if current.idx + 1 < current.node.len() {
                     current.idx += 1;
-                    this.stack.push(current);
+                    stack.push(current);
                     break;
                 }
             }
-            let Some(last) = this.stack.last() else {
+            let Some(last) = stack.last() else {
                 *it = None;
-                return;
+                return Ok(());
             };
-            let mut ptr = last.node.child(last.idx).expect("must not fail");
+            let mut ptr = child(&last.node, last.ptr, last.idx)?;
 
             loop {
-                let node = view.read(ptr);
+                let node = read_node(view, ptr)?;
                 if node.is_leaf() {
                     let idx = 0;
                     this.leaf = Level { ptr, node, idx };
+                    this.keys = None;
+                    this.stack = stack;
                     break;
                 } else {
                     let idx = 0;
-                    this.stack.push(Level { ptr, node, idx });
-                    ptr = node.child(idx).unwrap_or_else(|| panic!("{idx}"));
+                    stack.push(Level { ptr, node, idx });
+                    ptr = child(&node, ptr, idx)?;
                 }
             }
         }
+
+        Ok(())
+    }
+
+    /// Positioned at the largest key, `None` if the tree is empty
+    pub fn last(view: &FileIo, root: PagePtr<N>) -> io::Result<Option<Self>> {
+        let this = Self::rightmost(view, root, Vec::with_capacity(6))?;
+        Ok(this.has_value().then_some(this))
     }
 
+    fn rightmost(view: &FileIo, root: PagePtr<N>, mut stack: Vec<Level<N>>) -> io::Result<Self> {
+        let mut ptr = root;
+
+        loop {
+            let node = read_node(view, ptr)?;
+            let idx = node.len().saturating_sub(1);
+            if node.is_leaf() {
+                let leaf = Level { ptr, node, idx };
+                return Ok(EntryInner {
+                    stack,
+                    leaf,
+                    keys: None,
+                });
+            } else {
+                stack.push(Level { ptr, node, idx });
+                ptr = child(&node, ptr, idx)?;
+            }
+        }
+    }
+
+    /// Like `next`, but backward. On error the position stays the same
+    pub fn prev(it: &mut Option<Self>, view: &FileIo) -> io::Result<()> {
+        let Some(this) = it else {
+            return Ok(());
+        };
+
+        if this.leaf.idx > 0 {
+            this.leaf.idx -= 1;
+        } else {
+            let mut stack = this.stack.clone();
+            while let Some(mut current) = stack.pop() {
+                if current.idx > 0 {
+                    current.idx -= 1;
+                    stack.push(current);
+                    break;
+                }
+            }
+            let Some(last) = stack.last() else {
+                *it = None;
+                return Ok(());
+            };
+            let ptr = child(&last.node, last.ptr, last.idx)?;
+            *this = Self::rightmost(view, ptr, stack)?;
+        }
+
+        Ok(())
+    }
+
+    /// Moves `n` positions forward without reading the keys,
+    /// returns how many positions it did move before the end
+    pub fn advance(it: &mut Option<Self>, view: &FileIo, n: usize) -> io::Result<usize> {
+        let mut skipped = 0;
+        while skipped < n {
+            let Some(this) = it else {
+                break;
+            };
+            let left = this.leaf.node.len().saturating_sub(this.leaf.idx);
+            if n - skipped < left {
+                this.leaf.idx += n - skipped;
+                skipped = n;
+            } else {
+                // the rest of the leaf, stand on its last entry and go to the next leaf
+                this.leaf.idx = this.leaf.node.len().saturating_sub(1);
+                Self::next(it, view)?;
+                skipped += left;
+            }
+        }
+
+        Ok(skipped)
+    }
+
+    /// The value page, `None` if the cell is empty or the value is inline
     pub fn meta(&self) -> Option<PagePtr<MetadataPage>> {
-        self.leaf.node.child(self.leaf.idx).map(PagePtr::cast)
+        let ptr = (*self.leaf.node.child(self.leaf.idx))?;
+        (ptr.raw_number() != INLINE_PTR).then(|| ptr.cast())
     }
 
-    pub fn set_meta(&mut self, meta: PagePtr<MetadataPage>) {
-        *self.leaf.node.child_mut(self.leaf.idx) = Some(meta.cast());
+    pub fn set_meta(&mut self, meta: Option<PagePtr<MetadataPage>>) {
+        *self.leaf.node.child_mut(self.leaf.idx) = meta.map(PagePtr::cast);
     }
 
-    pub fn key(&self, view: &FileIo) -> Vec<u8> {
+    /// An inline value is read from the leaf and its tail pages
+    pub fn cell(&self, view: &FileIo) -> io::Result<Cell> {
+        let idx = self.leaf.idx;
+        let Some(ptr) = *self.leaf.node.child(idx) else {
+            return Ok(Cell::Empty);
+        };
+        if ptr.raw_number() == INLINE_PTR {
+            if let Some(inline) = self.leaf.node.inline(view, idx)? {
+                return Ok(Cell::Inline(inline));
+            }
+        }
+
+        Ok(Cell::Page(ptr.cast()))
+    }
+
+    /// The node must have room for inline values
+    pub fn set_inline(&mut self, rt: R<'_>, inline: &Inline) -> io::Result<()> {
+        let idx = self.leaf.idx;
+        *self.leaf.node.child_mut(idx) = Cell::Inline(*inline).ptr();
+        self.leaf.node.set_inline(rt, idx, inline)
+    }
+
+    pub fn key(&self, view: &FileIo) -> io::Result<Vec<u8>> {
         self.leaf.node.read_key(view, self.leaf.idx)
     }
 
-    pub fn insert(
-        self,
-        mut rt: R<'_>,
-        meta: Option<PagePtr<MetadataPage>>,
-        key: &[u8],
-    ) -> PagePtr<N> {
+    /// Like `key`, but reads every key of the leaf at once
+    /// and serves the next keys of the same leaf from memory
+    pub fn cached_key(&mut self, view: &FileIo) -> io::Result<Vec<u8>> {
+        self.cached_key_ref(view).map(<[u8]>::to_vec)
+    }
+
+    /// Borrows the key from the keys of the leaf, reads them once per leaf
+    pub fn cached_key_ref(&mut self, view: &FileIo) -> io::Result<&[u8]> {
+        let keys = match &mut self.keys {
+            Some(keys) => keys,
+            keys => keys.insert(self.leaf.node.read_keys(view)?),
+        };
+
+        Ok(&keys[self.leaf.idx])
+    }
+
+    pub fn insert(self, mut rt: R<'_>, cell: Cell, key: &[u8]) -> io::Result<PagePtr<N>> {
         let EntryInner {
             mut leaf,
             mut stack,
+            ..
         } = self;
 
-        leaf.node.realloc_keys(rt.reborrow());
-        let mut split =
-            leaf.node
-                .insert(rt.reborrow(), meta.map(PagePtr::cast), leaf.idx, key, false);
+        leaf.node.realloc_keys(rt.reborrow())?;
+        let mut split = leaf.node.insert(
+            rt.reborrow(),
+            cell.ptr(),
+            cell.inline(),
+            leaf.idx,
+            key,
+            false,
+        );
         rt.set(&mut leaf.ptr, leaf.node);
 
         let mut ptr = leaf.ptr;
         while let Some(mut level) = stack.pop() {
             *level.node.child_mut(level.idx) = Some(ptr);
             if let Some((key, neighbor)) = split {
-                level.node.realloc_keys(rt.reborrow());
-                split = level
-                    .node
-                    .insert(rt.reborrow(), Some(neighbor), level.idx, &key, true);
+                level.node.realloc_keys(rt.reborrow())?;
+                split = level.node.insert(
+                    rt.reborrow(),
+                    Some(neighbor),
+                    INLINE_ZERO,
+                    level.idx,
+                    &key,
+                    true,
+                );
             }
             rt.set(&mut level.ptr, level.node);
 
@@ -127,25 +482,46 @@ where
         if let Some((key, neighbor)) = split {
             let mut root = N::empty();
             root.append_child(ptr);
-            root.insert(rt.reborrow(), Some(neighbor), 0, &key, true);
+            root.insert(rt.reborrow(), Some(neighbor), INLINE_ZERO, 0, &key, true);
 
             let parent_ptr = rt.create();
             *rt.mutate(parent_ptr) = root;
             ptr = parent_ptr;
         }
 
+        Ok(ptr)
+    }
+
+    /// Writes the leaf and the path to it, keys stay the same
+    pub fn update(self, mut rt: R<'_>) -> PagePtr<N> {
+        let EntryInner {
+            mut leaf,
+            mut stack,
+            ..
+        } = self;
+
+        rt.set(&mut leaf.ptr, leaf.node);
+
+        let mut ptr = leaf.ptr;
+        while let Some(mut level) = stack.pop() {
+            *level.node.child_mut(level.idx) = Some(ptr);
+            rt.set(&mut level.ptr, level.node);
+            ptr = level.ptr;
+        }
+
         ptr
     }
 
-    pub fn remove(self, mut rt: R) -> PagePtr<N> {
+    pub fn remove(self, mut rt: R) -> io::Result<PagePtr<N>> {
         let EntryInner {
             mut leaf,
             mut stack,
+            ..
         } = self;
 
         let mut underflow = !leaf.node.can_donate();
-        leaf.node.realloc_keys(rt.reborrow());
-        let (_, _) = leaf.node.remove(rt.reborrow(), leaf.idx, false);
+        leaf.node.realloc_keys(rt.reborrow())?;
+        let (_, _, _) = leaf.node.remove(rt.reborrow(), leaf.idx, false);
         rt.set(&mut leaf.ptr, leaf.node);
 
         let mut prev = leaf.node;
@@ -153,39 +529,46 @@ where
 
         while let Some(mut level) = stack.pop() {
             if underflow {
-                level.node.realloc_keys(rt.reborrow());
-
-                let mut left = (level.idx > 0).then(|| {
-                    let ptr = level
-                        .node
-                        .child(level.idx - 1)
-                        .expect("left neighbor always present");
-                    NodeWithPtr {
-                        node: rt.io.read(ptr),
-                        ptr,
-                    }
-                });
-                let mut right = (level.idx < level.node.len() - 1)
+                level.node.realloc_keys(rt.reborrow())?;
+
+                let mut left = (level.idx > 0)
                     .then(|| {
-                        level.node.child(level.idx + 1).map(|ptr| NodeWithPtr {
-                            node: rt.io.read(ptr),
-                            ptr,
-                        })
+                        let ptr = level
+                            .node
+                            .child(level.idx - 1)
+                            .expect("left neighbor always present");
+                        let node = rt.io.read(ptr).and_then(|node| consistent(node, ptr));
+                        node.map(|node| NodeWithPtr { node, ptr })
+                    })
+                    .transpose()?;
+                let mut right = (level.idx < level.node.len() - 1)
+                    .then(|| *level.node.child(level.idx + 1))
+                    .flatten()
+                    .map(|ptr| {
+                        let node = rt.io.read(ptr).and_then(|node| consistent(node, ptr));
+                        node.map(|node| NodeWithPtr { node, ptr })
                     })
-                    .flatten();
+                    .transpose()?;
 
                 // for early return
                 #[allow(clippy::never_loop)]
                 loop {
                     if let Some(donor) = &mut left {
-                        if donor.can_donate() && right.as_ref().map_or(true, |r| r.le(donor)) {
+                        if donor.can_donate() && right.as_ref().is_none_or(|r| r.le(donor)) {
                             log::debug!("donate left");
 
-                            donor.node.realloc_keys(rt.reborrow());
-                            let (donated_ptr, donated_key) =
+                            donor.node.realloc_keys(rt.reborrow())?;
+                            let (donated_ptr, donated_inline, donated_key) =
                                 donor.node.remove(rt.reborrow(), donor.node.len() - 1, true);
 
-                            prev.insert(rt.reborrow(), donated_ptr, 0, &donated_key, false);
+                            prev.insert(
+                                rt.reborrow(),
+                                donated_ptr,
+                                donated_inline,
+                                0,
+                                &donated_key,
+                                false,
+                            );
                             *rt.mutate(ptr) = prev;
                             rt.set(&mut donor.ptr, donor.node);
 
@@ -206,13 +589,14 @@ where
                         if donor.can_donate() {
                             log::debug!("donate right");
 
-                            donor.node.realloc_keys(rt.reborrow());
-                            let (donated_ptr, donated_key) =
+                            donor.node.realloc_keys(rt.reborrow())?;
+                            let (donated_ptr, donated_inline, donated_key) =
                                 donor.node.remove(rt.reborrow(), 0, false);
 
                             prev.insert(
                                 rt.reborrow(),
                                 donated_ptr,
+                                donated_inline,
                                 N::M / 2 - 1,
                                 &donated_key,
                                 false,
@@ -230,13 +614,13 @@ where
                     }
 
                     if let Some(neighbor) = &mut left {
-                        if right.as_ref().map_or(true, |r| r.gt(neighbor)) {
+                        if right.as_ref().is_none_or(|r| r.gt(neighbor)) {
                             log::debug!("merge left");
                             underflow = !level.node.can_donate();
-                            neighbor.node.realloc_keys(rt.reborrow());
+                            neighbor.node.realloc_keys(rt.reborrow())?;
                             level.idx -= 1;
-                            let (_, key) = level.node.remove(rt.reborrow(), level.idx, false);
-                            neighbor.node.merge(&prev, rt.reborrow(), &key, false);
+                            let (_, _, key) = level.node.remove(rt.reborrow(), level.idx, false);
+                            neighbor.node.merge(&prev, rt.reborrow(), &key, false)?;
                             prev.free(rt.reborrow());
 
                             rt.free.free(ptr);
@@ -250,13 +634,15 @@ where
                     if let Some(neighbor) = right {
                         underflow = !level.node.can_donate();
                         log::debug!("merge right");
-                        let (neighbor_ptr, _) =
+                        // the key of the neighbor bounds the merged node,
+                        // the last key of the neighbor does not if it is a branch
+                        let (neighbor_ptr, _, neighbor_key) =
                             level.node.remove(rt.reborrow(), level.idx + 1, false);
                         let neighbor_ptr = neighbor_ptr.expect("must be there");
                         let key = level.node.get_key(rt.reborrow(), level.idx);
                         assert_eq!(neighbor_ptr, neighbor.ptr, "suppose to remove the neighbor");
-                        let last_key = prev.merge(&neighbor.node, rt.reborrow(), &key, true);
-                        level.node.set_key(rt.reborrow(), level.idx, &last_key);
+                        prev.merge(&neighbor.node, rt.reborrow(), &key, true)?;
+                        level.node.set_key(rt.reborrow(), level.idx, &neighbor_key);
                         neighbor.node.free(rt.reborrow());
                         rt.free.free(neighbor.ptr);
                         *rt.mutate(ptr) = prev;
@@ -279,7 +665,7 @@ where
             }
         }
 
-        ptr
+        Ok(ptr)
     }
 }
 
@@ -315,67 +701,110 @@ where
     }
 }
 
-// for debug
-#[cfg(test)]
-pub fn print<N, K, D>(rt: R<'_>, ptr: PagePtr<N>, k: K, old: bool)
+/// The page cannot be a node of the tree, the file is corrupted
+#[derive(Debug, Error)]
+#[error("bad node at page {0}")]
+pub struct BadNode(pub u32);
+
+impl BadNode {
+    /// Whether `err` is made of `BadNode`
+    pub fn is(err: &io::Error) -> bool {
+        err.get_ref().is_some_and(|inner| inner.is::<Self>())
+    }
+}
+
+impl From<BadNode> for io::Error {
+    fn from(err: BadNode) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// A node has the key twice, the tree is corrupted
+#[derive(Debug, Error)]
+#[error("the key {} is in the node twice", hex::encode(.0))]
+pub struct DuplicateKey(pub Vec<u8>);
+
+impl DuplicateKey {
+    /// The key, if `err` is made of `DuplicateKey`
+    pub fn key(err: &io::Error) -> Option<&[u8]> {
+        let inner = err.get_ref()?.downcast_ref::<Self>()?;
+        Some(&inner.0)
+    }
+}
+
+impl From<DuplicateKey> for io::Error {
+    fn from(err: DuplicateKey) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// Reads the node, fails with `BadNode` if the page is past the end
+/// or does not look like a node. A node that passes the check does not panic
+/// the tree, the panics left are bugs of the tree, not of the file.
+pub fn read_node<N>(view: &FileIo, ptr: PagePtr<N>) -> io::Result<N>
 where
     N: Copy + PlainData + Node,
-    K: Fn(&[u8]) -> D,
-    D: std::fmt::Display,
 {
-    // this is sad that I cannot debug B-Tree without using already existing B-Tree
-    use std::collections::BTreeMap;
-
-    let mut nodes = BTreeMap::new();
-    let mut edges = Vec::new();
-
-    fn print_inner<N, K, D>(
-        mut rt: R<'_>,
-        ptr: PagePtr<N>,
-        nodes: &mut BTreeMap<u32, String>,
-        edges: &mut Vec<(u32, u32)>,
-        k: &K,
-        old: bool,
-    ) where
-        N: Copy + PlainData + Node,
-        K: Fn(&[u8]) -> D,
-        D: std::fmt::Display,
-    {
-        let page = rt.io.read(ptr);
-        let node_text = (0..(page.len() - usize::from(!page.is_leaf())))
-            .map(|idx| {
-                if old {
-                    page.read_key(rt.io, idx)
-                } else {
-                    page.get_key(rt.reborrow(), idx)
-                }
-            })
-            .map(|key| format!("{}", k(&key)))
-            .collect::<Vec<_>>()
-            .join("|");
-        nodes.insert(ptr.raw_number(), format!("\"{node_text}\""));
-
-        for n in (0..page.len()).map(|idx| page.child(idx)) {
-            let child_ptr = n.map(PagePtr::raw_number).unwrap_or_default();
-            edges.push((ptr.raw_number(), child_ptr));
-            if !page.is_leaf() {
-                print_inner(rt.reborrow(), n.expect("BUG"), nodes, edges, k, old);
-            }
+    let n = ptr.raw_number();
+    if n >= view.pages() {
+        return Err(BadNode(n).into());
+    }
+    consistent(view.read(ptr)?, ptr)
+}
+
+fn consistent<N>(node: N, ptr: PagePtr<N>) -> io::Result<N>
+where
+    N: Node,
+{
+    if node.is_consistent() {
+        Ok(node)
+    } else {
+        Err(BadNode(ptr.raw_number()).into())
+    }
+}
+
+/// The child at `idx` of the stem at `ptr`
+fn child<N>(node: &N, ptr: PagePtr<N>, idx: usize) -> io::Result<PagePtr<N>>
+where
+    N: Node,
+{
+    node.child(idx)
+        .ok_or_else(|| BadNode(ptr.raw_number()).into())
+}
+
+/// Writes the tree in graphviz format, nodes are labeled with their keys,
+/// leaves point to the pages of their values, inline values are not shown
+pub fn graphviz<N>(
+    file: &FileIo,
+    ptr: PagePtr<N>,
+    w: &mut impl Write,
+    k: &impl Fn(&[u8]) -> String,
+) -> io::Result<()>
+where
+    N: Copy + PlainData + Node,
+{
+    fn escape(s: &str) -> String {
+        s.replace('\\', "\\\\").replace('"', "\\\"")
+    }
+
+    let node = file.read(ptr)?;
+    let n = ptr.raw_number();
+    let keys = (0..(node.len() - usize::from(!node.is_leaf())))
+        .map(|idx| node.read_key(file, idx).map(|key| k(&key)))
+        .collect::<io::Result<Vec<_>>>()?
+        .join("|");
+    writeln!(w, "    n{n} [label=\"{}\"];", escape(&keys))?;
+
+    let children = (0..node.len()).filter_map(|idx| *node.child(idx));
+    for child in children.filter(|child| child.raw_number() != INLINE_PTR) {
+        let c = child.raw_number();
+        if node.is_leaf() {
+            writeln!(w, "    n{c} [shape=box, label=\"{c}\"];")?;
+        } else {
+            graphviz(file, child, w, k)?;
         }
+        writeln!(w, "    n{n} -> n{c};")?;
     }
 
-    print_inner(rt, ptr, &mut nodes, &mut edges, &k, old);
-
-    let edges = edges
-        .into_iter()
-        .map(|(b, e)| {
-            format!(
-                "{} -> {}",
-                nodes[&b],
-                nodes.get(&e).cloned().unwrap_or(e.to_string())
-            )
-        })
-        .collect::<Vec<_>>()
-        .join("\n");
-    log::debug!("digraph {{\n{edges}\n}}\n");
+    Ok(())
 }
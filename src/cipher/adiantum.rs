@@ -1,4 +1,4 @@
-use std::{fs, io};
+use std::{fs, io, path::Path};
 
 use aligned_vec::{avec, AVec, ConstAlign};
 
@@ -12,11 +12,35 @@ use {
 
 use super::utils;
 
-pub struct Cipher(adiantum::Cipher<XChaCha12, Aes256>);
+// none if the database is bare
+pub struct Cipher(Option<adiantum::Cipher<XChaCha12, Aes256>>);
 
 pub enum Params<'a> {
-    Create { secret: Secret<'a>, seed: &'a [u8] },
-    Open { secret: Secret<'a> },
+    Create {
+        secret: Secret<'a>,
+        seed: &'a [u8],
+    },
+    Open {
+        secret: Secret<'a>,
+    },
+    /// Neither the header nor the encryption, the pages start at the beginning
+    /// of the file. For storage that is already encrypted, e.g. by LUKS.
+    Bare {
+        create: bool,
+    },
+    /// Same as `Create`, but the header is written to a file of its own at `header`,
+    /// the pages start at the beginning of the file. Without the header
+    /// the pages cannot be decrypted, `Db::crypt_shred` overwrites only it.
+    CreateDetached {
+        secret: Secret<'a>,
+        seed: &'a [u8],
+        header: &'a Path,
+    },
+    /// Opens the database created by `CreateDetached` with its header
+    OpenDetached {
+        secret: Secret<'a>,
+        header: &'a Path,
+    },
 }
 
 impl Params<'_> {
@@ -43,12 +67,53 @@ impl Params<'_> {
     }
 
     pub fn create(&self) -> bool {
-        matches!(self, &Self::Create { .. })
+        matches!(
+            self,
+            &Self::Create { .. } | &Self::Bare { create: true } | &Self::CreateDetached { .. }
+        )
+    }
+
+    /// Bytes before the first page of the file
+    pub fn header_size(&self) -> usize {
+        match self {
+            Self::Create { .. } | Self::Open { .. } => CRYPTO_SIZE,
+            _ => 0,
+        }
+    }
+
+    /// The file of the header, if it is not at the start of the database
+    pub fn header_path(&self) -> Option<&Path> {
+        match self {
+            Self::CreateDetached { header, .. } | Self::OpenDetached { header, .. } => Some(header),
+            _ => None,
+        }
+    }
+
+    /// The params to open the database these params create, the seed is dropped
+    pub fn into_open(self) -> Self {
+        match self {
+            Self::Create { secret, .. } => Self::Open { secret },
+            Self::Bare { .. } => Self::Bare { create: false },
+            Self::CreateDetached { secret, header, .. } => Self::OpenDetached { secret, header },
+            params => params,
+        }
     }
 }
 
+/// The secret is borrowed, the crate keeps no copy of it,
+/// so clearing the password or the key is up to the owner.
+/// The cost of the password hash is stored in the header, opening uses it
+/// instead of the given one. A header written before it was stored
+/// opens only with the cost given in `Pw`.
 pub enum Secret<'a> {
-    Pw { pw: &'a str, time: u32, memory: u32 },
+    Pw {
+        pw: &'a str,
+        time: u32,
+        memory: u32,
+    },
+    /// The password with the cost stored in the header,
+    /// creating the database uses the default cost of argon2
+    Password(&'a str),
     Key(&'a [u8; 32]),
 }
 
@@ -66,63 +131,151 @@ pub enum CipherError {
     InvalidComplexity,
     #[error("key blob is too short")]
     BadKeyBlob,
+    #[error("the header does not store the cost of the password hash, give it with `Secret::Pw`")]
+    NoStoredCost,
+    #[error("unknown key derivation {0} in the header")]
+    UnknownKdf(u8),
+    #[error("the database is not encrypted, there is no header to shred")]
+    NotEncrypted,
 }
 
 pub const CRYPTO_SIZE: usize = 1 << 20;
 
-fn password_aead(secret: Secret<'_>, salt: [u8; 16]) -> Result<ChaCha20Poly1305, CipherError> {
-    use argon2::{password_hash::SaltString, ParamsBuilder, PasswordHasher, Argon2, Algorithm, Version};
-    use chacha20poly1305::aead::generic_array::GenericArray;
+// the last bytes of the header, in plain, but authenticated with the key blob
+const KDF_SIZE: usize = 0x10;
+const KDF_MAGIC: [u8; 4] = *b"rejk";
+
+// how the key of the blob is derived
+#[derive(Clone, Copy)]
+enum Kdf {
+    // written before the cost was stored, the whole rest of the header is the blob
+    Legacy,
+    Raw,
+    Argon2id { time: u32, memory: u32 },
+}
+
+impl Kdf {
+    const RAW: u8 = 0;
+    const ARGON2ID: u8 = 1;
+
+    fn of(secret: &Secret<'_>) -> Self {
+        use argon2::Params;
+
+        match *secret {
+            Secret::Pw { time, memory, .. } => Kdf::Argon2id { time, memory },
+            Secret::Password(_) => Kdf::Argon2id {
+                time: Params::DEFAULT_T_COST,
+                memory: Params::DEFAULT_M_COST,
+            },
+            Secret::Key(_) => Kdf::Raw,
+        }
+    }
+
+    fn parse(bytes: &[u8; KDF_SIZE]) -> Result<Self, CipherError> {
+        let (magic, rest) = bytes.split_first_chunk::<4>().expect("cannot fail");
+        let (id, rest) = rest.split_first_chunk::<4>().expect("cannot fail");
+        let (time, memory) = rest.split_first_chunk::<4>().expect("cannot fail");
+        if *magic != KDF_MAGIC || id[1..] != [0; 3] {
+            return Ok(Kdf::Legacy);
+        }
+        let time = u32::from_le_bytes(*time);
+        let memory = u32::from_le_bytes(memory.try_into().expect("cannot fail"));
+        match id[0] {
+            Self::RAW => Ok(Kdf::Raw),
+            Self::ARGON2ID => Ok(Kdf::Argon2id { time, memory }),
+            id => Err(CipherError::UnknownKdf(id)),
+        }
+    }
+
+    fn to_bytes(self) -> [u8; KDF_SIZE] {
+        let (id, time, memory) = match self {
+            Kdf::Legacy => unreachable!("never written"),
+            Kdf::Raw => (Self::RAW, 0, 0),
+            Kdf::Argon2id { time, memory } => (Self::ARGON2ID, time, memory),
+        };
+        let mut bytes = [0; KDF_SIZE];
+        bytes[..4].copy_from_slice(&KDF_MAGIC);
+        bytes[4] = id;
+        bytes[8..12].copy_from_slice(&time.to_le_bytes());
+        bytes[12..].copy_from_slice(&memory.to_le_bytes());
+        bytes
+    }
 
-    let hash;
-    let key = match secret {
-        Secret::Pw { pw, time, memory } => {
-            let salt = SaltString::encode_b64(&salt).expect("length should be good");
-            let mut param_builder = ParamsBuilder::new();
-            param_builder.m_cost(memory);
-            param_builder.t_cost(time);
-
-            let hasher = Argon2::new(
-                Algorithm::Argon2id,
-                Version::V0x13,
-                param_builder
-                    .build()
-                    .map_err(|_| CipherError::InvalidComplexity)?,
-            );
-            hash = hasher
-                .hash_password(pw.as_bytes(), &salt)
-                .map_err(|_| CipherError::BadPassword)?
-                .hash
-                .ok_or(CipherError::BadPassword)?;
-            if hash.len() != 32 {
-                return Err(CipherError::BadPassword);
+    // the blob and the bytes of the derivation it is authenticated with
+    fn split(self, buf: &mut [u8]) -> (&mut [u8], &[u8]) {
+        match self {
+            Kdf::Legacy => (buf, &[]),
+            _ => {
+                let (blob, kdf) = buf.split_at_mut(buf.len() - KDF_SIZE);
+                (blob, kdf)
             }
-            hash.as_bytes()
         }
-        Secret::Key(key) => key,
+    }
+}
+
+// the cipher clears its key on drop, the hash is cleared here
+fn password_aead(
+    secret: Secret<'_>,
+    kdf: Kdf,
+    salt: [u8; 16],
+) -> Result<ChaCha20Poly1305, CipherError> {
+    use argon2::{ParamsBuilder, Argon2, Algorithm, Version};
+    use chacha20poly1305::aead::generic_array::GenericArray;
+
+    let (pw, time, memory) = match (secret, kdf) {
+        (Secret::Key(key), _) => {
+            return Ok(ChaCha20Poly1305::new(GenericArray::from_slice(key)));
+        }
+        (Secret::Pw { .. } | Secret::Password(_), Kdf::Raw) => {
+            return Err(CipherError::WrongSecret);
+        }
+        (Secret::Pw { pw, time, memory }, Kdf::Legacy) => (pw, time, memory),
+        (Secret::Password(_), Kdf::Legacy) => return Err(CipherError::NoStoredCost),
+        (Secret::Pw { pw, .. } | Secret::Password(pw), Kdf::Argon2id { time, memory }) => {
+            (pw, time, memory)
+        }
     };
-    let key = GenericArray::from_slice(key);
 
-    Ok(ChaCha20Poly1305::new(key))
+    let mut param_builder = ParamsBuilder::new();
+    param_builder.m_cost(memory);
+    param_builder.t_cost(time);
+
+    let hasher = Argon2::new(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        param_builder
+            .build()
+            .map_err(|_| CipherError::InvalidComplexity)?,
+    );
+    // the same hash as the password hash string with the salt in base64 had
+    let mut hash = [0; 32];
+    hasher
+        .hash_password_into(pw.as_bytes(), &salt, &mut hash)
+        .map_err(|_| CipherError::BadPassword)?;
+    let aead = ChaCha20Poly1305::new(GenericArray::from_slice(&hash));
+    hash.zeroize();
+
+    Ok(aead)
 }
 
 impl Cipher {
     pub fn new(file: &fs::File, params: Params<'_>) -> Result<Self, CipherError> {
         match params {
-            Params::Create { secret, seed } => {
+            Params::Create { secret, seed } | Params::CreateDetached { secret, seed, .. } => {
                 let (cipher, blob) = Self::setup(secret, seed)?;
                 utils::write_at(file, &blob, 0)?;
                 Ok(cipher)
             }
-            Params::Open { secret } => {
+            Params::Open { secret } | Params::OpenDetached { secret, .. } => {
                 let mut blob = avec![[4096]| 0; CRYPTO_SIZE];
                 utils::read_at(file, &mut blob, 0)?;
-                Self::open(blob, secret)
+                Self::open(&mut blob, secret)
             }
+            Params::Bare { .. } => Ok(Self(None)),
         }
     }
 
-    fn setup(
+    pub fn setup(
         secret: Secret<'_>,
         seed: &[u8],
     ) -> Result<(Self, AVec<u8, ConstAlign<4096>>), CipherError> {
@@ -141,43 +294,64 @@ impl Cipher {
         let mut full_buf = avec![[4096]| 0; CRYPTO_SIZE];
         rng.read(&mut full_buf);
 
+        let kdf = Kdf::of(&secret);
         let (salt, buf) = full_buf
             .split_first_chunk_mut::<0x10>()
             .expect("cannot fail");
         let (tag, buf) = buf.split_first_chunk_mut::<0x10>().expect("cannot fail");
+        let (_, kdf_bytes) = buf.split_at_mut(buf.len() - KDF_SIZE);
+        kdf_bytes.copy_from_slice(&kdf.to_bytes());
+        let (buf, kdf_bytes) = kdf.split(buf);
 
         let hkdf = Hkdf::<Sha3_256>::new(Some(&*salt), &*buf);
         let mut main_key = [0; 32];
         hkdf.expand(b"main_key", &mut main_key)
             .expect("cannot fail");
-        let cipher = Self(adiantum::Cipher::new(GenericArray::from_slice(&main_key)));
+        let cipher = Self(Some(adiantum::Cipher::new(GenericArray::from_slice(
+            &main_key,
+        ))));
         main_key.zeroize();
 
-        *tag = password_aead(secret, *salt)?
-            .encrypt_in_place_detached(&GenericArray::default(), b"main_blob", buf)
+        // the blob is the key material until it is encrypted
+        let aead = match password_aead(secret, kdf, *salt) {
+            Ok(aead) => aead,
+            Err(err) => {
+                buf.zeroize();
+                return Err(err);
+            }
+        };
+        *tag = aead
+            .encrypt_in_place_detached(
+                &GenericArray::default(),
+                &[b"main_blob".as_slice(), kdf_bytes].concat(),
+                buf,
+            )
             .expect("cannot fail")
             .into();
 
         Ok((cipher, full_buf))
     }
 
-    fn open(
-        mut full_buf: AVec<u8, ConstAlign<4096>>,
-        secret: Secret<'_>,
-    ) -> Result<Cipher, CipherError> {
+    /// Decrypts the blob to derive the key, then clears all but the salt and the tag
+    pub fn open(full_buf: &mut [u8], secret: Secret<'_>) -> Result<Cipher, CipherError> {
         use chacha20poly1305::aead::{AeadInPlace, generic_array::GenericArray};
         use sha3::Sha3_256;
         use hkdf::Hkdf;
 
+        if full_buf.len() < 0x20 + KDF_SIZE {
+            return Err(CipherError::BadKeyBlob);
+        }
         let (salt, buf) = full_buf
             .split_first_chunk_mut::<0x10>()
             .expect("cannot fail");
         let (tag, buf) = buf.split_first_chunk_mut::<0x10>().expect("cannot fail");
+        let kdf = Kdf::parse(buf.last_chunk().expect("cannot fail"))?;
+        let (buf, kdf_bytes) = kdf.split(buf);
 
-        password_aead(secret, *salt)?
+        password_aead(secret, kdf, *salt)?
             .decrypt_in_place_detached(
                 &GenericArray::default(),
-                b"main_blob",
+                &[b"main_blob".as_slice(), kdf_bytes].concat(),
                 buf,
                 GenericArray::from_slice(&*tag),
             )
@@ -187,19 +361,30 @@ impl Cipher {
         let mut main_key = [0; 32];
         hkdf.expand(b"main_key", &mut main_key)
             .expect("cannot fail");
-        let cipher = Self(adiantum::Cipher::new(GenericArray::from_slice(&main_key)));
+        let cipher = Self(Some(adiantum::Cipher::new(GenericArray::from_slice(
+            &main_key,
+        ))));
         main_key.zeroize();
-        buf.zeroize();
+        full_buf[0x20..].zeroize();
 
         Ok(cipher)
     }
 
+    /// `false` if the database is bare
+    pub fn is_active(&self) -> bool {
+        self.0.is_some()
+    }
+
     pub fn decrypt(&self, page: &mut [u8], n: u32) {
-        self.0.decrypt(page, &n.to_le_bytes());
+        if let Some(cipher) = &self.0 {
+            cipher.decrypt(page, &n.to_le_bytes());
+        }
     }
 
     pub fn encrypt(&self, page: &mut [u8], n: u32) {
-        self.0.encrypt(page, &n.to_le_bytes());
+        if let Some(cipher) = &self.0 {
+            cipher.encrypt(page, &n.to_le_bytes());
+        }
     }
 }
 
@@ -213,6 +398,7 @@ pub fn shred(seed: &[u8]) -> Result<Vec<u8>, CipherError> {
         return Err(CipherError::BadSeed);
     }
 
+    // the bytes go to the disk as they are, there is nothing to clear
     let mut rng = Shake256::default().chain(seed).finalize_xof();
     let mut full_buf = vec![0; CRYPTO_SIZE];
     rng.read(&mut full_buf);
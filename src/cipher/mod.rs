@@ -4,9 +4,9 @@ use super::utils;
 #[cfg(feature = "cipher")]
 mod adiantum;
 #[cfg(feature = "cipher")]
-pub use self::adiantum::{Secret, Params, Cipher, CipherError, CRYPTO_SIZE, shred};
+pub use self::adiantum::{Secret, Params, Cipher, CipherError, shred};
 
 #[cfg(not(feature = "cipher"))]
 mod plain;
 #[cfg(not(feature = "cipher"))]
-pub use self::plain::{Params, Cipher, CipherError, CRYPTO_SIZE, shred};
+pub use self::plain::{Params, Cipher, CipherError, shred};
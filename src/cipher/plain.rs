@@ -1,4 +1,4 @@
-use std::{fs, io};
+use std::{fs, io, path::Path};
 
 use thiserror::Error;
 
@@ -22,12 +22,29 @@ impl Params {
     pub fn create(&self) -> bool {
         matches!(self, &Self::Create)
     }
+
+    /// Bytes before the first page of the file
+    pub fn header_size(&self) -> usize {
+        CRYPTO_SIZE
+    }
+
+    /// The file of the header, if it is not at the start of the database
+    pub fn header_path(&self) -> Option<&Path> {
+        None
+    }
+
+    /// The params to open the database these params create
+    pub fn into_open(self) -> Self {
+        Self::Open
+    }
 }
 
 #[derive(Debug, Error)]
 pub enum CipherError {
     #[error("io: {0}")]
     Io(#[from] io::Error),
+    #[error("the database is not encrypted, there is no header to shred")]
+    NotEncrypted,
 }
 
 pub const CRYPTO_SIZE: usize = 0;
@@ -38,6 +55,10 @@ impl Cipher {
         Ok(Self)
     }
 
+    pub fn is_active(&self) -> bool {
+        false
+    }
+
     pub fn decrypt(&self, page: &mut [u8], n: u32) {
         let _ = (page, n);
     }
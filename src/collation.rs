@@ -0,0 +1,27 @@
+use std::cmp::Ordering;
+
+/// Order of the keys, the same for every tree of the database.
+/// The identifier is kept in the log, the database opens only with the collation
+/// it was created with, otherwise the trees would be searched in a wrong order.
+/// The prefix scans expect the keys sharing a prefix to be adjacent.
+pub trait Collation: Send + Sync {
+    /// Identifies the order, zero is reserved for `Bytewise`
+    fn id(&self) -> u64;
+
+    /// Must be a total order, and the same for the same identifier forever
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering;
+}
+
+/// Keys are ordered lexicographically byte by byte, a key goes before any longer key
+/// it is a prefix of. The default, the nodes compare the keys in place.
+pub struct Bytewise;
+
+impl Collation for Bytewise {
+    fn id(&self) -> u64 {
+        0
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+}
@@ -0,0 +1,294 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io,
+};
+
+use super::{
+    page::{PagePtr, RawPtr},
+    runtime::{PlainData, AbstractIo, PageKind},
+    file::FileIo,
+    node::{Node, INLINE_PTR},
+    wal::{Wal, WalLock, WalState, WalError, FreelistCache, TreesPage},
+};
+
+// a node of the tree and every page it refers to
+struct Branch {
+    ptr: u32,
+    refs: Vec<u32>,
+}
+
+/// Moves the pages of the tree to the beginning of the file and truncates it,
+/// returns the number of pages the file did shrink by.
+/// Moved pages and their ancestors are written into free pages, like any other
+/// change of the tree, so a crash leaves either the old tree or the new one.
+pub fn compact<N>(lock: &mut WalLock<'_>, file: &FileIo) -> Result<u32, WalError>
+where
+    N: Copy + PlainData + Node,
+{
+    if lock.is_pinned() {
+        return Err(WalError::Pinned);
+    }
+    if lock.is_allocated() {
+        return Err(WalError::Allocated);
+    }
+    lock.reclaim(file)?;
+
+    let root = lock.current_head::<N>();
+    let trees = lock.trees();
+    let size = lock.size();
+
+    let mut nodes = vec![];
+    let mut values = BTreeSet::new();
+    collect_all::<N>(lock, file, &mut nodes, &mut values)?;
+    let live = nodes
+        .iter()
+        .flat_map(|branch| branch.refs.iter().copied())
+        .chain([root.raw_number()])
+        .chain(trees.map(PagePtr::raw_number))
+        .collect::<BTreeSet<_>>();
+
+    // keep room for the freelist cache, otherwise the file grows right back
+    let mut target = Wal::SIZE + live.len() as u32 + FreelistCache::SIZE;
+    let (moved, free) = loop {
+        if target >= size {
+            return Ok(0);
+        }
+        let moved = moved_pages(&nodes, &live, target);
+        let free = (Wal::SIZE..target)
+            .filter(|n| !live.contains(n))
+            .collect::<Vec<_>>();
+        if free.len() >= moved.len() {
+            break (moved, free);
+        }
+        target += (moved.len() - free.len()) as u32;
+    };
+
+    // pages of the persistent freelist are about to be overwritten
+    lock.detach_freelist(file)?;
+
+    let map = moved.into_iter().zip(free).collect::<BTreeMap<_, _>>();
+    let relocated = |n| map.get(&n).copied().unwrap_or(n);
+    for branch in &nodes {
+        let Some(&new) = map.get(&branch.ptr) else {
+            continue;
+        };
+        if trees.map(PagePtr::raw_number) == Some(branch.ptr) {
+            let mut page = file.read::<TreesPage>(PagePtr::from_raw_number(branch.ptr))?;
+            page.relocate(relocated);
+            file.write(PagePtr::from_raw_number(new), PageKind::Tree, page)?;
+        } else {
+            let mut node = file.read::<N>(PagePtr::from_raw_number(branch.ptr))?;
+            node.relocate(relocated);
+            file.write(PagePtr::from_raw_number(new), PageKind::Tree, node)?;
+        }
+    }
+    let nodes = nodes
+        .iter()
+        .map(|branch| branch.ptr)
+        .collect::<BTreeSet<_>>();
+    for (&old, &new) in map.iter().filter(|(old, _)| !nodes.contains(old)) {
+        let kind = if values.contains(&old) {
+            PageKind::Data
+        } else {
+            PageKind::Tree
+        };
+        file.write_page(new, kind, file.read_page(old)?)?;
+    }
+
+    let head = PagePtr::<N>::from_raw_number(relocated(root.raw_number()))
+        .expect("page number must not be zero");
+    let trees = trees.and_then(|ptr| PagePtr::from_raw_number(relocated(ptr.raw_number())));
+    let live = live.into_iter().map(relocated).collect::<BTreeSet<_>>();
+    let free = (Wal::SIZE..target).filter(|n| !live.contains(n));
+    lock.install(file, head, trees, target, free)?;
+    file.values_moved();
+
+    log::info!("did compact database from {size} to {target} pages");
+
+    Ok(size - target)
+}
+
+/// Replaces the main tree with an empty leaf and drops the other trees,
+/// frees every page of the old ones.
+pub fn clear<N>(lock: &mut WalLock<'_>, file: &FileIo) -> Result<(), WalError>
+where
+    N: Copy + PlainData + Node,
+{
+    lock.reclaim(file)?;
+
+    let root = lock.current_head::<N>();
+    let trees = lock.trees();
+    let mut nodes = vec![];
+    let mut values = BTreeSet::new();
+    collect_all::<N>(lock, file, &mut nodes, &mut values)?;
+    let old = nodes
+        .iter()
+        .flat_map(|branch| branch.refs.iter().copied())
+        .chain([root.raw_number()])
+        .chain(trees.map(PagePtr::raw_number))
+        .map(|n| {
+            let kind = if values.contains(&n) {
+                PageKind::Data
+            } else {
+                PageKind::Tree
+            };
+            (kind, n)
+        });
+    lock.clear(file, old)?;
+
+    log::info!("did clear {} nodes", nodes.len());
+
+    Ok(())
+}
+
+/// Verifies that every page past the log is either used or free, but not both.
+/// Used pages are reachable from the head, or the orphan.
+pub fn check<N>(lock: &WalState, file: &FileIo) -> Result<(), WalError>
+where
+    N: Copy + PlainData + Node,
+{
+    // pages released while pinned are only in memory
+    if lock.is_pinned() {
+        return Err(WalError::Pinned);
+    }
+
+    let (used, _) = used_pages::<N>(lock, file)?;
+    let free = lock.free_pages(file)?;
+
+    let size = lock.size();
+    let total = size - Wal::SIZE;
+    let distinct = free.iter().copied().collect::<BTreeSet<_>>();
+    let consistent = distinct.len() == free.len()
+        && used.is_disjoint(&distinct)
+        && used.len() + free.len() == total as usize
+        && used
+            .iter()
+            .chain(&distinct)
+            .all(|n| (Wal::SIZE..size).contains(n));
+    if consistent {
+        Ok(())
+    } else {
+        Err(WalError::Inconsistent {
+            total,
+            used: used.len() as u32,
+            free: free.len() as u32,
+        })
+    }
+}
+
+/// Frees every page that is neither used nor free, a crash while a snapshot
+/// is pinned leaves the pages released meanwhile so, see `WalState::was_pinned`.
+pub fn reclaim_lost<N>(lock: &mut WalLock<'_>, file: &FileIo) -> Result<(), WalError>
+where
+    N: Copy + PlainData + Node,
+{
+    let (used, _) = used_pages::<N>(lock, file)?;
+    let free = lock.free_pages(file)?.into_iter().collect::<BTreeSet<_>>();
+    let lost = (Wal::SIZE..lock.size())
+        .filter(|n| !used.contains(n) && !free.contains(n))
+        .collect();
+
+    lock.free_lost(file, lost)
+}
+
+/// The pages of the trees and the pages of the values, the orphan included
+pub fn page_kinds<N>(lock: &WalState, file: &FileIo) -> io::Result<(u32, u32)>
+where
+    N: Copy + PlainData + Node,
+{
+    let (used, values) = used_pages::<N>(lock, file)?;
+    let data = values.len() + usize::from(lock.orphan().is_some());
+
+    Ok(((used.len() - data) as u32, data as u32))
+}
+
+// every page reachable from the head, the trees page or the orphan, and the values among them
+fn used_pages<N>(lock: &WalState, file: &FileIo) -> io::Result<(BTreeSet<u32>, BTreeSet<u32>)>
+where
+    N: Copy + PlainData + Node,
+{
+    let root = lock.current_head::<N>();
+    let mut nodes = vec![];
+    let mut values = BTreeSet::new();
+    collect_all::<N>(lock, file, &mut nodes, &mut values)?;
+    let used = nodes
+        .iter()
+        .flat_map(|branch| branch.refs.iter().copied())
+        .chain([root.raw_number()])
+        .chain(lock.trees().map(PagePtr::raw_number))
+        .chain(lock.orphan().map(PagePtr::raw_number))
+        .collect::<BTreeSet<_>>();
+
+    Ok((used, values))
+}
+
+// every tree, the trees page goes last as a node that refers to their roots
+fn collect_all<N>(
+    lock: &WalState,
+    file: &FileIo,
+    nodes: &mut Vec<Branch>,
+    values: &mut BTreeSet<u32>,
+) -> io::Result<()>
+where
+    N: Copy + PlainData + Node,
+{
+    collect::<N>(file, lock.current_head(), nodes, values)?;
+    if let Some(ptr) = lock.trees() {
+        let mut refs = vec![];
+        for (_, root) in file.read(ptr)?.roots() {
+            collect::<N>(file, root.cast(), nodes, values)?;
+            refs.push(root.raw_number());
+        }
+        nodes.push(Branch {
+            ptr: ptr.raw_number(),
+            refs,
+        });
+    }
+
+    Ok(())
+}
+
+// nodes go in post-order, after every node they refer to
+fn collect<N>(
+    file: &FileIo,
+    ptr: PagePtr<N>,
+    nodes: &mut Vec<Branch>,
+    values: &mut BTreeSet<u32>,
+) -> io::Result<()>
+where
+    N: Copy + PlainData + Node,
+{
+    let mut node = file.read(ptr)?;
+    let mut refs = vec![];
+    node.relocate(|n| {
+        refs.push(n);
+        n
+    });
+    let children = (0..node.len()).filter_map(|idx| *node.child(idx));
+    if node.is_leaf() {
+        let pages = children.map(PagePtr::raw_number);
+        values.extend(pages.filter(|n| *n != INLINE_PTR));
+    } else {
+        for child in children {
+            collect(file, child, nodes, values)?;
+        }
+    }
+    nodes.push(Branch {
+        ptr: ptr.raw_number(),
+        refs,
+    });
+
+    Ok(())
+}
+
+// live pages past the target and every node on the path to them
+fn moved_pages(nodes: &[Branch], live: &BTreeSet<u32>, target: u32) -> BTreeSet<u32> {
+    let mut moved = live.range(target..).copied().collect::<BTreeSet<_>>();
+    for branch in nodes {
+        if branch.refs.iter().any(|n| moved.contains(n)) {
+            moved.insert(branch.ptr);
+        }
+    }
+
+    moved
+}
@@ -1,26 +1,49 @@
-use std::{io, marker::PhantomData, mem, path::Path};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Read, Write},
+    marker::PhantomData,
+    mem, panic,
+    ops::{Bound, ControlFlow, Deref, Range, RangeBounds},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex, Weak,
+    },
+    thread,
+    time::{Duration, SystemTime},
+};
 
 use thiserror::Error;
 
 use super::{
-    page::{PagePtr, RawPtr},
-    runtime::{AbstractIo, Rt, Alloc},
+    utils::{Checked, read_array},
+    page::{PagePtr, RawPtr, PAGE_SIZE},
+    runtime::{AbstractIo, Rt},
     cipher::{CipherError, Params},
-    runtime::{PlainData, PageKind},
-    file::FileIo,
-    wal::{Wal, WalLock, WalError, DbStats},
+    runtime::{PlainData, PageKind, Free},
+    file::{FileIo, FileError, IoOptions, PageView, DatabaseFull},
+    wal::{
+        Wal, WalLock, BadFreelist, WalReadLock, WalError, DbStats, RecoveryReport, BatchPages,
+        TreesPage,
+    },
+    metrics::DbMetrics,
     value::MetadataPage,
-    node::Node,
-    btree,
+    node::{Node, NodeCPage, FixedKey, R, Inline, INLINE_PTR, INLINE_ZERO},
+    replica::{self, ChangeSet},
+    collation::{Collation, Bytewise},
+    btree::{self, Cell},
+    compact,
 };
 
-pub enum Entry<'a, N, K> {
+pub enum Entry<'a, N> {
     Occupied(Occupied<'a, N>),
     Empty(EmptyCell<'a, N>),
-    Vacant(Vacant<'a, N, K>),
+    Vacant(Vacant<'a, N>),
 }
 
-impl<'a, N, K> Entry<'a, N, K>
+impl<'a, N> Entry<'a, N>
 where
     N: Copy + PlainData + Node,
 {
@@ -28,15 +51,24 @@ where
         match self {
             Self::Occupied(v) => {
                 let inner = Some(v.inner);
-                DbIterator { inner }
+                DbIterator {
+                    inner,
+                    tree: v.tree,
+                }
             }
             Self::Empty(v) => {
                 let inner = Some(v.inner);
-                DbIterator { inner }
+                DbIterator {
+                    inner,
+                    tree: v.tree,
+                }
             }
             Self::Vacant(v) => {
-                let inner = v.inner.has_value().then_some(v.inner);
-                DbIterator { inner }
+                let inner = v.inner.filter(btree::EntryInner::has_value);
+                DbIterator {
+                    inner,
+                    tree: v.tree,
+                }
             }
         }
     }
@@ -57,81 +89,313 @@ where
         }
     }
 
-    pub fn vacant(self) -> Option<Vacant<'a, N, K>> {
+    pub fn vacant(self) -> Option<Vacant<'a, N>> {
         if let Self::Vacant(v) = self {
             Some(v)
         } else {
             None
         }
     }
+
+    /// Runs `f` with the value if the entry is occupied, the entry stays locked,
+    /// so an inline value must not be written in `f`, see `Value::write_at`
+    pub fn and_modify(self, f: impl FnOnce(Value<'a>)) -> Self {
+        if let Self::Occupied(v) = &self {
+            f(v.as_value());
+        }
+        self
+    }
+
+    /// Inserts the bytes made by `init` if there is no value, otherwise `update`
+    /// changes the bytes of the value, all `Value::capacity` of them.
+    /// The bytes go to a new page in the same transaction as the tree,
+    /// or to the leaf if they fit, see `Value::INLINE`,
+    /// so a crash leaves either the old value or the new one.
+    /// An expired value and an empty cell count as no value.
+    pub fn upsert_with<F, G>(self, init: F, update: G) -> Result<(), DbError>
+    where
+        F: FnOnce() -> Vec<u8>,
+        G: FnOnce(&mut Vec<u8>),
+    {
+        let (inner, tree, mut lock, file, page) = match self {
+            Self::Occupied(v) => {
+                let mut page = v.as_value().metadata()?;
+                let expiry = v.file.expiry();
+                let plain = if page.is_expired(expiry, v.now) {
+                    page = MetadataPage::empty();
+                    init()
+                } else {
+                    let mut plain = page.plain(expiry).to_vec();
+                    update(&mut plain);
+                    plain
+                };
+                Value::check_bounds(v.file, 0, plain.len())?;
+                page.set_plain(&plain, expiry);
+                (v.inner, v.tree, v.lock, v.file, page)
+            }
+            Self::Empty(v) => {
+                let plain = init();
+                Value::check_bounds(v.file, 0, plain.len())?;
+                (v.inner, v.tree, v.lock, v.file, MetadataPage::new(&plain))
+            }
+            Self::Vacant(v) => {
+                let plain = init();
+                Value::check_bounds(v.file, 0, plain.len())?;
+                return v.insert_plain(&plain);
+            }
+        };
+
+        store_value(inner, tree, &mut lock, file, page).map(drop)
+    }
 }
 
 pub struct Occupied<'a, N> {
     inner: btree::EntryInner<N>,
+    tree: u8,
     lock: WalLock<'a>,
+    wal: &'a Wal,
     file: &'a FileIo,
+    bytes: Vec<u8>,
+    // read once, an inline value is in the tail pages as well
+    cell: Cell,
+    now: SystemTime,
 }
 
+/// The key is present, but has no value page, `Vacant::insert_empty` makes one.
+/// `Db::get` returns `None` as if there is no key, but `Db::next` yields the key.
 pub struct EmptyCell<'a, N> {
     inner: btree::EntryInner<N>,
+    tree: u8,
     lock: WalLock<'a>,
+    wal: &'a Wal,
     file: &'a FileIo,
+    now: SystemTime,
 }
 
-pub struct Vacant<'a, N, K> {
-    inner: btree::EntryInner<N>,
+pub struct Vacant<'a, N> {
+    // none if the tree does not exist yet, the insert creates it
+    inner: Option<btree::EntryInner<N>>,
+    tree: u8,
     lock: WalLock<'a>,
     file: &'a FileIo,
-    bytes: K,
+    bytes: Vec<u8>,
+    now: SystemTime,
 }
 
-#[derive(Clone, Copy)]
 pub struct Value<'a> {
     ptr: PagePtr<MetadataPage>,
     file: &'a FileIo,
+    // set if the value is allocated by `Db::allocate` and not inserted yet
+    allocated: Option<&'a Wal>,
+    // set if the value is in the leaf, `ptr` means nothing then
+    inline: Option<Box<InlineValue<'a>>>,
+    // `FileIo::freed` when the value is found
+    freed: u64,
+}
+
+// the value is found by its key every time it is written
+struct InlineValue<'a> {
+    wal: &'a Wal,
+    tree: u8,
+    key: Vec<u8>,
+    // as it was last seen through this handle
+    place: Mutex<Place>,
+    // `write_inline` of the node type of the database
+    write: WriteInline,
+}
+
+type WriteInline = fn(
+    &InlineValue<'_>,
+    &FileIo,
+    &mut dyn FnMut(&mut MetadataPage) -> bool,
+) -> Result<(Place, bool), DbError>;
+
+#[derive(Clone, Copy)]
+enum Place {
+    Page(PagePtr<MetadataPage>),
+    Inline(Inline),
 }
 
 pub struct DbIterator<N> {
     inner: Option<btree::EntryInner<N>>,
+    // inline values are found by the key in this tree
+    tree: u8,
+}
+
+/// The values of a key inserted by `Db::insert_dup`, in the order of insertion,
+/// each with its number. Made by `Db::entry_dup`.
+pub struct DupIter<'a, N> {
+    db: &'a Db<N>,
+    it: DbIterator<N>,
+    key: Vec<u8>,
+}
+
+/// The keys of a range of the main tree with the bytes of their values,
+/// owned, so they outlive the database. Made by `Db::scan_values`.
+pub struct ScanValues<'a, N> {
+    db: &'a Db<N>,
+    it: DbIterator<N>,
+    end: Bound<Vec<u8>>,
+}
+
+/// A position in the main tree that moves both ways and seeks again
+/// from where it stands, see `Db::cursor`
+pub struct Cursor<'a, N> {
+    db: &'a Db<N>,
+    // `None` when off the tree
+    inner: Option<btree::EntryInner<N>>,
+    // the record the position was found in, its nodes are stale after another record
+    seq: u64,
 }
 
-impl<'a, N, K> Vacant<'a, N, K>
+impl<'a, N> Vacant<'a, N>
 where
     N: Copy + PlainData + Node,
-    K: AsRef<[u8]>,
 {
+    /// The key currently at the insertion point, `None` if it is past the end
+    pub fn insertion_point_key(&self) -> Result<Option<Vec<u8>>, DbError> {
+        let inner = self.inner.as_ref().filter(|inner| inner.has_value());
+        Ok(inner.map(|inner| inner.key(self.file)).transpose()?)
+    }
+
     pub fn insert_empty(self) -> Result<(), DbError> {
-        self.insert_inner::<false>().map(drop)
+        self.insert_inner::<false>(&[]).map(drop)
     }
 
     pub fn insert(self) -> Result<Value<'a>, DbError> {
-        self.insert_inner::<true>().map(Option::unwrap)
+        self.insert_inner::<true>(&[]).map(Option::unwrap)
+    }
+
+    /// Inserts the key with `buf` at the start of its value. The page of the value
+    /// is not reused by anyone while the key refers to it, but after `insert`
+    /// readers see zeros until `write_at`, and if a sync of another writer
+    /// or of the background thread comes in between, a crash keeps the zeros.
+    /// Here the bytes are written with the key by one log record.
+    pub fn insert_with(self, buf: &[u8]) -> Result<Value<'a>, DbError> {
+        Value::check_bounds(self.file, 0, buf.len())?;
+        self.insert_inner::<true>(buf).map(Option::unwrap)
+    }
+
+    /// Inserts a value that expires after `ttl`, fails with `DbError::NoExpiry`
+    /// if the database is created by an older version
+    pub fn insert_with_ttl(self, ttl: Duration) -> Result<Value<'a>, DbError> {
+        if !self.file.expiry() {
+            return Err(DbError::NoExpiry);
+        }
+        let now = self.now;
+        let value = self.insert()?;
+        value.set_expires(Some(now + ttl))?;
+
+        Ok(value)
+    }
+
+    /// Inserts a value of at most `Value::INLINE` bytes, it is kept in the leaf.
+    /// A tree of `NodeCPage` has no room for it, so there it goes to a page.
+    pub fn insert_small(self, bytes: &[u8]) -> Result<(), DbError> {
+        if bytes.len() > Value::INLINE {
+            return Err(DbError::OutOfBounds);
+        }
+        self.insert_plain(bytes)
     }
 
-    fn insert_inner<const METADATA: bool>(self) -> Result<Option<Value<'a>>, DbError> {
+    /// Inserts the value allocated by `Db::allocate` of the same database,
+    /// only the pointer is written while the log is locked.
+    pub fn insert_value(self, mut value: Value<'a>) -> Result<Value<'a>, DbError> {
         let Vacant {
             inner,
+            tree,
             mut lock,
             file,
             bytes,
+            ..
         } = self;
         let wal_lock = &mut lock;
 
-        let (alloc, free) = wal_lock.cache_mut();
-        let mut storage = Default::default();
-        let mut rt = Rt::new(alloc, free, file, &mut storage);
+        if value.allocated.is_none() || wal_lock.allocated() != Some(value.ptr) {
+            return Err(DbError::NotAllocated);
+        }
 
-        let ptr = METADATA.then(|| {
-            let ptr = rt.create();
-            *rt.mutate::<MetadataPage>(ptr) = MetadataPage::empty();
-            ptr
-        });
+        let inner = vacant_inner(inner, tree, wal_lock, file, &bytes)?;
+        let new_head = transaction(wal_lock, file, |rt| {
+            inner.insert(rt, Cell::Page(value.ptr), &bytes)
+        })?;
+        wal_lock.publish(value.ptr);
+        value.allocated = None;
+        value.freed = file.freed();
+        wal_lock.new_tree_head(file, tree, new_head, None)?;
+        if tree == Wal::MAIN {
+            wal_lock.touch(bytes);
+        }
+        file.counters().insert(1);
 
-        let new_head = inner.insert(rt.reborrow(), ptr, bytes.as_ref());
-        rt.flush()?;
-        wal_lock.new_head(self.file, new_head, None)?;
+        Ok(value)
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "insert", skip_all, fields(tree = self.tree, value = METADATA))
+    )]
+    fn insert_inner<const METADATA: bool>(
+        self,
+        plain: &[u8],
+    ) -> Result<Option<Value<'a>>, DbError> {
+        let file = self.file;
+        let cell = self.insert_cell(|rt| {
+            if METADATA {
+                let ptr = rt.create();
+                *rt.mutate::<MetadataPage>(ptr) = MetadataPage::new(plain);
+                Cell::Page(ptr)
+            } else {
+                Cell::Empty
+            }
+        })?;
+
+        Ok(match cell {
+            Cell::Page(ptr) => Some(Value {
+                ptr,
+                file,
+                allocated: None,
+                inline: None,
+                freed: file.freed(),
+            }),
+            _ => None,
+        })
+    }
+
+    // the bytes go to the leaf if they fit, see `Value::INLINE`
+    fn insert_plain(self, plain: &[u8]) -> Result<(), DbError> {
+        match inline_of::<N>(plain) {
+            Some(inline) => self.insert_cell(|_| Cell::Inline(inline)).map(drop),
+            None => self.insert_inner::<true>(plain).map(drop),
+        }
+    }
+
+    fn insert_cell(self, f: impl FnOnce(&mut R<'_>) -> Cell) -> Result<Cell, DbError> {
+        let Vacant {
+            inner,
+            tree,
+            mut lock,
+            file,
+            bytes,
+            ..
+        } = self;
+        let wal_lock = &mut lock;
+
+        let inner = vacant_inner(inner, tree, wal_lock, file, &bytes)?;
+        let writes = file.writes();
+        let (new_head, cell) = transaction(wal_lock, file, |mut rt| {
+            let cell = f(&mut rt);
+            let new_head = inner.insert(rt, cell, &bytes)?;
+            Ok((new_head, cell))
+        })?;
+        wal_lock.new_tree_head(file, tree, new_head, None)?;
+        wal_lock.op_written(file.writes().wrapping_sub(writes));
+        if tree == Wal::MAIN {
+            wal_lock.touch(bytes);
+        }
+        file.counters().insert(1);
 
-        Ok(ptr.map(|ptr| Value { ptr, file }))
+        Ok(cell)
     }
 }
 
@@ -139,28 +403,98 @@ impl<'a, N> EmptyCell<'a, N>
 where
     N: Copy + PlainData + Node,
 {
-    pub fn occupy(mut self) -> Occupied<'a, N> {
-        let (alloc, _) = self.lock.cache_mut();
-        self.inner.set_meta(alloc.alloc());
-        let EmptyCell { inner, lock, file } = self;
-        Occupied { inner, lock, file }
+    pub fn key(&self) -> Result<Vec<u8>, DbError> {
+        Ok(self.inner.key(self.file)?)
     }
 
-    pub fn remove(self) -> Result<(), DbError> {
+    /// Removes the key, the entry stays locked and can be inserted again
+    pub fn into_vacant(self) -> Result<Vacant<'a, N>, DbError> {
         let EmptyCell {
             inner,
+            tree,
             mut lock,
             file,
+            now,
+            ..
         } = self;
         let wal_lock = &mut lock;
+        let key = inner.key(file)?;
 
-        let (alloc, free) = wal_lock.cache_mut();
-        let mut storage = Default::default();
-        let mut rt = Rt::new(alloc, free, file, &mut storage);
-        let new_head = inner.remove(rt.reborrow());
-        rt.flush()?;
+        let new_head = transaction(wal_lock, file, |rt| inner.remove(rt))?;
+        wal_lock.new_tree_head(file, tree, new_head, None)?;
+        if tree == Wal::MAIN {
+            wal_lock.touch(key.clone());
+        }
+        file.counters().remove(1);
+
+        let (inner, _) = btree::EntryInner::new(file, new_head, &key)?;
+        Ok(Vacant {
+            inner: Some(inner),
+            tree,
+            lock,
+            file,
+            bytes: key,
+            now,
+        })
+    }
+
+    /// Puts a new empty value in the cell
+    pub fn occupy(self) -> Result<Occupied<'a, N>, DbError> {
+        let EmptyCell {
+            mut inner,
+            tree,
+            mut lock,
+            wal,
+            file,
+            now,
+        } = self;
+        let wal_lock = &mut lock;
+        let key = inner.key(file)?;
+
+        let new_head = transaction(wal_lock, file, |mut rt| {
+            let ptr = rt.create();
+            *rt.mutate::<MetadataPage>(ptr) = MetadataPage::empty();
+            inner.set_meta(Some(ptr));
+            Ok(inner.update(rt))
+        })?;
+        wal_lock.new_tree_head(file, tree, new_head, None)?;
+        if tree == Wal::MAIN {
+            wal_lock.touch(key.clone());
+        }
+
+        let (inner, _) = btree::EntryInner::new(file, new_head, &key)?;
+        Ok(Occupied {
+            cell: inner.cell(file)?,
+            inner,
+            tree,
+            lock,
+            wal,
+            file,
+            bytes: key,
+            now,
+        })
+    }
+
+    /// Removes the key, there is no value to return
+    pub fn remove(self) -> Result<(), DbError> {
+        let EmptyCell {
+            inner,
+            tree,
+            mut lock,
+            file,
+            ..
+        } = self;
+        let wal_lock = &mut lock;
+        let key = changed_key(tree, &inner, file)?;
 
-        wal_lock.new_head(file, new_head, None)?;
+        let writes = file.writes();
+        let new_head = transaction(wal_lock, file, |rt| inner.remove(rt))?;
+        wal_lock.new_tree_head(file, tree, new_head, None)?;
+        wal_lock.op_written(file.writes().wrapping_sub(writes));
+        if let Some(key) = key {
+            wal_lock.touch(key);
+        }
+        file.counters().remove(1);
 
         Ok(())
     }
@@ -170,179 +504,3069 @@ impl<'a, N> Occupied<'a, N>
 where
     N: Copy + PlainData + Node,
 {
-    pub fn into_value(self) -> Value<'a> {
-        self.as_value()
+    pub fn key(&self) -> Result<Vec<u8>, DbError> {
+        Ok(self.inner.key(self.file)?)
     }
 
-    pub fn as_value(&self) -> Value<'a> {
-        let ptr = self.inner.meta().expect("must be metadata");
-        let Occupied { file, .. } = self;
-        Value { ptr, file }
+    pub fn into_value(self) -> Value<'a> {
+        self.as_value()
     }
 
-    pub fn remove(self) -> Result<Value<'a>, DbError> {
+    /// Frees the value page if any, but keeps the key, so the entry becomes an empty cell
+    pub fn into_empty(self) -> Result<EmptyCell<'a, N>, DbError> {
         let Occupied {
-            inner,
+            mut inner,
+            tree,
             mut lock,
+            wal,
             file,
+            now,
+            ..
         } = self;
         let wal_lock = &mut lock;
+        let key = inner.key(file)?;
 
-        let ptr = inner.meta().expect("must be metadata");
-        let old = mem::replace(wal_lock.orphan_mut(), Some(ptr.cast()));
-
-        let (alloc, free) = wal_lock.cache_mut();
-        let mut storage = Default::default();
-        let mut rt = Rt::new(alloc, free, file, &mut storage);
-        let new_head = inner.remove(rt.reborrow());
-        rt.flush()?;
+        let ptr = inner.meta();
+        let new_head = transaction(wal_lock, file, |rt| {
+            if let Some(ptr) = ptr {
+                rt.free.free(ptr);
+            }
+            inner.set_meta(None);
+            Ok(inner.update(rt))
+        })?;
+        if let Some(ptr) = ptr {
+            file.value_freed(ptr.raw_number());
+        }
+        wal_lock.new_tree_head(file, tree, new_head, None)?;
+        if tree == Wal::MAIN {
+            wal_lock.touch(key.clone());
+        }
 
-        wal_lock.new_head(file, new_head, old)?;
+        let (inner, _) = btree::EntryInner::new(file, new_head, &key)?;
+        Ok(EmptyCell {
+            inner,
+            tree,
+            lock,
+            wal,
+            file,
+            now,
+        })
+    }
 
-        Ok(Value { ptr, file })
+    /// The value is still here, but `Db::get` does not see it
+    /// and `Db::purge_expired` removes it
+    pub fn is_expired(&self) -> Result<bool, DbError> {
+        let expiry = self.file.expiry();
+        Ok(self.as_value().metadata()?.is_expired(expiry, self.now))
     }
-}
 
-impl Value<'_> {
-    pub fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), DbError> {
-        let page = self.file.read_page(self.ptr.raw_number())?;
-        buf.clone_from_slice(&page[offset..][..buf.len()]);
+    /// Sets the value to expire after `ttl` from now, `None` to never expire.
+    /// An inline value never expires, it moves to a page of its own first.
+    pub fn set_ttl(&mut self, ttl: Option<Duration>) -> Result<(), DbError> {
+        let time = ttl.map(|ttl| self.now + ttl);
+        let Cell::Inline(inline) = self.cell else {
+            return self.as_value().set_expires(time);
+        };
+        if time.is_none() {
+            return Ok(());
+        }
+        if !self.file.expiry() {
+            return Err(DbError::NoExpiry);
+        }
+
+        let mut page = MetadataPage::new(&inline);
+        page.set_expires(time);
+        let inner = self.inner.clone();
+        let (new_head, _) = store_value(inner, self.tree, &mut self.lock, self.file, page)?;
+        (self.inner, _) = btree::EntryInner::new(self.file, new_head, &self.bytes)?;
+        self.cell = self.inner.cell(self.file)?;
 
         Ok(())
     }
 
-    pub fn read_to_vec(&self, offset: usize, len: usize) -> Result<Vec<u8>, DbError> {
-        let mut buf = vec![0; len];
-        self.read(offset, &mut buf)?;
-
-        Ok(buf)
+    pub fn as_value(&self) -> Value<'a> {
+        let Occupied {
+            tree,
+            wal,
+            file,
+            bytes,
+            cell,
+            ..
+        } = self;
+        cell_value::<N>(*cell, wal, file, *tree, bytes).expect("must be occupied")
     }
 
-    pub fn write_at(&self, offset: usize, buf: &[u8]) -> Result<(), DbError> {
-        let mut page = self.file.read_page(self.ptr.raw_number())?;
-        page[offset..][..buf.len()].clone_from_slice(buf);
-        self.file
-            .write_page(self.ptr.raw_number(), PageKind::Data, page)?;
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(tree = self.tree))
+    )]
+    /// Removes the key, returns the removed value, see `RemovedValue` for how long it is readable
+    pub fn remove(self) -> Result<RemovedValue<'a>, DbError> {
+        let Occupied {
+            inner,
+            tree,
+            mut lock,
+            file,
+            cell,
+            ..
+        } = self;
+        let wal_lock = &mut lock;
 
-        Ok(())
+        let key = changed_key(tree, &inner, file)?;
+        let writes = file.writes();
+        let (new_head, ptr) = transaction(wal_lock, file, |mut rt| {
+            let ptr = match cell {
+                // the removed value is returned, so it needs a page
+                Cell::Inline(inline) => {
+                    let ptr = rt.create();
+                    *rt.mutate::<MetadataPage>(ptr) = MetadataPage::new(&inline);
+                    ptr
+                }
+                _ => inner.meta().expect("must be metadata"),
+            };
+            Ok((inner.remove(rt)?, ptr))
+        })?;
+        let old = wal_lock.replace_orphan(ptr.cast());
+        wal_lock.new_tree_head(file, tree, new_head, old)?;
+        wal_lock.op_written(file.writes().wrapping_sub(writes));
+        if let Some(key) = key {
+            wal_lock.touch(key);
+        }
+        file.counters().remove(1);
+
+        Ok(RemovedValue(Value {
+            ptr,
+            file,
+            allocated: None,
+            inline: None,
+            freed: file.freed(),
+        }))
     }
 }
 
-#[derive(Debug, Error)]
-pub enum DbError {
-    #[error("{0}")]
-    Io(#[from] io::Error),
-    #[error("{0}")]
-    WalError(#[from] WalError),
-    #[error("cipher: {0}")]
-    Cipher(#[from] CipherError),
+/// One of the trees of the database, they share the log and the file.
+/// Iterate with `Db::next` as usual.
+pub struct TreeHandle<'a, N> {
+    db: &'a Db<N>,
+    id: u8,
 }
 
-pub struct Db<N> {
-    file: FileIo,
-    wal: Wal,
-    phantom_data: PhantomData<N>,
-}
+impl<'a, N> TreeHandle<'a, N>
+where
+    N: Copy + PlainData + Node,
+{
+    pub fn id(&self) -> u8 {
+        self.id
+    }
 
-impl<N> Db<N> {
-    pub fn new(path: impl AsRef<Path>, params: Params) -> Result<Self, DbError> {
-        let create = params.create();
-        let file = FileIo::new(path, params)?;
-        let wal = Wal::new(create, &file)?;
+    /// Same as `Db::entry`, creates the tree if it does not exist yet
+    pub fn entry<K>(&self, bytes: K) -> Result<Entry<'a, N>, DbError>
+    where
+        K: AsRef<[u8]>,
+    {
+        let bytes = bytes.as_ref();
+        check_key::<N>(bytes.len())?;
+        self.db
+            .entry_locked(lock_wal(&self.db.wal)?, self.id, bytes)
+    }
 
-        Ok(Db {
-            file,
-            wal,
-            phantom_data: PhantomData,
-        })
+    /// Same as `Db::get`
+    pub fn get(&self, key: &[u8]) -> Result<Option<Value<'a>>, DbError> {
+        self.db.get_in(self.id, key)
     }
 
-    /// Makes sense only for encrypted database
-    pub fn m_lock(&self) {
-        self.file.m_lock();
+    /// Same as `Db::multi_get`
+    pub fn multi_get(&self, keys: &[&[u8]]) -> Result<Vec<Option<Value<'a>>>, DbError> {
+        self.db.multi_get_in(self.id, keys)
     }
 
-    pub fn sync(&self) -> Result<(), DbError> {
-        self.file.sync()?;
+    /// Same as `Db::iter_from`
+    pub fn iter_from(&self, key: &[u8]) -> Result<DbIterator<N>, DbError> {
+        let mut it = DbIterator {
+            inner: None,
+            tree: self.id,
+        };
+        self.db.seek_in(self.id, &mut it, key)?;
 
-        Ok(())
+        Ok(it)
     }
 
-    /// Makes sense only for encrypted database
-    pub fn crypt_shred(&self, seed: &[u8]) -> Result<(), DbError> {
-        self.file.crypt_shred(seed)?;
-
-        Ok(())
+    /// Same as `Db::seek`
+    pub fn seek(&self, it: &mut DbIterator<N>, key: &[u8]) -> Result<(), DbError> {
+        self.db.seek_in(self.id, it, key)
     }
+}
 
-    #[cfg(test)]
-    pub fn with_simulator(mut self, crash_at: u32, mess_page: bool) -> Self {
-        use super::file::Simulator;
+impl<'a, N> Iterator for DupIter<'a, N>
+where
+    N: Copy + PlainData + Node,
+{
+    type Item = Result<(u64, Value<'a>), DbError>;
 
-        self.file.simulator = Simulator {
-            crash_at,
-            mess_page,
-        };
-        self
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (key, value) = match self.db.next(&mut self.it) {
+                Ok(next) => next?,
+                Err(err) => {
+                    self.it.inner = None;
+                    return Some(Err(err));
+                }
+            };
+            if !key.starts_with(&self.key) {
+                self.it.inner = None;
+                return None;
+            }
+            // longer keys of the prefix are in between, and so are empty cells
+            let Ok(n) = <[u8; DUP_SUFFIX]>::try_from(&key[self.key.len()..]) else {
+                continue;
+            };
+            if let Some(value) = value {
+                return Some(Ok((u64::from_be_bytes(n), value)));
+            }
+        }
     }
+}
 
-    pub fn stats(&self) -> DbStats {
-        self.wal.lock().stats(&self.file)
+impl<N> Iterator for ScanValues<'_, N>
+where
+    N: Copy + PlainData + Node,
+{
+    type Item = Result<(Vec<u8>, Vec<u8>), DbError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let next = self.db.next(&mut self.it).and_then(|next| {
+                let Some((key, value)) = next else {
+                    return Ok(None);
+                };
+                let past = match &self.end {
+                    Bound::Included(end) => self.db.file.compare(&key, end).is_gt(),
+                    Bound::Excluded(end) => self.db.file.compare(&key, end).is_ge(),
+                    Bound::Unbounded => false,
+                };
+                if past {
+                    return Ok(None);
+                }
+                let bytes = value
+                    .map(|value| value.read_to_vec(0, value.len()?))
+                    .transpose()?;
+                Ok(Some((key, bytes)))
+            });
+            match next {
+                Ok(Some((key, Some(bytes)))) => return Some(Ok((key, bytes))),
+                // an empty cell or an expired value
+                Ok(Some((_, None))) => {}
+                Ok(None) => {
+                    self.it.inner = None;
+                    return None;
+                }
+                Err(err) => {
+                    self.it.inner = None;
+                    return Some(Err(err));
+                }
+            }
+        }
     }
 }
 
-impl<N> Db<N>
+impl<'a, N> Cursor<'a, N>
 where
     N: Copy + PlainData + Node,
 {
-    #[cfg(test)]
-    pub fn print<K, D>(&self, k: K)
-    where
-        K: Fn(&[u8]) -> D,
-        D: std::fmt::Display,
-    {
-        let mut wal_lock = self.wal.lock();
-        let old_head = wal_lock.current_head();
-        let (alloc, free) = wal_lock.cache_mut();
-        let io = &self.file;
-        let mut storage = Default::default();
-        let rt = Rt::new(alloc, free, io, &mut storage);
+    /// Positions at `key` and returns `true` if it is there, otherwise at the next
+    /// greater key or off the tree. Unless the database did change since the last move,
+    /// only the nodes of the path whose range does not hold `key` are read again,
+    /// so seeking keys in order is about as cheap as iterating.
+    /// On error the cursor is off the tree.
+    pub fn seek(&mut self, key: &[u8]) -> Result<bool, DbError> {
+        check_prefix::<N>(key.len())?;
+        let file = &*self.db.file;
+        let lock = read_wal(&self.db.wal)?;
+        let inner = self.inner.take().filter(|_| self.seq == lock.seq());
+        let (inner, found) = match inner {
+            Some(inner) => inner.reposition(file, key)?,
+            None => btree::EntryInner::new(file, lock.current_head(), key)?,
+        };
+        self.seq = lock.seq();
+        let past_leaf = !inner.has_value();
+        self.inner = Some(inner);
+        if past_leaf {
+            btree::EntryInner::next(&mut self.inner, file)?;
+        }
+        self.settle()?;
 
-        btree::print::<N, K, D>(rt, old_head, k, true);
+        Ok(found)
     }
 
-    pub fn entry<K>(&self, bytes: K) -> Entry<'_, N, K>
-    where
-        K: AsRef<[u8]>,
-    {
-        let lock = self.wal.lock();
-        let file = &self.file;
+    /// Moves to the next key, from off the tree to the first one.
+    /// Returns `false` if it went off the tree.
+    /// If the database did change since the last move, the position is found again
+    /// by its key, and the next key is the first one greater than it.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<bool, DbError> {
+        let file = &*self.db.file;
+        let lock = read_wal(&self.db.wal)?;
+        match self.refresh(&lock)? {
+            Some((inner, found)) => {
+                let past_leaf = !inner.has_value();
+                self.inner = Some(inner);
+                // not found, it is at the next greater key already unless past the leaf
+                if found || past_leaf {
+                    btree::EntryInner::next(&mut self.inner, file)?;
+                }
+            }
+            None if self.inner.is_some() => btree::EntryInner::next(&mut self.inner, file)?,
+            None => self.inner = btree::EntryInner::first(file, lock.current_head())?,
+        }
+        self.seq = lock.seq();
+        self.settle()?;
 
-        let (inner, occupied) = btree::EntryInner::new(file, lock.current_head(), bytes.as_ref());
-        if occupied {
-            if inner.meta().is_some() {
-                Entry::Occupied(Occupied { inner, lock, file })
-            } else {
-                Entry::Empty(EmptyCell { inner, lock, file })
+        Ok(self.inner.is_some())
+    }
+
+    /// Moves to the previous key, from off the tree to the last one.
+    /// Returns `false` if it went off the tree.
+    /// If the database did change since the last move, the position is found again
+    /// by its key, and the previous key is the last one less than it.
+    pub fn prev(&mut self) -> Result<bool, DbError> {
+        let file = &*self.db.file;
+        let lock = read_wal(&self.db.wal)?;
+        match self.refresh(&lock)? {
+            Some((inner, _)) => {
+                self.inner = Some(inner);
+                btree::EntryInner::prev(&mut self.inner, file)?;
             }
-        } else {
-            Entry::Vacant(Vacant {
-                inner,
-                lock,
-                file,
-                bytes,
-            })
+            None if self.inner.is_some() => btree::EntryInner::prev(&mut self.inner, file)?,
+            None => self.inner = btree::EntryInner::last(file, lock.current_head())?,
+        }
+        self.seq = lock.seq();
+        self.settle()?;
+
+        Ok(self.inner.is_some())
+    }
+
+    // the position found again in the current tree if it is found in an older record,
+    // its nodes may be freed since, and whether the key is still there
+    fn refresh(
+        &mut self,
+        lock: &WalReadLock<'_>,
+    ) -> Result<Option<(btree::EntryInner<N>, bool)>, DbError> {
+        let file = &*self.db.file;
+        let Some(inner) = self.inner.as_mut().filter(|_| self.seq != lock.seq()) else {
+            return Ok(None);
+        };
+        // cached by `settle` while the nodes were current
+        let key = inner.cached_key(file)?;
+
+        Ok(Some(btree::EntryInner::new(
+            file,
+            lock.current_head(),
+            &key,
+        )?))
+    }
+
+    // reads the key while the nodes are current, so `refresh` does not read stale pages
+    fn settle(&mut self) -> Result<(), DbError> {
+        if let Some(inner) = &mut self.inner {
+            inner.cached_key_ref(&self.db.file)?;
         }
+
+        Ok(())
+    }
+
+    /// The key at the position, `None` off the tree
+    pub fn key(&mut self) -> Result<Option<Vec<u8>>, DbError> {
+        let file = &*self.db.file;
+        let key = self.inner.as_mut().map(|inner| inner.cached_key(file));
+        Ok(key.transpose()?)
     }
 
-    pub fn next<'a>(&'a self, it: &mut DbIterator<N>) -> Option<(Vec<u8>, Option<Value<'a>>)> {
-        let file = &self.file;
-        let inner = it.inner.as_mut()?;
-        let key = inner.key(file);
-        let value = inner.meta().map(|ptr| Value { ptr, file });
+    /// The value at the position, `None` off the tree, if the cell is empty
+    /// or the value is expired. Also `None` if the key is removed since the last move.
+    pub fn value(&mut self) -> Result<Option<Value<'a>>, DbError> {
+        let file = &*self.db.file;
+        let lock = read_wal(&self.db.wal)?;
+        match self.refresh(&lock)? {
+            Some((inner, true)) => {
+                self.inner = Some(inner);
+                self.seq = lock.seq();
+                self.settle()?;
+            }
+            // the next move finds the position again
+            Some((_, false)) => return Ok(None),
+            None => {}
+        }
+        let Some(inner) = self.inner.as_mut() else {
+            return Ok(None);
+        };
+        let key = inner.cached_key_ref(file)?.to_vec();
+        let value = cell_value::<N>(inner.cell(file)?, &self.db.wal, file, Wal::MAIN, &key);
 
-        btree::EntryInner::next(&mut it.inner, file);
+        self.db.unexpired(value, (self.db.clock)())
+    }
+}
+
+/// Changes of one or several trees written to the log with one record,
+/// so after a crash either all of them are in the database or none,
+/// e.g. a record and the entry of a secondary index of it.
+/// Holds the log lock until it is committed or dropped, dropping undoes the changes.
+/// Values obtained from the batch must not be used after it is dropped.
+/// If the process crashes before the commit, the pages it did allocate are lost
+/// until the next compaction.
+pub struct Batch<'a, N> {
+    db: &'a Db<N>,
+    lock: WalLock<'a>,
+    // `None` once committed
+    pages: Option<BatchPages>,
+    // roots of the trees changed by the batch
+    roots: BTreeMap<u8, PagePtr<N>>,
+    // keys of the main tree for the change feed
+    changed: Vec<Vec<u8>>,
+    // counted when committed
+    inserts: u64,
+    removes: u64,
+}
+
+impl<'a, N> Batch<'a, N>
+where
+    N: Copy + PlainData + Node,
+{
+    /// Returns the value of the key, inserts a new empty value if there is none
+    pub fn insert(&mut self, tree: u8, key: &[u8]) -> Result<Value<'a>, DbError> {
+        check_key::<N>(key.len())?;
+        let file = &*self.db.file;
+        let root = self.root(tree)?;
+        let (mut inner, occupied) = btree::EntryInner::new(file, root, key)?;
+        let cell = if occupied {
+            inner.cell(file)?
+        } else {
+            Cell::Empty
+        };
+        // an inline value moves to a page, it cannot be written while the batch is locked
+        let page = match cell {
+            Cell::Page(ptr) => {
+                return Ok(Value {
+                    ptr,
+                    file,
+                    allocated: None,
+                    inline: None,
+                    freed: file.freed(),
+                });
+            }
+            Cell::Inline(inline) => MetadataPage::new(&inline),
+            Cell::Empty => MetadataPage::empty(),
+        };
+
+        let (root, ptr) = self.change(None, |mut rt| {
+            let ptr = rt.create();
+            *rt.mutate::<MetadataPage>(ptr) = page;
+            let root = if occupied {
+                inner.set_meta(Some(ptr));
+                inner.update(rt)
+            } else {
+                inner.insert(rt, Cell::Page(ptr), key)?
+            };
+            Ok((root, ptr))
+        })?;
+        self.roots.insert(tree, root);
+        self.touch(tree, key);
+        if !occupied {
+            self.inserts += 1;
+        }
+
+        Ok(Value {
+            ptr,
+            file,
+            allocated: None,
+            inline: None,
+            freed: file.freed(),
+        })
+    }
+
+    /// Inserts the key without a value, does nothing if the key is there
+    pub fn insert_empty(&mut self, tree: u8, key: &[u8]) -> Result<(), DbError> {
+        check_key::<N>(key.len())?;
+        let root = self.root(tree)?;
+        let (inner, occupied) = btree::EntryInner::new(&self.db.file, root, key)?;
+        if !occupied {
+            let root = self.change(None, |rt| inner.insert(rt, Cell::Empty, key))?;
+            self.roots.insert(tree, root);
+            self.touch(tree, key);
+            self.inserts += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the key and its value, returns `false` if there is no such key.
+    /// The value is freed when the batch is committed.
+    pub fn remove(&mut self, tree: u8, key: &[u8]) -> Result<bool, DbError> {
+        check_key::<N>(key.len())?;
+        let root = self.root(tree)?;
+        let (inner, occupied) = btree::EntryInner::new(&self.db.file, root, key)?;
+        if !occupied {
+            return Ok(false);
+        }
+        let value = inner.meta().map(PagePtr::cast);
+        let root = self.change(value, |rt| inner.remove(rt))?;
+        self.roots.insert(tree, root);
+        self.touch(tree, key);
+        self.removes += 1;
+
+        Ok(true)
+    }
+
+    /// Writes the record with every changed tree
+    pub fn commit(mut self) -> Result<(), DbError> {
+        let pages = self.pages.take().expect("must not be committed");
+        let head = self
+            .roots
+            .remove(&Wal::MAIN)
+            .unwrap_or_else(|| self.lock.current_head());
+        let roots = self.roots.iter().map(|(&id, &root)| (id, root));
+        let roots = roots.collect::<Vec<_>>();
+        self.lock.commit_batch(&self.db.file, pages, head, &roots)?;
+        for key in mem::take(&mut self.changed) {
+            self.lock.touch(key);
+        }
+        let counters = self.db.file.counters();
+        counters.insert(self.inserts);
+        counters.remove(self.removes);
+
+        Ok(())
+    }
+
+    // whether the key is in the tree, including the changes of the batch
+    fn contains(&mut self, tree: u8, key: &[u8]) -> Result<bool, DbError> {
+        check_key::<N>(key.len())?;
+        let root = self.root(tree)?;
+        let (_, occupied) = btree::EntryInner::new(&self.db.file, root, key)?;
+
+        Ok(occupied)
+    }
+
+    fn touch(&mut self, tree: u8, key: &[u8]) {
+        if tree == Wal::MAIN {
+            self.changed.push(key.to_vec());
+        }
+    }
+
+    // the tree is created if it does not exist yet
+    fn root(&mut self, tree: u8) -> Result<PagePtr<N>, DbError> {
+        if let Some(&root) = self.roots.get(&tree) {
+            return Ok(root);
+        }
+        if let Some(root) = self.lock.tree_head(&self.db.file, tree)? {
+            return Ok(root);
+        }
+        // a zeroed node is an empty leaf
+        let root = self.change(None, |mut rt| Ok(rt.create()))?;
+        self.roots.insert(tree, root);
+
+        Ok(root)
+    }
+
+    // `value` is the page of a removed value
+    fn change<T>(
+        &mut self,
+        value: Option<PagePtr<()>>,
+        f: impl FnOnce(R<'_>) -> io::Result<T>,
+    ) -> Result<T, DbError> {
+        let file = &*self.db.file;
+        let pages = self.pages.as_mut().expect("must not be committed");
+        let res = transaction(&mut self.lock, file, f)?;
+        self.lock.batch_step(file, pages, value)?;
+
+        Ok(res)
+    }
+}
+
+impl<N> Drop for Batch<'_, N> {
+    fn drop(&mut self) {
+        if let Some(pages) = self.pages.take() {
+            if let Err(err) = self.lock.abort_batch(&self.db.file, pages) {
+                log::error!("{err}");
+            }
+        }
+    }
+}
+
+// only the keys of the main tree go to the change feed
+fn changed_key<N>(
+    tree: u8,
+    inner: &btree::EntryInner<N>,
+    file: &FileIo,
+) -> io::Result<Option<Vec<u8>>>
+where
+    N: Copy + PlainData + Node,
+{
+    (tree == Wal::MAIN).then(|| inner.key(file)).transpose()
+}
+
+// the path to the key, the tree is created first if a lookup did not find it
+fn vacant_inner<N>(
+    inner: Option<btree::EntryInner<N>>,
+    tree: u8,
+    lock: &mut WalLock<'_>,
+    file: &FileIo,
+    key: &[u8],
+) -> Result<btree::EntryInner<N>, DbError>
+where
+    N: Copy + PlainData + Node,
+{
+    if let Some(inner) = inner {
+        return Ok(inner);
+    }
+    let root = lock.create_tree(file, tree)?;
+
+    Ok(btree::EntryInner::new(file, root, key)?.0)
+}
+
+// puts `page` to the leaf if it fits, otherwise to a new value page of the entry,
+// frees the old page if any
+fn store_value<N>(
+    mut inner: btree::EntryInner<N>,
+    tree: u8,
+    wal_lock: &mut WalLock<'_>,
+    file: &FileIo,
+    page: MetadataPage,
+) -> Result<(PagePtr<N>, Place), DbError>
+where
+    N: Copy + PlainData + Node,
+{
+    let key = changed_key(tree, &inner, file)?;
+
+    let old = inner.meta();
+    let expiry = file.expiry();
+    let inline = page
+        .expires(expiry)
+        .is_none()
+        .then(|| inline_of::<N>(page.plain(expiry)));
+    let (new_head, place) = transaction(wal_lock, file, |mut rt| {
+        if let Some(ptr) = old {
+            rt.free.free(ptr);
+        }
+        let place = match inline.flatten() {
+            Some(inline) => {
+                inner.set_inline(rt.reborrow(), &inline)?;
+                Place::Inline(inline)
+            }
+            None => {
+                let ptr = rt.create();
+                *rt.mutate::<MetadataPage>(ptr) = page;
+                inner.set_meta(Some(ptr));
+                Place::Page(ptr)
+            }
+        };
+        Ok((inner.update(rt), place))
+    })?;
+    if let Some(ptr) = old {
+        file.value_freed(ptr.raw_number());
+    }
+    wal_lock.new_tree_head(file, tree, new_head, None)?;
+    if let Some(key) = key {
+        wal_lock.touch(key);
+    }
+
+    Ok((new_head, place))
+}
+
+// the bytes fit in the leaf, see `Value::INLINE`
+fn inline_of<N>(plain: &[u8]) -> Option<Inline>
+where
+    N: Node,
+{
+    let len = plain.len().min(Value::INLINE);
+    if !N::INLINE || plain[len..].iter().any(|b| *b != 0) {
+        return None;
+    }
+    let mut inline = INLINE_ZERO;
+    inline[..len].clone_from_slice(&plain[..len]);
+
+    Some(inline)
+}
+
+// the value of the cell, `None` if it is empty
+fn cell_value<'a, N>(
+    cell: Cell,
+    wal: &'a Wal,
+    file: &'a FileIo,
+    tree: u8,
+    key: &[u8],
+) -> Option<Value<'a>>
+where
+    N: Copy + PlainData + Node,
+{
+    let (ptr, inline) = match cell {
+        Cell::Empty => return None,
+        Cell::Page(ptr) => (ptr, None),
+        Cell::Inline(inline) => {
+            let inline = InlineValue {
+                wal,
+                tree,
+                key: key.to_vec(),
+                place: Mutex::new(Place::Inline(inline)),
+                write: write_inline::<N>,
+            };
+            let ptr = PagePtr::from_raw_number(INLINE_PTR).expect("must not be zero");
+            (ptr, Some(Box::new(inline)))
+        }
+    };
+
+    Some(Value {
+        ptr,
+        file,
+        allocated: None,
+        inline,
+        freed: file.freed(),
+    })
+}
+
+// finds the key under the log lock and applies `f` to its value,
+// it stays in the leaf if it still fits
+fn write_inline<N>(
+    value: &InlineValue<'_>,
+    file: &FileIo,
+    f: &mut dyn FnMut(&mut MetadataPage) -> bool,
+) -> Result<(Place, bool), DbError>
+where
+    N: Copy + PlainData + Node,
+{
+    let mut lock = lock_wal(value.wal)?;
+    let root = lock.tree_head(file, value.tree)?;
+    let root = root.ok_or(DbError::KeyNotFound)?;
+    let (inner, occupied) = btree::EntryInner::<N>::new(file, root, &value.key)?;
+    let inline = match inner.cell(file)? {
+        Cell::Inline(inline) if occupied => inline,
+        // moved to a page through another handle
+        Cell::Page(ptr) if occupied => {
+            let changed = file.update_page(ptr.raw_number(), PageKind::Data, |page| {
+                f(MetadataPage::as_this_mut(page))
+            })?;
+            return Ok((Place::Page(ptr), changed));
+        }
+        _ => return Err(DbError::KeyNotFound),
+    };
+
+    let mut page = MetadataPage::new(&inline);
+    if !f(&mut page) {
+        return Ok((Place::Inline(inline), false));
+    }
+    let (_, place) = store_value(inner, value.tree, &mut lock, file, page)?;
+
+    Ok((place, true))
+}
+
+// the change is undone if it fails, so the head stays the same
+fn transaction<T>(
+    lock: &mut WalLock<'_>,
+    file: &FileIo,
+    f: impl FnOnce(R<'_>) -> io::Result<T>,
+) -> io::Result<T> {
+    lock.transaction(|alloc, free| {
+        let mut storage = Default::default();
+        let mut rt = Rt::new(alloc, free, file, &mut storage);
+        let res = f(rt.reborrow())?;
+        rt.flush()?;
+
+        Ok(res)
+    })
+}
+
+impl<N> Drop for Db<N> {
+    fn drop(&mut self) {
+        self.stop_background_sync();
+        // the state may be inconsistent after a panic, leave it as it crashed
+        if thread::panicking() {
+            return;
+        }
+        if let Err(err) = self.wal.close(&self.file) {
+            log::error!("failed to close the database: {err}");
+        }
+    }
+}
+
+impl Drop for Value<'_> {
+    fn drop(&mut self) {
+        if let Some(wal) = self.allocated {
+            if let Err(err) = wal.release_allocated(self.file, self.ptr.cast()) {
+                log::error!("failed to free the allocated value: {err}");
+            }
+        }
+    }
+}
+
+impl<'a> Value<'a> {
+    /// Maximal length of a value, the page keeps its expiration in the rest.
+    /// A database created by an older version has no room for it, see `capacity`.
+    pub const CAPACITY: usize = MetadataPage::CAPACITY;
+
+    /// A value that never expires and whose bytes past the first `INLINE` are zero
+    /// is kept in the leaf of `NodePage` by `Entry::upsert_with`, `Db::try_insert`
+    /// and `Vacant::insert_small`, without a page of its own. The first bytes are
+    /// in the leaf itself, the rest in pages shared by the values of the leaf.
+    /// It moves to a page once it does not fit.
+    pub const INLINE: usize = mem::size_of::<Inline>();
+
+    /// Maximal length of the value, `CAPACITY`, or the whole page
+    /// if the database is created by an older version, its values never expire
+    pub fn capacity(&self) -> usize {
+        MetadataPage::capacity(self.file.expiry())
+    }
+
+    fn check_bounds(file: &FileIo, offset: usize, len: usize) -> Result<(), DbError> {
+        if offset.saturating_add(len) > MetadataPage::capacity(file.expiry()) {
+            return Err(DbError::OutOfBounds);
+        }
+
+        Ok(())
+    }
+
+    fn place(&self) -> Place {
+        match &self.inline {
+            Some(inline) => *inline.place.lock().expect("poisoned"),
+            None => Place::Page(self.ptr),
+        }
+    }
+
+    // fails if the page may belong to another key by now,
+    // a freed page is reused only after `FileIo::freed` grows,
+    // so it is checked after the page is read, or under the cache lock when written
+    fn check(&self) -> Result<(), DbError> {
+        // nobody else frees an allocated value
+        if self.allocated.is_none() && self.file.freed() != self.freed {
+            return Err(DbError::Stale);
+        }
+
+        Ok(())
+    }
+
+    // `f` changes the page under the cache lock, returns whether it did
+    fn update_page(
+        &self,
+        ptr: PagePtr<MetadataPage>,
+        f: impl FnOnce(&mut [u8]) -> bool,
+    ) -> Result<bool, DbError> {
+        let mut stale = Ok(());
+        let changed = self
+            .file
+            .update_page(ptr.raw_number(), PageKind::Data, |page| {
+                stale = self.check();
+                stale.is_ok() && f(page)
+            })?;
+        stale?;
+
+        Ok(changed)
+    }
+
+    // `f` changes the inline value under the log lock, returns whether it did
+    fn update_inline(&self, mut f: impl FnMut(&mut MetadataPage) -> bool) -> Result<bool, DbError> {
+        let inline = self.inline.as_ref().expect("must be inline");
+        let (place, changed) = (inline.write)(inline, self.file, &mut f)?;
+        *inline.place.lock().expect("poisoned") = place;
+
+        Ok(changed)
+    }
+
+    fn metadata(&self) -> Result<MetadataPage, DbError> {
+        match self.place() {
+            Place::Inline(inline) => Ok(MetadataPage::new(&inline)),
+            Place::Page(ptr) => {
+                let page = self.file.read(ptr)?;
+                self.check()?;
+                Ok(page)
+            }
+        }
+    }
+
+    #[cfg(feature = "async")]
+    async fn metadata_async(&self) -> Result<MetadataPage, DbError> {
+        match self.place() {
+            Place::Inline(inline) => Ok(MetadataPage::new(&inline)),
+            Place::Page(ptr) => {
+                let page = self.file.read_page_async(ptr.raw_number()).await?;
+                let metadata = *MetadataPage::as_this(&*page);
+                self.file.recycle_page(page);
+                self.check()?;
+                Ok(metadata)
+            }
+        }
+    }
+
+    // the last bytes of the page are the value's own if the database does not keep
+    // the expiration, so there is nothing to clear
+    fn set_expires(&self, time: Option<SystemTime>) -> Result<(), DbError> {
+        if !self.file.expiry() {
+            return time.map_or(Ok(()), |_| Err(DbError::NoExpiry));
+        }
+        let ptr = match self.place() {
+            Place::Inline(_) => {
+                self.update_inline(|page| {
+                    page.set_expires(time);
+                    true
+                })?;
+                return Ok(());
+            }
+            Place::Page(ptr) => ptr,
+        };
+        self.update_page(ptr, |page| {
+            MetadataPage::as_this_mut(page).set_expires(time);
+            true
+        })?;
+
+        Ok(())
+    }
+
+    /// An inline value is read from the copy made when the value was found,
+    /// or last written through this handle. Fails with `DbError::Stale`
+    /// if any value page is freed since the value was found, e.g. by a removal,
+    /// the page may belong to another key by now. So does every access to the value.
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), DbError> {
+        Self::check_bounds(self.file, offset, buf.len())?;
+        let ptr = match self.place() {
+            Place::Inline(inline) => {
+                let page = MetadataPage::new(&inline);
+                buf.clone_from_slice(&page.plain(false)[offset..][..buf.len()]);
+                return Ok(());
+            }
+            Place::Page(ptr) => ptr,
+        };
+        let page = self.file.read_page(ptr.raw_number())?;
+        buf.clone_from_slice(&page[offset..][..buf.len()]);
+        self.file.recycle_page(page);
+
+        self.check()
+    }
+
+    /// Same as `read`, but a page missing in the cache is awaited instead of blocking
+    /// the thread. Writes only change the cache, they do not wait for the disk.
+    #[cfg(feature = "async")]
+    pub async fn read_async(&self, offset: usize, buf: &mut [u8]) -> Result<(), DbError> {
+        Self::check_bounds(self.file, offset, buf.len())?;
+        let ptr = match self.place() {
+            Place::Inline(inline) => {
+                let page = MetadataPage::new(&inline);
+                buf.clone_from_slice(&page.plain(false)[offset..][..buf.len()]);
+                return Ok(());
+            }
+            Place::Page(ptr) => ptr,
+        };
+        let page = self.file.read_page_async(ptr.raw_number()).await?;
+        buf.clone_from_slice(&page[offset..][..buf.len()]);
+        self.file.recycle_page(page);
+
+        self.check()
+    }
+
+    /// Borrows the value right from the page cache, without copying.
+    /// The whole cache is locked while the guard is alive,
+    /// any other access to the database waits until it is dropped,
+    /// so the same thread must drop the guard before touching the database again.
+    /// An inline value is copied.
+    pub fn as_slice(&self) -> Result<impl Deref<Target = [u8]> + 'a, DbError> {
+        match self.place() {
+            Place::Inline(inline) => {
+                let page = Box::new(MetadataPage::new(&inline));
+                Ok(ValueView::Inline(page, self.capacity()))
+            }
+            Place::Page(ptr) => {
+                let view = self.file.view(ptr.raw_number())?;
+                self.check()?;
+                Ok(ValueView::Page(view, self.capacity()))
+            }
+        }
+    }
+
+    /// A value is as long as its page, this is up to its last byte that is not zero
+    pub fn len(&self) -> Result<usize, DbError> {
+        let plain = self.as_slice()?;
+        Ok(plain.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1))
+    }
+
+    /// Every byte is zero
+    pub fn is_empty(&self) -> Result<bool, DbError> {
+        self.len().map(|len| len == 0)
+    }
+
+    /// Passes the bytes from `offset` to `offset + len` to `f`, `chunk` bytes at a time,
+    /// the last piece may be shorter. A value is at most a page, it is read and decrypted
+    /// once into a page of the pool instead of a buffer of `len` bytes.
+    /// Nothing is locked while `f` runs. Stops at the first error of `f`.
+    /// Fails with `DbError::OutOfBounds` if `chunk` is zero.
+    pub fn read_chunks<F>(
+        &self,
+        offset: usize,
+        len: usize,
+        chunk: usize,
+        f: F,
+    ) -> Result<(), DbError>
+    where
+        F: FnMut(&[u8]) -> Result<(), DbError>,
+    {
+        if chunk == 0 {
+            return Err(DbError::OutOfBounds);
+        }
+        Self::check_bounds(self.file, offset, len)?;
+        let ptr = match self.place() {
+            Place::Inline(inline) => {
+                let page = MetadataPage::new(&inline);
+                return page.plain(false)[offset..][..len]
+                    .chunks(chunk)
+                    .try_for_each(f);
+            }
+            Place::Page(ptr) => ptr,
+        };
+        let page = self.file.read_page(ptr.raw_number())?;
+        let res = self
+            .check()
+            .and_then(|()| page[offset..][..len].chunks(chunk).try_for_each(f));
+        self.file.recycle_page(page);
+
+        res
+    }
+
+    pub fn read_to_vec(&self, offset: usize, len: usize) -> Result<Vec<u8>, DbError> {
+        let mut buf = vec![0; len];
+        self.read(offset, &mut buf)?;
+
+        Ok(buf)
+    }
+
+    /// Passes to `f` a page aligned buffer the size of a page, the decrypted bytes
+    /// of the value from `offset` on are at its start, the rest is zeroed.
+    /// It is ready for direct I/O as is, the buffer goes back to the pool after `f`.
+    /// The buffer is a copy, nothing is locked while `f` runs.
+    pub fn read_aligned<F, T>(&self, offset: usize, f: F) -> Result<T, DbError>
+    where
+        F: FnOnce(&[u8]) -> T,
+    {
+        Self::check_bounds(self.file, offset, 0)?;
+        let mut page = match self.place() {
+            Place::Inline(inline) => {
+                let mut page = self.file.new_page();
+                page[..inline.len()].clone_from_slice(&inline);
+                page
+            }
+            Place::Page(ptr) => {
+                let page = self.file.read_page(ptr.raw_number())?;
+                self.check()?;
+                page
+            }
+        };
+        let capacity = self.capacity();
+        page.copy_within(offset..capacity, 0);
+        page[(capacity - offset)..].fill(0);
+        let res = f(&*page);
+        self.file.recycle_page(page);
+
+        Ok(res)
+    }
+
+    /// Sets the value to expire at `unix_secs` seconds since the unix epoch, `None` to never expire.
+    /// The expiry is lazy for reads, `Db::get`, `Db::next`, the cursor and the scans skip
+    /// the value once the clock of the database passes it, but the value stays in the tree
+    /// until `Db::purge_expired`. An entry of the key is still `Entry::Occupied`,
+    /// see `Occupied::is_expired`.
+    /// An inline value moves to a page of its own first. Fails with `DbError::NoExpiry`
+    /// if the database is created by an older version, unless `unix_secs` is `None`.
+    pub fn set_expiry(&self, unix_secs: Option<u64>) -> Result<(), DbError> {
+        let time = unix_secs.map(|secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+        self.set_expires(time)
+    }
+
+    /// The page is changed in the cache, `flush` makes it durable.
+    /// A value is a single page, zeroed past its length, so writing past the end
+    /// leaves zeros in the gap and the length becomes `offset + buf.len()`,
+    /// unless `buf` ends with zeros. Past `capacity` it fails with `DbError::OutOfBounds`.
+    /// An inline value is written to the leaf under the log lock, like an entry does,
+    /// so not while an entry of the same database is held by this thread.
+    /// It moves to a page if it does not fit anymore, see `INLINE`.
+    /// Fails with `DbError::KeyNotFound` if its key is removed meanwhile.
+    pub fn write_at(&self, offset: usize, buf: &[u8]) -> Result<(), DbError> {
+        Self::check_bounds(self.file, offset, buf.len())?;
+        let ptr = match self.place() {
+            Place::Inline(_) => {
+                self.update_inline(|page| {
+                    page.plain_mut(false)[offset..][..buf.len()].clone_from_slice(buf);
+                    true
+                })?;
+                return Ok(());
+            }
+            Place::Page(ptr) => ptr,
+        };
+        self.update_page(ptr, |page| {
+            page[offset..][..buf.len()].clone_from_slice(buf);
+            true
+        })?;
+
+        Ok(())
+    }
+
+    /// Writes `new` at `offset` only if the bytes there are equal to `expected`,
+    /// returns whether it did. No other write of the value gets in between.
+    /// The lengths may differ, `expected.len()` bytes are compared
+    /// and `new.len()` bytes are written, the rest is left as is.
+    pub fn compare_and_swap(
+        &self,
+        offset: usize,
+        expected: &[u8],
+        new: &[u8],
+    ) -> Result<bool, DbError> {
+        Self::check_bounds(self.file, offset, expected.len().max(new.len()))?;
+        let swap = |page: &mut [u8]| {
+            if &page[offset..][..expected.len()] != expected {
+                return false;
+            }
+            page[offset..][..new.len()].clone_from_slice(new);
+            true
+        };
+        let swapped = match self.place() {
+            Place::Inline(_) => self.update_inline(|page| swap(page.plain_mut(false)))?,
+            Place::Page(ptr) => self.update_page(ptr, swap)?,
+        };
+
+        Ok(swapped)
+    }
+
+    /// Writes the value to the disk and waits until it is there.
+    /// A write is visible right away, but it is durable only after this call or `Db::sync`,
+    /// a crash before leaves the value as it was after the last one.
+    /// An inline value is in the tree, so the whole database is synced.
+    pub fn flush(&self) -> Result<(), DbError> {
+        if self.inline.is_some() {
+            self.file.sync()?;
+        } else {
+            self.file.flush_page(self.ptr.raw_number())?;
+        }
+
+        Ok(())
+    }
+
+    /// Keeps only first `new_len` bytes, the rest of the value is zeroed.
+    /// The value always occupies one page, so no page is freed.
+    pub fn truncate(&self, new_len: usize) -> Result<(), DbError> {
+        Self::check_bounds(self.file, new_len, 0)?;
+        let ptr = match self.place() {
+            Place::Inline(_) => {
+                self.update_inline(|page| {
+                    page.plain_mut(false)[new_len..].fill(0);
+                    true
+                })?;
+                return Ok(());
+            }
+            Place::Page(ptr) => ptr,
+        };
+        self.update_page(ptr, |page| {
+            page[new_len..self.capacity()].fill(0);
+            true
+        })?;
+
+        Ok(())
+    }
+
+    /// Writes `v` in postcard format framed with COBS,
+    /// the frame ends with the first zero byte, so the rest of the value is ignored
+    #[cfg(feature = "serde")]
+    pub fn write_serde<T>(&self, v: &T) -> Result<(), DbError>
+    where
+        T: serde::Serialize,
+    {
+        let buf = postcard::to_allocvec_cobs(v)?;
+        self.write_at(0, &buf)
+    }
+
+    /// Reads the value written by `write_serde`
+    #[cfg(feature = "serde")]
+    pub fn read_serde<T>(&self) -> Result<T, DbError>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let mut buf = self.read_to_vec(0, self.capacity())?;
+
+        Ok(postcard::from_bytes_cobs(&mut buf)?)
+    }
+}
+
+/// The value removed by `Occupied::remove`, it is read only.
+/// Its page is not freed at once, the log keeps it in the orphan slot until the next
+/// removal takes the slot, or until `Db::reclaim`, `Db::allocate` or closing frees it.
+/// Any value page freed since the removal makes every read fail with `DbError::Stale`,
+/// the page may belong to another key by then. `to_vec` copies the bytes out to keep them.
+pub struct RemovedValue<'a>(Value<'a>);
+
+impl RemovedValue<'_> {
+    /// Whether the page is freed since the removal, every read fails then
+    pub fn is_stale(&self) -> bool {
+        self.0.check().is_err()
+    }
+
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), DbError> {
+        self.0.read(offset, buf)
+    }
+
+    pub fn read_to_vec(&self, offset: usize, len: usize) -> Result<Vec<u8>, DbError> {
+        self.0.read_to_vec(offset, len)
+    }
+
+    pub fn len(&self) -> Result<usize, DbError> {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> Result<bool, DbError> {
+        self.0.is_empty()
+    }
+
+    /// The bytes up to the last one that is not zero, they stay after the page is freed
+    pub fn to_vec(&self) -> Result<Vec<u8>, DbError> {
+        self.0.read_to_vec(0, self.0.len()?)
+    }
+}
+
+// with the capacity of the value
+enum ValueView<'a> {
+    Page(PageView<'a>, usize),
+    Inline(Box<MetadataPage>, usize),
+}
+
+impl Deref for ValueView<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            ValueView::Page(page, capacity) => &page[..*capacity],
+            ValueView::Inline(page, capacity) => &page.plain(false)[..*capacity],
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum DbError {
+    #[error("{0}")]
+    Io(io::Error),
+    #[error("{0}")]
+    WalError(WalError),
+    #[error("cipher: {0}")]
+    Cipher(CipherError),
+    #[error("the database is already open in this process")]
+    AlreadyOpen,
+    #[error("the database is locked by another process")]
+    Locked,
+    #[error("the log is locked by this thread already, e.g. by an entry still held")]
+    WouldDeadlock,
+    #[error("the database is full, it cannot grow past {max} pages")]
+    DatabaseFull { max: u32 },
+    #[error("the queue depth {0} is not a power of two up to {max}", max = IoOptions::MAX_QUEUE_DEPTH)]
+    BadQueueDepth(u32),
+    #[error("out of value bounds")]
+    OutOfBounds,
+    #[error("the database is created by an older version, its values cannot expire")]
+    NoExpiry,
+    #[error("bad dump")]
+    BadDump,
+    #[error("unsupported dump version {0}")]
+    DumpVersion(u32),
+    #[error("the checksum of the dump does not match")]
+    Corrupted,
+    #[error("the file is corrupted, a page is not what the tree or the log refers to")]
+    CorruptedFile,
+    #[error("the value is not allocated by `Db::allocate`")]
+    NotAllocated,
+    #[error("the key is {len} bytes long, longer than {max}")]
+    KeyTooLong { len: usize, max: usize },
+    #[error("the key is {len} bytes long, shorter than {min}")]
+    KeyTooShort { len: usize, min: usize },
+    #[error("bad change set")]
+    BadChangeSet,
+    #[error("the key is absent")]
+    KeyNotFound,
+    #[error("the key is present already")]
+    KeyExists,
+    #[error("the ranges overlap, or a key is out of its range or order")]
+    BadRange,
+    #[error(
+        "the key {} is in the tree twice, the data is corrupted",
+        hex::encode(key)
+    )]
+    DuplicateKey { key: Vec<u8> },
+    #[error("a value page is freed since the value was found, it may belong to another key")]
+    Stale,
+    #[error("the changes after version {version} are forgotten, the oldest known is {oldest}")]
+    VersionTooOld { version: u64, oldest: u64 },
+    #[cfg(feature = "serde")]
+    #[error("serde: {0}")]
+    Serde(#[from] postcard::Error),
+}
+
+impl From<io::Error> for DbError {
+    fn from(err: io::Error) -> Self {
+        if let Some(full) = DatabaseFull::of(&err) {
+            DbError::DatabaseFull { max: full.0 }
+        } else if btree::BadNode::is(&err) || BadFreelist::is(&err) {
+            DbError::CorruptedFile
+        } else if let Some(key) = btree::DuplicateKey::key(&err) {
+            DbError::DuplicateKey { key: key.to_vec() }
+        } else {
+            DbError::Io(err)
+        }
+    }
+}
+
+impl From<WalError> for DbError {
+    fn from(err: WalError) -> Self {
+        match err {
+            WalError::Io(err) if DatabaseFull::of(&err).is_some() || BadFreelist::is(&err) => {
+                err.into()
+            }
+            err => DbError::WalError(err),
+        }
+    }
+}
+
+impl From<CipherError> for DbError {
+    fn from(err: CipherError) -> Self {
+        match err {
+            CipherError::Io(err) => err.into(),
+            err => Self::Cipher(err),
+        }
+    }
+}
+
+impl From<FileError> for DbError {
+    fn from(err: FileError) -> Self {
+        match err {
+            FileError::Io(err) => err.into(),
+            FileError::Cipher(err) => err.into(),
+            FileError::AlreadyOpen => Self::AlreadyOpen,
+            FileError::Locked => Self::Locked,
+            FileError::BadQueueDepth(depth) => Self::BadQueueDepth(depth),
+        }
+    }
+}
+
+// a key to look for, may be shorter than a key to insert
+fn check_prefix<N>(len: usize) -> Result<(), DbError>
+where
+    N: Node,
+{
+    if len > N::MAX_KEY {
+        Err(DbError::KeyTooLong {
+            len,
+            max: N::MAX_KEY,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+// the log lock is not reentrant, the thread holding it would wait for itself
+fn lock_wal(wal: &Wal) -> Result<WalLock<'_>, DbError> {
+    if wal.is_locked_here() {
+        return Err(DbError::WouldDeadlock);
+    }
+    Ok(wal.lock())
+}
+
+fn read_wal(wal: &Wal) -> Result<WalReadLock<'_>, DbError> {
+    if wal.is_locked_here() {
+        return Err(DbError::WouldDeadlock);
+    }
+    Ok(wal.read())
+}
+
+// for the getters that cannot fail, they panic like `Db::batch`
+fn read_wal_or_panic(wal: &Wal) -> WalReadLock<'_> {
+    assert!(
+        !wal.is_locked_here(),
+        "the log is locked by this thread already"
+    );
+    wal.read()
+}
+
+fn check_key<N>(len: usize) -> Result<(), DbError>
+where
+    N: Node,
+{
+    check_prefix::<N>(len)?;
+    if len < N::MIN_KEY {
+        Err(DbError::KeyTooShort {
+            len,
+            min: N::MIN_KEY,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+// a key and its value
+type Pair = (Vec<u8>, Vec<u8>);
+
+// drains the items of one range of `Db::insert_ranges`
+fn load_range<N, I>(file: &FileIo, range: Range<Vec<u8>>, items: I) -> Result<Vec<Pair>, DbError>
+where
+    N: Node,
+    I: IntoIterator<Item = Pair>,
+{
+    let mut loaded = Vec::<Pair>::new();
+    for (key, plain) in items {
+        check_key::<N>(key.len())?;
+        Value::check_bounds(file, 0, plain.len())?;
+        let below = match loaded.last() {
+            Some((last, _)) => file.compare(&key, last).is_le(),
+            None => file.compare(&key, &range.start).is_lt(),
+        };
+        if below || file.compare(&key, &range.end).is_ge() {
+            return Err(DbError::BadRange);
+        }
+        loaded.push((key, plain));
+    }
+
+    Ok(loaded)
+}
+
+const DUMP_MAGIC: [u8; 8] = *b"rej dump";
+// the first version has the whole pages and no checksum,
+// the second has the values without the trailing zeros, their expiration and checksums
+const DUMP_VERSION: u32 = 2;
+// the length of the key marks the end of the dump
+const DUMP_END: u32 = u32::MAX;
+// the length of the value marks an empty cell
+const DUMP_EMPTY: u32 = u32::MAX;
+// records between the checksums, `Db::import_with` commits them at once
+const DUMP_CHUNK: u64 = 0x100;
+
+// the version of the dump
+fn read_dump_header(r: &mut impl Read) -> Result<u32, DbError> {
+    if read_array(r)? != DUMP_MAGIC {
+        return Err(DbError::BadDump);
+    }
+    let version = u32::from_le_bytes(read_array(r)?);
+    if !(1..=DUMP_VERSION).contains(&version) {
+        return Err(DbError::DumpVersion(version));
+    }
+
+    Ok(version)
+}
+
+/// Made by `Db::size_histogram`. Bucket `i > 0` counts the lengths
+/// from `2^(i-1)` up to `2^i - 1`, the bucket 0 counts the empty ones
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SizeHistogram {
+    pub keys: [u64; SizeHistogram::BUCKETS],
+    /// Lengths by `Value::len`, empty cells have no value
+    pub values: [u64; SizeHistogram::BUCKETS],
+    pub key_bytes: u64,
+    pub value_bytes: u64,
+    /// Keys scanned, empty cells included
+    pub records: u64,
+    /// `false` if the scan is stopped before the end
+    pub complete: bool,
+}
+
+impl SizeHistogram {
+    /// Enough for the longest value, the whole page of a database
+    /// created by an older version, see `Value::capacity`
+    pub const BUCKETS: usize = 14;
+
+    pub fn bucket(len: usize) -> usize {
+        (usize::BITS - len.leading_zeros()) as usize
+    }
+}
+
+/// Made by `Db::export`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ExportStats {
+    /// Keys written, empty cells included
+    pub records: u64,
+    /// Keys written with a value
+    pub values: u64,
+}
+
+/// Made by `Db::import`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ImportStats {
+    /// Keys read from the stream
+    pub records: u64,
+    /// Keys that were in the database already, kept or replaced
+    /// depending on `ImportOptions::overwrite`
+    pub duplicates: u64,
+}
+
+/// How `Db::import_with` treats the keys that are in the database already
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ImportOptions {
+    /// Replace the value of such a key, otherwise the old one is kept
+    pub overwrite: bool,
+}
+
+/// The tree `Db::entry`, `Db::get` and the iterators work with
+pub const MAIN_TREE: u8 = Wal::MAIN;
+
+// the number of a value after its key, see `Db::insert_dup`
+const DUP_SUFFIX: usize = 8;
+
+/// The database is `Send + Sync` and can be shared behind an `Arc`.
+/// Changes of the tree are serialized by the log lock, an `Entry` holds it
+/// until dropped, so `entry` of another thread waits for it,
+/// `try_entry` and `entry_timeout` do not wait forever.
+/// Readers like `get`, `iter_from`, `seek` and `stats` share the lock,
+/// they wait only for a change in progress, not for each other.
+/// Values are read and written without the log lock.
+/// `Value`, the iterators, `Cursor` and `TreeHandle` are `Send + Sync` as well,
+/// they borrow the database, so a thread needs its own `Arc` or a scope.
+/// Entries and batches hold the log lock and stay on the thread that took it.
+/// The lock is not reentrant: while the thread holds an entry, another `entry`,
+/// `get` or a value write of it fails with `DbError::WouldDeadlock` instead of waiting for itself.
+/// `batch` and the getters that cannot fail, like `stats` or `version`, panic there.
+pub struct Db<N> {
+    // shared with the background sync thread
+    file: Arc<FileIo>,
+    wal: Arc<Wal>,
+    clock: fn() -> SystemTime,
+    background: Mutex<Option<BackgroundSync>>,
+    phantom_data: PhantomData<N>,
+}
+
+struct BackgroundSync {
+    stop: mpsc::Sender<()>,
+    handle: thread::JoinHandle<()>,
+}
+
+impl BackgroundSync {
+    fn spawn(file: Weak<FileIo>, wal: Weak<Wal>, interval: Duration) -> Self {
+        let (stop, rx) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(interval) {
+                let (Some(file), Some(wal)) = (file.upgrade(), wal.upgrade()) else {
+                    break;
+                };
+                // writers wait for this sync only, the lock is released in between
+                let _lock = wal.read();
+                if let Err(err) = file.sync() {
+                    log::error!("failed to sync in background: {err}");
+                }
+            }
+        });
+        BackgroundSync { stop, handle }
+    }
+
+    fn stop(self) {
+        drop(self.stop);
+        if self.handle.join().is_err() {
+            log::error!("background sync thread did panic");
+        }
+    }
+}
+
+/// The thread started by `Db::start_checkpointer`, dropping it stops the thread
+/// and syncs once more. It does not keep the database open.
+pub struct CheckpointHandle {
+    inner: Option<BackgroundSync>,
+    file: Weak<FileIo>,
+    wal: Weak<Wal>,
+}
+
+impl Drop for CheckpointHandle {
+    fn drop(&mut self) {
+        if let Some(inner) = self.inner.take() {
+            inner.stop();
+        }
+        // the state may be inconsistent after a panic, leave it as it crashed
+        if thread::panicking() {
+            return;
+        }
+        if let (Some(file), Some(wal)) = (self.file.upgrade(), self.wal.upgrade()) {
+            // the next sync writes the pages if this thread holds an entry
+            let Ok(_lock) = read_wal(&wal) else {
+                return;
+            };
+            if let Err(err) = file.sync() {
+                log::error!("failed to sync after the checkpointer: {err}");
+            }
+        }
+    }
+}
+
+impl<N> Db<N> {
+    /// Replaces the clock used to expire values, `SystemTime::now` by default
+    pub fn with_clock(mut self, clock: fn() -> SystemTime) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Whether the file is accessed with direct IO,
+    /// it is `false` if the filesystem rejected it
+    pub fn direct(&self) -> bool {
+        self.file.direct()
+    }
+
+    /// Whether the pages are written by io_uring, it is `false` if it is off
+    /// in `IoOptions`, refused by the kernel, or the database is only in memory
+    pub fn uring(&self) -> bool {
+        self.file.uring()
+    }
+
+    /// The maximal number of children of a node, `Node::M` the file is created with.
+    /// It is the const parameter of the node type, `NodePage<8>` and `NodePage<256>`
+    /// coexist in a build, but a file is not opened as a node of another fanout.
+    pub fn fanout(&self) -> usize {
+        read_wal_or_panic(&self.wal).fanout()
+    }
+
+    /// Makes sense only for encrypted database
+    pub fn m_lock(&self) {
+        self.file.m_lock();
+    }
+
+    /// Frees the pages released by the last change, writes the final record
+    /// and waits until the file is on the disk.
+    /// Dropping the database does the same, but ignores errors.
+    pub fn close(self) -> Result<(), DbError> {
+        self.wal.close(&self.file)?;
+
+        Ok(())
+    }
+
+    /// Writes the dirty pages, the operating system may still hold them in memory
+    pub fn sync(&self) -> Result<(), DbError> {
+        self.file.sync()?;
+
+        Ok(())
+    }
+
+    /// Same as `sync`, but waits until the contents of the pages are on the disk,
+    /// like `fdatasync`. The length the file did grow to is on the disk too,
+    /// other metadata, e.g. the modification time, may be lost by a crash.
+    pub fn sync_data(&self) -> Result<(), DbError> {
+        self.file.sync_data()?;
+
+        Ok(())
+    }
+
+    /// Same as `sync_data`, but waits for all the metadata of the file too, like `fsync`
+    pub fn sync_all(&self) -> Result<(), DbError> {
+        self.file.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Spawns a thread that writes the dirty pages every `interval`,
+    /// so they are on the disk even if nobody calls `sync`.
+    /// A change in progress is never flushed half done, the thread waits for it.
+    /// Replaces the thread started before, if any. Dropping the database stops it.
+    pub fn start_background_sync(&self, interval: Duration) {
+        self.stop_background_sync();
+
+        let file = Arc::downgrade(&self.file);
+        let wal = Arc::downgrade(&self.wal);
+        *self.background.lock().expect("poisoned") =
+            Some(BackgroundSync::spawn(file, wal, interval));
+    }
+
+    /// Stops the thread started by `start_background_sync` and waits for it.
+    /// Call it while no entry of this database is held, or it deadlocks.
+    pub fn stop_background_sync(&self) {
+        let background = self.background.lock().expect("poisoned").take();
+        if let Some(background) = background {
+            background.stop();
+        }
+    }
+
+    /// Like `start_background_sync`, but the thread lives as long as the handle,
+    /// so the owner, e.g. a server, stops it without touching the database.
+    /// Each sync makes the last record durable, a crash after it loses nothing before.
+    /// Dropping the handle syncs once more, unless it is dropped by a panic,
+    /// drop it while no entry of this database is held, or it deadlocks.
+    pub fn start_checkpointer(&self, interval: Duration) -> CheckpointHandle {
+        let file = Arc::downgrade(&self.file);
+        let wal = Arc::downgrade(&self.wal);
+        CheckpointHandle {
+            inner: Some(BackgroundSync::spawn(file.clone(), wal.clone(), interval)),
+            file,
+            wal,
+        }
+    }
+
+    /// Writes the dirty pages, then drops every page cached in memory,
+    /// e.g. after a big scan. Returns the number of pages dropped,
+    /// always zero if the database is only in memory.
+    pub fn release_cache(&self) -> Result<u32, DbError> {
+        Ok(self.file.release_cache()?)
+    }
+
+    /// Overwrites the header, so the pages cannot be decrypted anymore.
+    /// Fails with `CipherError::NotEncrypted` if the database is not encrypted.
+    pub fn crypt_shred(&self, seed: &[u8]) -> Result<(), DbError> {
+        self.file.crypt_shred(seed)?;
+
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub fn with_simulator(mut self, crash_at: u32, mess_page: bool) -> Self {
+        use super::file::Simulator;
+
+        Arc::get_mut(&mut self.file)
+            .expect("must not be shared yet")
+            .simulator = Simulator {
+            crash_at,
+            mess_page,
+        };
+        self
+    }
+
+    #[cfg(test)]
+    pub fn fail_read_after(&self, n: u32) {
+        self.file
+            .read_budget
+            .store(n, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    #[cfg(test)]
+    pub fn fail_read_of(&self, page: u32) {
+        self.file
+            .failing_page
+            .store(page, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    #[cfg(test)]
+    pub fn fail_syncs(&self, n: u32) {
+        self.file.fail_syncs(n);
+    }
+
+    #[cfg(test)]
+    pub fn set_max_pages(&self, n: u32) {
+        self.file.set_max_pages(n);
+    }
+
+    // as `backup_to` does around the copy
+    #[cfg(test)]
+    pub fn pin_snapshot(&self, pinned: bool) {
+        let mut lock = self.wal.lock();
+        if pinned {
+            lock.pin::<N>();
+        } else {
+            lock.unpin(&self.file).unwrap();
+        }
+    }
+
+    #[cfg(test)]
+    pub fn head(&self) -> u32 {
+        read_wal_or_panic(&self.wal)
+            .current_head::<()>()
+            .raw_number()
+    }
+
+    /// Walks the freelist, takes time proportional to its length
+    pub fn stats(&self) -> DbStats {
+        read_wal_or_panic(&self.wal).stats(&self.file)
+    }
+
+    /// Same as `stats`, but the freelist length is the one kept in the log
+    pub fn stats_fast(&self) -> DbStats {
+        read_wal_or_panic(&self.wal).stats_fast(&self.file)
+    }
+
+    /// What opening the database did find after the last run, `None` if it is created
+    pub fn last_recovery(&self) -> Option<RecoveryReport> {
+        read_wal_or_panic(&self.wal).recovery()
+    }
+
+    /// Counters of operations and page IO since the database is open,
+    /// cheap enough to poll, unlike `stats` it does not take the log lock
+    pub fn metrics(&self) -> DbMetrics {
+        self.file.metrics()
+    }
+
+    /// Keys of the main tree inserted, removed or occupied after `version`, sorted,
+    /// and the current version to ask with next time. Writing a value is not a change.
+    /// Only the last 4096 changes since the database is open are kept in memory,
+    /// a version older than that fails with `DbError::VersionTooOld`,
+    /// so does a version from before `clear`.
+    pub fn changes_since(&self, version: u64) -> Result<(Vec<Vec<u8>>, u64), DbError> {
+        let lock = read_wal(&self.wal)?;
+        let keys = lock
+            .changes_since(version)
+            .map_err(|oldest| DbError::VersionTooOld { version, oldest })?;
+
+        Ok((keys, lock.seq()))
+    }
+
+    /// The sequence number of the last log record, it grows with every record written,
+    /// so an unchanged version means nothing did change since it was taken.
+    /// Bookkeeping of the free pages writes records too, a new version
+    /// does not always mean a changed key.
+    /// The record lands in the slot `version % 256` of the log ring,
+    /// but the version itself does not wrap around, it is 64 bits.
+    /// It survives a reopen, closing writes one more record. After a crash the version
+    /// of the last synced record is back, so take a checkpoint after `sync`.
+    pub fn version(&self) -> u64 {
+        read_wal_or_panic(&self.wal).seq()
+    }
+
+    /// Copies the database into a new file at `dest`.
+    /// Writers are blocked during the copy, so it is consistent.
+    /// The copy is encrypted the same way and can be open with the same secret.
+    /// Fails with `io::ErrorKind::Unsupported` if the database is only in memory,
+    /// or if its header is in a file of its own, see `Params::CreateDetached`,
+    /// `backup_to` works then.
+    pub fn backup(&self, dest: impl AsRef<Path>) -> Result<(), DbError> {
+        let lock = read_wal(&self.wal)?;
+        self.file.backup(dest, lock.size())?;
+
+        Ok(())
+    }
+
+    /// A value not bound to any key yet, it can be written without holding
+    /// the log lock and then inserted by `Vacant::insert_value`.
+    /// Dropping it frees the page, so does a crash before the insertion.
+    /// Only one value can be allocated at a time.
+    /// Dropping it does not wait for the log lock, if an entry holds the lock,
+    /// the page is freed by the next `allocate`, `reclaim` or by closing the database.
+    pub fn allocate(&self) -> Result<Value<'_>, DbError> {
+        let mut lock = lock_wal(&self.wal)?;
+        let ptr = lock.allocate::<MetadataPage>(&self.file)?;
+        self.file
+            .write(ptr, PageKind::Data, MetadataPage::empty())?;
+
+        Ok(Value {
+            ptr,
+            file: &self.file,
+            allocated: Some(&self.wal),
+            inline: None,
+            freed: self.file.freed(),
+        })
+    }
+
+    /// Every page available for allocation:
+    /// the in-memory caches and the persistent freelist
+    pub fn free_pages(&self) -> Result<Vec<u32>, DbError> {
+        Ok(read_wal(&self.wal)?.free_pages(&self.file)?)
+    }
+
+    /// The pages of the persistent freelist in the order it is walked,
+    /// fails with `DbError::CorruptedFile` if a page is out of the file or the freelist loops
+    pub fn freelist_pages(&self) -> Result<Vec<u32>, DbError> {
+        Ok(read_wal(&self.wal)?.freelist_pages(&self.file)?)
+    }
+
+    /// Grows the file by `pages` at once and puts them in the persistent freelist,
+    /// so a bulk load does not grow it chunk by chunk. The space is reserved
+    /// on the disk where the filesystem supports it.
+    pub fn preallocate(&self, pages: u32) -> Result<(), DbError> {
+        lock_wal(&self.wal)?.preallocate(&self.file, pages)?;
+
+        Ok(())
+    }
+
+    /// Moves the deferred garbage to the freelist right now,
+    /// returns the number of pages moved.
+    /// The value returned by the last `Occupied::remove` is freed as well,
+    /// it must not be used after this call.
+    pub fn reclaim(&self) -> Result<u32, DbError> {
+        Ok(lock_wal(&self.wal)?.reclaim(&self.file)?)
+    }
+}
+
+impl<N> Db<N>
+where
+    N: Copy + PlainData + Node,
+{
+    pub fn new(path: impl AsRef<Path>, params: Params) -> Result<Self, DbError> {
+        Self::new_with(path, params, IoOptions::default())
+    }
+
+    /// Same as `new`, but the way the file is accessed is configurable
+    pub fn new_with(
+        path: impl AsRef<Path>,
+        params: Params,
+        options: IoOptions,
+    ) -> Result<Self, DbError> {
+        Self::new_with_collation(path, params, options, Arc::new(Bytewise))
+    }
+
+    /// Opens the database if the file exists and is not empty, otherwise creates it
+    /// with `params`, which must be the params to create it, e.g. `Params::Create` with its seed.
+    /// An existing file is opened with the same secret, so its header is never written again.
+    pub fn open_or_create(
+        path: impl AsRef<Path>,
+        params: Params,
+        options: IoOptions,
+    ) -> Result<Self, DbError> {
+        let path = path.as_ref();
+        let exists = match fs::metadata(path) {
+            Ok(metadata) => metadata.len() > 0,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => false,
+            Err(err) => return Err(err.into()),
+        };
+        let params = if exists { params.into_open() } else { params };
+
+        Self::new_with(path, params, options)
+    }
+
+    /// Same as `new_with`, but the keys are ordered by `collation`.
+    /// The database must be opened with the same collation every time,
+    /// otherwise it fails with `WalError::Collation`.
+    pub fn new_with_collation(
+        path: impl AsRef<Path>,
+        params: Params,
+        options: IoOptions,
+        collation: Arc<dyn Collation>,
+    ) -> Result<Self, DbError> {
+        let create = params.create();
+        let file = FileIo::new(path, params, options)?.with_collation(collation);
+        let wal = Wal::new(create, &file, N::M)?;
+        if !create {
+            let head = wal.read().current_head();
+            btree::read_node::<N>(&file, head).map_err(|err| {
+                if btree::BadNode::is(&err) {
+                    WalError::BadHead
+                } else {
+                    WalError::Io(err)
+                }
+            })?;
+            let mut lock = wal.lock();
+            if lock.was_pinned() {
+                compact::reclaim_lost::<N>(&mut lock, &file)?;
+            }
+        }
+
+        let db = Db {
+            file: Arc::new(file),
+            wal: Arc::new(wal),
+            clock: SystemTime::now,
+            background: Mutex::new(None),
+            phantom_data: PhantomData,
+        };
+
+        Ok(db)
+    }
+
+    /// Creates an empty database that lives only in memory, for tests and
+    /// ephemeral use. Nothing is written to the disk, the pages are not encrypted,
+    /// everything is gone when the database is dropped.
+    pub fn in_memory() -> Result<Self, DbError> {
+        let file = FileIo::memory();
+        let wal = Wal::new(true, &file, N::M)?;
+
+        Ok(Db {
+            file: Arc::new(file),
+            wal: Arc::new(wal),
+            clock: SystemTime::now,
+            background: Mutex::new(None),
+            phantom_data: PhantomData,
+        })
+    }
+
+    /// Same as `stats`, but also splits the used pages into tree and data pages,
+    /// walks the trees and reads them into the cache
+    pub fn stats_full(&self) -> DbStats {
+        let lock = read_wal_or_panic(&self.wal);
+        let mut stats = lock.stats(&self.file);
+        match compact::page_kinds::<N>(&lock, &self.file) {
+            Ok((tree, data)) => (stats.tree_pages, stats.data_pages) = (tree, data),
+            Err(err) => log::error!("failed to walk the trees: {err}"),
+        }
+
+        stats
+    }
+
+    /// Walks the whole tree, fails with `WalError::Inconsistent` if a page is lost
+    /// or both used and free. Pages can be lost if the process crashes during
+    /// `compact` or `clear`, otherwise it is a bug.
+    pub fn check(&self) -> Result<(), DbError> {
+        compact::check::<N>(&*read_wal(&self.wal)?, &self.file)?;
+
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub fn print<K, D>(&self, k: K)
+    where
+        K: Fn(&[u8]) -> D,
+        D: std::fmt::Display,
+    {
+        let mut v = vec![];
+        self.debug_graphviz(&mut v, |key| k(key).to_string())
+            .expect("debug print");
+        log::debug!("{}", String::from_utf8_lossy(&v));
+    }
+
+    /// Writes the tree as a graphviz digraph, `fmt` renders the keys.
+    /// Writers are blocked until it is done.
+    pub fn debug_graphviz(
+        &self,
+        mut w: impl Write,
+        fmt: impl Fn(&[u8]) -> String,
+    ) -> Result<(), DbError> {
+        let lock = read_wal(&self.wal)?;
+        writeln!(w, "digraph {{")?;
+        btree::graphviz::<N>(&self.file, lock.current_head(), &mut w, &fmt)?;
+        writeln!(w, "}}")?;
+
+        Ok(())
+    }
+
+    /// The entry keeps the log locked until it is dropped,
+    /// so any other call that changes the database blocks meanwhile.
+    /// An expired value is still `Entry::Occupied`, see `Occupied::is_expired`.
+    /// Fails if the length of the key is out of `N::MIN_KEY..=N::MAX_KEY`,
+    /// or with `DbError::WouldDeadlock` if this thread holds an entry or a batch already.
+    pub fn entry<K>(&self, bytes: K) -> Result<Entry<'_, N>, DbError>
+    where
+        K: AsRef<[u8]>,
+    {
+        let bytes = bytes.as_ref();
+        check_key::<N>(bytes.len())?;
+        self.entry_locked(lock_wal(&self.wal)?, Wal::MAIN, bytes)
+    }
+
+    /// Like `entry`, but `None` if the log is locked by another entry
+    pub fn try_entry<K>(&self, bytes: K) -> Result<Option<Entry<'_, N>>, DbError>
+    where
+        K: AsRef<[u8]>,
+    {
+        let bytes = bytes.as_ref();
+        check_key::<N>(bytes.len())?;
+        self.wal
+            .try_lock()
+            .map(|lock| self.entry_locked(lock, Wal::MAIN, bytes))
+            .transpose()
+    }
+
+    /// Like `entry`, but `None` if the log is still locked after `timeout`
+    pub fn entry_timeout<K>(
+        &self,
+        bytes: K,
+        timeout: Duration,
+    ) -> Result<Option<Entry<'_, N>>, DbError>
+    where
+        K: AsRef<[u8]>,
+    {
+        let bytes = bytes.as_ref();
+        check_key::<N>(bytes.len())?;
+        if self.wal.is_locked_here() {
+            return Err(DbError::WouldDeadlock);
+        }
+        self.wal
+            .lock_timeout(timeout)
+            .map(|lock| self.entry_locked(lock, Wal::MAIN, bytes))
+            .transpose()
+    }
+
+    // the tree is created if it does not exist yet
+    fn entry_locked<'a>(
+        &'a self,
+        lock: WalLock<'a>,
+        tree: u8,
+        bytes: &[u8],
+    ) -> Result<Entry<'a, N>, DbError> {
+        let file = &*self.file;
+        let now = (self.clock)();
+
+        file.counters().lookup(1);
+        // a lookup does not create the tree, only an insert does
+        let Some(root) = lock.tree_head(file, tree)? else {
+            return Ok(Entry::Vacant(Vacant {
+                inner: None,
+                tree,
+                lock,
+                file,
+                bytes: bytes.to_vec(),
+                now,
+            }));
+        };
+        let (inner, occupied) = btree::EntryInner::new(file, root, bytes)?;
+        let entry = if occupied {
+            let cell = inner.cell(file)?;
+            if !matches!(cell, Cell::Empty) {
+                Entry::Occupied(Occupied {
+                    cell,
+                    inner,
+                    tree,
+                    lock,
+                    wal: &self.wal,
+                    file,
+                    bytes: bytes.to_vec(),
+                    now,
+                })
+            } else {
+                Entry::Empty(EmptyCell {
+                    inner,
+                    tree,
+                    lock,
+                    wal: &self.wal,
+                    file,
+                    now,
+                })
+            }
+        } else {
+            Entry::Vacant(Vacant {
+                inner: Some(inner),
+                tree,
+                lock,
+                file,
+                bytes: bytes.to_vec(),
+                now,
+            })
+        };
+
+        Ok(entry)
+    }
+
+    /// Same as `export`, without the counts.
+    /// Writers are blocked until the dump is done.
+    pub fn dump(&self, w: impl Write) -> Result<(), DbError> {
+        self.export(w).map(drop)
+    }
+
+    /// Writes the keys and values of the main tree in sorted order into a portable
+    /// stream: each key with its value and expiration, a checksum after every
+    /// chunk of records and at the end. The trailing zeros of a value are not written.
+    /// `Db::restore` and `Db::import` read it. Writers are blocked until the export is done.
+    pub fn export(&self, w: impl Write) -> Result<ExportStats, DbError> {
+        let lock = read_wal(&self.wal)?;
+        let mut it = DbIterator {
+            inner: None,
+            tree: Wal::MAIN,
+        };
+        btree::EntryInner::seek(&mut it.inner, &self.file, lock.current_head(), b"")?;
+
+        let mut w = Checked::new(w);
+        w.write_all(&DUMP_MAGIC)?;
+        w.write_all(&DUMP_VERSION.to_le_bytes())?;
+        let mut stats = ExportStats::default();
+        while let Some((key, value)) = self.next(&mut it)? {
+            w.write_all(&(key.len() as u32).to_le_bytes())?;
+            w.write_all(&key)?;
+            if let Some(value) = value {
+                let page = value.metadata()?;
+                let plain = page.plain(self.file.expiry());
+                let len = plain.iter().rposition(|b| *b != 0).map_or(0, |pos| pos + 1);
+                let expires = page.expires(self.file.expiry()).map_or(0, |time| {
+                    let ms = time.duration_since(SystemTime::UNIX_EPOCH);
+                    (ms.unwrap_or_default().as_millis() as u64).max(1)
+                });
+                w.write_all(&(len as u32).to_le_bytes())?;
+                w.write_all(&plain[..len])?;
+                w.write_all(&expires.to_le_bytes())?;
+                stats.values += 1;
+            } else {
+                w.write_all(&DUMP_EMPTY.to_le_bytes())?;
+            }
+            stats.records += 1;
+            if stats.records % DUMP_CHUNK == 0 {
+                let crc = w.crc;
+                w.write_all(&crc.to_le_bytes())?;
+            }
+        }
+        drop(lock);
+        w.write_all(&DUMP_END.to_le_bytes())?;
+        let crc = w.crc;
+        w.inner.write_all(&crc.to_le_bytes())?;
+
+        Ok(stats)
+    }
+
+    /// Same as `import_with` with the default options,
+    /// the keys that are in the database already are kept as they are
+    pub fn import(&self, r: impl Read) -> Result<ImportStats, DbError> {
+        self.import_with(r, ImportOptions::default())
+    }
+
+    /// Inserts the records written by `export` or `dump` into the main tree, the database
+    /// may be of another node type. Each chunk of records goes into a batch committed
+    /// only if its checksum matches, so a corrupted stream fails with `DbError::Corrupted`
+    /// and the chunks before it stay. The log is locked while a chunk is inserted.
+    pub fn import_with(
+        &self,
+        r: impl Read,
+        options: ImportOptions,
+    ) -> Result<ImportStats, DbError> {
+        let mut r = Checked::new(r);
+        let version = read_dump_header(&mut r)?;
+
+        self.import_records(r, version, options)
+    }
+
+    /// Creates a new database at `path` from the stream written by `Db::dump` or `Db::export`.
+    /// The node type and the feature set may differ from the dumped database.
+    pub fn restore(path: impl AsRef<Path>, params: Params, r: impl Read) -> Result<Self, DbError> {
+        let mut r = Checked::new(r);
+        let version = read_dump_header(&mut r)?;
+
+        let db = Self::new(path, params)?;
+        db.import_records(r, version, ImportOptions::default())?;
+        db.sync()?;
+
+        Ok(db)
+    }
+
+    fn import_records(
+        &self,
+        mut r: Checked<impl Read>,
+        version: u32,
+        options: ImportOptions,
+    ) -> Result<ImportStats, DbError> {
+        let mut stats = ImportStats::default();
+        let mut batch = self.try_batch()?;
+        loop {
+            let key_len = u32::from_le_bytes(read_array(&mut r)?);
+            if key_len == DUMP_END {
+                break;
+            }
+            check_key::<N>(key_len as usize)?;
+            let mut key = vec![0; key_len as usize];
+            r.read_exact(&mut key)?;
+
+            let value_len = u32::from_le_bytes(read_array(&mut r)?);
+            let value = if value_len == DUMP_EMPTY {
+                None
+            } else if version == 1 {
+                // the page as is, including the expiration
+                if u64::from(value_len) > PAGE_SIZE {
+                    return Err(DbError::BadDump);
+                }
+                let mut page = vec![0; value_len as usize];
+                r.read_exact(&mut page)?;
+                Some((page, None))
+            } else {
+                // the value of a database created by an older version takes the whole page,
+                // `write_at` fails if it does not fit here
+                if u64::from(value_len) > PAGE_SIZE {
+                    return Err(DbError::BadDump);
+                }
+                let mut plain = vec![0; value_len as usize];
+                r.read_exact(&mut plain)?;
+                let expires = u64::from_le_bytes(read_array(&mut r)?);
+                Some((plain, Some(expires)))
+            };
+
+            stats.records += 1;
+            let duplicate = batch.contains(Wal::MAIN, &key)?;
+            if duplicate {
+                stats.duplicates += 1;
+                if options.overwrite {
+                    batch.remove(Wal::MAIN, &key)?;
+                }
+            }
+            if !duplicate || options.overwrite {
+                match value {
+                    None => batch.insert_empty(Wal::MAIN, &key)?,
+                    Some((page, None)) => {
+                        let value = batch.insert(Wal::MAIN, &key)?;
+                        let n = value.ptr.raw_number();
+                        let mut full = self.file.read_page(n)?;
+                        full[..page.len()].clone_from_slice(&page);
+                        self.file.write_page(n, PageKind::Data, full)?;
+                    }
+                    Some((plain, Some(expires))) => {
+                        let value = batch.insert(Wal::MAIN, &key)?;
+                        value.write_at(0, &plain)?;
+                        if expires != 0 {
+                            value.set_expires(Some(
+                                SystemTime::UNIX_EPOCH + Duration::from_millis(expires),
+                            ))?;
+                        }
+                    }
+                }
+            }
+
+            // the first version has no checksum, the chunks are committed all the same
+            if stats.records % DUMP_CHUNK == 0 {
+                if version > 1 {
+                    let crc = r.crc;
+                    if u64::from_le_bytes(read_array(&mut r)?) != crc {
+                        return Err(DbError::Corrupted);
+                    }
+                }
+                batch.commit()?;
+                batch = self.try_batch()?;
+            }
+        }
+        if version > 1 {
+            let crc = r.crc;
+            if u64::from_le_bytes(read_array(&mut r.inner)?) != crc {
+                return Err(DbError::Corrupted);
+            }
+        }
+        batch.commit()?;
+
+        Ok(stats)
+    }
+
+    /// The changes after `version` for a replica, ship them with `ChangeSet::write_to`.
+    /// Ask with `version` of the replica, each time the replica gets the version
+    /// of this database. If the version is older than the last 4096 records since
+    /// the database is open, 0 for instance, the changes are the whole database.
+    /// Writers are blocked meanwhile, unless they only write values.
+    pub fn export_changes(&self, version: u64) -> Result<ChangeSet, DbError> {
+        let lock = read_wal(&self.wal)?;
+
+        Ok(replica::export::<N>(&lock, &self.file, version)?)
+    }
+
+    /// Takes the changes of another database of the same node type and features,
+    /// they must follow the current version unless they are the whole database.
+    /// The replica must not change meanwhile, neither should it be reopen,
+    /// both write records of its own and `WalError::Diverged` follows,
+    /// the whole database is needed again then.
+    pub fn apply_changes(&self, changes: &ChangeSet) -> Result<(), DbError> {
+        let mut lock = lock_wal(&self.wal)?;
+        replica::apply::<N>(&mut lock, &self.file, changes)
+    }
+
+    /// Copies the current state into a new database at `path`
+    /// created with `params`, so it can be encrypted differently.
+    /// Writers are not blocked, the pages they release are not reused until the copy is done.
+    /// Only the tree is a snapshot, a value written in place meanwhile,
+    /// by `Value::write_at` for instance, may be copied old, new or partly written.
+    /// If the process crashes meanwhile, the next open frees those pages.
+    pub fn backup_to(&self, path: impl AsRef<Path>, params: Params) -> Result<(), DbError> {
+        let (head, trees) = {
+            let mut lock = lock_wal(&self.wal)?;
+            (lock.pin(), lock.trees())
+        };
+        let res = self.copy_snapshot(head, trees, path, params);
+        lock_wal(&self.wal)?.unpin(&self.file)?;
+
+        res
+    }
+
+    /// Moves every page to the beginning of the file and shrinks the file.
+    /// Values and iterators obtained before must not be used after this call,
+    /// the value returned by the last `Occupied::remove` is freed as well.
+    /// Fails with `WalError::Pinned` while `backup_to` is running.
+    /// If the process crashes meanwhile, the free pages are lost until the next compaction.
+    pub fn compact(&self) -> Result<(), DbError> {
+        let mut lock = lock_wal(&self.wal)?;
+        compact::compact::<N>(&mut lock, &self.file)?;
+
+        Ok(())
+    }
+
+    /// Removes every record at once, instead of one by one, of every tree.
+    /// The file, its header and the log stay, the freed pages are reused.
+    /// Values and iterators obtained before must not be used after this call,
+    /// the value returned by the last `Occupied::remove` is freed as well.
+    /// If the process crashes meanwhile, the database is either intact or empty,
+    /// the pages of the old tree are lost until the next compaction.
+    pub fn clear(&self) -> Result<(), DbError> {
+        let mut lock = lock_wal(&self.wal)?;
+        compact::clear::<N>(&mut lock, &self.file)?;
+
+        Ok(())
+    }
+
+    fn copy_snapshot(
+        &self,
+        head: PagePtr<N>,
+        trees: Option<PagePtr<TreesPage>>,
+        path: impl AsRef<Path>,
+        params: Params,
+    ) -> Result<(), DbError> {
+        let collation = self.file.collation().cloned();
+        let collation = collation.unwrap_or_else(|| Arc::new(Bytewise));
+        let dest = Db::<N>::new_with_collation(path, params, IoOptions::default(), collation)?;
+        let file = &*self.file;
+        // the value pages are copied as they are, with or without the expiration
+        if !file.expiry() {
+            lock_wal(&dest.wal)?.set_expiry(&dest.file, false)?;
+        }
+
+        self.copy_tree(&dest.tree(Wal::MAIN), head)?;
+        if let Some(ptr) = trees {
+            for (id, root) in file.read(ptr)?.roots() {
+                self.copy_tree(&dest.tree(id), root.cast())?;
+            }
+        }
+        dest.sync()
+    }
+
+    fn copy_tree(&self, dest: &TreeHandle<'_, N>, root: PagePtr<N>) -> Result<(), DbError> {
+        let file = &*self.file;
+
+        let mut it = btree::EntryInner::first(file, root)?;
+        while let Some(inner) = &it {
+            let key = inner.key(file)?;
+            let vacant = dest.entry(&key)?.vacant().expect("keys must be unique");
+            match inner.cell(file)? {
+                Cell::Page(ptr) => {
+                    let page = file.read_page(ptr.raw_number())?;
+                    let value = vacant.insert()?;
+                    dest.db
+                        .file
+                        .write_page(value.ptr.raw_number(), PageKind::Data, page)?;
+                }
+                Cell::Inline(inline) => vacant.insert_plain(&inline)?,
+                Cell::Empty => vacant.insert_empty()?,
+            }
+            btree::EntryInner::next(&mut it, file)?;
+        }
+
+        Ok(())
+    }
+
+    /// The value, `None` if there is none or it is expired.
+    /// An expired value is skipped, not removed, see `purge_expired`.
+    /// Takes the shared lock, so readers do not wait for each other.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Value<'_>>, DbError> {
+        self.get_in(Wal::MAIN, key)
+    }
+
+    fn get_in(&self, tree: u8, key: &[u8]) -> Result<Option<Value<'_>>, DbError> {
+        check_key::<N>(key.len())?;
+        let file = &*self.file;
+        let now = (self.clock)();
+        file.counters().lookup(1);
+
+        let lock = read_wal(&self.wal)?;
+        let value = self.find_in(&lock, tree, key)?;
+        let value = self.unexpired(value, now)?;
+        drop(lock);
+
+        Ok(value)
+    }
+
+    // `None` if the value is expired by `now`, every read skips it by this check
+    fn unexpired<'a>(
+        &self,
+        value: Option<Value<'a>>,
+        now: SystemTime,
+    ) -> Result<Option<Value<'a>>, DbError> {
+        let Some(value) = value else {
+            return Ok(None);
+        };
+        let expired = value.metadata()?.is_expired(self.file.expiry(), now);
+
+        Ok((!expired).then_some(value))
+    }
+
+    /// Same as `get`, the tree is walked as usual, but the value page is awaited
+    /// if it is missing in the cache, see `Value::read_async`
+    #[cfg(feature = "async")]
+    pub async fn get_async(&self, key: &[u8]) -> Result<Option<Value<'_>>, DbError> {
+        check_key::<N>(key.len())?;
+        let now = (self.clock)();
+        self.file.counters().lookup(1);
+
+        let value = self.find_in(&read_wal(&self.wal)?, Wal::MAIN, key)?;
+        let Some(value) = value else {
+            return Ok(None);
+        };
+        let expired = value
+            .metadata_async()
+            .await?
+            .is_expired(self.file.expiry(), now);
+
+        Ok((!expired).then_some(value))
+    }
+
+    // the value of the key, expired or not
+    fn find_in(
+        &self,
+        lock: &WalReadLock<'_>,
+        tree: u8,
+        key: &[u8],
+    ) -> Result<Option<Value<'_>>, DbError> {
+        let file = &*self.file;
+        let Some(root) = lock.tree_head(file, tree)? else {
+            return Ok(None);
+        };
+        let (inner, occupied) = btree::EntryInner::<N>::new(file, root, key)?;
+        let cell = if occupied {
+            inner.cell(file)?
+        } else {
+            Cell::Empty
+        };
+
+        Ok(cell_value::<N>(cell, &self.wal, file, tree, key))
+    }
+
+    /// Keeps the metadata page of the value in the cache, neither a sync nor
+    /// `release_cache` drops it. False if there is no such key or the value is inline.
+    /// The page is unpinned once it is freed, e.g. the value is removed or replaced.
+    pub fn pin(&self, key: &[u8]) -> Result<bool, DbError> {
+        check_key::<N>(key.len())?;
+        let lock = read_wal(&self.wal)?;
+        let Some(value) = self.find_in(&lock, Wal::MAIN, key)? else {
+            return Ok(false);
+        };
+        let Place::Page(ptr) = value.place() else {
+            return Ok(false);
+        };
+        self.file.pin(ptr.raw_number());
+        value.metadata()?;
+
+        Ok(true)
+    }
+
+    /// Undoes `pin`, false if the value is not pinned
+    pub fn unpin(&self, key: &[u8]) -> Result<bool, DbError> {
+        check_key::<N>(key.len())?;
+        let lock = read_wal(&self.wal)?;
+        let Some(value) = self.find_in(&lock, Wal::MAIN, key)? else {
+            return Ok(false);
+        };
+        let Place::Page(ptr) = value.place() else {
+            return Ok(false);
+        };
+
+        Ok(self.file.unpin(ptr.raw_number()))
+    }
+
+    /// Values of the keys in the order of `keys`, same as `get` for each of them.
+    /// The keys are looked up in sorted order under one shared lock, each lookup
+    /// starts from the nodes of the previous one where the paths split.
+    pub fn multi_get(&self, keys: &[&[u8]]) -> Result<Vec<Option<Value<'_>>>, DbError> {
+        self.multi_get_in(Wal::MAIN, keys)
+    }
+
+    fn multi_get_in(&self, tree: u8, keys: &[&[u8]]) -> Result<Vec<Option<Value<'_>>>, DbError> {
+        for key in keys {
+            check_key::<N>(key.len())?;
+        }
+        let file = &*self.file;
+        let now = (self.clock)();
+        file.counters().lookup(keys.len() as u64);
+
+        let mut order = (0..keys.len()).collect::<Vec<_>>();
+        order.sort_by(|a, b| file.compare(keys[*a], keys[*b]));
+
+        let mut values = keys.iter().map(|_| None).collect::<Vec<_>>();
+        let lock = read_wal(&self.wal)?;
+        let Some(root) = lock.tree_head(file, tree)? else {
+            return Ok(values);
+        };
+        let mut inner = None::<btree::EntryInner<N>>;
+        for i in order {
+            let (this, occupied) = match inner.take() {
+                Some(inner) => inner.redescend(file, keys[i])?,
+                None => btree::EntryInner::new(file, root, keys[i])?,
+            };
+            let cell = if occupied {
+                this.cell(file)?
+            } else {
+                Cell::Empty
+            };
+            let value = cell_value::<N>(cell, &self.wal, file, tree, keys[i]);
+            values[i] = self.unexpired(value, now)?;
+            inner = Some(this);
+        }
+        drop(lock);
+
+        Ok(values)
+    }
+
+    /// Iterator at the first key that is not less than `key`,
+    /// takes the shared lock only to find it
+    pub fn iter_from(&self, key: &[u8]) -> Result<DbIterator<N>, DbError> {
+        let mut it = DbIterator {
+            inner: None,
+            tree: Wal::MAIN,
+        };
+        self.seek_in(Wal::MAIN, &mut it, key)?;
+
+        Ok(it)
+    }
+
+    /// Iterator at the first key that is greater than `key`, whether `key` is there
+    /// or not, to resume a scan after the last seen key
+    pub fn range_after(&self, key: &[u8]) -> Result<DbIterator<N>, DbError> {
+        let mut it = self.iter_from(key)?;
+        let file = &*self.file;
+        if let Some(inner) = it.inner.as_mut() {
+            if file.compare(&inner.cached_key(file)?, key).is_eq() {
+                btree::EntryInner::next(&mut it.inner, file)?;
+            }
+        }
+
+        Ok(it)
+    }
+
+    /// An unpositioned cursor over the main tree, see `Cursor`
+    pub fn cursor(&self) -> Cursor<'_, N> {
+        Cursor {
+            db: self,
+            inner: None,
+            seq: 0,
+        }
+    }
+
+    /// The tree `id` of the database, `MAIN_TREE` is the one of `entry` and `get`.
+    /// A tree is created by its first entry or batch, until then it is empty.
+    pub fn tree(&self, id: u8) -> TreeHandle<'_, N> {
+        TreeHandle { db: self, id }
+    }
+
+    /// Changes of several trees committed at once, see `Batch`.
+    /// Panics if this thread holds an entry or a batch already, see `try_batch`.
+    pub fn batch(&self) -> Batch<'_, N> {
+        self.try_batch()
+            .expect("the log is locked by this thread already")
+    }
+
+    /// Same as `batch`, but fails with `DbError::WouldDeadlock`
+    /// if this thread holds an entry or a batch already
+    pub fn try_batch(&self) -> Result<Batch<'_, N>, DbError> {
+        let mut lock = lock_wal(&self.wal)?;
+        let pages = Some(lock.begin_batch());
+        Ok(Batch {
+            db: self,
+            lock,
+            pages,
+            roots: BTreeMap::new(),
+            changed: vec![],
+            inserts: 0,
+            removes: 0,
+        })
+    }
+
+    /// Removes the keys and their values in one batch, either all or none of them.
+    /// The keys are sorted first, so the neighbours share the path, the pages they
+    /// free are held by the batch rather than the freelist cache of the record.
+    /// Returns how many keys were there, an absent or repeated key is skipped.
+    pub fn remove_many(&self, keys: &[&[u8]]) -> Result<usize, DbError> {
+        for key in keys {
+            check_key::<N>(key.len())?;
+        }
+        let file = &*self.file;
+        let mut keys = keys.to_vec();
+        keys.sort_by(|a, b| file.compare(a, b));
+        keys.dedup_by(|a, b| file.compare(a, b).is_eq());
+
+        let mut batch = self.try_batch()?;
+        let mut removed = 0;
+        for key in keys {
+            if batch.remove(Wal::MAIN, key)? {
+                removed += 1;
+            }
+        }
+        batch.commit()?;
+
+        Ok(removed)
+    }
+
+    /// Inserts disjoint ranges of the main tree, the iterator of each range is drained
+    /// on a thread of its own, without the log lock, the keys must be ascending
+    /// and inside the range. Only producing and checking the items is parallel,
+    /// the tree is not: one batch on the calling thread inserts all of them in order,
+    /// so either every key is in the database or none.
+    /// It only appends, it fails with `DbError::KeyExists` if a key is there,
+    /// and with `DbError::BadRange` if the ranges overlap or a key is out of place.
+    /// Returns the number of inserted keys.
+    pub fn insert_ranges<I>(&self, mut ranges: Vec<(Range<Vec<u8>>, I)>) -> Result<u64, DbError>
+    where
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)> + Send,
+    {
+        let file = &*self.file;
+        ranges.sort_by(|(a, _), (b, _)| file.compare(&a.start, &b.start));
+        for pair in ranges.windows(2) {
+            if file.compare(&pair[0].0.end, &pair[1].0.start).is_gt() {
+                return Err(DbError::BadRange);
+            }
+        }
+        let loaded = thread::scope(|s| {
+            let loaders = ranges
+                .into_iter()
+                .map(|(range, items)| s.spawn(move || load_range::<N, _>(file, range, items)))
+                .collect::<Vec<_>>();
+            loaders
+                .into_iter()
+                .map(|loader| {
+                    loader
+                        .join()
+                        .unwrap_or_else(|err| panic::resume_unwind(err))
+                })
+                .collect::<Result<Vec<_>, _>>()
+        })?;
+
+        let mut batch = self.try_batch()?;
+        let mut inserted = 0;
+        for (key, plain) in loaded.into_iter().flatten() {
+            if batch.contains(Wal::MAIN, &key)? {
+                return Err(DbError::KeyExists);
+            }
+            batch.insert(Wal::MAIN, &key)?.write_at(0, &plain)?;
+            inserted += 1;
+        }
+        batch.commit()?;
+
+        Ok(inserted)
+    }
+
+    /// Inserts `value` only if there is no `key`, returns whether it did.
+    /// The value is written before the log is unlocked, so nobody sees the key without it.
+    /// An expired value is replaced, an empty cell counts as present.
+    pub fn try_insert(&self, key: &[u8], value: &[u8]) -> Result<bool, DbError> {
+        Value::check_bounds(&self.file, 0, value.len())?;
+        match self.entry(key)? {
+            Entry::Occupied(v) if v.is_expired()? => {
+                self.file
+                    .write(v.as_value().ptr, PageKind::Data, MetadataPage::new(value))?;
+                Ok(true)
+            }
+            Entry::Occupied(_) | Entry::Empty(_) => Ok(false),
+            Entry::Vacant(v) => {
+                v.insert_plain(value)?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Moves the value of `from` to `to` with one log record, the value page is the same,
+    /// so a `Value` of it stays valid. An empty cell moves as well.
+    /// A `Value` of an inline value is found by `from`, it does not follow.
+    /// Fails if there is no `from` or if there is `to` already.
+    pub fn rename(&self, from: &[u8], to: &[u8]) -> Result<(), DbError> {
+        check_key::<N>(from.len())?;
+        check_key::<N>(to.len())?;
+        let file = &*self.file;
+        let mut lock = lock_wal(&self.wal)?;
+
+        let root = lock.current_head();
+        file.counters().lookup(2);
+        let (inner, occupied) = btree::EntryInner::<N>::new(file, root, from)?;
+        if !occupied {
+            return Err(DbError::KeyNotFound);
+        }
+        if btree::EntryInner::<N>::new(file, root, to)?.1 {
+            return Err(DbError::KeyExists);
+        }
+        let cell = inner.cell(file)?;
+        // the removal is written first, the new path goes through its pages
+        let new_head = lock.transaction(|alloc, free| {
+            let mut storage = Default::default();
+            let mut rt = Rt::new(&mut *alloc, &mut *free, file, &mut storage);
+            let head = inner.remove(rt.reborrow())?;
+            rt.flush()?;
+
+            let (inner, _) = btree::EntryInner::<N>::new(file, head, to)?;
+            let mut storage = Default::default();
+            let mut rt = Rt::new(alloc, free, file, &mut storage);
+            let head = inner.insert(rt.reborrow(), cell, to)?;
+            rt.flush()?;
+
+            io::Result::Ok(head)
+        })?;
+        lock.new_head(file, new_head, None)?;
+        lock.touch(from.to_vec());
+        lock.touch(to.to_vec());
+
+        Ok(())
+    }
+
+    /// Inserts one more value of `key` and returns it, several values of the same key
+    /// are found by `entry_dup`. The tree keeps `key` followed by the number
+    /// of the value, big endian, one more than the last one, so `entry` of such a key
+    /// reaches the single value. A plain key that looks the same is taken for a value
+    /// of `key`. The number follows the order of insertion if the order is bytewise.
+    pub fn insert_dup(&self, key: &[u8]) -> Result<Value<'_>, DbError> {
+        check_key::<N>(key.len() + DUP_SUFFIX)?;
+        let file = &*self.file;
+        let mut bytes = [key, &[0xff; DUP_SUFFIX]].concat();
+        let Entry::Vacant(vacant) = self.entry_locked(lock_wal(&self.wal)?, Wal::MAIN, &bytes)?
+        else {
+            // every number is taken
+            return Err(DbError::OutOfBounds);
+        };
+        // the last value is right before the place of the greatest number
+        let mut last = vacant.inner.clone();
+        btree::EntryInner::prev(&mut last, file)?;
+        let last = last.map(|last| last.key(file)).transpose()?;
+        let n = last
+            .filter(|last| last.len() == bytes.len() && last.starts_with(key))
+            .map_or(0, |last| {
+                let n = last[key.len()..].try_into().expect("must be the suffix");
+                u64::from_be_bytes(n) + 1
+            });
+        bytes[key.len()..].clone_from_slice(&n.to_be_bytes());
+
+        // a separator may lie between the two keys, so it is found again
+        match self.entry_locked(vacant.lock, Wal::MAIN, &bytes)? {
+            Entry::Vacant(vacant) => vacant.insert(),
+            _ => Err(DbError::KeyExists),
+        }
+    }
+
+    /// The values inserted by `insert_dup`, see `DupIter`
+    pub fn entry_dup(&self, key: &[u8]) -> Result<DupIter<'_, N>, DbError> {
+        check_key::<N>(key.len() + DUP_SUFFIX)?;
+        let it = self.iter_from(&[key, &[0; DUP_SUFFIX]].concat())?;
+
+        Ok(DupIter {
+            db: self,
+            it,
+            key: key.to_vec(),
+        })
+    }
+
+    /// The keys of the main tree in the range with copies of their values,
+    /// each cut after its last byte that is not zero, see `Value::len`.
+    /// Keys without a value are skipped. The database may change in between,
+    /// like `next` it continues from the key after the last one.
+    pub fn scan_values<R>(&self, range: R) -> Result<ScanValues<'_, N>, DbError>
+    where
+        R: RangeBounds<[u8]>,
+    {
+        let it = match range.start_bound() {
+            Bound::Included(key) => self.iter_from(key)?,
+            Bound::Excluded(key) => self.range_after(key)?,
+            Bound::Unbounded => self.iter_from(&[])?,
+        };
+
+        Ok(ScanValues {
+            db: self,
+            it,
+            end: range.end_bound().map(<[u8]>::to_vec),
+        })
+    }
+
+    /// Makes the key present without a value: inserts an empty cell,
+    /// or frees the value if there is one. Does nothing to an empty cell.
+    pub fn insert_tombstone(&self, key: &[u8]) -> Result<(), DbError> {
+        match self.entry(key)? {
+            Entry::Occupied(v) => v.into_empty().map(drop),
+            Entry::Empty(_) => Ok(()),
+            Entry::Vacant(v) => v.insert_empty(),
+        }
+    }
+
+    /// Returns the value, inserts a new empty value if there is none.
+    /// An expired value is replaced with an empty one.
+    pub fn get_or_insert(&self, key: &[u8]) -> Result<Value<'_>, DbError> {
+        match self.entry(key)? {
+            Entry::Occupied(v) if v.is_expired()? => {
+                let value = v.into_value();
+                self.file
+                    .write(value.ptr, PageKind::Data, MetadataPage::empty())?;
+                Ok(value)
+            }
+            Entry::Occupied(v) => Ok(v.into_value()),
+            Entry::Empty(v) => v.occupy().map(Occupied::into_value),
+            Entry::Vacant(v) => v.insert(),
+        }
+    }
+
+    /// Removes the values expired by the clock of the database, returns how many.
+    /// Reads only skip expired values, this is what frees their pages.
+    /// The log is locked for a batch of records at a time, so writers can interleave.
+    pub fn purge_expired(&self) -> Result<u32, DbError> {
+        self.purge_expired_at((self.clock)())
+    }
+
+    /// Same as `purge_expired`, but the values are expired by `now`, not by the clock
+    pub fn purge_expired_at(&self, now: SystemTime) -> Result<u32, DbError> {
+        const BATCH: usize = 0x40;
+
+        let file = &*self.file;
+        let mut purged = 0;
+        let mut from = None::<Vec<u8>>;
+        loop {
+            let mut lock = lock_wal(&self.wal)?;
+            let mut it = match &from {
+                None => btree::EntryInner::<N>::first(file, lock.current_head())?,
+                Some(key) => {
+                    let (inner, _) = btree::EntryInner::new(file, lock.current_head(), key)?;
+                    let mut it = Some(inner);
+                    // the position may be past the end of the leaf
+                    if it.as_ref().is_some_and(|inner| !inner.has_value()) {
+                        btree::EntryInner::next(&mut it, file)?;
+                    }
+                    it
+                }
+            };
+
+            let mut expired = vec![];
+            for _ in 0..BATCH {
+                let Some(inner) = &it else {
+                    break;
+                };
+                if let Some(ptr) = inner.meta() {
+                    let page = file.read_page(ptr.raw_number())?;
+                    if MetadataPage::as_this(&*page).is_expired(file.expiry(), now) {
+                        expired.push(inner.key(file)?);
+                    }
+                }
+                btree::EntryInner::next(&mut it, file)?;
+            }
+            from = it.map(|inner| inner.key(file)).transpose()?;
+
+            for key in expired {
+                let (inner, _) = btree::EntryInner::<N>::new(file, lock.current_head(), &key)?;
+                let ptr = inner.meta().expect("must be metadata");
+
+                let new_head = transaction(&mut lock, file, |rt| inner.remove(rt))?;
+                lock.new_head(file, new_head, Some(ptr.cast()))?;
+                lock.touch(key);
+                file.counters().remove(1);
+                purged += 1;
+            }
+            drop(lock);
+
+            if from.is_none() {
+                break;
+            }
+        }
+
+        Ok(purged)
+    }
+
+    /// Like `next`, but only the key
+    pub fn next_key(&self, it: &mut DbIterator<N>) -> Result<Option<Vec<u8>>, DbError> {
+        let file = &*self.file;
+        let Some(inner) = it.inner.as_mut() else {
+            return Ok(None);
+        };
+        let key = inner.cached_key(file)?;
+        btree::EntryInner::next(&mut it.inner, file)?;
+
+        Ok(Some(key))
+    }
+
+    /// Skips `n` entries without reading their keys and values,
+    /// returns how many were skipped, less than `n` if the end is reached
+    pub fn advance_by(&self, it: &mut DbIterator<N>, n: usize) -> Result<usize, DbError> {
+        Ok(btree::EntryInner::advance(&mut it.inner, &self.file, n)?)
+    }
+
+    /// Moves the iterator to the first key that is not less than `key`,
+    /// forward or backward. It is exhausted if there is no such key.
+    pub fn seek(&self, it: &mut DbIterator<N>, key: &[u8]) -> Result<(), DbError> {
+        self.seek_in(Wal::MAIN, it, key)
+    }
+
+    fn seek_in(&self, tree: u8, it: &mut DbIterator<N>, key: &[u8]) -> Result<(), DbError> {
+        check_prefix::<N>(key.len())?;
+        let lock = read_wal(&self.wal)?;
+        it.tree = tree;
+        match lock.tree_head(&self.file, tree)? {
+            Some(root) => btree::EntryInner::seek(&mut it.inner, &self.file, root, key)?,
+            None => it.inner = None,
+        }
+
+        Ok(())
+    }
+
+    /// Folds the values whose keys start with `prefix` in order of keys.
+    /// The key is borrowed from the leaf, empty cells and expired values are skipped.
+    /// Stops early as soon as `f` breaks.
+    /// The log is locked only to find the first key, so `f` may use the database.
+    pub fn fold_prefix<K, B, F>(&self, prefix: K, init: B, mut f: F) -> Result<B, DbError>
+    where
+        K: AsRef<[u8]>,
+        F: FnMut(B, &[u8], Value<'_>) -> ControlFlow<B, B>,
+    {
+        let file = &*self.file;
+        let now = (self.clock)();
+        let prefix = prefix.as_ref();
+        check_prefix::<N>(prefix.len())?;
+        let mut it = None::<btree::EntryInner<N>>;
+        {
+            let lock = read_wal(&self.wal)?;
+            btree::EntryInner::seek(&mut it, file, lock.current_head(), prefix)?;
+        }
+
+        let mut acc = init;
+        while let Some(inner) = &mut it {
+            let cell = inner.cell(file)?;
+            let key = inner.cached_key_ref(file)?;
+            if !key.starts_with(prefix) {
+                break;
+            }
+            let value = cell_value::<N>(cell, &self.wal, file, Wal::MAIN, key);
+            if let Some(value) = self.unexpired(value, now)? {
+                match f(acc, key, value) {
+                    ControlFlow::Continue(b) => acc = b,
+                    ControlFlow::Break(b) => return Ok(b),
+                }
+            }
+            btree::EntryInner::next(&mut it, file)?;
+        }
+
+        Ok(acc)
+    }
+
+    /// Counts the lengths of the keys and the values of the main tree, see `SizeHistogram`.
+    /// Reads every leaf and every value page, so it stops as soon as `stop` is set,
+    /// what is counted so far is returned. Like `fold_prefix` it locks the log
+    /// only to find the first key. Expired values are counted, they keep their pages
+    /// until `purge_expired`.
+    pub fn size_histogram(&self, stop: &AtomicBool) -> Result<SizeHistogram, DbError> {
+        let file = &*self.file;
+        let mut it = {
+            let lock = read_wal(&self.wal)?;
+            btree::EntryInner::<N>::first(file, lock.current_head())?
+        };
+
+        let mut histogram = SizeHistogram::default();
+        while let Some(inner) = &mut it {
+            if stop.load(Ordering::Relaxed) {
+                return Ok(histogram);
+            }
+            let cell = inner.cell(file)?;
+            let key = inner.cached_key_ref(file)?;
+            histogram.keys[SizeHistogram::bucket(key.len())] += 1;
+            histogram.key_bytes += key.len() as u64;
+            histogram.records += 1;
+            if let Some(value) = cell_value::<N>(cell, &self.wal, file, Wal::MAIN, key) {
+                let len = value.len()?;
+                histogram.values[SizeHistogram::bucket(len)] += 1;
+                histogram.value_bytes += len as u64;
+            }
+            btree::EntryInner::next(&mut it, file)?;
+        }
+        histogram.complete = true;
+
+        Ok(histogram)
+    }
+
+    /// Estimates the number of keys not less than `start` and less than `end`,
+    /// empty cells included, without reading the leaves in between.
+    /// Exact if both fall in the same leaf, otherwise within a factor of about two
+    /// for keys spread evenly.
+    pub fn approximate_count(&self, start: &[u8], end: &[u8]) -> Result<u64, DbError> {
+        check_prefix::<N>(start.len())?;
+        check_prefix::<N>(end.len())?;
+        if self.file.compare(start, end).is_ge() {
+            return Ok(0);
+        }
+        let file = &*self.file;
+        let lock = read_wal(&self.wal)?;
+        let root = lock.current_head();
+        let (start, _) = btree::EntryInner::<N>::new(file, root, start)?;
+        let (end, _) = btree::EntryInner::new(file, root, end)?;
+
+        Ok(start.approximate_distance(&end))
+    }
+
+    /// The key and its value, `None` if the cell is empty or the value is expired
+    #[allow(clippy::type_complexity)]
+    pub fn next<'a>(
+        &'a self,
+        it: &mut DbIterator<N>,
+    ) -> Result<Option<(Vec<u8>, Option<Value<'a>>)>, DbError> {
+        let file = &*self.file;
+        let Some(inner) = it.inner.as_mut() else {
+            return Ok(None);
+        };
+        let key = inner.cached_key(file)?;
+        let value = cell_value::<N>(inner.cell(file)?, &self.wal, file, it.tree, &key);
+        let value = self.unexpired(value, (self.clock)())?;
+
+        btree::EntryInner::next(&mut it.inner, file)?;
+
+        Ok(Some((key, value)))
+    }
+}
+
+/// The length of a key is checked by the compiler, `entry` and `get` of a slice
+/// fail with `DbError::KeyTooShort` or `DbError::KeyTooLong` instead.
+impl Db<NodeCPage> {
+    pub fn entry_fixed(&self, key: FixedKey) -> Result<Entry<'_, NodeCPage>, DbError> {
+        self.entry(key)
+    }
 
-        Some((key, value))
+    pub fn get_fixed(&self, key: &FixedKey) -> Result<Option<Value<'_>>, DbError> {
+        self.get(key)
     }
 }
@@ -1,22 +1,29 @@
 use std::{
-    collections::BTreeMap,
-    fs, io, mem,
-    path::Path,
+    collections::{BTreeMap, BTreeSet},
+    fs, io, iter, mem,
+    ops::Deref,
+    cmp,
+    path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicU32, Ordering},
-        Mutex,
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
     },
 };
 
 use fs4::fs_std::FileExt;
-use io_uring::IoUring;
+use thiserror::Error;
 
 use super::{
     utils,
+    backend::{Backend, SyncBackend},
     page::{PagePtr, RawPtr, PAGE_SIZE},
     runtime::{AbstractIo, PBox, PageKind},
+    metrics::{Counters, DbMetrics},
+    collation::Collation,
 };
-use super::cipher::{self, Cipher, CipherError, Params, CRYPTO_SIZE};
+use super::cipher::{self, Cipher, CipherError, Params};
+#[cfg(feature = "async")]
+use super::backend::{AsyncReader, ReadFuture};
 
 #[cfg(test)]
 #[derive(Clone, Copy)]
@@ -35,55 +42,302 @@ impl Default for Simulator {
     }
 }
 
+#[derive(Debug, Error)]
+pub enum FileError {
+    #[error("{0}")]
+    Io(io::Error),
+    #[error("{0}")]
+    Cipher(CipherError),
+    #[error("the database is already open in this process")]
+    AlreadyOpen,
+    #[error("the database is locked by another process")]
+    Locked,
+    #[error("the queue depth {0} is not a power of two up to {max}", max = IoOptions::MAX_QUEUE_DEPTH)]
+    BadQueueDepth(u32),
+}
+
+impl From<io::Error> for FileError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+// a disk error on the cipher path is a disk error still
+impl From<CipherError> for FileError {
+    fn from(err: CipherError) -> Self {
+        match err {
+            CipherError::Io(err) => Self::Io(err),
+            err => Self::Cipher(err),
+        }
+    }
+}
+
+/// The file would grow past `FileIo::max_pages`
+#[derive(Debug, Error)]
+#[error("the database is full, it cannot grow past {0} pages")]
+pub struct DatabaseFull(pub u32);
+
+impl DatabaseFull {
+    /// The limit, if `err` is made of `DatabaseFull`
+    pub fn of(err: &io::Error) -> Option<&Self> {
+        err.get_ref()?.downcast_ref::<Self>()
+    }
+}
+
+impl From<DatabaseFull> for io::Error {
+    fn from(err: DatabaseFull) -> Self {
+        io::Error::new(io::ErrorKind::StorageFull, err)
+    }
+}
+
+// canonical paths of databases open in this process
+static OPEN: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+struct OpenPath(PathBuf);
+
+impl OpenPath {
+    fn new(path: PathBuf) -> Option<Self> {
+        let inserted = OPEN.lock().expect("poisoned").insert(path.clone());
+        inserted.then(|| OpenPath(path))
+    }
+}
+
+impl Drop for OpenPath {
+    fn drop(&mut self) {
+        OPEN.lock().expect("poisoned").remove(&self.0);
+    }
+}
+
+/// How the database file is accessed
+#[derive(Clone, Copy, Debug)]
+pub struct IoOptions {
+    /// Open the file with `O_DIRECT` bypassing the page cache of the OS,
+    /// falls back to buffered IO if the filesystem does not support it
+    pub direct: bool,
+    /// Entries of the io_uring the pages are written by, a power of two up to
+    /// `MAX_QUEUE_DEPTH`. A sync writes more pages in chunks of this many.
+    /// A smaller ring is tried if the kernel refuses it. Unused without io_uring.
+    pub queue_depth: u32,
+    /// Write and read by io_uring, falls back to plain `pwrite` and `pread`
+    /// if the kernel refuses it. Always off if built without io_uring.
+    pub uring: bool,
+}
+
+impl IoOptions {
+    pub const MAX_QUEUE_DEPTH: u32 = 0x8000;
+}
+
+impl Default for IoOptions {
+    fn default() -> Self {
+        IoOptions {
+            direct: true,
+            queue_depth: 64,
+            uring: true,
+        }
+    }
+}
+
 pub struct FileIo {
-    file: fs::File,
+    // none if the pages are only in memory
+    disk: Option<Disk>,
+    direct: bool,
     write_counter: AtomicU32,
-    regular_file: bool,
+    // the length in pages, a page past it is not a part of the database
+    pages: AtomicU32,
+    // `FileIo::MAX_PAGES`, less in tests
+    max_pages: AtomicU32,
+    // grows whenever a value page is freed, it may be reused by another key then
+    freed: AtomicU64,
     cache: Mutex<Cache>,
+    counters: Counters,
+    // none if the keys are ordered bytewise
+    collation: Option<Arc<dyn Collation>>,
+    // the value pages keep the expiration in their last 8 bytes,
+    // the log record of a database created by an older version says they do not
+    expiry: AtomicBool,
+    // spawned by the first async read
+    #[cfg(feature = "async")]
+    reader: Mutex<Option<AsyncReader>>,
     #[cfg(test)]
     pub simulator: Simulator,
+    // reads that succeed before every next one fails
+    #[cfg(test)]
+    pub read_budget: AtomicU32,
+    // every read of this page fails
+    #[cfg(test)]
+    pub failing_page: AtomicU32,
 }
 
-impl FileIo {
-    const CRYPTO_PAGES: u32 = (CRYPTO_SIZE as u64 / PAGE_SIZE) as u32;
+struct Disk {
+    file: fs::File,
+    // buffered handle for the cipher header, it is not page aligned
+    header: fs::File,
+    // bytes before the first page, zero if the database is not encrypted
+    header_size: u64,
+    // `header` is a file of its own, the pages start at the beginning of `file`
+    sidecar: bool,
+    regular_file: bool,
+    // the pages are written by io_uring, it is not refused
+    uring: bool,
+    _path: OpenPath,
+}
 
-    pub fn new(path: impl AsRef<Path>, params: Params) -> Result<Self, CipherError> {
-        use std::os::unix::fs::FileTypeExt;
+impl FileIo {
+    /// A page number is 32 bits, so the file is at most 16 TiB
+    pub const MAX_PAGES: u32 = u32::MAX;
 
-        let file = utils::open_file(path, true)?;
-        let metadata = file.metadata()?;
-        let regular_file = !metadata.file_type().is_block_device();
+    pub fn new(
+        path: impl AsRef<Path>,
+        params: Params,
+        options: IoOptions,
+    ) -> Result<Self, FileError> {
+        let depth = options.queue_depth;
+        if !depth.is_power_of_two() || depth > IoOptions::MAX_QUEUE_DEPTH {
+            return Err(FileError::BadQueueDepth(depth));
+        }
+        let (file, direct) = match utils::open_file(&path, options.direct) {
+            Ok(file) => (file, options.direct),
+            Err(err) if options.direct && err.kind() == io::ErrorKind::InvalidInput => {
+                log::warn!("the filesystem does not support direct io, fall back to buffered");
+                (utils::open_file(&path, false)?, false)
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let header = match params.header_path() {
+            Some(header) => fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(params.create())
+                .open(header)?,
+            None => utils::open_file(&path, false)?,
+        };
+        let sidecar = params.header_path().is_some();
+        let path = path.as_ref().canonicalize()?;
+        let path = OpenPath::new(path).ok_or(FileError::AlreadyOpen)?;
+        let regular_file = utils::is_regular_file(&file)?;
         if regular_file {
-            file.lock_exclusive()?;
+            file.try_lock_exclusive().map_err(|err| {
+                if err.raw_os_error() == fs4::lock_contended_error().raw_os_error() {
+                    FileError::Locked
+                } else {
+                    err.into()
+                }
+            })?;
             if params.create() {
-                file.set_len(CRYPTO_SIZE as u64)?;
+                file.set_len(params.header_size() as u64)?;
             }
         }
 
-        let cipher = Cipher::new(&file, params)?;
-
-        Ok(FileIo {
+        let header_size = params.header_size() as u64;
+        let cipher = Cipher::new(&header, params)?;
+        let backend = Backend::new(depth, options.uring);
+        let uring = backend.is_uring();
+        let cache = Cache::new(Some(CacheDisk {
+            file: file.try_clone()?,
+            header_size,
+            cipher,
+            backend,
+        }));
+        let disk = Disk {
             file,
-            write_counter: AtomicU32::new(0),
+            header,
+            header_size,
+            sidecar,
             regular_file,
-            cache: Mutex::new(Cache::new(cipher)?),
+            uring,
+            _path: path,
+        };
+
+        Ok(Self::with_disk(Some(disk), direct, cache))
+    }
+
+    /// Keeps every page in memory, nothing is written and nothing is encrypted
+    pub fn memory() -> Self {
+        Self::with_disk(None, false, Cache::new(None))
+    }
+
+    fn with_disk(disk: Option<Disk>, direct: bool, cache: Cache) -> Self {
+        FileIo {
+            disk,
+            direct,
+            write_counter: AtomicU32::new(0),
+            pages: AtomicU32::new(0),
+            max_pages: AtomicU32::new(Self::MAX_PAGES),
+            freed: AtomicU64::new(0),
+            cache: Mutex::new(cache),
+            counters: Counters::default(),
+            collation: None,
+            expiry: AtomicBool::new(true),
+            #[cfg(feature = "async")]
+            reader: Mutex::new(None),
             #[cfg(test)]
             simulator: Simulator::default(),
-        })
+            #[cfg(test)]
+            read_budget: AtomicU32::new(u32::MAX),
+            #[cfg(test)]
+            failing_page: AtomicU32::new(u32::MAX),
+        }
+    }
+
+    /// Orders the keys with `collation` instead of bytewise
+    pub fn with_collation(mut self, collation: Arc<dyn Collation>) -> Self {
+        self.collation = (collation.id() != 0).then_some(collation);
+        self
+    }
+
+    /// `None` if the keys are ordered bytewise
+    pub fn collation(&self) -> Option<&Arc<dyn Collation>> {
+        self.collation.as_ref()
+    }
+
+    pub fn collation_id(&self) -> u64 {
+        self.collation
+            .as_ref()
+            .map_or(0, |collation| collation.id())
+    }
+
+    /// Whether the value pages keep the expiration, set by the log record at open
+    pub fn expiry(&self) -> bool {
+        self.expiry.load(Ordering::Relaxed)
+    }
+
+    pub fn set_expiry(&self, expiry: bool) {
+        self.expiry.store(expiry, Ordering::Relaxed);
+    }
+
+    pub fn compare(&self, a: &[u8], b: &[u8]) -> cmp::Ordering {
+        match &self.collation {
+            Some(collation) => collation.compare(a, b),
+            None => a.cmp(b),
+        }
     }
 
     pub fn m_lock(&self) {
-        utils::m_lock(&self.cache.lock().expect("poisoned").cipher);
+        if let Some(disk) = &self.cache.lock().expect("poisoned").disk {
+            utils::m_lock(&disk.cipher);
+        }
     }
 
+    /// Fails with `CipherError::NotEncrypted` if there is no header to shred,
+    /// a bare database has none, the blob would overwrite the log
     pub fn crypt_shred(&self, seed: &[u8]) -> Result<(), CipherError> {
+        let disk = self.disk.as_ref();
+        let disk = disk.filter(|disk| disk.header_size != 0 || disk.sidecar);
+        let disk = disk.ok_or(CipherError::NotEncrypted)?;
         let blob = cipher::shred(seed)?;
-        if !blob.is_empty() {
-            utils::write_at(&self.file, &blob, 0)?;
-        }
+        utils::write_at(&disk.header, &blob, 0)?;
+
         Ok(())
     }
 
+    pub fn direct(&self) -> bool {
+        self.direct
+    }
+
+    pub fn uring(&self) -> bool {
+        self.disk.as_ref().is_some_and(|disk| disk.uring)
+    }
+
     fn write_stats(&self, offset: u64) {
         let old = self.write_counter.fetch_add(1, Ordering::SeqCst);
         #[cfg(test)]
@@ -91,10 +345,11 @@ impl FileIo {
             use rand::RngCore;
 
             if old == self.simulator.crash_at {
-                if self.simulator.mess_page {
-                    let mut data = [0; PAGE_SIZE as usize];
-                    rand::thread_rng().fill_bytes(&mut data);
-                    utils::write_at(&self.file, &data, offset).unwrap_or_default();
+                if let Some(disk) = self.disk.as_ref().filter(|_| self.simulator.mess_page) {
+                    let mut data = PBox::new(4096, [0; PAGE_SIZE as usize]);
+                    rand::thread_rng().fill_bytes(&mut *data);
+                    let offset = offset + disk.header_size;
+                    utils::write_at(&disk.file, &*data, offset).unwrap_or_default();
                 }
                 panic!("intentional panic for test");
             }
@@ -104,62 +359,477 @@ impl FileIo {
     }
 
     pub fn sync(&self) -> io::Result<()> {
-        self.cache.lock().expect("poisoned").sync(&self.file)
+        self.cache.lock().expect("poisoned").sync()
     }
 
+    /// Writes the dirty pages and drops every cached page but the pinned ones, returns how many.
+    /// In memory the cache is the only copy of the pages, nothing is dropped.
+    pub fn release_cache(&self) -> io::Result<u32> {
+        let mut cache = self.cache.lock().expect("poisoned");
+        if cache.disk.is_none() {
+            return Ok(0);
+        }
+        let n = cache.unpinned_len();
+        cache.sync()?;
+
+        Ok(n)
+    }
+
+    /// Number of pages held in the cache, the log and the pinned pages excluded
+    pub fn cache_len(&self) -> u32 {
+        self.cache.lock().expect("poisoned").unpinned_len()
+    }
+
+    /// Number of pinned pages held in the cache
+    pub fn pinned_len(&self) -> u32 {
+        let cache = self.cache.lock().expect("poisoned");
+        cache.inner.len() as u32 - cache.unpinned_len()
+    }
+
+    /// A pinned page stays in the cache once read, a sync does not drop it
+    pub fn pin(&self, n: u32) {
+        self.cache.lock().expect("poisoned").pinned.insert(n);
+    }
+
+    /// Returns false if the page is not pinned
+    pub fn unpin(&self, n: u32) -> bool {
+        self.cache.lock().expect("poisoned").pinned.remove(&n)
+    }
+
+    /// Pins the root of the main tree and the trees page instead of the previous ones,
+    /// they are read by every operation
+    pub fn pin_roots(&self, head: u32, trees: Option<u32>) {
+        self.cache.lock().expect("poisoned").roots = [head, trees.unwrap_or(0)];
+    }
+
+    /// Writes the dirty pages and waits until their contents and the length
+    /// of the file are on the disk, other metadata may lag behind, see `sync_all`
+    pub fn sync_data(&self) -> io::Result<()> {
+        self.sync()?;
+        if let Some(disk) = &self.disk {
+            disk.header.sync_data()?;
+            disk.file.sync_data()?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the dirty pages and waits until the file is on the disk,
+    /// all of its metadata too
+    pub fn sync_all(&self) -> io::Result<()> {
+        self.sync()?;
+        if let Some(disk) = &self.disk {
+            disk.header.sync_all()?;
+            disk.file.sync_all()?;
+        }
+
+        Ok(())
+    }
+
+    /// Fails with `DatabaseFull` instead of growing past `max_pages`
     pub fn grow<T>(&self, old: u32, n: u32) -> io::Result<Option<PagePtr<T>>> {
-        self.set_pages(old + n)?;
+        let max = self.max_pages();
+        let new = old.checked_add(n).filter(|new| *new <= max);
+        self.set_pages(new.ok_or(DatabaseFull(max))?)?;
 
         use super::runtime::PBox;
 
         let mut cache = self.cache.lock().expect("poisoned");
         for i in old..(old + n) {
             let page = PBox::new(4096, [0; PAGE_SIZE as usize]);
-            cache.write(&self.file, PageKind::Clear, i, page)?;
+            cache.write(PageKind::Clear, i, page);
         }
 
         Ok(PagePtr::from_raw_number(old))
     }
 
+    /// The file cannot grow past this many pages, `MAX_PAGES` unless lowered by a test
+    pub fn max_pages(&self) -> u32 {
+        self.max_pages.load(Ordering::Relaxed)
+    }
+
+    #[cfg(test)]
+    pub fn set_max_pages(&self, n: u32) {
+        self.max_pages.store(n, Ordering::Relaxed);
+    }
+
+    /// The length set by `set_pages`, the pages from it on are not used
+    pub fn pages(&self) -> u32 {
+        self.pages.load(Ordering::Relaxed)
+    }
+
+    /// Grows whenever a value page is freed, a value found before
+    /// may read the page of another key if it did grow since
+    pub fn freed(&self) -> u64 {
+        self.freed.load(Ordering::Acquire)
+    }
+
+    /// The value page `n` is freed, it is no longer pinned
+    pub fn value_freed(&self, n: u32) {
+        self.freed.fetch_add(1, Ordering::AcqRel);
+        self.unpin(n);
+    }
+
+    /// Any value page may be freed or moved, none of them stays pinned
+    pub fn values_moved(&self) {
+        self.freed.fetch_add(1, Ordering::AcqRel);
+        self.cache.lock().expect("poisoned").pinned.clear();
+    }
+
+    /// Whether the pages are encrypted on the disk
+    pub fn encrypted(&self) -> bool {
+        let cache = self.cache.lock().expect("poisoned");
+        cache
+            .disk
+            .as_ref()
+            .is_some_and(|disk| disk.cipher.is_active())
+    }
+
+    /// The length of the file in pages, `None` in memory or on a device
+    pub fn disk_pages(&self) -> io::Result<Option<u32>> {
+        let Some(disk) = self.disk.as_ref().filter(|disk| disk.regular_file) else {
+            return Ok(None);
+        };
+        let len = disk.file.metadata()?.len().saturating_sub(disk.header_size);
+
+        Ok(Some((len / PAGE_SIZE) as u32))
+    }
+
     pub fn set_pages(&self, pages: u32) -> io::Result<()> {
-        if self.regular_file {
-            self.file
-                .set_len((pages + Self::CRYPTO_PAGES) as u64 * PAGE_SIZE)?;
+        self.pages.store(pages, Ordering::Relaxed);
+        // cached pages past the end must not be written back
+        self.cache
+            .lock()
+            .expect("poisoned")
+            .inner
+            .retain(|n, _| *n < pages);
+        if let Some(disk) = self.disk.as_ref().filter(|disk| disk.regular_file) {
+            let len = n_to_o(pages, disk.header_size);
+            // reserve the space instead of leaving holes,
+            // filling a sparse file with direct io is slow
+            if len <= disk.file.metadata()?.len() {
+                disk.file.set_len(len)?;
+            } else if let Err(err) = disk.file.allocate(len) {
+                log::warn!("cannot allocate the space, fall back to set length: {err}");
+                disk.file.set_len(len)?;
+            }
         }
 
         Ok(())
     }
 
+    /// Writes the pages straight to the file in one go, they are not kept
+    /// in the cache. Meant for many pages that are not used yet.
+    pub fn write_batch(&self, kind: PageKind, pages: Vec<(u32, PBox)>) -> io::Result<()> {
+        for (n, _) in &pages {
+            self.write_stats(u64::from(*n) * PAGE_SIZE);
+        }
+
+        self.cache
+            .lock()
+            .expect("poisoned")
+            .write_through(kind, pages)
+    }
+
+    /// Copies the crypto header and first `pages` pages into a new file as is.
+    /// Holds the cache lock, so no page can change during the copy.
+    /// Unsupported if the header is in a file of its own.
+    pub fn backup(&self, path: impl AsRef<Path>, pages: u32) -> io::Result<()> {
+        use std::io::Write;
+
+        let Some(disk) = &self.disk else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the database is only in memory",
+            ));
+        };
+        if disk.sidecar {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the header is in a file of its own",
+            ));
+        }
+        let mut dest = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+
+        let mut cache = self.cache.lock().expect("poisoned");
+        cache.sync()?;
+
+        let mut page = PBox::new(4096, [0; PAGE_SIZE as usize]);
+        for offset in (0..n_to_o(pages, disk.header_size)).step_by(PAGE_SIZE as usize) {
+            utils::read_at(&disk.file, &mut *page, offset)?;
+            dest.write_all(&*page)?;
+        }
+        drop(cache);
+        dest.sync_all()
+    }
+
+    /// Locks the cache and borrows the page from it.
+    /// Only pages past the write-ahead log are cached.
+    pub fn view(&self, n: u32) -> io::Result<PageView<'_>> {
+        assert!(n >= 256, "log pages are not cached");
+        #[cfg(test)]
+        self.inject_read_failure(n)?;
+
+        let mut cache = self.cache.lock().expect("poisoned");
+        if cache.inner.contains_key(&n) {
+            cache.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            cache.read(n)?;
+        }
+
+        Ok(PageView { cache, n })
+    }
+
+    /// Same as `read_page`, but a page missing in the cache is read by the async reader,
+    /// the caller awaits it instead of blocking
+    #[cfg(feature = "async")]
+    pub async fn read_page_async(&self, n: u32) -> io::Result<PBox> {
+        #[cfg(test)]
+        self.inject_read_failure(n)?;
+
+        let (read, writes) = {
+            let mut cache = self.cache.lock().expect("poisoned");
+            let offset = match &cache.disk {
+                Some(disk) if !cache.inner.contains_key(&n) => n_to_o(n, disk.header_size),
+                _ => return cache.read(n),
+            };
+            let page = cache.new_page();
+            let writes = cache.writes.load(Ordering::Relaxed);
+            (self.read_async(offset, page)?, writes)
+        };
+        let mut page = read.await?;
+
+        let mut cache = self.cache.lock().expect("poisoned");
+        // the page may be written meanwhile, the copy in the cache or on the disk is newer
+        if cache.inner.contains_key(&n) || cache.writes.load(Ordering::Relaxed) != writes {
+            cache.recycle_page(page);
+            return cache.read(n);
+        }
+        cache.misses.fetch_add(1, Ordering::Relaxed);
+        cache.reads.fetch_add(1, Ordering::Relaxed);
+        if let Some(disk) = &cache.disk {
+            // see `Cache::read`
+            if n >= 256 || page.iter().any(|b| *b != 0) {
+                disk.cipher.decrypt(&mut *page, n);
+            }
+        }
+        let item = CacheItem {
+            page: cache.copy_page(&page),
+            dirty: false,
+            kind: PageKind::Clear,
+        };
+        cache.inner.insert(n, item);
+
+        Ok(page)
+    }
+
+    #[cfg(feature = "async")]
+    fn read_async(&self, offset: u64, page: PBox) -> io::Result<ReadFuture> {
+        let mut reader = self.reader.lock().expect("poisoned");
+        let reader = match &mut *reader {
+            Some(reader) => reader,
+            None => {
+                let disk = self.disk.as_ref().expect("must be on the disk");
+                reader.insert(AsyncReader::spawn(disk.file.try_clone()?, disk.uring)?)
+            }
+        };
+
+        Ok(reader.read(offset, page))
+    }
+
+    /// Writes the page if it is dirty and waits until it is on the disk
+    pub fn flush_page(&self, n: u32) -> io::Result<()> {
+        assert!(n >= 256, "log pages are not cached");
+
+        self.cache.lock().expect("poisoned").flush(n)?;
+        match &self.disk {
+            Some(disk) => disk.file.sync_data(),
+            None => Ok(()),
+        }
+    }
+
+    /// Reads the page and lets `f` change it, writes it back if `f` returns true.
+    /// The cache stays locked meanwhile, so no one else writes the page in between.
+    pub fn update_page(
+        &self,
+        n: u32,
+        kind: PageKind,
+        f: impl FnOnce(&mut [u8]) -> bool,
+    ) -> io::Result<bool> {
+        assert!(n >= 256, "log pages are not cached");
+        #[cfg(test)]
+        self.inject_read_failure(n)?;
+
+        let mut cache = self.cache.lock().expect("poisoned");
+        let mut page = cache.read(n)?;
+        if !f(&mut *page) {
+            return Ok(false);
+        }
+        self.write_stats(u64::from(n) * PAGE_SIZE);
+        cache.write(kind, n, page);
+
+        Ok(true)
+    }
+
     pub fn writes(&self) -> u32 {
         self.write_counter.load(Ordering::SeqCst)
     }
+
+    /// The next `n` syncs fail after writing the pages, as if the disk is full
+    #[cfg(test)]
+    pub fn fail_syncs(&self, n: u32) {
+        self.cache.lock().expect("poisoned").failing_syncs = n;
+    }
+
+    #[cfg(test)]
+    fn inject_read_failure(&self, n: u32) -> io::Result<()> {
+        let exhausted = self
+            .read_budget
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |b| b.checked_sub(1))
+            .is_err();
+        if exhausted || self.failing_page.load(Ordering::SeqCst) == n {
+            Err(io::Error::other("intentional read failure for test"))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns `(hits, misses)` of the page cache
+    pub fn cache_stats(&self) -> (u64, u64) {
+        let cache = self.cache.lock().expect("poisoned");
+        (
+            cache.hits.load(Ordering::Relaxed),
+            cache.misses.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Lookups, inserts and removes, counted by the database
+    pub fn counters(&self) -> &Counters {
+        &self.counters
+    }
+
+    pub fn metrics(&self) -> DbMetrics {
+        let cache = self.cache.lock().expect("poisoned");
+        DbMetrics {
+            page_reads: cache.reads.load(Ordering::Relaxed),
+            page_writes: cache.writes.load(Ordering::Relaxed),
+            cache_hits: cache.hits.load(Ordering::Relaxed),
+            cache_misses: cache.misses.load(Ordering::Relaxed),
+            ..self.counters.metrics()
+        }
+    }
+
+    /// Number of completed syncs, a page written before the last one is on the disk
+    pub fn syncs(&self) -> u64 {
+        self.cache
+            .lock()
+            .expect("poisoned")
+            .syncs
+            .load(Ordering::SeqCst)
+    }
+
+    /// Numbers of the pages written since the last call, the log excluded
+    pub fn take_written(&self) -> BTreeSet<u32> {
+        mem::take(&mut self.cache.lock().expect("poisoned").written)
+    }
+
+    /// Gives back the pages `take_written` did return, the record they were for is not written
+    pub fn restore_written(&self, pages: BTreeSet<u32>) {
+        let mut cache = self.cache.lock().expect("poisoned");
+        cache.written.extend(pages);
+    }
+
+    /// Same as `take_written`, but the pages stay
+    pub fn written(&self) -> BTreeSet<u32> {
+        self.cache.lock().expect("poisoned").written.clone()
+    }
 }
 
 impl AbstractIo for FileIo {
     fn read_page(&self, n: u32) -> io::Result<PBox> {
-        self.cache.lock().expect("poisoned").read(&self.file, n)
+        #[cfg(test)]
+        self.inject_read_failure(n)?;
+
+        self.cache.lock().expect("poisoned").read(n)
     }
 
     fn write_page(&self, n: u32, kind: PageKind, page: PBox) -> io::Result<()> {
         self.write_stats(u64::from(n) * PAGE_SIZE);
 
-        self.cache
-            .lock()
-            .expect("poisoned")
-            .write(&self.file, kind, n, page)
+        self.cache.lock().expect("poisoned").write(kind, n, page);
+
+        Ok(())
+    }
+
+    fn new_page(&self) -> PBox {
+        self.cache.lock().expect("poisoned").new_page()
+    }
+
+    fn recycle_page(&self, page: PBox) {
+        self.cache.lock().expect("poisoned").recycle_page(page);
+    }
+}
+
+pub struct PageView<'a> {
+    cache: MutexGuard<'a, Cache>,
+    n: u32,
+}
+
+impl Deref for PageView<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &*self.cache.inner[&self.n].page
     }
 }
 
-fn n_to_o(n: u32) -> u64 {
-    (u64::from(n) * PAGE_SIZE) + CRYPTO_SIZE as u64
+fn n_to_o(n: u32, header_size: u64) -> u64 {
+    (u64::from(n) * PAGE_SIZE) + header_size
+}
+
+// zeroed right away, no decrypted page stays in the pool
+fn recycle(pool: &mut Vec<PBox>, mut page: PBox) {
+    if pool.len() < Cache::POOL_SIZE {
+        page.fill(0);
+        pool.push(page);
+    }
 }
 
 struct Cache {
-    cipher: Cipher,
-    ring: IoUring,
-    log: Option<(u32, CacheItem)>,
+    // none if the pages are only in memory, they are never evicted then
+    disk: Option<CacheDisk>,
+    // both copies of the last record
+    log: Vec<(u32, CacheItem)>,
     inner: BTreeMap<u32, CacheItem>,
+    // written since the log did take them, for the log shipping
+    written: BTreeSet<u32>,
+    // kept over a sync, pinned by `Db::pin`
+    pinned: BTreeSet<u32>,
+    // kept over a sync too, the root of the main tree and the trees page
+    roots: [u32; 2],
     calls: BTreeMap<PageKind, usize>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    // pages read from and written to the file
+    reads: AtomicU64,
+    writes: AtomicU64,
+    // completed syncs
+    syncs: AtomicU64,
+    // zeroed buffers to reuse, a page allocation is a syscall away otherwise
+    pool: Vec<PBox>,
+    // the next syncs that fail as if the disk is full
+    #[cfg(test)]
+    failing_syncs: u32,
+}
+
+struct CacheDisk {
+    file: fs::File,
+    header_size: u64,
+    cipher: Cipher,
+    backend: Backend,
 }
 
 struct CacheItem {
@@ -169,74 +839,163 @@ struct CacheItem {
 }
 
 impl Cache {
-    fn new(cipher: Cipher) -> io::Result<Self> {
-        Ok(Cache {
-            cipher,
-            ring: IoUring::new(64)?,
-            log: None,
+    fn new(disk: Option<CacheDisk>) -> Self {
+        Cache {
+            disk,
+            log: Vec::with_capacity(2),
             inner: BTreeMap::default(),
+            written: BTreeSet::default(),
+            pinned: BTreeSet::default(),
+            roots: [0; 2],
             calls: BTreeMap::default(),
-        })
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            reads: AtomicU64::new(0),
+            writes: AtomicU64::new(0),
+            syncs: AtomicU64::new(0),
+            pool: Vec::with_capacity(Self::POOL_SIZE),
+            #[cfg(test)]
+            failing_syncs: 0,
+        }
     }
 }
 
 impl Cache {
-    fn sync(&mut self, file: &fs::File) -> io::Result<()> {
-        use io_uring::{opcode, types};
-        use std::os::unix::io::AsRawFd;
+    const POOL_SIZE: usize = 0x100;
+
+    fn new_page(&mut self) -> PBox {
+        self.pool
+            .pop()
+            .unwrap_or_else(|| PBox::new(4096, [0; PAGE_SIZE as usize]))
+    }
+
+    fn copy_page(&mut self, page: &PBox) -> PBox {
+        let mut copy = self.new_page();
+        copy.copy_from_slice(&**page);
+        copy
+    }
+
+    fn recycle_page(&mut self, page: PBox) {
+        recycle(&mut self.pool, page);
+    }
 
+    // log pages are never cached, so zero in `roots` means none
+    fn is_pinned(&self, n: u32) -> bool {
+        self.roots.contains(&n) || self.pinned.contains(&n)
+    }
+
+    #[cfg(test)]
+    fn inject_write_failure(&mut self) -> io::Result<()> {
+        if self.failing_syncs == 0 {
+            return Ok(());
+        }
+        self.failing_syncs -= 1;
+        Err(io::Error::new(
+            io::ErrorKind::StorageFull,
+            "intentional write failure for test",
+        ))
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            skip_all,
+            fields(pages = tracing::field::Empty, bytes = tracing::field::Empty, us = tracing::field::Empty)
+        )
+    )]
+    fn sync(&mut self) -> io::Result<()> {
+        let Some(disk) = &mut self.disk else {
+            self.syncs.fetch_add(1, Ordering::SeqCst);
+            return Ok(());
+        };
+        #[cfg(feature = "tracing")]
+        let start = std::time::Instant::now();
         let mut map = mem::take(&mut self.inner);
-        let mut log = self.log.take();
+        let mut log = mem::take(&mut self.log);
         let mut written = BTreeMap::<_, usize>::default();
+        let mut encrypted = BTreeSet::new();
         let it = map
             .iter_mut()
-            .chain(log.as_mut().map(|(n, item)| (&*n, item)))
+            .chain(log.iter_mut().map(|(n, item)| (&*n, item)))
             .filter(|(_, item)| item.dirty)
             .map(|(n, item)| {
                 *written.entry(item.kind).or_default() += 1;
                 let data = &mut *item.page;
-                self.cipher.encrypt(data, *n);
-                (*n, data.as_ptr())
+                disk.cipher.encrypt(data, *n);
+                encrypted.insert(*n);
+                (n_to_o(*n, disk.header_size), &data[..])
             });
-
-        let fd = file.as_raw_fd();
-
-        for (n, ptr) in it {
-            let op = opcode::Write::new(types::Fd(fd), ptr, 0x1000)
-                .offset(n_to_o(n))
-                .build()
-                .user_data(n as _);
-
-            while unsafe { self.ring.submission().push(&op).is_err() } {
-                let l = self.ring.submission().len();
-                self.ring.submit_and_wait(l)?;
-                self.ring.completion().sync();
-                while let Some(cqe) = self.ring.completion().next() {
-                    if cqe.result() < 0 {
-                        log::error!("Error: {}", io::Error::from_raw_os_error(-cqe.result()));
-                    }
+        let res = disk.backend.write_pages(&disk.file, it);
+        #[cfg(test)]
+        let res = res.and_then(|()| self.inject_write_failure());
+        if let Err(err) = res {
+            // nothing is lost, the pages stay dirty and the next sync writes them again
+            let disk = self.disk.as_ref().expect("must be on disk");
+            let items = map
+                .iter_mut()
+                .chain(log.iter_mut().map(|(n, item)| (&*n, item)));
+            for (n, item) in items.filter(|(n, _)| encrypted.contains(*n)) {
+                disk.cipher.decrypt(&mut *item.page, *n);
+            }
+            self.inner = map;
+            self.log = log;
+            return Err(err);
+        }
+        for (n, mut item) in map {
+            if self.is_pinned(n) {
+                if encrypted.contains(&n) {
+                    let disk = self.disk.as_ref().expect("must be on disk");
+                    disk.cipher.decrypt(&mut *item.page, n);
                 }
+                item.dirty = false;
+                self.inner.insert(n, item);
+            } else {
+                self.recycle_page(item.page);
             }
         }
+        for (_, item) in log {
+            self.recycle_page(item.page);
+        }
+        let pages = written.values().sum::<usize>() as u64;
+        self.writes.fetch_add(pages, Ordering::Relaxed);
+        self.syncs.fetch_add(1, Ordering::SeqCst);
+        #[cfg(feature = "tracing")]
+        {
+            let span = tracing::Span::current();
+            span.record("pages", pages);
+            span.record("bytes", pages * PAGE_SIZE);
+            span.record("us", start.elapsed().as_micros() as u64);
+        }
+
         let calls = mem::take(&mut self.calls);
-        log::debug!("calls: {calls:?}, will write: {written:?}");
+        log::debug!("calls: {calls:?}, did write: {written:?}");
 
-        let l = self.ring.submission().len();
-        if l == 0 {
-            return Ok(());
-        }
+        Ok(())
+    }
 
-        self.ring.submit_and_wait(l)?;
-        while let Some(cqe) = self.ring.completion().next() {
-            if cqe.result() < 0 {
-                log::error!("Error: {}", io::Error::from_raw_os_error(-cqe.result()));
-            }
-        }
+    fn flush(&mut self, n: u32) -> io::Result<()> {
+        let Some(disk) = &mut self.disk else {
+            return Ok(());
+        };
+        let Some(item) = self.inner.get_mut(&n).filter(|item| item.dirty) else {
+            return Ok(());
+        };
+        // the page stays in the cache, encrypt a copy
+        let mut data = self.pool.pop().unwrap_or_else(|| item.page.clone());
+        data.copy_from_slice(&*item.page);
+        disk.cipher.encrypt(&mut *data, n);
+        disk.backend.write_pages(
+            &disk.file,
+            iter::once((n_to_o(n, disk.header_size), &data[..])),
+        )?;
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        item.dirty = false;
+        self.recycle_page(data);
 
         Ok(())
     }
 
-    fn write(&mut self, _file: &fs::File, kind: PageKind, n: u32, page: PBox) -> io::Result<()> {
+    fn write(&mut self, kind: PageKind, n: u32, page: PBox) {
         let item = CacheItem {
             page,
             dirty: true,
@@ -244,26 +1003,83 @@ impl Cache {
         };
         *self.calls.entry(kind).or_default() += 1;
         if n < 256 {
-            self.log = Some((n, item));
+            if let Some(i) = self.log.iter().position(|(m, _)| *m == n) {
+                let (_, old) = self.log.remove(i);
+                self.recycle_page(old.page);
+            }
+            if self.log.len() == 2 {
+                let (_, old) = self.log.remove(0);
+                self.recycle_page(old.page);
+            }
+            self.log.push((n, item));
         } else {
-            self.inner.insert(n.into(), item);
+            self.written.insert(n);
+            if let Some(old) = self.inner.insert(n, item) {
+                self.recycle_page(old.page);
+            }
+        }
+    }
+
+    fn write_through(&mut self, kind: PageKind, mut pages: Vec<(u32, PBox)>) -> io::Result<()> {
+        let Some(disk) = &mut self.disk else {
+            for (n, page) in pages {
+                self.write(kind, n, page);
+            }
+            return Ok(());
+        };
+        *self.calls.entry(kind).or_default() += pages.len();
+        for (n, page) in &mut pages {
+            // a stale copy must not be written back over the page
+            if let Some(old) = self.inner.remove(n) {
+                recycle(&mut self.pool, old.page);
+            }
+            self.written.insert(*n);
+            disk.cipher.encrypt(&mut **page, *n);
+        }
+        let it = pages
+            .iter()
+            .map(|(n, page)| (n_to_o(*n, disk.header_size), &page[..]));
+        disk.backend.write_pages(&disk.file, it)?;
+        self.writes.fetch_add(pages.len() as u64, Ordering::Relaxed);
+        for (_, page) in pages {
+            self.recycle_page(page);
         }
 
         Ok(())
     }
 
-    fn read(&mut self, file: &fs::File, n: u32) -> io::Result<PBox> {
+    fn unpinned_len(&self) -> u32 {
+        self.inner.keys().filter(|n| !self.is_pinned(**n)).count() as u32
+    }
+
+    fn read(&mut self, n: u32) -> io::Result<PBox> {
         if let Some(item) = self.inner.get(&n) {
-            return Ok(item.page.clone());
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            let page = &item.page;
+            let mut copy = self.pool.pop().unwrap_or_else(|| page.clone());
+            copy.copy_from_slice(&**page);
+            return Ok(copy);
+        }
+        // log pages are never cached, they do not miss
+        if n >= 256 {
+            self.misses.fetch_add(1, Ordering::Relaxed);
         }
 
-        let mut page = PBox::new(4096, [0; PAGE_SIZE as usize]);
+        let mut page = self.new_page();
 
-        utils::read_at(file, &mut *page, n_to_o(n))?;
-        self.cipher.decrypt(&mut *page, n);
+        // in memory, a page never written is zeroed
+        if let Some(disk) = &self.disk {
+            utils::read_at(&disk.file, &mut *page, n_to_o(n, disk.header_size))?;
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            // a log slot never written stays zeroed, so opening does not count it as torn,
+            // a written one is ciphertext and is never all zeros
+            if n >= 256 || page.iter().any(|b| *b != 0) {
+                disk.cipher.decrypt(&mut *page, n);
+            }
+        }
         if n >= 256 {
             let item = CacheItem {
-                page: page.clone(),
+                page: self.copy_page(&page),
                 dirty: false,
                 kind: PageKind::Clear,
             };
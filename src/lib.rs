@@ -1,20 +1,27 @@
 //! Database
-//! Maximal size: (2 ^ 44) B = 16 TiB
+//! Maximal size: (2 ^ 44) B = 16 TiB, growing past it is `DbError::DatabaseFull`
 //! Maximal key size: (2 ^ 10) B = 1 kiB
 //! Maximal number of records: 2 ^ 30
-//! Maximal value size: 1572864 B = 1536 kiB
+//! Maximal value size: 4088 B, the last 8 bytes of the page keep the expiration
+//! Keys are ordered lexicographically byte by byte, a key goes before any longer key
+//! it is a prefix of.
 
 mod utils;
 mod page;
 mod runtime;
 
 mod cipher;
+mod backend;
 mod file;
 mod wal;
+mod metrics;
+mod collation;
 
 mod value;
 mod node;
 mod btree;
+mod compact;
+mod replica;
 mod db;
 
 #[cfg(test)]
@@ -25,7 +32,15 @@ pub use self::cipher::Secret;
 
 pub use self::{
     cipher::{Params, CipherError},
-    wal::{DbStats, WalError},
-    node::{NodePage, NodeCPage},
-    db::{Db, DbError, DbIterator, Value, Entry, Occupied, Vacant},
+    file::IoOptions,
+    wal::{DbStats, RecoveryReport, WalError},
+    metrics::DbMetrics,
+    collation::{Collation, Bytewise},
+    node::{NodePage, NodeCPage, FixedKey},
+    replica::ChangeSet,
+    db::{
+        Db, DbError, DbIterator, Cursor, DupIter, ScanValues, Value, RemovedValue, Entry, Occupied,
+        EmptyCell, Vacant, TreeHandle, Batch, CheckpointHandle, MAIN_TREE, SizeHistogram,
+        ExportStats, ImportStats, ImportOptions,
+    },
 };
@@ -0,0 +1,51 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters since the database is open, each of them only grows
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DbMetrics {
+    /// Keys looked up by an entry, `get` or `multi_get`
+    pub lookups: u64,
+    /// Keys inserted by a vacant entry or a committed batch
+    pub inserts: u64,
+    /// Keys removed by an entry, a committed batch or `purge_expired`
+    pub removes: u64,
+    /// Pages read from the file, the log included
+    pub page_reads: u64,
+    /// Pages written to the file, the log included
+    pub page_writes: u64,
+    /// Reads of pages past the log served from the cache
+    pub cache_hits: u64,
+    /// Reads of pages past the log that went to the file
+    pub cache_misses: u64,
+}
+
+/// Operations on the trees, the page counters are kept by the cache
+#[derive(Default)]
+pub struct Counters {
+    lookups: AtomicU64,
+    inserts: AtomicU64,
+    removes: AtomicU64,
+}
+
+impl Counters {
+    pub fn lookup(&self, n: u64) {
+        self.lookups.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn insert(&self, n: u64) {
+        self.inserts.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn remove(&self, n: u64) {
+        self.removes.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn metrics(&self) -> DbMetrics {
+        DbMetrics {
+            lookups: self.lookups.load(Ordering::Relaxed),
+            inserts: self.inserts.load(Ordering::Relaxed),
+            removes: self.removes.load(Ordering::Relaxed),
+            ..DbMetrics::default()
+        }
+    }
+}
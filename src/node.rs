@@ -1,13 +1,30 @@
-use std::mem;
+use std::{cmp::Ordering, io, mem};
 
 use super::{
-    page::PagePtr,
+    page::{PagePtr, RawPtr, PAGE_SIZE},
     runtime::{PlainData, Alloc, Free, AbstractIo, Rt},
     file::FileIo,
-    wal::FreelistCache,
+    wal::{FreelistCache, Garbage},
+    btree::DuplicateKey,
 };
 
-pub type R<'a> = Rt<'a, FreelistCache, FreelistCache, FileIo>;
+pub type R<'a> = Rt<'a, FreelistCache, Garbage, FileIo>;
+
+/// A small value kept in the leaf instead of a value page, the rest of the value is zero.
+/// The first bytes are in the leaf itself, the others in the tail pages of the leaf.
+pub type Inline = [u8; 0x40];
+
+/// The inline value of zeros
+pub const INLINE_ZERO: Inline = [0; 0x40];
+
+// bytes of an inline value kept in the leaf itself
+const HEAD: usize = 8;
+
+// tail pages of a leaf, each keeps `0x10` bytes of every inline value
+const TAIL: usize = (mem::size_of::<Inline>() - HEAD).div_ceil(0x10);
+
+/// The child of a leaf whose value is inline, it is not a page
+pub const INLINE_PTR: u32 = u32::MAX;
 
 pub trait Node
 where
@@ -15,6 +32,13 @@ where
 {
     const M: usize;
 
+    /// Bounds of the length of a key in bytes
+    const MIN_KEY: usize;
+    const MAX_KEY: usize;
+
+    /// Whether a leaf has room for inline values
+    const INLINE: bool;
+
     fn empty() -> Self;
 
     fn append_child(&mut self, ptr: PagePtr<Self>);
@@ -23,6 +47,13 @@ where
 
     fn child_mut(&mut self, idx: usize) -> &mut Option<PagePtr<Self>>;
 
+    /// The inline value at `idx` of a leaf, `None` if the node has no room for them
+    fn inline(&self, file: &FileIo, idx: usize) -> io::Result<Option<Inline>>;
+
+    /// Writes the inline value at `idx` of a leaf, the pages it changes are copied.
+    /// Does nothing if the node has no room for them.
+    fn set_inline(&mut self, rt: R<'_>, idx: usize, inline: &Inline) -> io::Result<()>;
+
     fn len(&self) -> usize;
 
     fn can_donate(&self) -> bool {
@@ -31,56 +62,108 @@ where
 
     fn is_leaf(&self) -> bool;
 
-    fn read_key(&self, file: &FileIo, idx: usize) -> Vec<u8>;
+    /// Whether the fields of a node read from the file agree with each other,
+    /// the other methods may panic on a node that is not
+    fn is_consistent(&self) -> bool;
+
+    fn read_key(&self, file: &FileIo, idx: usize) -> io::Result<Vec<u8>>;
+
+    /// Every key of the node, each page is read once
+    fn read_keys(&self, file: &FileIo) -> io::Result<Vec<Vec<u8>>>;
 
     fn get_key(&self, rt: R<'_>, idx: usize) -> Vec<u8>;
 
-    fn search(&self, file: &FileIo, key: &[u8]) -> Result<usize, usize>;
+    fn search(&self, file: &FileIo, key: &[u8]) -> io::Result<Result<usize, usize>>;
 
-    fn realloc_keys(&mut self, rt: R<'_>);
+    fn realloc_keys(&mut self, rt: R<'_>) -> io::Result<()>;
 
+    /// The inline value goes along with the child, it is ignored if there is no room for it
     fn insert(
         &mut self,
         rt: R<'_>,
         ptr: Option<PagePtr<Self>>,
+        inline: Inline,
         idx: usize,
         key: &[u8],
         rev: bool,
     ) -> Option<(Vec<u8>, PagePtr<Self>)>;
 
-    fn remove(&mut self, rt: R<'_>, idx: usize, rev: bool) -> (Option<PagePtr<Self>>, Vec<u8>);
+    fn remove(
+        &mut self,
+        rt: R<'_>,
+        idx: usize,
+        rev: bool,
+    ) -> (Option<PagePtr<Self>>, Inline, Vec<u8>);
 
     fn set_key(&mut self, rt: R<'_>, idx: usize, key: &[u8]) -> Vec<u8>;
 
-    fn merge(&mut self, other: &Self, rt: R<'_>, key: &[u8], old: bool) -> Vec<u8>;
+    fn merge(&mut self, other: &Self, rt: R<'_>, key: &[u8], old: bool) -> io::Result<()>;
 
     fn free(&self, rt: R<'_>);
+
+    /// Calls `f` for every page the node refers to, `f` returns the new location of the page
+    fn relocate(&mut self, f: impl FnMut(u32) -> u32);
 }
 
+fn relocate_ptr<T>(ptr: &mut Option<PagePtr<T>>, f: &mut impl FnMut(u32) -> u32) {
+    if let Some(old) = ptr.filter(|old| old.raw_number() != INLINE_PTR) {
+        *ptr = PagePtr::from_raw_number(f(old.raw_number()));
+    }
+}
+
+/// The key of `NodeCPage`, every key of the tree is exactly this long
+pub type FixedKey = [u8; 0x10];
+
+/// The fanout of `NodeCPage` unless given, the `small` feature makes the tree deep for tests
+#[cfg(feature = "small")]
+pub const DEFAULT_C_FANOUT: usize = 0x8;
+
+#[cfg(not(feature = "small"))]
+pub const DEFAULT_C_FANOUT: usize = 0xc0;
+
+/// The fanout of `NodePage` unless given
+#[cfg(feature = "small")]
+pub const DEFAULT_FANOUT: usize = 0x8;
+
+#[cfg(not(feature = "small"))]
+pub const DEFAULT_FANOUT: usize = 0x100;
+
+/// The tree of keys of fixed length, see `FixedKey`, stored in the node itself,
+/// `M` is the fanout, it is even and the node must fit a page
 #[repr(C, align(0x1000))]
 #[derive(Clone, Copy)]
-pub struct NodeCPage {
-    child: [Option<PagePtr<Self>>; Self::M],
-    keys: [[u8; 0x10]; Self::M],
+pub struct NodeCPage<const M: usize = DEFAULT_C_FANOUT> {
+    child: [Option<PagePtr<Self>>; M],
+    keys: [FixedKey; M],
     stem: u16,
     len: u16,
 }
 
-unsafe impl PlainData for NodeCPage {
+unsafe impl<const M: usize> PlainData for NodeCPage<M> {
     const NAME: &str = "NodeCPage";
 }
 
-impl Node for NodeCPage {
-    #[cfg(feature = "small")]
-    const M: usize = 0x8;
+impl<const M: usize> Node for NodeCPage<M> {
+    const M: usize = {
+        assert!(M >= 4 && M.is_multiple_of(2), "the fanout must be even");
+        assert!(
+            mem::size_of::<Self>() == PAGE_SIZE as usize,
+            "the node must fit a page"
+        );
+        M
+    };
 
-    #[cfg(not(feature = "small"))]
-    const M: usize = 0xc0;
+    // keys are stored in place, padding would be ambiguous
+    const MIN_KEY: usize = 0x10;
+    const MAX_KEY: usize = 0x10;
+
+    // the keys take the whole page
+    const INLINE: bool = false;
 
     fn empty() -> Self {
         NodeCPage {
-            child: [None; Self::M],
-            keys: [[0; 0x10]; Self::M],
+            child: [None; M],
+            keys: [[0; 0x10]; M],
             stem: 1,
             len: 0,
         }
@@ -99,6 +182,14 @@ impl Node for NodeCPage {
         &mut self.child[idx]
     }
 
+    fn inline(&self, _file: &FileIo, _idx: usize) -> io::Result<Option<Inline>> {
+        Ok(None)
+    }
+
+    fn set_inline(&mut self, _rt: R<'_>, _idx: usize, _inline: &Inline) -> io::Result<()> {
+        Ok(())
+    }
+
     fn len(&self) -> usize {
         self.len as usize
     }
@@ -107,25 +198,40 @@ impl Node for NodeCPage {
         self.stem == 0
     }
 
-    fn read_key(&self, _file: &FileIo, idx: usize) -> Vec<u8> {
-        self.keys[idx].to_vec()
+    fn is_consistent(&self) -> bool {
+        let len = self.len();
+        len <= Self::M
+            && (self.is_leaf() || len > 0 && self.child[..len].iter().all(Option::is_some))
+    }
+
+    fn read_key(&self, _file: &FileIo, idx: usize) -> io::Result<Vec<u8>> {
+        Ok(self.keys[idx].to_vec())
+    }
+
+    fn read_keys(&self, _file: &FileIo) -> io::Result<Vec<Vec<u8>>> {
+        let len = self.len() - usize::from(!self.is_leaf());
+        Ok(self.keys[..len].iter().map(|key| key.to_vec()).collect())
     }
 
     fn get_key(&self, _rt: R<'_>, idx: usize) -> Vec<u8> {
         self.keys[idx].to_vec()
     }
 
-    fn search(&self, _file: &FileIo, key: &[u8]) -> Result<usize, usize> {
+    fn search(&self, file: &FileIo, key: &[u8]) -> io::Result<Result<usize, usize>> {
         let len = self.len() - usize::from(!self.is_leaf());
-        self.keys[..len].binary_search(key.try_into().unwrap())
+        // a shorter key is a prefix to look for, it goes before any longer key
+        Ok(self.keys[..len].binary_search_by(|k| file.compare(k, key)))
     }
 
-    fn realloc_keys(&mut self, _rt: R<'_>) {}
+    fn realloc_keys(&mut self, _rt: R<'_>) -> io::Result<()> {
+        Ok(())
+    }
 
     fn insert(
         &mut self,
         mut rt: R<'_>,
         new_child_ptr: Option<PagePtr<Self>>,
+        _inline: Inline,
         idx: usize,
         key: &[u8],
         rev: bool,
@@ -144,19 +250,19 @@ impl Node for NodeCPage {
             self.child.swap(idx, idx + 1);
         }
 
-        fn split(this: &mut NodeCPage, mut rt: R<'_>) -> PagePtr<NodeCPage> {
-            const K: usize = NodeCPage::M / 2;
+        fn split<const M: usize>(this: &mut NodeCPage<M>, mut rt: R<'_>) -> PagePtr<NodeCPage<M>> {
+            let k = M / 2;
 
             let new_ptr = rt.create();
-            let new = rt.mutate::<NodeCPage>(new_ptr);
+            let new = rt.mutate::<NodeCPage<M>>(new_ptr);
             new.stem = this.stem;
-            new.len = K as u16;
-            this.len = K as u16;
+            new.len = k as u16;
+            this.len = k as u16;
 
-            new.child[..K].clone_from_slice(&this.child[K..]);
-            this.child[K..].iter_mut().for_each(|x| *x = None);
-            new.keys[..K].clone_from_slice(&this.keys[K..]);
-            this.keys[K..].iter_mut().for_each(|x| *x = [0; 0x10]);
+            new.child[..k].clone_from_slice(&this.child[k..]);
+            this.child[k..].iter_mut().for_each(|x| *x = None);
+            new.keys[..k].clone_from_slice(&this.keys[k..]);
+            this.keys[k..].iter_mut().for_each(|x| *x = [0; 0x10]);
 
             new_ptr
         }
@@ -171,7 +277,12 @@ impl Node for NodeCPage {
         }
     }
 
-    fn remove(&mut self, _rt: R<'_>, idx: usize, rev: bool) -> (Option<PagePtr<Self>>, Vec<u8>) {
+    fn remove(
+        &mut self,
+        _rt: R<'_>,
+        idx: usize,
+        rev: bool,
+    ) -> (Option<PagePtr<Self>>, Inline, Vec<u8>) {
         let new_len = self.len() - 1;
         self.len = new_len as u16;
 
@@ -189,14 +300,14 @@ impl Node for NodeCPage {
         // just in case
         self.child[new_len] = None;
 
-        (old_ptr, old_key.to_vec())
+        (old_ptr, INLINE_ZERO, old_key.to_vec())
     }
 
     fn set_key(&mut self, _rt: R<'_>, idx: usize, key: &[u8]) -> Vec<u8> {
         mem::replace(&mut self.keys[idx], key.try_into().unwrap()).to_vec()
     }
 
-    fn merge(&mut self, other: &Self, mut rt: R<'_>, key: &[u8], _old: bool) -> Vec<u8> {
+    fn merge(&mut self, other: &Self, mut rt: R<'_>, key: &[u8], _old: bool) -> io::Result<()> {
         let new_len = self.len + other.len;
         if !self.is_leaf() {
             self.set_key(rt.reborrow(), self.len() - 1, key);
@@ -206,86 +317,256 @@ impl Node for NodeCPage {
         self.child[to.clone()].clone_from_slice(&other.child[from.clone()]);
         self.keys[to.clone()].clone_from_slice(&other.keys[from.clone()]);
         self.len = new_len;
-        self.keys[(new_len as usize) - 1].to_vec()
+        Ok(())
     }
 
     fn free(&self, _rt: R<'_>) {}
+
+    fn relocate(&mut self, mut f: impl FnMut(u32) -> u32) {
+        let len = self.len();
+        for ptr in &mut self.child[..len] {
+            relocate_ptr(ptr, &mut f);
+        }
+    }
 }
 
 #[repr(C, align(0x1000))]
 #[derive(Clone, Copy)]
-pub struct NodePage {
+pub struct NodePage<const M: usize = DEFAULT_FANOUT> {
     // if the node is root or branch, the pointer is `Self`,
-    // but if the node is leaf, the pointer is a metadata page
-    child: [Option<PagePtr<Self>>; Self::M],
+    // but if the node is leaf, the pointer is a metadata page or `INLINE_PTR`
+    child: [Option<PagePtr<Self>>; M],
     // length in bytes of each key
-    keys_len: [u16; Self::M],
+    keys_len: [u16; M],
     // pointers to additional pages that stores keys
     // maximal key size is `0x40 * 0x10 = 1 kiB`
-    key: [Option<PagePtr<KeyPage>>; 0x40],
+    key: [Option<PagePtr<KeyPage<M>>>; 0x40],
     // if stem is true than the node is root or branch
     // otherwise it is a leaf
     stem: u16,
     // number of children
     len: u16,
+    // first bytes of the values of the leaf whose child is `INLINE_PTR`,
+    // older versions did not have them
+    inline: [[u8; HEAD]; M],
+    // pointers to additional pages that store the rest of the inline values,
+    // they are laid out like the key pages, older versions did not have them
+    tail: [Option<PagePtr<KeyPage<M>>>; TAIL],
+    // the chunks every key of the leaf starts with, they are not in the key pages,
+    // the first key page holds the chunk that follows them
+    prefix: Option<PagePtr<KeyPage<M>>>,
+    // number of the chunks of the prefix
+    prefix_len: u16,
 }
 
-unsafe impl PlainData for NodePage {
+unsafe impl<const M: usize> PlainData for NodePage<M> {
     const NAME: &str = "Node";
 }
 
+// a chunk of every key, or of every inline value, of the node
 #[repr(C, align(0x1000))]
 #[derive(Clone, Copy)]
-struct KeyPage {
-    keys: [[u8; 0x10]; NodePage::M],
+struct KeyPage<const M: usize> {
+    keys: [[u8; 0x10]; M],
 }
 
-unsafe impl PlainData for KeyPage {
+unsafe impl<const M: usize> PlainData for KeyPage<M> {
     const NAME: &str = "Key";
 }
 
-impl NodePage {
-    fn keys_ptr(&self) -> impl Iterator<Item = PagePtr<KeyPage>> {
+// moves the upper half of every page to a new one
+fn split_pages<const M: usize>(
+    mut rt: Rt<'_, impl Alloc, impl Free, impl AbstractIo>,
+    pages: impl Iterator<Item = PagePtr<KeyPage<M>>>,
+    new_pages: &mut [Option<PagePtr<KeyPage<M>>>],
+) {
+    let k = M / 2;
+
+    for (ptr, new) in pages.zip(new_pages) {
+        let new_page_ptr = rt.create();
+
+        let mut temp = [[0; 16]; M];
+        let key_page = rt.mutate(ptr);
+        key_page.keys[k..]
+            .iter_mut()
+            .zip(temp.iter_mut())
+            .for_each(|(from, to)| *to = mem::take(from));
+
+        let new_page = rt.mutate::<KeyPage<M>>(new_page_ptr);
+        *new = Some(new_page_ptr);
+        new_page.keys[..k].clone_from_slice(&temp[..k]);
+    }
+}
+
+impl<const M: usize> NodePage<M> {
+    fn keys_ptr(&self) -> impl Iterator<Item = PagePtr<KeyPage<M>>> {
         self.key
             .into_iter()
             .take_while(Option::is_some)
             .map(Option::unwrap)
     }
 
+    // whether the key starts with the prefix of the leaf, so the key pages fit it as they are
+    fn shares_prefix(
+        &self,
+        rt: &Rt<'_, impl Alloc, impl Free, impl AbstractIo>,
+        key: &[u8],
+    ) -> bool {
+        let Some(ptr) = self.prefix else {
+            return true;
+        };
+        let common = &rt.look(ptr).keys[..usize::from(self.prefix_len)];
+        key.len() >= common.len() * 0x10 && key.chunks(0x10).zip(common).all(|(a, b)| a == b)
+    }
+
+    // puts the prefix back to the key pages, a chunk of it for every key
+    fn expand_prefix(&mut self, mut rt: Rt<'_, impl Alloc, impl Free, impl AbstractIo>) {
+        let Some(ptr) = self.prefix.take() else {
+            return;
+        };
+        let k = usize::from(mem::take(&mut self.prefix_len));
+        let common = rt.look(ptr).keys;
+        let mut key = [None; 0x40];
+        for (new, chunk) in key.iter_mut().zip(&common[..k]) {
+            let page_ptr = rt.create();
+            rt.mutate::<KeyPage<M>>(page_ptr).keys[..self.len()].fill(*chunk);
+            *new = Some(page_ptr);
+        }
+        key[k..].clone_from_slice(&self.key[..(0x40 - k)]);
+        self.key = key;
+        rt.free.free(ptr);
+    }
+
+    // moves the chunks every key of the leaf starts with out of the key pages to the prefix,
+    // only the whole chunks, so the keys stay ordered chunk by chunk
+    fn compress_prefix(&mut self, mut rt: Rt<'_, impl Alloc, impl Free, impl AbstractIo>) {
+        let len = self.len();
+        if !self.is_leaf() || len < 2 {
+            return;
+        }
+        let k = usize::from(self.prefix_len);
+        // the prefix page holds a chunk in place of each key
+        let max = Self::M.min(0x40);
+        let mut shared = vec![];
+        for ptr in self.keys_ptr().take(max - k) {
+            let page = rt.look(ptr);
+            let depth = (k + shared.len() + 1) * 0x10;
+            let chunk = page.keys[0];
+            let keys = page.keys[..len].iter().zip(&self.keys_len[..len]);
+            if !keys
+                .into_iter()
+                .all(|(c, l)| usize::from(*l) >= depth && *c == chunk)
+            {
+                break;
+            }
+            shared.push(chunk);
+        }
+        let n = shared.len();
+        if n == 0 {
+            return;
+        }
+
+        let new = rt.create();
+        if let Some(old) = self.prefix {
+            let common = rt.look(old).keys;
+            rt.mutate::<KeyPage<M>>(new).keys[..k].clone_from_slice(&common[..k]);
+            rt.free.free(old);
+        }
+        rt.mutate::<KeyPage<M>>(new).keys[k..(k + n)].clone_from_slice(&shared);
+        for ptr in self.keys_ptr().take(n) {
+            rt.free.free(ptr);
+        }
+        self.key.copy_within(n.., 0);
+        self.key[(0x40 - n)..].fill(None);
+        self.prefix = Some(new);
+        self.prefix_len = (k + n) as u16;
+    }
+
+    fn tails_ptr(&self) -> impl Iterator<Item = PagePtr<KeyPage<M>>> {
+        self.tail
+            .into_iter()
+            .take_while(Option::is_some)
+            .map(Option::unwrap)
+    }
+
+    // the head of the inline value at `idx` followed by its chunks of the tail pages
+    fn join_inline(&self, idx: usize, chunks: impl IntoIterator<Item = [u8; 0x10]>) -> Inline {
+        let mut inline = INLINE_ZERO;
+        inline[..HEAD].clone_from_slice(&self.inline[idx]);
+        for (to, chunk) in inline[HEAD..].chunks_mut(0x10).zip(chunks) {
+            to.clone_from_slice(&chunk[..to.len()]);
+        }
+        inline
+    }
+
+    // writes the tail of the inline value at `idx`, the chunks of `idx..old_len` shift first,
+    // a page is created only for a chunk that is not zero, or for one before it
+    fn insert_tail(
+        &mut self,
+        mut rt: Rt<'_, impl Alloc, impl Free, impl AbstractIo>,
+        idx: usize,
+        old_len: usize,
+        inline: &Inline,
+    ) {
+        let tail = &inline[HEAD..];
+        let depth = tail
+            .iter()
+            .rposition(|b| *b != 0)
+            .map_or(0, |last| last / 0x10 + 1);
+        let mut chunks = tail.chunks(0x10);
+        for (d, ptr) in self.tail.iter_mut().enumerate() {
+            let chunk = chunks.next().unwrap_or_default();
+            let absent = ptr.is_none();
+            if absent && d >= depth {
+                break;
+            }
+            let ptr = *ptr.get_or_insert_with(|| rt.create());
+            let page = rt.mutate(ptr);
+            if !absent {
+                for i in (idx..old_len).rev() {
+                    page.keys[i + 1] = page.keys[i];
+                }
+            }
+            page.keys[idx] = [0; 0x10];
+            page.keys[idx][..chunk.len()].clone_from_slice(chunk);
+        }
+    }
+
     fn split(&mut self, mut rt: Rt<'_, impl Alloc, impl Free, impl AbstractIo>) -> PagePtr<Self> {
-        const K: usize = NodePage::M / 2;
+        let k = M / 2;
 
         let new_ptr = rt.create();
         let new = rt.mutate::<Self>(new_ptr);
         new.stem = self.stem;
-        new.len = K as u16;
-        self.len = K as u16;
+        new.len = k as u16;
+        self.len = k as u16;
 
-        new.child[..K].clone_from_slice(&self.child[K..]);
-        self.child[K..].iter_mut().for_each(|x| *x = None);
-        new.keys_len[..K].clone_from_slice(&self.keys_len[K..]);
-        self.keys_len[K..].iter_mut().for_each(|x| *x = 0);
+        new.child[..k].clone_from_slice(&self.child[k..]);
+        self.child[k..].iter_mut().for_each(|x| *x = None);
+        new.keys_len[..k].clone_from_slice(&self.keys_len[k..]);
+        self.keys_len[k..].iter_mut().for_each(|x| *x = 0);
+        new.inline[..k].clone_from_slice(&self.inline[k..]);
+        self.inline[k..].iter_mut().for_each(|x| *x = [0; HEAD]);
 
         let mut new_keys = [None; 0x40];
-        for (ptr, new) in self.key.iter().zip(new_keys.iter_mut()) {
-            let Some(ptr) = *ptr else {
-                break;
-            };
-            let new_page_ptr = rt.create();
-
-            let mut temp = [[0; 16]; K];
-            let key_page = rt.mutate(ptr);
-            key_page.keys[K..]
-                .iter_mut()
-                .zip(temp.iter_mut())
-                .for_each(|(from, to)| *to = mem::take(from));
-
-            let new_page = rt.mutate::<KeyPage>(new_page_ptr);
-            *new = Some(new_page_ptr);
-            new_page.keys[..K].clone_from_slice(&temp);
+        split_pages(rt.reborrow(), self.keys_ptr(), &mut new_keys);
+        let mut new_tail = [None; TAIL];
+        split_pages(rt.reborrow(), self.tails_ptr(), &mut new_tail);
+
+        let mut new = *rt.mutate::<Self>(new_ptr);
+        new.key = new_keys;
+        new.tail = new_tail;
+        if let Some(ptr) = self.prefix {
+            let common = rt.look(ptr).keys;
+            let copy = rt.create();
+            rt.mutate::<KeyPage<M>>(copy).keys = common;
+            new.prefix = Some(copy);
+            new.prefix_len = self.prefix_len;
         }
-
-        rt.mutate::<Self>(new_ptr).key = new_keys;
+        // the halves may share more than the whole leaf did
+        self.compress_prefix(rt.reborrow());
+        new.compress_prefix(rt.reborrow());
+        *rt.mutate(new_ptr) = new;
 
         new_ptr
     }
@@ -297,7 +578,10 @@ impl NodePage {
         old_len: usize,
         key: &[u8],
     ) {
-        let mut it = key.chunks(0x10);
+        // every existing page shifts, even past the last chunk of the key (or the empty key),
+        // an absent page means every key of the node is shorter, it is created only for a chunk,
+        // the key must share the prefix
+        let mut it = key.chunks(0x10).skip(usize::from(self.prefix_len));
         for ptr in &mut self.key {
             let chunk = it.next();
             let absent = ptr.is_none();
@@ -319,20 +603,37 @@ impl NodePage {
     }
 }
 
-impl Node for NodePage {
-    #[cfg(feature = "small")]
-    const M: usize = 0x8;
-
-    #[cfg(not(feature = "small"))]
-    const M: usize = 0x100;
+impl<const M: usize> Node for NodePage<M> {
+    // a key page holds a chunk of every key
+    const M: usize = {
+        assert!(M >= 4 && M.is_multiple_of(2), "the fanout must be even");
+        assert!(
+            mem::size_of::<Self>() == PAGE_SIZE as usize,
+            "the node must fit a page"
+        );
+        assert!(
+            mem::size_of::<KeyPage<M>>() == PAGE_SIZE as usize,
+            "the key page too"
+        );
+        M
+    };
+
+    const MIN_KEY: usize = 0;
+    const MAX_KEY: usize = 0x40 * 0x10;
+
+    const INLINE: bool = true;
 
     fn empty() -> Self {
         NodePage {
-            child: [None; Self::M],
-            keys_len: [0; Self::M],
+            child: [None; M],
+            keys_len: [0; M],
             key: [None; 64],
             stem: 1,
             len: 0,
+            inline: [[0; HEAD]; M],
+            tail: [None; TAIL],
+            prefix: None,
+            prefix_len: 0,
         }
     }
 
@@ -349,6 +650,25 @@ impl Node for NodePage {
         &mut self.child[idx]
     }
 
+    fn inline(&self, file: &FileIo, idx: usize) -> io::Result<Option<Inline>> {
+        let chunks = self
+            .tails_ptr()
+            .map(|ptr| Ok(file.read(ptr)?.keys[idx]))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        Ok(Some(self.join_inline(idx, chunks)))
+    }
+
+    fn set_inline(&mut self, mut rt: R<'_>, idx: usize, inline: &Inline) -> io::Result<()> {
+        for ptr in self.tail.iter_mut().flatten() {
+            rt.read(ptr)?;
+        }
+        self.inline[idx].clone_from_slice(&inline[..HEAD]);
+        self.insert_tail(rt, idx, idx, inline);
+
+        Ok(())
+    }
+
     fn len(&self) -> usize {
         self.len as usize
     }
@@ -357,23 +677,78 @@ impl Node for NodePage {
         self.stem == 0
     }
 
-    fn read_key(&self, file: &FileIo, idx: usize) -> Vec<u8> {
+    fn is_consistent(&self) -> bool {
+        let len = self.len();
+        if len > Self::M
+            || !self.is_leaf() && (len == 0 || self.child[..len].iter().any(Option::is_none))
+        {
+            return false;
+        }
+        let k = usize::from(self.prefix_len);
+        if k > Self::M || k > 0 && self.prefix.is_none() {
+            return false;
+        }
+        // every key has the chunks of the prefix and a key page for each of the rest
+        let depth = self.keys_ptr().count();
+        let keys = &self.keys_len[..len - usize::from(!self.is_leaf())];
+        keys.iter().all(|l| {
+            let chunks = usize::from(*l).div_ceil(0x10);
+            chunks.checked_sub(k).is_some_and(|rest| rest <= depth)
+        })
+    }
+
+    fn read_key(&self, file: &FileIo, idx: usize) -> io::Result<Vec<u8>> {
         let len = self.keys_len[idx] as usize;
-        let depth = len.div_ceil(0x10);
+        let k = usize::from(self.prefix_len);
+        let depth = len.div_ceil(0x10) - k;
         // start with small allocation, optimistically assume the key is small
         let mut v = Vec::with_capacity(0x10 * 4);
+        if let Some(ptr) = self.prefix {
+            v.extend_from_slice(file.read(ptr)?.keys[..k].as_flattened());
+        }
         for i in &self.key[..depth] {
             let ptr = i.expect("BUG key length inconsistent with key pages");
-            let page = file.read(ptr);
+            let page = file.read(ptr)?;
             v.extend_from_slice(&page.keys[idx]);
         }
         v.truncate(len);
-        v
+        Ok(v)
+    }
+
+    fn read_keys(&self, file: &FileIo) -> io::Result<Vec<Vec<u8>>> {
+        let len = self.len() - usize::from(!self.is_leaf());
+        let mut keys = self.keys_len[..len]
+            .iter()
+            .map(|l| Vec::with_capacity(usize::from(*l)))
+            .collect::<Vec<_>>();
+        let k = usize::from(self.prefix_len);
+        if let Some(ptr) = self.prefix {
+            let page = file.read(ptr)?;
+            for key in &mut keys {
+                key.extend_from_slice(page.keys[..k].as_flattened());
+            }
+        }
+        for (depth, ptr) in self.keys_ptr().enumerate() {
+            let page = file.read(ptr)?;
+            for (idx, key) in keys.iter_mut().enumerate() {
+                if usize::from(self.keys_len[idx]).div_ceil(0x10) > k + depth {
+                    key.extend_from_slice(&page.keys[idx]);
+                }
+            }
+        }
+        for (key, l) in keys.iter_mut().zip(self.keys_len) {
+            key.truncate(usize::from(l));
+        }
+
+        Ok(keys)
     }
 
     fn get_key(&self, rt: R<'_>, idx: usize) -> Vec<u8> {
         // start with small allocation, optimistically assume the key is small
         let mut v = Vec::with_capacity(0x10 * 4);
+        if let Some(ptr) = self.prefix {
+            v.extend_from_slice(rt.look(ptr).keys[..usize::from(self.prefix_len)].as_flattened());
+        }
         for ptr in self.keys_ptr() {
             let page = rt.look(ptr);
             v.extend_from_slice(&page.keys[idx]);
@@ -382,10 +757,19 @@ impl Node for NodePage {
         v
     }
 
+    // Keys are compared chunk by chunk, each chunk padded with zeros,
+    // then by length. This is exactly the lexicographic order
+    // as long as the tail of every key in every key page is zero.
+    // A custom collation compares the whole keys instead.
     // TODO: SIMD optimization
-    fn search(&self, file: &FileIo, key: &[u8]) -> Result<usize, usize> {
+    fn search(&self, file: &FileIo, key: &[u8]) -> io::Result<Result<usize, usize>> {
         use std::ops::Range;
 
+        if let Some(collation) = file.collation() {
+            let keys = self.read_keys(file)?;
+            return Ok(keys.binary_search_by(|k| collation.compare(k, key)));
+        }
+
         let len = self.len() - usize::from(!self.is_leaf());
 
         #[inline(always)]
@@ -401,7 +785,7 @@ impl Node for NodePage {
                 range.start -= 1;
             }
 
-            while range.end < len - 1 && cmp(range.end) {
+            while range.end < len && cmp(range.end) {
                 range.end += 1;
             }
 
@@ -414,67 +798,100 @@ impl Node for NodePage {
         let mut chunks = key.chunks(0x10);
         let mut pointers = self.keys_ptr();
 
+        // every key has the same chunks of the prefix, so either all of them are in range or none
+        if let Some(ptr) = self.prefix {
+            let page = file.read(ptr)?;
+            for common in &page.keys[..usize::from(self.prefix_len)] {
+                let Some(chunk) = chunks.next() else {
+                    break;
+                };
+                let mut key_b = [0; 0x10];
+                key_b[..chunk.len()].clone_from_slice(chunk);
+                match key_b.cmp(common) {
+                    Ordering::Less => return Ok(Err(range.start)),
+                    Ordering::Greater => return Ok(Err(range.end)),
+                    Ordering::Equal => {}
+                }
+            }
+        }
+
         for (ptr, chunk) in (&mut pointers).zip(&mut chunks) {
-            let buffer = &file.read(ptr).keys;
+            let buffer = &file.read(ptr)?.keys;
 
             let mut key_b = [0; 0x10];
             let l = chunk.len().min(0x10);
             key_b[..l].clone_from_slice(&chunk[..l]);
 
-            let i = buffer[range.clone()]
-                .binary_search(&key_b)
-                .map_err(|i| range.start + i)?;
+            let i = match buffer[range.clone()].binary_search(&key_b) {
+                Ok(i) => i,
+                Err(i) => return Ok(Err(range.start + i)),
+            };
 
             extend_range(len, i, &mut range, |i| buffer[i] == key_b);
         }
 
         let original_len = key.len() as u16;
-        let i = self.keys_len[range.clone()]
-            .binary_search(&original_len)
-            .map_err(|i| range.start + i)?;
+        let i = match self.keys_len[range.clone()].binary_search(&original_len) {
+            Ok(i) => i,
+            Err(i) => return Ok(Err(range.start + i)),
+        };
 
         extend_range(len, i, &mut range, |i| self.keys_len[i] == original_len);
 
         if chunks.next().is_some() {
-            Err(range.end)
+            Ok(Err(range.end))
         } else if pointers.next().is_some() {
             if range.len() == 1 {
-                Ok(range.start)
+                Ok(Ok(range.start))
             } else {
-                Err(range.start)
+                Ok(Err(range.start))
             }
         } else if range.len() == 1 {
-            Ok(range.start)
+            Ok(Ok(range.start))
         } else {
-            panic!("BUG: two identical keys detected {}", hex::encode(key));
+            Err(DuplicateKey(key.to_vec()).into())
         }
     }
 
-    fn realloc_keys(&mut self, mut rt: R) {
-        for ptr in self.key.iter_mut().flatten() {
-            rt.read(ptr);
+    fn realloc_keys(&mut self, mut rt: R) -> io::Result<()> {
+        let pages = self.key.iter_mut().chain(&mut self.tail);
+        for ptr in pages.chain(Some(&mut self.prefix)).flatten() {
+            rt.read(ptr)?;
         }
+
+        Ok(())
     }
 
     fn insert(
         &mut self,
         mut rt: R,
         new_child_ptr: Option<PagePtr<Self>>,
+        inline: Inline,
         idx: usize,
         key: &[u8],
         rev: bool,
     ) -> Option<(Vec<u8>, PagePtr<Self>)> {
+        if !self.shares_prefix(&rt, key) {
+            self.expand_prefix(rt.reborrow());
+        }
         let old_len = self.len();
         self.len = (old_len + 1) as u16;
 
         for i in (idx..old_len).rev() {
             self.child[i + 1] = self.child[i];
             self.keys_len[i + 1] = self.keys_len[i];
+            self.inline[i + 1] = self.inline[i];
         }
 
         self.child[idx] = new_child_ptr;
+        self.inline[idx].clone_from_slice(&inline[..HEAD]);
+        self.insert_tail(rt.reborrow(), idx, old_len, &inline);
         if rev {
             self.child.swap(idx, idx + 1);
+            self.inline.swap(idx, idx + 1);
+            for ptr in self.tails_ptr() {
+                rt.mutate(ptr).keys.swap(idx, idx + 1);
+            }
         }
         self.keys_len[idx] = key.len() as u16;
         self.insert_key(rt.reborrow(), idx, old_len, key);
@@ -489,26 +906,49 @@ impl Node for NodePage {
         }
     }
 
-    fn remove(&mut self, mut rt: R, idx: usize, rev: bool) -> (Option<PagePtr<Self>>, Vec<u8>) {
+    fn remove(
+        &mut self,
+        mut rt: R,
+        idx: usize,
+        rev: bool,
+    ) -> (Option<PagePtr<Self>>, Inline, Vec<u8>) {
         let new_len = self.len() - 1;
         self.len = new_len as u16;
 
         let old_ptr = self.child[idx];
+        let chunks = self.tails_ptr().map(|ptr| rt.look(ptr).keys[idx]);
+        let old_inline = self.join_inline(idx, chunks.collect::<Vec<_>>());
         let old_key_len = self.keys_len[idx];
 
         if rev {
             self.child.swap(idx, idx + 1);
+            self.inline.swap(idx, idx + 1);
+        }
+        for ptr in self.tails_ptr() {
+            let page = rt.mutate(ptr);
+            if rev {
+                page.keys.swap(idx, idx + 1);
+            }
+            for i in idx..new_len {
+                page.keys[i] = page.keys[i + 1];
+            }
+            page.keys[new_len] = [0; 0x10];
         }
 
         for i in idx..new_len {
             self.child[i] = self.child[i + 1];
             self.keys_len[i] = self.keys_len[i + 1];
+            self.inline[i] = self.inline[i + 1];
         }
         // just in case
         self.child[new_len] = None;
+        self.inline[new_len] = [0; HEAD];
 
         // start with small allocation, optimistically assume the key is small
         let mut v = Vec::with_capacity(0x10 * 4);
+        if let Some(ptr) = self.prefix {
+            v.extend_from_slice(rt.look(ptr).keys[..usize::from(self.prefix_len)].as_flattened());
+        }
         for ptr in self.keys_ptr() {
             let page = rt.mutate(ptr);
             v.extend_from_slice(&page.keys[idx]);
@@ -518,29 +958,42 @@ impl Node for NodePage {
         }
         v.truncate(old_key_len as usize);
 
-        (old_ptr, v)
+        (old_ptr, old_inline, v)
     }
 
     fn set_key(&mut self, mut rt: R, idx: usize, key: &[u8]) -> Vec<u8> {
+        if !self.shares_prefix(&rt, key) {
+            self.expand_prefix(rt.reborrow());
+        }
         let old_key_len = mem::replace(&mut self.keys_len[idx], key.len() as u16);
 
-        let chunks = key.chunks(0x10);
+        let k = usize::from(self.prefix_len);
+        let mut chunks = key.chunks(0x10).skip(k);
 
         let mut v = Vec::with_capacity(0x10 * 4);
-        for (ptr, chunk) in self.key.iter_mut().zip(chunks) {
+        if let Some(ptr) = self.prefix {
+            v.extend_from_slice(rt.look(ptr).keys[..k].as_flattened());
+        }
+        for ptr in &mut self.key {
+            let chunk = chunks.next();
+            if ptr.is_none() && chunk.is_none() {
+                break;
+            }
             let ptr = ptr.get_or_insert_with(|| rt.create());
             let page = rt.mutate(*ptr);
             v.extend_from_slice(&page.keys[idx]);
 
+            // the tail of the key must be zero, `search` compares it
             page.keys[idx] = [0; 0x10];
-            let l = chunk.len().min(0x10);
-            page.keys[idx][..l].clone_from_slice(&chunk[..l]);
+            if let Some(chunk) = chunk {
+                page.keys[idx][..chunk.len()].clone_from_slice(chunk);
+            }
         }
         v.truncate(old_key_len as usize);
         v
     }
 
-    fn merge(&mut self, other: &Self, mut rt: R<'_>, key: &[u8], old: bool) -> Vec<u8> {
+    fn merge(&mut self, other: &Self, mut rt: R<'_>, key: &[u8], old: bool) -> io::Result<()> {
         let new_len = self.len + other.len;
         if !self.is_leaf() {
             self.set_key(rt.reborrow(), self.len() - 1, key);
@@ -548,34 +1001,57 @@ impl Node for NodePage {
         let to = (self.len as usize)..(new_len as usize);
         let from = 0..(other.len as usize);
         self.child[to.clone()].clone_from_slice(&other.child[from.clone()]);
+        self.inline[to.clone()].clone_from_slice(&other.inline[from.clone()]);
+        let tails = other
+            .tails_ptr()
+            .map(|ptr| rt.load(ptr))
+            .collect::<io::Result<Vec<KeyPage<M>>>>()?;
+        if !tails.is_empty() {
+            for (to, from) in to.clone().zip(from.clone()) {
+                let chunks = tails.iter().map(|page| page.keys[from]);
+                let inline = other.join_inline(from, chunks);
+                self.insert_tail(rt.reborrow(), to, to, &inline);
+            }
+        }
         // self.keys_len[to.clone()].clone_from_slice(&other.keys_len[from.clone()]);
         // self.table_id[to.clone()].clone_from_slice(&other.table_id[from.clone()]);
         // TODO: optimize
-        let mut last_key = None;
-        if old {
-            for (to, from) in to.zip(from) {
-                let key = other.read_key(rt.io, from);
-                if !key.is_empty() {
-                    last_key = Some(key.clone());
-                }
-                self.set_key(rt.reborrow(), to, &key);
-            }
+        let keys = if old {
+            from.map(|from| other.read_key(rt.io, from))
+                .collect::<io::Result<Vec<_>>>()?
         } else {
-            for (to, from) in to.zip(from) {
-                let key = other.get_key(rt.reborrow(), from);
-                if !key.is_empty() {
-                    last_key = Some(key.clone());
-                }
-                self.set_key(rt.reborrow(), to, &key);
-            }
+            from.map(|from| other.get_key(rt.reborrow(), from))
+                .collect::<Vec<_>>()
+        };
+        // the prefix goes back to the key pages before any key that does not share it
+        if !keys.iter().all(|key| self.shares_prefix(&rt, key)) {
+            self.expand_prefix(rt.reborrow());
+        }
+        for (to, key) in to.zip(keys) {
+            self.set_key(rt.reborrow(), to, &key);
         }
         self.len = new_len;
-        last_key.expect("loop must be not empty")
+        self.compress_prefix(rt);
+        Ok(())
     }
 
     fn free(&self, rt: R<'_>) {
-        for ptr in self.keys_ptr() {
+        for ptr in self.keys_ptr().chain(self.tails_ptr()).chain(self.prefix) {
             rt.free.free(ptr);
         }
     }
+
+    fn relocate(&mut self, mut f: impl FnMut(u32) -> u32) {
+        let len = self.len();
+        for ptr in &mut self.child[..len] {
+            relocate_ptr(ptr, &mut f);
+        }
+        for ptr in self.key.iter_mut().take_while(|ptr| ptr.is_some()) {
+            relocate_ptr(ptr, &mut f);
+        }
+        for ptr in self.tail.iter_mut().take_while(|ptr| ptr.is_some()) {
+            relocate_ptr(ptr, &mut f);
+        }
+        relocate_ptr(&mut self.prefix, &mut f);
+    }
 }
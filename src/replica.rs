@@ -0,0 +1,172 @@
+use std::io::{self, Read, Write};
+
+use super::{
+    utils::{Checked, read_array},
+    page::PAGE_SIZE,
+    runtime::{AbstractIo, PlainData, PBox},
+    file::FileIo,
+    node::Node,
+    wal::{Wal, WalLock, WalState},
+    db::DbError,
+};
+
+const MAGIC: [u8; 8] = *b"rej ship";
+const VERSION: u32 = 1;
+
+/// Pages written by a database after some record and the record after them,
+/// made by `Db::export_changes` and taken by `Db::apply_changes` of a replica.
+/// The pages are not encrypted, even if both databases are.
+pub struct ChangeSet {
+    node: Vec<u8>,
+    since: u64,
+    seq: u64,
+    // checksum of the record `since`, `None` if the pages are the whole database
+    base: Option<u64>,
+    record: PBox,
+    pages: Vec<(u32, PBox)>,
+}
+
+impl ChangeSet {
+    /// The version the changes follow
+    pub fn since(&self) -> u64 {
+        self.since
+    }
+
+    /// The version of the replica after the changes
+    pub fn version(&self) -> u64 {
+        self.seq
+    }
+
+    /// Every page of the database, not only the changed ones
+    pub fn is_full(&self) -> bool {
+        self.base.is_none()
+    }
+
+    /// Number of pages
+    pub fn len(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    /// Writes the changes, the stream ends with a checksum of everything before
+    pub fn write_to(&self, w: impl Write) -> io::Result<()> {
+        let mut w = Checked::new(w);
+        w.write_all(&MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        w.write_all(&(self.node.len() as u32).to_le_bytes())?;
+        w.write_all(&self.node)?;
+        w.write_all(&self.since.to_le_bytes())?;
+        w.write_all(&self.seq.to_le_bytes())?;
+        w.write_all(&[u8::from(self.base.is_some())])?;
+        w.write_all(&self.base.unwrap_or_default().to_le_bytes())?;
+        w.write_all(&*self.record)?;
+        w.write_all(&(self.pages.len() as u32).to_le_bytes())?;
+        for (n, page) in &self.pages {
+            w.write_all(&n.to_le_bytes())?;
+            w.write_all(&**page)?;
+        }
+        let crc = w.crc;
+        w.inner.write_all(&crc.to_le_bytes())
+    }
+
+    /// Reads the changes written by `write_to`,
+    /// fails with `DbError::BadChangeSet` if the checksum does not match
+    pub fn read_from(r: impl Read) -> Result<Self, DbError> {
+        let mut r = Checked::new(r);
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(DbError::BadChangeSet);
+        }
+        let version = u32::from_le_bytes(read_array(&mut r)?);
+        if version != VERSION {
+            return Err(DbError::BadChangeSet);
+        }
+        let node_len = u32::from_le_bytes(read_array(&mut r)?);
+        if node_len > 0x100 {
+            return Err(DbError::BadChangeSet);
+        }
+        let mut node = vec![0; node_len as usize];
+        r.read_exact(&mut node)?;
+        let since = u64::from_le_bytes(read_array(&mut r)?);
+        let seq = u64::from_le_bytes(read_array(&mut r)?);
+        let [has_base] = read_array(&mut r)?;
+        let base = u64::from_le_bytes(read_array(&mut r)?);
+        let base = (has_base != 0).then_some(base);
+        let record = read_page(&mut r)?;
+        let len = u32::from_le_bytes(read_array(&mut r)?);
+        let mut pages = vec![];
+        for _ in 0..len {
+            let n = u32::from_le_bytes(read_array(&mut r)?);
+            pages.push((n, read_page(&mut r)?));
+        }
+        let crc = r.crc;
+        let mut word = [0; 8];
+        r.inner.read_exact(&mut word)?;
+        if u64::from_le_bytes(word) != crc {
+            return Err(DbError::BadChangeSet);
+        }
+
+        Ok(ChangeSet {
+            node,
+            since,
+            seq,
+            base,
+            record,
+            pages,
+        })
+    }
+}
+
+/// The pages written after the record `since` or every page if the record
+/// is not kept, the current record and the pages the writing of values did not
+/// pass to a record yet. Values written meanwhile may be torn.
+pub fn export<N>(lock: &WalState, file: &FileIo, since: u64) -> io::Result<ChangeSet>
+where
+    N: PlainData,
+{
+    let size = lock.written_size();
+    let (base, mut numbers) = match lock.pages_since(since) {
+        Some((base, pages)) => (Some(base), pages),
+        None => (None, (Wal::SIZE..size).collect()),
+    };
+    numbers.extend(file.written());
+    let pages = numbers
+        .into_iter()
+        .filter(|n| *n < size)
+        .map(|n| Ok((n, file.read_page(n)?)))
+        .collect::<io::Result<_>>()?;
+
+    Ok(ChangeSet {
+        node: N::NAME.as_bytes().to_vec(),
+        since,
+        seq: lock.seq(),
+        base,
+        record: lock.record_page(),
+        pages,
+    })
+}
+
+/// Writes the pages and the record of the changes, the tree must be of the same type
+pub fn apply<N>(lock: &mut WalLock<'_>, file: &FileIo, changes: &ChangeSet) -> Result<(), DbError>
+where
+    N: PlainData + Node,
+{
+    if changes.node != N::NAME.as_bytes() {
+        return Err(DbError::BadChangeSet);
+    }
+    let pages = changes.pages.iter().map(|(n, page)| (*n, page.clone()));
+    lock.apply(file, changes.base, &changes.record, pages)?;
+
+    Ok(())
+}
+
+fn read_page(r: &mut impl Read) -> io::Result<PBox> {
+    let mut page = PBox::new(4096, [0; PAGE_SIZE as usize]);
+    r.read_exact(&mut *page)?;
+
+    Ok(page)
+}
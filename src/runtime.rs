@@ -16,6 +16,9 @@ where
 {
     const NAME: &str;
 
+    /// How the page is written, a value is `Data`, the rest of the tree is `Tree`
+    const KIND: PageKind = PageKind::Tree;
+
     fn as_this(slice: &[u8]) -> &Self {
         unsafe { &*slice.as_ptr().cast::<Self>() }
     }
@@ -45,15 +48,25 @@ pub trait Free {
 pub trait AbstractIo {
     fn read_page(&self, n: u32) -> io::Result<PBox>;
 
-    fn read<T>(&self, ptr: impl Into<Option<PagePtr<T>>>) -> T
+    /// A zeroed page, maybe reused
+    fn new_page(&self) -> PBox {
+        PBox::new(4096, [0; PAGE_SIZE as usize])
+    }
+
+    /// Gives the page back to be reused by `new_page`
+    fn recycle_page(&self, page: PBox) {
+        drop(page);
+    }
+
+    fn read<T>(&self, ptr: impl Into<Option<PagePtr<T>>>) -> io::Result<T>
     where
         T: PlainData + Copy,
     {
-        // TODO: unwrap
-        let page = self
-            .read_page(ptr.into().map_or(0, PagePtr::raw_number))
-            .unwrap();
-        *T::as_this(&*page)
+        let page = self.read_page(ptr.into().map_or(0, PagePtr::raw_number))?;
+        let v = *T::as_this(&*page);
+        self.recycle_page(page);
+
+        Ok(v)
     }
 
     fn write<T>(
@@ -65,7 +78,7 @@ pub trait AbstractIo {
     where
         T: PlainData,
     {
-        let mut page = PBox::new(4096, [0; PAGE_SIZE as usize]);
+        let mut page = self.new_page();
         let bytes = value.as_bytes();
         page[..bytes.len()].clone_from_slice(bytes);
 
@@ -89,7 +102,7 @@ pub struct Rt<'a, A, F, Io> {
     pub alloc: &'a mut A,
     pub free: &'a mut F,
     pub io: &'a Io,
-    storage: &'a mut BTreeMap<u32, PBox>,
+    storage: &'a mut BTreeMap<u32, (PageKind, PBox)>,
 }
 
 impl<A, F, Io> Rt<'_, A, F, Io> {
@@ -112,7 +125,7 @@ where
         alloc: &'a mut A,
         free: &'a mut F,
         io: &'a Io,
-        storage: &'a mut BTreeMap<u32, PBox>,
+        storage: &'a mut BTreeMap<u32, (PageKind, PBox)>,
     ) -> Self {
         Rt {
             alloc,
@@ -134,20 +147,26 @@ where
         T: PlainData,
     {
         let ptr = self.alloc.alloc();
-        let v = PBox::new(4096, [0; PAGE_SIZE as usize]);
-        self.storage.insert(ptr.raw_number(), v);
+        let v = self.io.new_page();
+        self.storage.insert(ptr.raw_number(), (T::KIND, v));
 
         ptr
     }
 
-    pub fn read<T>(&mut self, ptr: &mut PagePtr<T>)
+    /// Copies the page to a new one to be changed, a page created or copied
+    /// by this operation is already in the storage and stays as it is
+    pub fn read<T>(&mut self, ptr: &mut PagePtr<T>) -> io::Result<()>
     where
         T: PlainData,
     {
-        // TODO: unwrap
-        let page = self.io.read_page(ptr.raw_number()).unwrap();
+        if self.storage.contains_key(&ptr.raw_number()) {
+            return Ok(());
+        }
+        let page = self.io.read_page(ptr.raw_number())?;
         self.free.free(mem::replace(ptr, self.alloc.alloc::<T>()));
-        self.storage.insert(ptr.raw_number(), page);
+        self.storage.insert(ptr.raw_number(), (T::KIND, page));
+
+        Ok(())
     }
 
     pub fn set<T>(&mut self, ptr: &mut PagePtr<T>, v: T)
@@ -155,16 +174,16 @@ where
         T: PlainData,
     {
         self.free.free(mem::replace(ptr, self.alloc.alloc::<T>()));
-        let mut page = PBox::new(4096, [0; PAGE_SIZE as usize]);
+        let mut page = self.io.new_page();
         page[..v.as_bytes().len()].clone_from_slice(v.as_bytes());
-        self.storage.insert(ptr.raw_number(), page);
+        self.storage.insert(ptr.raw_number(), (T::KIND, page));
     }
 
     pub fn mutate<T>(&mut self, ptr: PagePtr<T>) -> &mut T
     where
         T: PlainData,
     {
-        let bytes = self
+        let (_, bytes) = self
             .storage
             .get_mut(&ptr.raw_number())
             .expect("read or create before mutate");
@@ -175,16 +194,28 @@ where
     where
         T: PlainData,
     {
-        let bytes = self
+        let (_, bytes) = self
             .storage
             .get(&ptr.raw_number())
             .expect("read or create before mutate");
         T::as_this(&**bytes)
     }
 
+    /// The page as this operation sees it, from the storage if it is there,
+    /// otherwise from the file
+    pub fn load<T>(&self, ptr: PagePtr<T>) -> io::Result<T>
+    where
+        T: PlainData + Copy,
+    {
+        match self.storage.get(&ptr.raw_number()) {
+            Some((_, bytes)) => Ok(*T::as_this(&**bytes)),
+            None => self.io.read(ptr),
+        }
+    }
+
     pub fn flush(self) -> io::Result<()> {
-        for (n, page) in mem::take(self.storage) {
-            self.io.write_page(n, PageKind::Tree, page)?;
+        for (n, (kind, page)) in mem::take(self.storage) {
+            self.io.write_page(n, kind, page)?;
         }
 
         Ok(())
@@ -0,0 +1,348 @@
+use std::{collections::BTreeMap, fs, sync::Barrier, thread, time::Duration};
+
+use tempdir::TempDir;
+
+use crate::{Db, DbError, Entry, ImportOptions, NodeCPage, NodePage, Params, Value};
+
+#[test]
+fn backup() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-backup");
+    let dest = dir.path().join("test-backup-copy");
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(true)).unwrap();
+    for i in 0..500u16 {
+        let key = format!("key {i:04}");
+        db.entry(key.as_bytes())
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap()
+            .write_at(0, &i.to_le_bytes())
+            .unwrap();
+    }
+    db.backup(&dest).unwrap();
+    assert!(db.backup(&dest).is_err());
+
+    // must not affect the copy
+    db.entry(b"key 0000")
+        .unwrap()
+        .occupied()
+        .unwrap()
+        .remove()
+        .unwrap();
+    db.entry(b"key 0001")
+        .unwrap()
+        .occupied()
+        .unwrap()
+        .into_value()
+        .write_at(0, &[0xff, 0xff])
+        .unwrap();
+    db.sync().unwrap();
+    drop(db);
+
+    let db = Db::<NodePage>::new(&dest, Params::new_mock(false)).unwrap();
+    for i in 0..500u16 {
+        let key = format!("key {i:04}");
+        let value = db
+            .entry(key.as_bytes())
+            .unwrap()
+            .occupied()
+            .unwrap()
+            .into_value()
+            .read_to_vec(0, 2)
+            .unwrap();
+        assert_eq!(value, i.to_le_bytes());
+    }
+}
+
+#[test]
+fn dump_restore() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-dump");
+    let dest = dir.path().join("test-dump-restored");
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(true)).unwrap();
+    for i in 0..500u16 {
+        let key = format!("key          {i:03}");
+        let vacant = db.entry(key.as_bytes()).unwrap().vacant().unwrap();
+        if i % 10 == 0 {
+            vacant.insert_empty().unwrap();
+        } else {
+            vacant
+                .insert()
+                .unwrap()
+                .write_at(0, &i.to_le_bytes())
+                .unwrap();
+        }
+    }
+    let mut dump = vec![];
+    db.dump(&mut dump).unwrap();
+
+    let mut bad = dump.clone();
+    bad[8] = 3;
+    assert!(matches!(
+        Db::<NodeCPage>::restore(&dest, Params::new_mock(true), bad.as_slice()),
+        Err(DbError::DumpVersion(3))
+    ));
+
+    // different node layout
+    let restored =
+        Db::<NodeCPage>::restore(&dest, Params::new_mock(true), dump.as_slice()).unwrap();
+    for i in 0..500u16 {
+        let key = format!("key          {i:03}");
+        match restored.entry(key.as_bytes()).unwrap() {
+            Entry::Empty(_) => assert_eq!(i % 10, 0),
+            Entry::Occupied(v) => {
+                let value = v.into_value().read_to_vec(0, 2).unwrap();
+                assert_eq!(value, i.to_le_bytes());
+            }
+            Entry::Vacant(_) => panic!("{key}"),
+        }
+    }
+
+    let mut again = vec![];
+    restored.dump(&mut again).unwrap();
+    assert_eq!(dump, again);
+
+    // the first version has the whole pages and no checksum
+    let old = dir.path().join("test-dump-old");
+    let mut v1 = b"rej dump".to_vec();
+    v1.extend_from_slice(&1u32.to_le_bytes());
+    v1.extend_from_slice(&1u32.to_le_bytes());
+    v1.extend_from_slice(b"a");
+    v1.extend_from_slice(&0x1000u32.to_le_bytes());
+    v1.extend((1..=3).chain([0; 0xffd]));
+    v1.extend_from_slice(&1u32.to_le_bytes());
+    v1.extend_from_slice(b"b");
+    v1.extend_from_slice(&u32::MAX.to_le_bytes());
+    v1.extend_from_slice(&u32::MAX.to_le_bytes());
+    let restored = Db::<NodePage>::restore(&old, Params::new_mock(true), v1.as_slice()).unwrap();
+    let value = restored.get(b"a").unwrap().unwrap();
+    assert_eq!(value.read_to_vec(0, 4).unwrap(), [1, 2, 3, 0]);
+    assert!(restored.entry(b"b").unwrap().empty().is_some());
+
+    // a record that does not fit fails the same way in either version
+    for version in 1..=2u32 {
+        let mut long = b"rej dump".to_vec();
+        long.extend_from_slice(&version.to_le_bytes());
+        long.extend_from_slice(&0x1000u32.to_le_bytes());
+        long.extend([0; 0x1000]);
+        assert!(matches!(
+            restored.import(long.as_slice()),
+            Err(DbError::KeyTooLong { .. })
+        ));
+
+        let mut big = b"rej dump".to_vec();
+        big.extend_from_slice(&version.to_le_bytes());
+        big.extend_from_slice(&1u32.to_le_bytes());
+        big.extend_from_slice(b"a");
+        big.extend_from_slice(&0x1001u32.to_le_bytes());
+        big.extend([0; 0x1001 + 8]);
+        assert!(matches!(
+            restored.import(big.as_slice()),
+            Err(DbError::BadDump)
+        ));
+    }
+}
+
+#[test]
+fn backup_to() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-backup-to");
+    let dest = dir.path().join("test-backup-to-copy");
+
+    let key = |i: u32| format!("key {i:05}");
+    let db = Db::<NodePage>::new(&path, Params::new_mock(true)).unwrap();
+    for i in 0..100 {
+        db.entry(key(i).as_bytes())
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert_empty()
+            .unwrap();
+    }
+    let barrier = Barrier::new(2);
+    thread::scope(|s| {
+        s.spawn(|| {
+            for i in 100..2000 {
+                if i == 500 {
+                    barrier.wait();
+                }
+                let value = db
+                    .entry(key(i).as_bytes())
+                    .unwrap()
+                    .vacant()
+                    .unwrap()
+                    .insert()
+                    .unwrap();
+                value.write_at(0, &i.to_le_bytes()).unwrap();
+            }
+        });
+        barrier.wait();
+        db.backup_to(&dest, Params::new_mock(true)).unwrap();
+    });
+    drop(db);
+
+    // the copy is a prefix of the insertion sequence
+    let db = Db::<NodePage>::new(&dest, Params::new_mock(false)).unwrap();
+    let mut it = db.entry(b"").unwrap().into_db_iter();
+    let mut values = vec![];
+    while let Some((k, value)) = db.next(&mut it).unwrap() {
+        assert_eq!(k, key(values.len() as u32).as_bytes());
+        values.push(value);
+    }
+    assert!(values.len() >= 500);
+    // the last value might be inserted, but not written yet
+    values.pop();
+    for (i, value) in values.into_iter().enumerate().skip(100) {
+        let value = value.unwrap().read_to_vec(0, 4).unwrap();
+        assert_eq!(value, (i as u32).to_le_bytes());
+    }
+}
+
+#[test]
+fn backup_to_crash() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-backup-to-crash");
+    let crashed = dir.path().join("test-backup-to-crashed");
+
+    let key = |i: u32| format!("key {i:05}");
+    let db = Db::<NodePage>::new(&path, Params::new_mock(true)).unwrap();
+    for i in 0..1000 {
+        let vacant = db.entry(key(i).as_bytes()).unwrap().vacant().unwrap();
+        vacant
+            .insert()
+            .unwrap()
+            .write_at(0, &i.to_le_bytes())
+            .unwrap();
+    }
+    // the process crashes while `backup_to` copies the snapshot
+    db.pin_snapshot(true);
+    for i in (0..1000).step_by(2) {
+        let occupied = db.entry(key(i).as_bytes()).unwrap().occupied().unwrap();
+        drop(occupied.remove().unwrap());
+    }
+    db.sync().unwrap();
+    fs::copy(&path, &crashed).unwrap();
+    db.pin_snapshot(false);
+    drop(db);
+
+    // the pages released meanwhile are free again
+    let db = Db::<NodePage>::new(&crashed, Params::new_mock(false)).unwrap();
+    assert!(db.last_recovery().unwrap().lost_reclaimed >= 500);
+    db.check().unwrap();
+    for i in (1..1000).step_by(2) {
+        let value = db.entry(key(i).as_bytes()).unwrap().occupied().unwrap();
+        assert_eq!(
+            value.into_value().read_to_vec(0, 4).unwrap(),
+            i.to_le_bytes()
+        );
+    }
+    drop(db);
+
+    let db = Db::<NodePage>::new(&crashed, Params::new_mock(false)).unwrap();
+    assert!(db.last_recovery().unwrap().is_clean());
+}
+
+#[test]
+fn export_import() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-export");
+    let dest = dir.path().join("test-export-imported");
+
+    // long keys take several chunks, some values fill the whole page
+    let key = |i: u16| format!("key {i:03} {}", "x".repeat(usize::from(i) * 3)).into_bytes();
+    let value = |i: u16| vec![i as u8 | 1; usize::from(i) * 13 % Value::CAPACITY + 1];
+    let content = |db: &Db<NodePage>| {
+        let mut it = db.iter_from(b"").unwrap();
+        let mut content = BTreeMap::new();
+        while let Some((key, value)) = db.next(&mut it).unwrap() {
+            let value = value.map(|v| v.read_to_vec(0, Value::CAPACITY).unwrap());
+            content.insert(key, value);
+        }
+        content
+    };
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(true)).unwrap();
+    for i in 0..300u16 {
+        let vacant = db.entry(key(i)).unwrap().vacant().unwrap();
+        match i % 10 {
+            0 => vacant.insert_empty().unwrap(),
+            1 => {
+                let v = vacant.insert_with_ttl(Duration::from_secs(3600)).unwrap();
+                v.write_at(0, &value(i)).unwrap();
+            }
+            _ => vacant.insert().unwrap().write_at(0, &value(i)).unwrap(),
+        }
+    }
+    db.entry(key(299))
+        .unwrap()
+        .occupied()
+        .unwrap()
+        .into_value()
+        .write_at(0, &[0xff; Value::CAPACITY])
+        .unwrap();
+    let expected = content(&db);
+
+    let mut export = vec![];
+    let stats = db.export(&mut export).unwrap();
+    assert_eq!(stats.records, 300);
+    assert_eq!(stats.values, 270);
+
+    let imported = Db::<NodePage>::new(&dest, Params::new_mock(true)).unwrap();
+    let stats = imported.import(export.as_slice()).unwrap();
+    assert_eq!(stats.records, 300);
+    assert_eq!(stats.duplicates, 0);
+    imported.check().unwrap();
+    assert_eq!(content(&imported), expected);
+    let ttl = imported.entry(key(1)).unwrap().occupied().unwrap();
+    assert!(!ttl.is_expired().unwrap());
+    drop(ttl);
+
+    let mut again = vec![];
+    imported.export(&mut again).unwrap();
+    assert_eq!(export, again);
+
+    // the keys are there already, kept unless overwritten
+    imported
+        .get(&key(5))
+        .unwrap()
+        .unwrap()
+        .write_at(0, b"changed")
+        .unwrap();
+    let stats = imported.import(export.as_slice()).unwrap();
+    assert_eq!(stats.duplicates, 300);
+    let changed = imported.get(&key(5)).unwrap().unwrap();
+    assert_eq!(changed.read_to_vec(0, 7).unwrap(), b"changed");
+    let options = ImportOptions { overwrite: true };
+    let stats = imported.import_with(export.as_slice(), options).unwrap();
+    assert_eq!(stats.duplicates, 300);
+    assert_eq!(content(&imported), expected);
+    imported.check().unwrap();
+
+    // a corrupted chunk is not imported, nor the chunks after it
+    let empty = dir.path().join("test-export-corrupted");
+    let empty = Db::<NodePage>::new(&empty, Params::new_mock(true)).unwrap();
+    let mut bad = export.clone();
+    bad[0x100] ^= 1;
+    assert!(matches!(
+        empty.import(bad.as_slice()),
+        Err(DbError::Corrupted)
+    ));
+    assert!(content(&empty).is_empty());
+    empty.check().unwrap();
+
+    let mut bad = export.clone();
+    let pos = bad.len() - 0x20;
+    bad[pos] ^= 1;
+    assert!(matches!(
+        empty.import(bad.as_slice()),
+        Err(DbError::Corrupted)
+    ));
+    let imported = content(&empty);
+    assert_eq!(imported.len(), 0x100);
+    assert!(imported.iter().eq(expected.iter().take(0x100)));
+    empty.check().unwrap();
+}
@@ -1,14 +1,14 @@
-use std::iter;
+use std::{collections::BTreeSet, iter};
 
 use rand::{seq::SliceRandom, Rng};
 
 use crate::NodePage;
 
-use super::with_db;
+use super::with_each_db;
 
 #[test]
 fn scan() {
-    with_db::<_, _, NodePage>(0x123, |db, rng| {
+    with_each_db::<_, NodePage>(0x123, |db, rng| {
         let mut rand_key = |i: u16| {
             let mut v = rng.gen::<[u8; 16]>();
             v[..2].clone_from_slice(&i.to_be_bytes());
@@ -20,6 +20,7 @@ fn scan() {
         keys.shuffle(rng);
         for key in &keys {
             db.entry(key)
+                .unwrap()
                 .vacant()
                 .unwrap()
                 .insert()
@@ -29,9 +30,9 @@ fn scan() {
         }
 
         let start = 10u16;
-        let mut it = db.entry(&(start * 4).to_be_bytes()).into_db_iter();
+        let mut it = db.entry((start * 4).to_be_bytes()).unwrap().into_db_iter();
         let mut expected = start..1000;
-        while let Some((key, value)) = db.next(&mut it) {
+        while let Some((key, value)) = db.next(&mut it).unwrap() {
             log::debug!("{}", hex::encode(&key));
             let expected = expected.next().unwrap();
             let value = value.unwrap().read_to_vec(0, 16).unwrap();
@@ -43,7 +44,7 @@ fn scan() {
 
 #[test]
 fn keys() {
-    with_db::<_, _, NodePage>(0x123, |db, rng| {
+    with_each_db::<_, NodePage>(0x123, |db, rng| {
         let mut keys = (1..100)
             .map(|i| {
                 [0, 1]
@@ -57,6 +58,7 @@ fn keys() {
         keys.shuffle(rng);
         for key in &keys {
             db.entry(key)
+                .unwrap()
                 .vacant()
                 .unwrap_or_else(|| panic!("{}", printer(key)))
                 .insert()
@@ -66,6 +68,7 @@ fn keys() {
         keys.shuffle(rng);
         for key in &keys {
             db.entry(key)
+                .unwrap()
                 .occupied()
                 .unwrap_or_else(|| panic!("{}", printer(key)));
         }
@@ -74,6 +77,7 @@ fn keys() {
         for key in &keys {
             log::debug!("will {}", printer(key));
             db.entry(key)
+                .unwrap()
                 .occupied()
                 .unwrap_or_else(|| panic!("{}", printer(key)))
                 .remove()
@@ -85,49 +89,71 @@ fn keys() {
 
 #[test]
 fn remove_merge_with_right() {
-    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+    with_each_db::<_, NodePage>(0x123, |db, _rng| {
         for i in 0..8 {
-            db.entry(&[i]).vacant().unwrap().insert().unwrap();
+            db.entry([i]).unwrap().vacant().unwrap().insert().unwrap();
         }
         db.print(|key| key[0]);
-        db.entry(&[3]).occupied().unwrap().remove().unwrap();
+        db.entry([3]).unwrap().occupied().unwrap().remove().unwrap();
         db.print(|key| key[0]);
     })
 }
 
+#[test]
+fn remove_merge_branch_with_right() {
+    with_each_db::<_, NodePage>(0x123, |db, rng| {
+        let mut present = BTreeSet::new();
+        for _ in 0..4000 {
+            let key = format!("key {:03}", rng.gen_range(0..500u16));
+            let entry = db.entry(key.as_bytes()).unwrap();
+            if present.remove(&key) {
+                entry.occupied().unwrap().remove().unwrap();
+            } else {
+                entry.vacant().unwrap().insert().unwrap();
+                present.insert(key);
+            }
+        }
+        // a merged branch keeps the bound of its right neighbor, so every key is found
+        for key in &present {
+            assert!(db.entry(key.as_bytes()).unwrap().occupied().is_some());
+        }
+    })
+}
+
 #[test]
 fn remove_merge_with_left() {
-    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+    with_each_db::<_, NodePage>(0x123, |db, _rng| {
         for i in 0..8 {
-            db.entry(&[i]).vacant().unwrap().insert().unwrap();
+            db.entry([i]).unwrap().vacant().unwrap().insert().unwrap();
         }
         db.print(|key| key[0]);
-        db.entry(&[5]).occupied().unwrap().remove().unwrap();
+        db.entry([5]).unwrap().occupied().unwrap().remove().unwrap();
         db.print(|key| key[0]);
     })
 }
 
 #[test]
 fn remove_borrow() {
-    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+    with_each_db::<_, NodePage>(0x123, |db, _rng| {
         for i in 0..9 {
-            db.entry(&[i]).vacant().unwrap().insert().unwrap();
+            db.entry([i]).unwrap().vacant().unwrap().insert().unwrap();
         }
-        db.entry(&[3]).occupied().unwrap().remove().unwrap();
+        db.entry([3]).unwrap().occupied().unwrap().remove().unwrap();
         db.print(|key| key[0]);
-        db.entry(&[3]).vacant().unwrap().insert().unwrap();
+        db.entry([3]).unwrap().vacant().unwrap().insert().unwrap();
         db.print(|key| key[0]);
-        db.entry(&[5]).occupied().unwrap().remove().unwrap();
+        db.entry([5]).unwrap().occupied().unwrap().remove().unwrap();
         db.print(|key| key[0]);
     })
 }
 
 #[test]
 fn remove_all() {
-    with_db::<_, _, NodePage>(0x123, |db, rng| {
+    with_each_db::<_, NodePage>(0x123, |db, rng| {
         let mut keys = (0..17).map(|i| vec![i]).collect::<Vec<_>>();
         for key in &keys {
             db.entry(key)
+                .unwrap()
                 .vacant()
                 .unwrap()
                 .insert()
@@ -143,6 +169,7 @@ fn remove_all() {
             log::debug!("{}", printer(key));
             let vec = db
                 .entry(key)
+                .unwrap()
                 .occupied()
                 .unwrap_or_else(|| {
                     db.print(printer);
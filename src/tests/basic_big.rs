@@ -1,10 +1,10 @@
 use crate::NodePage;
 
-use super::with_db;
+use super::with_each_db;
 
 #[test]
 fn big() {
-    with_db::<_, _, NodePage>(0x123, |db, rng| {
+    with_each_db::<_, NodePage>(0x123, |db, rng| {
         use rand::seq::SliceRandom;
 
         const NUM: u16 = 1000;
@@ -14,6 +14,7 @@ fn big() {
         for i in &indexes {
             let key = format!("key                  {i:03}");
             db.entry(key.as_bytes())
+                .unwrap()
                 .vacant()
                 .unwrap()
                 .insert()
@@ -26,6 +27,7 @@ fn big() {
             let key = format!("key                  {i:03}");
             let vec = db
                 .entry(key.as_bytes())
+                .unwrap()
                 .occupied()
                 .unwrap()
                 .into_value()
@@ -39,6 +41,7 @@ fn big() {
             let key = format!("key                  {i:03}");
             let vec = db
                 .entry(key.as_bytes())
+                .unwrap()
                 .occupied()
                 .unwrap_or_else(|| panic!("{key}"))
                 .remove()
@@ -47,7 +50,7 @@ fn big() {
                 .unwrap();
             println!("deleted {key}");
             assert_eq!(vec, &i.to_le_bytes());
-            assert!(db.entry(key.as_bytes()).vacant().is_some());
+            assert!(db.entry(key.as_bytes()).unwrap().vacant().is_some());
         }
     })
 }
@@ -0,0 +1,84 @@
+use std::fs;
+
+use tempdir::TempDir;
+
+use crate::{Db, NodePage, Params};
+
+fn check(db: &Db<NodePage>) {
+    for i in (0..2000u16).step_by(10) {
+        // long enough to be stored in key pages
+        let key = format!("key {i:04} with a long tail to spill into key pages");
+        let value = db
+            .entry(key.as_bytes())
+            .unwrap()
+            .occupied()
+            .unwrap()
+            .into_value()
+            .read_to_vec(0, 2)
+            .unwrap();
+        assert_eq!(value, i.to_le_bytes());
+    }
+    let stats = db.stats();
+    let mut pages = db.free_pages().unwrap();
+    pages.sort();
+    pages.dedup();
+    assert_eq!(pages.len() as u32, stats.cached + stats.free);
+}
+
+#[test]
+fn compact() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-compact");
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(true)).unwrap();
+    for i in 0..2000u16 {
+        let key = format!("key {i:04} with a long tail to spill into key pages");
+        db.entry(key.as_bytes())
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap()
+            .write_at(0, &i.to_le_bytes())
+            .unwrap();
+    }
+    for i in (0..2000u16).filter(|i| i % 10 != 0) {
+        let key = format!("key {i:04} with a long tail to spill into key pages");
+        db.entry(key.as_bytes())
+            .unwrap()
+            .occupied()
+            .unwrap()
+            .remove()
+            .unwrap();
+    }
+    db.sync().unwrap();
+    let before = fs::metadata(&path).unwrap().len();
+
+    db.compact().unwrap();
+    let after = fs::metadata(&path).unwrap().len();
+    assert!(
+        after * 2 < before,
+        "{after} must be much less than {before}"
+    );
+    check(&db);
+
+    // the tree is still writable
+    db.entry(b"new key")
+        .unwrap()
+        .vacant()
+        .unwrap()
+        .insert()
+        .unwrap();
+    db.entry(b"new key")
+        .unwrap()
+        .occupied()
+        .unwrap()
+        .remove()
+        .unwrap();
+    db.sync().unwrap();
+    drop(db);
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(false)).unwrap();
+    check(&db);
+    assert!(fs::metadata(&path).unwrap().len() * 2 < before);
+}
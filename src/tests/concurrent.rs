@@ -0,0 +1,302 @@
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    CheckpointHandle, Cursor, Db, DbError, DbIterator, DupIter, NodePage, ScanValues, TreeHandle,
+    Value,
+};
+
+use super::with_db;
+
+const fn assert_send_sync<T: Send + Sync>() {}
+
+const fn assert_send<T: Send>() {}
+
+const _: () = assert_send_sync::<Db<NodePage>>();
+const _: () = assert_send_sync::<Value<'_>>();
+const _: () = assert_send_sync::<DbIterator<NodePage>>();
+const _: () = assert_send_sync::<DupIter<'_, NodePage>>();
+const _: () = assert_send_sync::<ScanValues<'_, NodePage>>();
+const _: () = assert_send_sync::<Cursor<'_, NodePage>>();
+const _: () = assert_send_sync::<TreeHandle<'_, NodePage>>();
+const _: () = assert_send::<CheckpointHandle>();
+
+#[test]
+fn try_entry() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        db.entry(b"key")
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap();
+
+        let (locked_tx, locked_rx) = mpsc::channel();
+        let (done_tx, done_rx) = mpsc::channel();
+        let db = &db;
+        thread::scope(|s| {
+            s.spawn(move || {
+                let occupied = db.entry(b"key").unwrap().occupied().unwrap();
+                locked_tx.send(()).unwrap();
+                done_rx.recv().unwrap();
+                occupied.remove().unwrap();
+            });
+
+            locked_rx.recv().unwrap();
+            // the other thread holds the entry
+            assert!(db.try_entry(b"other key").unwrap().is_none());
+            let timeout = Duration::from_millis(20);
+            assert!(db.entry_timeout(b"other key", timeout).unwrap().is_none());
+            done_tx.send(()).unwrap();
+
+            // the entry is released once the other thread is done
+            let entry = db.entry_timeout(b"key", Duration::from_secs(10)).unwrap();
+            assert!(entry.unwrap().vacant().is_some());
+        });
+        assert!(db.try_entry(b"key").unwrap().unwrap().vacant().is_some());
+    })
+}
+
+#[test]
+fn reentrant() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        let mut dump = vec![];
+        db.dump(&mut dump).unwrap();
+        let vacant = db.entry(b"key a").unwrap().vacant().unwrap();
+
+        // the same thread fails right away instead of waiting for itself
+        let timeout = Duration::from_secs(10);
+        assert!(matches!(db.entry(b"key b"), Err(DbError::WouldDeadlock)));
+        assert!(matches!(
+            db.entry_timeout(b"key b", timeout),
+            Err(DbError::WouldDeadlock)
+        ));
+        assert!(matches!(
+            db.tree(1).entry(b"key b"),
+            Err(DbError::WouldDeadlock)
+        ));
+        assert!(matches!(db.get(b"key b"), Err(DbError::WouldDeadlock)));
+        assert!(matches!(db.allocate(), Err(DbError::WouldDeadlock)));
+        assert!(db.try_entry(b"key b").unwrap().is_none());
+        let batch = panic::catch_unwind(AssertUnwindSafe(|| drop(db.batch())));
+        assert!(batch.is_err());
+        assert!(matches!(db.try_batch(), Err(DbError::WouldDeadlock)));
+        let keys = vec![(b"key c".to_vec(), vec![1])];
+        let res = db.insert_ranges(vec![(b"key c".to_vec()..b"key d".to_vec(), keys)]);
+        assert!(matches!(res, Err(DbError::WouldDeadlock)));
+        assert!(matches!(db.import(&dump[..]), Err(DbError::WouldDeadlock)));
+        let res = db.remove_many(&[b"key a".as_slice()]);
+        assert!(matches!(res, Err(DbError::WouldDeadlock)));
+
+        // every reader fails too, the ones that cannot fail panic
+        let would_deadlock = |res: Result<(), DbError>| matches!(res, Err(DbError::WouldDeadlock));
+        let mut cursor = db.cursor();
+        assert!(would_deadlock(cursor.seek(b"key").map(drop)));
+        assert!(would_deadlock(cursor.next().map(drop)));
+        assert!(would_deadlock(cursor.prev().map(drop)));
+        assert!(would_deadlock(db.iter_from(b"key").map(drop)));
+        assert!(would_deadlock(db.check()));
+        assert!(would_deadlock(db.dump(vec![])));
+        assert!(would_deadlock(db.export(vec![]).map(drop)));
+        assert!(would_deadlock(db.free_pages().map(drop)));
+        assert!(would_deadlock(db.changes_since(0).map(drop)));
+        assert!(would_deadlock(db.export_changes(0).map(drop)));
+        let stats = panic::catch_unwind(AssertUnwindSafe(|| db.stats()));
+        assert!(stats.is_err());
+        let stats = panic::catch_unwind(AssertUnwindSafe(|| db.stats_fast()));
+        assert!(stats.is_err());
+        let fanout = panic::catch_unwind(AssertUnwindSafe(|| db.fanout()));
+        assert!(fanout.is_err());
+
+        // another thread still waits for the entry
+        thread::scope(|s| {
+            let other = s.spawn(|| {
+                let entry = db.entry_timeout(b"key b", Duration::from_millis(20));
+                entry.unwrap().is_none()
+            });
+            assert!(other.join().unwrap());
+        });
+
+        vacant.insert().unwrap();
+        db.entry(b"key b")
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap();
+        assert!(db.get(b"key a").unwrap().is_some());
+    })
+}
+
+#[test]
+fn readers_and_writer() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        const N: u32 = 2000;
+        // the writer removes the key inserted that many keys ago
+        const WINDOW: u32 = 300;
+
+        let key = |i: u32| format!("key {i:05}");
+        let written = AtomicU32::new(0);
+        let db = &db;
+        let written = &written;
+        thread::scope(|s| {
+            for t in 0..4 {
+                s.spawn(move || {
+                    let mut rng = StdRng::seed_from_u64(t);
+                    let mut seq = 0;
+                    loop {
+                        let n = written.load(Ordering::SeqCst);
+                        if n > 0 {
+                            let i = rng.gen_range(n.saturating_sub(WINDOW)..n);
+                            match db.get(key(i).as_bytes()).unwrap() {
+                                Some(value) => {
+                                    let mut buf = [0; 4];
+                                    match value.read(0, &mut buf) {
+                                        // the page may be reused after some key is removed
+                                        Err(DbError::Stale) => {}
+                                        res => {
+                                            res.unwrap();
+                                            assert_eq!(u32::from_le_bytes(buf), i);
+                                        }
+                                    }
+                                }
+                                None => assert!(written.load(Ordering::SeqCst) >= i + WINDOW),
+                            }
+                        }
+
+                        let stats = db.stats();
+                        assert!(stats.seq >= seq);
+                        seq = stats.seq;
+                        if rng.gen_ratio(1, 100) {
+                            db.check().unwrap();
+                            db.iter_from(key(n).as_bytes()).unwrap();
+                        }
+
+                        if n == N {
+                            break;
+                        }
+                    }
+                });
+            }
+
+            for i in 0..N {
+                // written before the insertion, readers never see it empty
+                let value = db.allocate().unwrap();
+                value.write_at(0, &i.to_le_bytes()).unwrap();
+                let vacant = db.entry(key(i)).unwrap().vacant().unwrap();
+                vacant.insert_value(value).unwrap();
+                if i >= WINDOW {
+                    let occupied = db.entry(key(i - WINDOW)).unwrap().occupied().unwrap();
+                    occupied.remove().unwrap();
+                }
+                written.store(i + 1, Ordering::SeqCst);
+            }
+        });
+
+        db.check().unwrap();
+        let mut it = db.iter_from(b"").unwrap();
+        for i in N - WINDOW..N {
+            assert_eq!(db.next_key(&mut it).unwrap().unwrap(), key(i).into_bytes());
+        }
+        assert!(db.next_key(&mut it).unwrap().is_none());
+    })
+}
+
+#[test]
+fn shared_arc() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        const THREADS: u32 = 4;
+        const N: u32 = 500;
+
+        let key = |i: u32| format!("key {i:05}");
+        let db = Arc::new(db);
+        let handles = (0..THREADS)
+            .map(|t| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    for i in (t * N)..((t + 1) * N) {
+                        let vacant = db.entry(key(i)).unwrap().vacant().unwrap();
+                        vacant.insert_with(&i.to_le_bytes()).unwrap();
+                        // visible to readers once the entry is dropped
+                        assert!(db.get(key(i).as_bytes()).unwrap().is_some());
+                    }
+                    db.stats().seq
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            assert!(handle.join().unwrap() > 0);
+        }
+
+        let db = Arc::into_inner(db).unwrap();
+        db.check().unwrap();
+        let mut it = db.iter_from(b"").unwrap();
+        for i in 0..THREADS * N {
+            let (k, value) = db.next(&mut it).unwrap().unwrap();
+            assert_eq!(k, key(i).into_bytes());
+            assert_eq!(value.unwrap().read_to_vec(0, 4).unwrap(), i.to_le_bytes());
+        }
+        assert!(db.next(&mut it).unwrap().is_none());
+    })
+}
+
+#[test]
+fn insert_ranges() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        const N: u32 = 500;
+
+        let key = |i: u32| format!("key {i:05}").into_bytes();
+        let range = |r: u32| {
+            let items = (r * N..(r + 1) * N).map(move |i| (key(i), i.to_le_bytes().to_vec()));
+            (key(r * N)..key((r + 1) * N), items)
+        };
+        // the order of the ranges does not matter
+        let ranges = [2, 0, 3, 1].map(range).into();
+        assert_eq!(db.insert_ranges(ranges).unwrap(), u64::from(4 * N));
+
+        db.check().unwrap();
+        let mut it = db.iter_from(b"").unwrap();
+        for i in 0..4 * N {
+            let (k, value) = db.next(&mut it).unwrap().unwrap();
+            assert_eq!(k, key(i));
+            assert_eq!(value.unwrap().read_to_vec(0, 4).unwrap(), i.to_le_bytes());
+        }
+        assert!(db.next(&mut it).unwrap().is_none());
+
+        // nothing is inserted if any range fails
+        let used = db.stats().used;
+        let overlap = vec![
+            (key(5000)..key(5002), vec![]),
+            (key(5001)..key(5003), vec![]),
+        ];
+        assert!(matches!(db.insert_ranges(overlap), Err(DbError::BadRange)));
+        let outside = vec![(key(5000)..key(5001), vec![(key(5001), vec![])])];
+        assert!(matches!(db.insert_ranges(outside), Err(DbError::BadRange)));
+        let present = vec![
+            (key(0)..key(1), vec![(key(0), vec![])]),
+            // goes first
+            (b"a".to_vec()..b"b".to_vec(), vec![(b"a".to_vec(), vec![])]),
+        ];
+        assert!(matches!(db.insert_ranges(present), Err(DbError::KeyExists)));
+        assert!(db.get(b"a").unwrap().is_none());
+        assert_eq!(db.stats().used, used);
+    })
+}
+
+#[cfg(feature = "async")]
+#[allow(dead_code)]
+fn async_is_send(db: &Db<NodePage>, value: &Value<'_>, buf: &mut [u8]) {
+    fn send<T: Send>(_: T) {}
+
+    send(db.get_async(b"key"));
+    send(value.read_async(0, buf));
+}
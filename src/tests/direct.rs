@@ -0,0 +1,145 @@
+use tempdir::TempDir;
+
+use crate::{Db, DbError, IoOptions, NodePage, Params};
+
+use super::with_db_options;
+
+fn populate(db: &Db<NodePage>) {
+    for i in 0..1000u16 {
+        let key = format!("key {i:04} with a long tail to spill into key pages");
+        db.entry(key.as_bytes())
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap()
+            .write_at(0, &i.to_le_bytes())
+            .unwrap();
+    }
+    for i in (0..1000u16).filter(|i| i % 3 != 0) {
+        let key = format!("key {i:04} with a long tail to spill into key pages");
+        db.entry(key.as_bytes())
+            .unwrap()
+            .occupied()
+            .unwrap()
+            .remove()
+            .unwrap();
+    }
+}
+
+fn check(db: &Db<NodePage>) {
+    for i in 0..1000u16 {
+        let key = format!("key {i:04} with a long tail to spill into key pages");
+        let entry = db.entry(key.as_bytes()).unwrap();
+        if i % 3 != 0 {
+            assert!(entry.vacant().is_some());
+        } else {
+            let value = entry.occupied().unwrap().into_value();
+            assert_eq!(value.read_to_vec(0, 2).unwrap(), i.to_le_bytes());
+        }
+    }
+}
+
+#[test]
+fn buffered() {
+    let options = IoOptions {
+        direct: false,
+        ..IoOptions::default()
+    };
+    with_db_options::<_, _, NodePage>(0x123, options, |db, _rng| {
+        assert!(!db.direct());
+        populate(&db);
+        check(&db);
+    })
+}
+
+#[test]
+fn switch_mode() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-direct");
+
+    // the mode is not stored in the file, reopen it the other way around
+    let options = IoOptions {
+        direct: true,
+        ..IoOptions::default()
+    };
+    let db = Db::<NodePage>::new_with(&path, Params::new_mock(true), options).unwrap();
+    log::info!("direct io: {}", db.direct());
+    populate(&db);
+    db.sync().unwrap();
+    drop(db);
+
+    let options = IoOptions {
+        direct: false,
+        ..IoOptions::default()
+    };
+    let db = Db::<NodePage>::new_with(&path, Params::new_mock(false), options).unwrap();
+    check(&db);
+    db.sync().unwrap();
+    drop(db);
+
+    let options = IoOptions {
+        direct: true,
+        ..IoOptions::default()
+    };
+    let db = Db::<NodePage>::new_with(&path, Params::new_mock(false), options).unwrap();
+    check(&db);
+}
+
+#[test]
+fn queue_depth() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-depth");
+
+    for depth in [0, 12, IoOptions::MAX_QUEUE_DEPTH * 2] {
+        let options = IoOptions {
+            queue_depth: depth,
+            ..IoOptions::default()
+        };
+        let res = Db::<NodePage>::new_with(&path, Params::new_mock(true), options);
+        assert!(matches!(res, Err(DbError::BadQueueDepth(d)) if d == depth));
+    }
+
+    // a sync writes far more pages than the ring holds, in chunks
+    let options = IoOptions {
+        queue_depth: 8,
+        ..IoOptions::default()
+    };
+    let db = Db::<NodePage>::new_with(&path, Params::new_mock(true), options).unwrap();
+    populate(&db);
+    let before = db.metrics().page_writes;
+    db.sync().unwrap();
+    assert!(db.metrics().page_writes > before + 8);
+    drop(db);
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(false)).unwrap();
+    check(&db);
+}
+
+#[test]
+fn plain_writes() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-plain");
+
+    // forced off, the pages are written and read one by one
+    let options = IoOptions {
+        uring: false,
+        ..IoOptions::default()
+    };
+    let db = Db::<NodePage>::new_with(&path, Params::new_mock(true), options).unwrap();
+    assert!(!db.uring());
+    populate(&db);
+    check(&db);
+    db.sync().unwrap();
+    db.release_cache().unwrap();
+    check(&db);
+    drop(db);
+
+    // the file is the same either way
+    let db = Db::<NodePage>::new(&path, Params::new_mock(false)).unwrap();
+    check(&db);
+    db.sync().unwrap();
+    drop(db);
+    let db = Db::<NodePage>::new_with(&path, Params::new_mock(false), options).unwrap();
+    check(&db);
+}
@@ -0,0 +1,382 @@
+use std::{io, thread};
+
+use crate::{btree::DuplicateKey, Db, DbError, EmptyCell, Entry, NodeCPage, NodePage};
+
+use super::with_db;
+
+#[test]
+fn key() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        let long = b"the key is longer than sixteen bytes, it takes several chunks";
+        db.entry(long).unwrap().vacant().unwrap().insert().unwrap();
+        db.entry(b"empty")
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert_empty()
+            .unwrap();
+
+        assert_eq!(
+            db.entry(long).unwrap().occupied().unwrap().key().unwrap(),
+            long
+        );
+        assert_eq!(
+            db.entry(b"empty").unwrap().empty().unwrap().key().unwrap(),
+            b"empty"
+        );
+
+        let vacant = db.entry(b"a").unwrap().vacant().unwrap();
+        assert_eq!(vacant.insertion_point_key().unwrap().unwrap(), b"empty");
+        drop(vacant);
+        let vacant = db.entry(b"z").unwrap().vacant().unwrap();
+        assert_eq!(vacant.insertion_point_key().unwrap(), None);
+    })
+}
+
+#[test]
+fn key_not_borrowed() {
+    // the entry does not name the type of the key
+    fn insert(entry: Entry<'_, NodePage>) -> bool {
+        entry.vacant().map(|v| v.insert_empty().unwrap()).is_some()
+    }
+
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        let entry = {
+            let key = format!("key {:04}", 1);
+            db.entry(&key).unwrap()
+        };
+        assert!(insert(entry));
+        assert!(insert(db.entry(b"key 0002").unwrap()));
+        assert!(!insert(db.entry(String::from("key 0001")).unwrap()));
+        assert!(db.entry(b"key 0001").unwrap().empty().is_some());
+    })
+}
+
+#[test]
+fn get_or_insert() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        db.entry(b"occupied")
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap()
+            .write_at(0, b"old")
+            .unwrap();
+        db.entry(b"empty")
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert_empty()
+            .unwrap();
+
+        let value = db.get_or_insert(b"occupied").unwrap();
+        assert_eq!(value.read_to_vec(0, 3).unwrap(), b"old");
+
+        for key in [b"empty".as_slice(), b"vacant"] {
+            let value = db.get_or_insert(key).unwrap();
+            assert_eq!(value.read_to_vec(0, 3).unwrap(), [0; 3]);
+            value.write_at(0, b"new").unwrap();
+
+            let value = db.entry(key).unwrap().occupied().unwrap().into_value();
+            assert_eq!(value.read_to_vec(0, 3).unwrap(), b"new");
+        }
+    })
+}
+
+#[test]
+fn try_insert() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        assert!(db.try_insert(b"key", b"first").unwrap());
+        assert!(!db.try_insert(b"key", b"second").unwrap());
+        let value = db.get(b"key").unwrap().unwrap();
+        assert_eq!(value.read_to_vec(0, 5).unwrap(), b"first");
+
+        let long = [0; 0x1001];
+        let res = db.try_insert(b"long", &long);
+        assert!(matches!(res, Err(DbError::OutOfBounds)));
+        assert!(db.get(b"long").unwrap().is_none());
+    })
+}
+
+#[test]
+fn empty_cell() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        let vacant = db.entry(b"key").unwrap().vacant().unwrap();
+        vacant.insert_empty().unwrap();
+        assert!(db.get(b"key").unwrap().is_none());
+        let used = db.stats().used;
+
+        let empty = db.entry(b"key").unwrap().empty().unwrap();
+        let occupied = empty.occupy().unwrap();
+        occupied.into_value().write_at(0, b"value").unwrap();
+        let value = db.get(b"key").unwrap().unwrap();
+        assert_eq!(value.read_to_vec(0, 5).unwrap(), b"value");
+
+        let occupied = db.entry(b"key").unwrap().occupied().unwrap();
+        let empty = occupied.into_empty().unwrap();
+        assert_eq!(empty.key().unwrap(), b"key");
+        drop(empty);
+        // the value page is freed
+        assert_eq!(db.stats().used, used);
+        let empty = db.entry(b"key").unwrap().empty().unwrap();
+        let vacant = empty.into_vacant().unwrap();
+        vacant.insert_empty().unwrap();
+        assert!(db.entry(b"key").unwrap().empty().is_some());
+
+        db.insert_tombstone(b"key").unwrap();
+        db.insert_tombstone(b"other").unwrap();
+        db.get_or_insert(b"other").unwrap();
+        db.insert_tombstone(b"other").unwrap();
+        for key in [b"key".as_slice(), b"other"] {
+            let empty: EmptyCell<'_, _> = db.entry(key).unwrap().empty().unwrap();
+            empty.into_vacant().unwrap();
+        }
+        db.check().unwrap();
+    })
+}
+
+#[test]
+fn upsert() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        let count = |plain: &[u8]| u64::from_le_bytes(plain[..8].try_into().unwrap());
+        let increment = |db: &Db<NodePage>| {
+            db.entry(b"counter")
+                .unwrap()
+                .upsert_with(
+                    || 1u64.to_le_bytes().to_vec(),
+                    |plain| {
+                        let new = count(plain) + 1;
+                        plain[..8].clone_from_slice(&new.to_le_bytes());
+                    },
+                )
+                .unwrap();
+        };
+
+        increment(&db);
+        let used = db.stats().used;
+        for _ in 1..3000 {
+            increment(&db);
+        }
+        let value = db.get(b"counter").unwrap().unwrap();
+        assert_eq!(count(&value.read_to_vec(0, 8).unwrap()), 3000);
+        // each upsert frees the page it replaces
+        assert_eq!(db.stats().used, used);
+        db.check().unwrap();
+
+        let mut seen = None;
+        db.entry(b"counter")
+            .unwrap()
+            .and_modify(|value| seen = Some(value.read_to_vec(0, 8).unwrap()))
+            .occupied()
+            .unwrap();
+        assert_eq!(count(&seen.unwrap()), 3000);
+        let entry = db.entry(b"absent").unwrap().and_modify(|_| unreachable!());
+        assert!(entry.vacant().is_some());
+
+        db.insert_tombstone(b"empty").unwrap();
+        db.entry(b"empty")
+            .unwrap()
+            .upsert_with(|| b"init".to_vec(), |_| unreachable!())
+            .unwrap();
+        let value = db.get(b"empty").unwrap().unwrap();
+        assert_eq!(value.read_to_vec(0, 4).unwrap(), b"init");
+
+        let res = db
+            .entry(b"counter")
+            .unwrap()
+            .upsert_with(Vec::new, |plain| plain.push(0));
+        assert!(matches!(res, Err(DbError::OutOfBounds)));
+        let value = db.get(b"counter").unwrap().unwrap();
+        assert_eq!(count(&value.read_to_vec(0, 8).unwrap()), 3000);
+    })
+}
+
+#[test]
+fn rename() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        let key = |i: u16| format!("key {i:04}");
+        for i in 0..300 {
+            let value = db
+                .entry(key(i))
+                .unwrap()
+                .vacant()
+                .unwrap()
+                .insert()
+                .unwrap();
+            value.write_at(0, &i.to_le_bytes()).unwrap();
+        }
+        db.insert_tombstone(b"empty").unwrap();
+        #[cfg(not(feature = "small"))]
+        let used = db.stats().used;
+
+        let value = db.get(key(10).as_bytes()).unwrap().unwrap();
+        db.rename(key(10).as_bytes(), b"renamed").unwrap();
+        assert!(db.get(key(10).as_bytes()).unwrap().is_none());
+        let renamed = db.get(b"renamed").unwrap().unwrap();
+        assert_eq!(renamed.read_to_vec(0, 2).unwrap(), 10u16.to_le_bytes());
+        // the bytes are not copied, the value handle still reads them
+        assert_eq!(value.read_to_vec(0, 2).unwrap(), 10u16.to_le_bytes());
+        // with the small fanout the leaves split and merge differently for the new key
+        #[cfg(not(feature = "small"))]
+        assert_eq!(db.stats().used, used);
+
+        db.rename(b"empty", b"moved").unwrap();
+        assert!(db.entry(b"moved").unwrap().empty().is_some());
+        assert!(db.entry(b"empty").unwrap().vacant().is_some());
+
+        let res = db.rename(b"renamed", key(11).as_bytes());
+        assert!(matches!(res, Err(DbError::KeyExists)));
+        let res = db.rename(key(10).as_bytes(), b"other");
+        assert!(matches!(res, Err(DbError::KeyNotFound)));
+        let value = db.get(key(11).as_bytes()).unwrap().unwrap();
+        assert_eq!(value.read_to_vec(0, 2).unwrap(), 11u16.to_le_bytes());
+        db.check().unwrap();
+    })
+}
+
+#[test]
+fn key_length() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        let key = [0xab; 1024];
+        db.entry(key).unwrap().vacant().unwrap().insert().unwrap();
+        let entry = db.entry(key).unwrap().occupied().unwrap();
+        assert_eq!(entry.key().unwrap(), key);
+        drop(entry);
+
+        let key = [0xab; 1025];
+        assert!(matches!(
+            db.entry(key),
+            Err(DbError::KeyTooLong {
+                len: 1025,
+                max: 1024
+            })
+        ));
+        assert!(matches!(db.try_entry(key), Err(DbError::KeyTooLong { .. })));
+
+        // the empty key goes before any other
+        db.entry(b"").unwrap().vacant().unwrap().insert().unwrap();
+        let mut it = db.entry(b"").unwrap().into_db_iter();
+        assert_eq!(db.next_key(&mut it).unwrap().unwrap(), b"");
+        assert_eq!(db.next_key(&mut it).unwrap().unwrap(), [0xab; 1024]);
+    });
+
+    with_db::<_, _, NodeCPage>(0x123, |db, _rng| {
+        db.entry([1; 16])
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap();
+        assert!(matches!(
+            db.entry([1; 17]),
+            Err(DbError::KeyTooLong { len: 17, max: 16 })
+        ));
+        assert!(matches!(
+            db.entry([1; 15]),
+            Err(DbError::KeyTooShort { len: 15, min: 16 })
+        ));
+
+        // a shorter prefix is fine to look for
+        let mut it = db.entry([0; 16]).unwrap().into_db_iter();
+        db.seek(&mut it, b"").unwrap();
+        assert_eq!(db.next_key(&mut it).unwrap().unwrap(), [1; 16]);
+        let count = db
+            .fold_prefix([1; 4], 0, |n, _, _| std::ops::ControlFlow::Continue(n + 1))
+            .unwrap();
+        assert_eq!(count, 1);
+    })
+}
+
+#[test]
+fn duplicates() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        let dups = |key: &[u8]| {
+            db.entry_dup(key)
+                .unwrap()
+                .map(|dup| {
+                    let (n, value) = dup.unwrap();
+                    (n, value.read_to_vec(0, 2).unwrap())
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // other keys around and in between the values of the key
+        for key in [b"dup".as_slice(), b"dup\x00", b"duq", b"dun"] {
+            db.entry(key).unwrap().vacant().unwrap().insert().unwrap();
+        }
+        assert!(dups(b"dup").is_empty());
+        for i in 0..300u16 {
+            let key = if i % 2 == 0 {
+                b"dup".as_slice()
+            } else {
+                b"other"
+            };
+            db.insert_dup(key)
+                .unwrap()
+                .write_at(0, &i.to_le_bytes())
+                .unwrap();
+        }
+        let expected = (0..150u16)
+            .map(|i| (u64::from(i), (i * 2).to_le_bytes().to_vec()))
+            .collect::<Vec<_>>();
+        assert_eq!(dups(b"dup"), expected);
+        assert_eq!(dups(b"other").len(), 150);
+
+        // a single value is reached by its number, the next one is after the last one
+        let key = [b"dup".as_slice(), &7u64.to_be_bytes()].concat();
+        db.entry(key).unwrap().occupied().unwrap().remove().unwrap();
+        let key = [b"dup".as_slice(), &149u64.to_be_bytes()].concat();
+        db.insert_tombstone(&key).unwrap();
+        db.insert_dup(b"dup").unwrap();
+        let numbers = dups(b"dup").into_iter().map(|(n, _)| n);
+        let expected = (0..151).filter(|n| *n != 7 && *n != 149);
+        assert!(numbers.eq(expected));
+        db.check().unwrap();
+    })
+}
+
+#[test]
+fn duplicates_concurrent() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        let db = &db;
+        let inserted = thread::scope(|s| {
+            let threads = (0..4u8)
+                .map(|t| {
+                    s.spawn(move || {
+                        for i in 0..50u8 {
+                            db.insert_dup(b"key").unwrap().write_at(0, &[t, i]).unwrap();
+                        }
+                        // only one of the threads finds it vacant
+                        match db.entry(b"once").unwrap() {
+                            Entry::Vacant(vacant) => vacant.insert().map(|_| 1).unwrap(),
+                            _ => 0,
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            threads.into_iter().map(|t| t.join().unwrap()).sum::<u32>()
+        });
+        assert_eq!(inserted, 1);
+
+        // the values of each thread are in its order
+        let mut last = [None; 4];
+        let mut count = 0;
+        for (n, dup) in db.entry_dup(b"key").unwrap().map(Result::unwrap) {
+            assert_eq!(n, count);
+            let [t, i] = <[u8; 2]>::try_from(dup.read_to_vec(0, 2).unwrap()).unwrap();
+            assert!(last[usize::from(t)] < Some(i));
+            last[usize::from(t)] = Some(i);
+            count += 1;
+        }
+        assert_eq!(count, 200);
+        assert_eq!(last, [Some(49); 4]);
+    })
+}
+
+#[test]
+fn duplicate_key_error() {
+    let err = io::Error::from(DuplicateKey(b"key".to_vec()));
+    let err = DbError::from(err);
+    assert!(matches!(err, DbError::DuplicateKey { key } if key == b"key"));
+}
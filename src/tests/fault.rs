@@ -0,0 +1,267 @@
+use std::{collections::BTreeSet, io};
+
+use tempdir::TempDir;
+
+use crate::{
+    Db, DbError, Entry, NodePage, Params,
+    wal::{Wal, WalError},
+};
+
+use super::with_db;
+
+fn key(i: u32) -> String {
+    format!("key {i:04}")
+}
+
+// the change either happens or leaves no trace
+fn attempt(
+    db: &Db<NodePage>,
+    n: u32,
+    f: impl FnOnce() -> Result<(), DbError>,
+) -> Result<(), DbError> {
+    let before = db.stats();
+    db.fail_read_after(n);
+    let res = f();
+    db.fail_read_after(u32::MAX);
+    if res.is_err() {
+        let after = db.stats();
+        assert_eq!((after.seq, after.used), (before.seq, before.used));
+    }
+    res
+}
+
+#[test]
+fn read_failure() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        let mut keys = (0..200).collect::<BTreeSet<u32>>();
+        for &i in &keys {
+            db.entry(key(i).as_bytes())
+                .unwrap()
+                .vacant()
+                .unwrap()
+                .insert()
+                .unwrap();
+        }
+
+        let mut failed = 0;
+        for n in 0..16 {
+            let i = 200 + n;
+            let res = attempt(&db, n, || {
+                db.entry(key(i).as_bytes())?.vacant().unwrap().insert()?;
+                Ok(())
+            });
+            match res {
+                Ok(()) => drop(keys.insert(i)),
+                Err(DbError::Io(_)) => failed += 1,
+                Err(err) => panic!("{err}"),
+            }
+
+            let i = n * 7;
+            let res = attempt(&db, n, || {
+                db.entry(key(i).as_bytes())?.occupied().unwrap().remove()?;
+                Ok(())
+            });
+            match res {
+                Ok(()) => drop(keys.remove(&i)),
+                Err(DbError::Io(_)) => failed += 1,
+                Err(err) => panic!("{err}"),
+            }
+        }
+        assert!(failed > 0);
+
+        for i in 0..300 {
+            let present = db.get(key(i).as_bytes()).unwrap().is_some();
+            assert_eq!(present, keys.contains(&i), "{}", key(i));
+        }
+    })
+}
+
+#[test]
+fn failing_page() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        let mut keys = BTreeSet::new();
+        for i in (0..600).step_by(2) {
+            db.entry(key(i).as_bytes())
+                .unwrap()
+                .vacant()
+                .unwrap()
+                .insert()
+                .unwrap()
+                .write_at(0, &i.to_le_bytes())
+                .unwrap();
+            keys.insert(i);
+        }
+
+        let mut failed = 0;
+        let size = Wal::SIZE + db.stats().total;
+        for page in Wal::SIZE..size {
+            db.fail_read_of(page);
+            for i in [page % 600, page * 7 % 600] {
+                let k = key(i);
+                let res = (|| {
+                    match db.entry(k.as_bytes())? {
+                        Entry::Vacant(v) => {
+                            // written before the insertion, freed if it fails
+                            drop(v);
+                            let value = db.allocate()?;
+                            value.write_at(0, &i.to_le_bytes())?;
+                            let v = db.entry(k.as_bytes())?.vacant().unwrap();
+                            v.insert_value(value)?;
+                            keys.insert(i);
+                        }
+                        Entry::Occupied(v) => {
+                            let mut buf = [0; 4];
+                            v.into_value().read(0, &mut buf)?;
+                            assert_eq!(u32::from_le_bytes(buf), i);
+                        }
+                        Entry::Empty(_) => unreachable!(),
+                    }
+                    Ok(())
+                })();
+                match res {
+                    Ok(()) => {}
+                    Err(DbError::Io(err)) => {
+                        assert_eq!(err.kind(), io::ErrorKind::Other);
+                        failed += 1;
+                    }
+                    Err(err) => panic!("{err}"),
+                }
+            }
+        }
+        db.fail_read_of(u32::MAX);
+        assert!(failed > 0);
+
+        let mut it = db.entry(key(0).as_bytes()).unwrap().into_db_iter();
+        let mut present = BTreeSet::new();
+        while let Some((k, value)) = db.next(&mut it).unwrap() {
+            let i = u32::from_le_bytes(
+                value
+                    .unwrap()
+                    .read_to_vec(0, 4)
+                    .unwrap()
+                    .try_into()
+                    .unwrap(),
+            );
+            assert_eq!(k, key(i).into_bytes());
+            present.insert(i);
+        }
+        assert_eq!(present, keys);
+    })
+}
+
+#[test]
+fn disk_full() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-disk-full");
+
+    let insert = |db: &Db<NodePage>, i: u32| -> Result<(), DbError> {
+        let value = db.entry(key(i).as_bytes())?.vacant().unwrap().insert()?;
+        value.write_at(0, &i.to_le_bytes())?;
+        Ok(())
+    };
+    let is_full = |err: DbError| match err {
+        DbError::Io(err) | DbError::WalError(WalError::Io(err)) => {
+            err.kind() == io::ErrorKind::StorageFull
+        }
+        _ => false,
+    };
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(true)).unwrap();
+    for i in 0..200 {
+        insert(&db, i).unwrap();
+    }
+    db.fail_syncs(3);
+    assert!(is_full(db.sync().unwrap_err()));
+
+    // a failed change leaves no trace, the same one succeeds once there is space
+    let mut failed = 0;
+    for i in 200..1000 {
+        loop {
+            let before = db.stats();
+            match insert(&db, i) {
+                Ok(()) => break,
+                Err(err) => {
+                    assert!(is_full(err));
+                    let after = db.stats();
+                    assert_eq!(
+                        (after.seq, after.used, after.total),
+                        (before.seq, before.used, before.total)
+                    );
+                    failed += 1;
+                }
+            }
+        }
+    }
+    assert_eq!(failed, 2);
+    db.sync().unwrap();
+    db.check().unwrap();
+    let stats = db.stats();
+    assert_eq!(
+        db.free_pages().unwrap().len() as u32,
+        stats.cached + stats.free
+    );
+    drop(db);
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(false)).unwrap();
+    db.check().unwrap();
+    for i in 0..1000 {
+        let value = db.get(key(i).as_bytes()).unwrap().unwrap();
+        assert_eq!(value.read_to_vec(0, 4).unwrap(), i.to_le_bytes());
+    }
+}
+
+#[test]
+fn database_full() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        let insert = |i: u32| -> Result<(), DbError> {
+            let value = db.entry(key(i).as_bytes())?.vacant().unwrap().insert()?;
+            value.write_at(0, &i.to_le_bytes())?;
+            Ok(())
+        };
+        for i in 0..200 {
+            insert(i).unwrap();
+        }
+        assert_eq!(
+            db.stats().capacity_left,
+            u32::MAX - db.stats().total - Wal::SIZE
+        );
+
+        // near the limit, the file grows until the next growth would pass it
+        let max = db.stats().total + Wal::SIZE + 40;
+        db.set_max_pages(max);
+        let mut left = db.stats().capacity_left;
+        assert_eq!(left, 40);
+        let mut i = 200;
+        let err = loop {
+            let before = db.stats();
+            match insert(i) {
+                Ok(()) => {
+                    let after = db.stats();
+                    assert!(after.capacity_left <= left);
+                    left = after.capacity_left;
+                    i += 1;
+                }
+                Err(err) => {
+                    let after = db.stats();
+                    assert_eq!(
+                        (after.seq, after.used, after.total),
+                        (before.seq, before.used, before.total)
+                    );
+                    break err;
+                }
+            }
+        };
+        assert!(matches!(err, DbError::DatabaseFull { max: m } if m == max));
+        assert!(db.stats().total + Wal::SIZE <= max);
+        db.check().unwrap();
+        for j in 0..i {
+            let value = db.get(key(j).as_bytes()).unwrap().unwrap();
+            assert_eq!(value.read_to_vec(0, 4).unwrap(), j.to_le_bytes());
+        }
+
+        // the same insert succeeds once the limit is raised
+        db.set_max_pages(u32::MAX);
+        insert(i).unwrap();
+        db.check().unwrap();
+    });
+}
@@ -0,0 +1,56 @@
+use std::collections::BTreeMap;
+
+use rand::Rng;
+
+use crate::{Entry, FixedKey, NodeCPage};
+
+use super::with_each_db;
+
+#[test]
+fn fixed() {
+    with_each_db::<_, NodeCPage>(0x123, |db, rng| {
+        let key = |i: u16| -> FixedKey {
+            let mut key = [b' '; 0x10];
+            key[..8].clone_from_slice(format!("key {i:04}").as_bytes());
+            key
+        };
+
+        // enough keys for several levels, some of them removed
+        let mut model = BTreeMap::new();
+        for _ in 0..3000 {
+            let i = rng.gen_range(0..2000u16);
+            match db.entry_fixed(key(i)).unwrap() {
+                Entry::Vacant(vacant) => {
+                    let value = vacant.insert().unwrap();
+                    value.write_at(0, &i.to_le_bytes()).unwrap();
+                    model.insert(key(i), i);
+                }
+                Entry::Occupied(occupied) => {
+                    let value = occupied.remove().unwrap();
+                    assert_eq!(value.read_to_vec(0, 2).unwrap(), i.to_le_bytes());
+                    model.remove(&key(i));
+                }
+                Entry::Empty(_) => panic!("no empty cells inserted"),
+            }
+        }
+        db.check().unwrap();
+
+        for i in 0..2000u16 {
+            let value = db.get_fixed(&key(i)).unwrap();
+            assert_eq!(value.is_some(), model.contains_key(&key(i)));
+        }
+
+        let mut it = db.iter_from(b"").unwrap();
+        for (key, i) in &model {
+            let (k, value) = db.next(&mut it).unwrap().unwrap();
+            assert_eq!(k, key);
+            assert_eq!(value.unwrap().read_to_vec(0, 2).unwrap(), i.to_le_bytes());
+        }
+        assert!(db.next(&mut it).unwrap().is_none());
+
+        // a prefix shorter than the key is fine to look for
+        let mut it = db.iter_from(b"key 1").unwrap();
+        let first = model.range(key(1000)..).next().unwrap().0;
+        assert_eq!(db.next_key(&mut it).unwrap().unwrap(), first);
+    })
+}
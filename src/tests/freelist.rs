@@ -0,0 +1,337 @@
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use tempdir::TempDir;
+
+use crate::{Db, Entry, NodePage, Params, wal::CACHE_SIZE};
+
+fn check(db: &Db<NodePage>) -> Vec<u32> {
+    let stats = db.stats();
+    let mut pages = db.free_pages().unwrap();
+    assert_eq!(pages.len() as u32, stats.cached + stats.free);
+    pages.sort();
+    pages.dedup();
+    assert_eq!(pages.len() as u32, stats.cached + stats.free);
+    pages
+}
+
+#[test]
+fn free_pages() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-freelist");
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(true)).unwrap();
+    for round in 0..4u16 {
+        for i in 0..600u16 {
+            let key = format!("key {round} {i:04}");
+            db.entry(key.as_bytes())
+                .unwrap()
+                .vacant()
+                .unwrap()
+                .insert()
+                .unwrap();
+        }
+        check(&db);
+        for i in (0..600u16).step_by(2) {
+            let key = format!("key {round} {i:04}");
+            db.entry(key.as_bytes())
+                .unwrap()
+                .occupied()
+                .unwrap()
+                .remove()
+                .unwrap();
+        }
+        check(&db);
+    }
+
+    let before = check(&db);
+    assert_eq!(db.reclaim().unwrap(), 1);
+    assert_eq!(db.reclaim().unwrap(), 0);
+    let reclaimed = check(&db);
+    assert_eq!(reclaimed.len(), before.len() + 1);
+    db.sync().unwrap();
+    drop(db);
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(false)).unwrap();
+    assert_eq!(check(&db), reclaimed);
+}
+
+#[test]
+fn reopen() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-reopen");
+    let mut rng = StdRng::seed_from_u64(0x123);
+
+    let mut db = Db::<NodePage>::new(&path, Params::new_mock(true)).unwrap();
+    for round in 0..20 {
+        for _ in 0..200 {
+            let key = format!("key {:03}", rng.gen_range(0..500u16));
+            match db.entry(key.as_bytes()).unwrap() {
+                Entry::Vacant(v) if rng.gen() => {
+                    v.insert().unwrap().write_at(0, b"value").unwrap();
+                }
+                Entry::Vacant(v) => v.insert_empty().unwrap(),
+                Entry::Occupied(v) => drop(v.remove().unwrap()),
+                Entry::Empty(v) => v.remove().unwrap(),
+            }
+        }
+        // the value removed last is the orphan, it is freed on close
+        db.check().unwrap();
+        let stats = db.stats();
+
+        // close explicitly or by drop
+        if round % 2 == 0 {
+            db.close().unwrap();
+        } else {
+            drop(db);
+        }
+        db = Db::<NodePage>::new(&path, Params::new_mock(false)).unwrap();
+        db.check().unwrap();
+        let reopened = db.stats();
+        assert_eq!(reopened.total, stats.total);
+        assert!(reopened.used <= stats.used);
+        assert_eq!(check(&db).len() as u32, stats.total - reopened.used);
+    }
+}
+
+#[test]
+fn clear() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-clear");
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(true)).unwrap();
+    let empty = db.stats().used;
+
+    // every third key is long enough to take several chunks
+    let key = |i: u16| {
+        let tail = if i.is_multiple_of(3) {
+            "x".repeat(100)
+        } else {
+            String::new()
+        };
+        format!("key {i:04} {tail}")
+    };
+    for i in 0..3000u16 {
+        let vacant = db.entry(key(i)).unwrap().vacant().unwrap();
+        if i % 2 == 0 {
+            vacant.insert().unwrap().write_at(0, b"value").unwrap();
+        } else {
+            vacant.insert_empty().unwrap();
+        }
+    }
+    db.entry(key(0).as_bytes())
+        .unwrap()
+        .occupied()
+        .unwrap()
+        .remove()
+        .unwrap();
+    let before = db.stats();
+
+    db.clear().unwrap();
+    db.check().unwrap();
+    let stats = db.stats();
+    assert_eq!(stats.used, empty);
+    assert_eq!(stats.total, before.total);
+    assert_eq!(check(&db).len() as u32, stats.total - empty);
+    for i in 0..3000u16 {
+        assert!(matches!(
+            db.entry(key(i).as_bytes()).unwrap(),
+            Entry::Vacant(_)
+        ));
+    }
+
+    // the freed pages are reused, the file does not grow
+    for i in 0..1000u16 {
+        let vacant = db.entry(key(i)).unwrap().vacant().unwrap();
+        vacant.insert().unwrap().write_at(0, b"value").unwrap();
+    }
+    assert_eq!(db.stats().total, before.total);
+    db.close().unwrap();
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(false)).unwrap();
+    db.check().unwrap();
+    let mut it = db.entry(b"").unwrap().into_db_iter();
+    assert_eq!(db.advance_by(&mut it, 2000).unwrap(), 1000);
+}
+
+#[test]
+fn preallocate() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-preallocate");
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(true)).unwrap();
+    let before = db.stats();
+    db.preallocate(4000).unwrap();
+    let stats = db.stats();
+    assert_eq!(stats.total, before.total + 4000);
+    assert_eq!(stats.freelist_len, before.freelist_len + 4000);
+    assert_eq!(stats.used, before.used);
+    assert_eq!(check(&db).len() as u32, stats.cached + stats.free);
+
+    // the file does not grow during the load
+    for i in 0..2000u16 {
+        let key = format!("key {i:04}");
+        let vacant = db.entry(key.as_bytes()).unwrap().vacant().unwrap();
+        vacant.insert().unwrap().write_at(0, b"value").unwrap();
+    }
+    db.check().unwrap();
+    assert_eq!(db.stats().total, stats.total);
+    drop(db);
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(false)).unwrap();
+    db.check().unwrap();
+    assert_eq!(db.stats().total, stats.total);
+    let value = db.get(b"key 1999").unwrap().unwrap();
+    assert_eq!(value.read_to_vec(0, 5).unwrap(), b"value");
+}
+
+#[test]
+fn remove_many() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-remove-many");
+    let key = |i: u32| format!("key {i:05}").into_bytes();
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(true)).unwrap();
+    // several times the freelist cache of the record
+    let n = (CACHE_SIZE * 8) as u32;
+    for i in 0..n {
+        let value = db
+            .entry(key(i))
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap();
+        value.write_at(0, &i.to_le_bytes()).unwrap();
+    }
+    let seq = db.stats().seq;
+
+    // every odd key, some twice, and some absent ones, in random order
+    let mut rng = StdRng::seed_from_u64(0x123);
+    let mut keys = (1..n)
+        .step_by(2)
+        .chain(n..n + 10)
+        .map(key)
+        .collect::<Vec<_>>();
+    keys.extend((1..100).step_by(2).map(key));
+    keys.shuffle(&mut rng);
+    let refs = keys.iter().map(Vec::as_slice).collect::<Vec<_>>();
+    assert_eq!(db.remove_many(&refs).unwrap(), (n / 2) as usize);
+    assert!(db.stats().seq > seq);
+    check(&db);
+    db.check().unwrap();
+    for i in 0..n {
+        let value = db.get(&key(i)).unwrap();
+        match i % 2 {
+            0 => assert_eq!(value.unwrap().read_to_vec(0, 4).unwrap(), i.to_le_bytes()),
+            _ => assert!(value.is_none()),
+        }
+    }
+    assert_eq!(db.remove_many(&refs).unwrap(), 0);
+
+    // the freed pages are used again
+    let total = db.stats().total;
+    for i in (1..n).step_by(2) {
+        db.entry(key(i))
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap();
+    }
+    assert_eq!(db.stats().total, total);
+    db.check().unwrap();
+}
+
+#[cfg(feature = "debug_checks")]
+#[test]
+#[should_panic(expected = "is freed twice")]
+fn double_free() {
+    use std::io;
+
+    use crate::{
+        file::FileIo,
+        node::Node,
+        runtime::{Alloc, Free},
+        wal::{Wal, TreesPage},
+    };
+
+    let file = FileIo::memory();
+    let wal = Wal::new(true, &file, <NodePage>::M).unwrap();
+    let mut lock = wal.lock();
+    let ptr = lock
+        .transaction(|alloc, free| {
+            let ptr = alloc.alloc::<TreesPage>();
+            free.free(ptr);
+            io::Result::Ok(ptr)
+        })
+        .unwrap();
+    // the garbage goes to the cache, the page is free
+    lock.new_head(&file, lock.current_head::<()>(), None)
+        .unwrap();
+
+    lock.transaction(|_, free| {
+        free.free(ptr);
+        io::Result::Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn freelist_cycle() {
+    use std::{fs, os::unix::fs::FileExt as _};
+
+    use crate::DbError;
+
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-freelist-cycle");
+    // the pages are patched in place, they must not be encrypted
+    #[cfg(feature = "cipher")]
+    let params = |create| Params::Bare { create };
+    #[cfg(not(feature = "cipher"))]
+    let params = Params::new_mock;
+    let key = |i: u32| format!("key {i:04}");
+
+    let db = Db::<NodePage>::new(&path, params(true)).unwrap();
+    let n = CACHE_SIZE as u32 * 4;
+    for i in 0..n {
+        db.entry(key(i))
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap();
+    }
+    for i in 0..n {
+        let Entry::Occupied(entry) = db.entry(key(i)).unwrap() else {
+            panic!("must be present");
+        };
+        entry.remove().unwrap();
+    }
+    db.reclaim().unwrap();
+    let pages = db.freelist_pages().unwrap();
+    assert_eq!(pages.len() as u32, db.stats().freelist_len);
+    assert!(pages.len() > 2);
+
+    // the second free page points back to the first
+    let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+    let patch = |n: u32, next: u32| {
+        file.write_all_at(&next.to_ne_bytes(), u64::from(n) * 0x1000)
+            .unwrap();
+    };
+    patch(pages[1], pages[0]);
+    db.release_cache().unwrap();
+    assert!(matches!(db.freelist_pages(), Err(DbError::CorruptedFile)));
+    assert!(matches!(db.free_pages(), Err(DbError::CorruptedFile)));
+    // falls back to the length kept in the log
+    assert_eq!(db.stats().freelist_len, pages.len() as u32);
+
+    // the first one points past the end of the file
+    patch(pages[0], db.stats().total + 0x200);
+    db.release_cache().unwrap();
+    assert!(matches!(db.freelist_pages(), Err(DbError::CorruptedFile)));
+    drop(db);
+
+    // opening trusts the length kept in the log, it does not walk the freelist
+    let db = Db::<NodePage>::new(&path, params(false)).unwrap();
+    assert_eq!(db.stats_fast().freelist_len, pages.len() as u32);
+    assert!(matches!(db.freelist_pages(), Err(DbError::CorruptedFile)));
+}
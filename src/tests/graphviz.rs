@@ -0,0 +1,57 @@
+use std::collections::BTreeSet;
+
+use crate::NodePage;
+
+use super::with_db;
+
+#[test]
+fn graphviz() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        for i in 0..1000u16 {
+            let key = format!("key \"{i:04}\"");
+            db.entry(key.as_bytes())
+                .unwrap()
+                .vacant()
+                .unwrap()
+                .insert()
+                .unwrap();
+        }
+        let seq = db.stats().seq;
+
+        let mut v = vec![];
+        db.debug_graphviz(&mut v, |key| String::from_utf8_lossy(key).into_owned())
+            .unwrap();
+        assert_eq!(db.stats().seq, seq);
+
+        let text = String::from_utf8(v).unwrap();
+        let mut lines = text.lines();
+        assert_eq!(lines.next(), Some("digraph {"));
+        assert_eq!(lines.next_back(), Some("}"));
+
+        let (mut nodes, mut values, mut edges) = (BTreeSet::new(), 0, vec![]);
+        for line in lines {
+            let line = line.trim().strip_suffix(';').unwrap();
+            if let Some((from, to)) = line.split_once(" -> ") {
+                edges.push((from.to_owned(), to.to_owned()));
+            } else {
+                let (id, attrs) = line.split_once(" [").unwrap();
+                let attrs = attrs.strip_suffix(']').unwrap();
+                let label = attrs.split_once("label=\"").unwrap().1;
+                let label = label.strip_suffix('"').unwrap();
+                // quotes inside the label are escaped
+                assert!(!label.replace("\\\"", "").contains('"'));
+                if attrs.starts_with("shape=box") {
+                    values += 1;
+                }
+                assert!(nodes.insert(id.to_owned()), "{id} is unique");
+            }
+        }
+
+        assert_eq!(values, 1000);
+        // a tree has one edge less than nodes
+        assert_eq!(edges.len() + 1, nodes.len());
+        assert!(edges
+            .iter()
+            .all(|(from, to)| nodes.contains(from) && nodes.contains(to)));
+    })
+}
@@ -4,15 +4,66 @@ mod recovery;
 mod basic;
 #[cfg(not(feature = "small"))]
 mod basic_big;
+mod backup;
+mod compact;
+mod concurrent;
+mod direct;
+mod entry;
+mod fault;
+mod fixed;
+mod freelist;
+mod graphviz;
+mod open;
+mod order;
+mod replica;
+mod stats;
+mod trees;
+mod ttl;
+mod value;
+#[cfg(feature = "serde")]
+mod value_serde;
 
 use tempdir::TempDir;
 use rand::{rngs::StdRng, SeedableRng};
 
-use crate::{Db, Params};
+use crate::{Db, IoOptions, Params, node::Node, runtime::PlainData};
 
+// every test runs in direct mode unless it asks otherwise
 pub fn with_db<F, T, N>(seed: u64, f: F) -> T
 where
     F: FnOnce(Db<N>, &mut StdRng) -> T,
+    N: Copy + PlainData + Node,
+{
+    with_db_options(seed, IoOptions::default(), f)
+}
+
+// runs the test on a file, with io_uring and without, then again in memory
+pub fn with_each_db<F, N>(seed: u64, f: F)
+where
+    F: Fn(Db<N>, &mut StdRng),
+    N: Copy + PlainData + Node,
+{
+    with_db(seed, &f);
+
+    #[cfg(all(target_os = "linux", not(feature = "no-uring")))]
+    {
+        log::info!("without io_uring");
+        let options = IoOptions {
+            uring: false,
+            ..IoOptions::default()
+        };
+        with_db_options(seed, options, &f);
+    }
+
+    log::info!("in memory");
+    let mut rng = StdRng::seed_from_u64(seed);
+    f(Db::in_memory().unwrap(), &mut rng);
+}
+
+pub fn with_db_options<F, T, N>(seed: u64, options: IoOptions, f: F) -> T
+where
+    F: FnOnce(Db<N>, &mut StdRng) -> T,
+    N: Copy + PlainData + Node,
 {
     let env = env_logger::Env::new().filter_or(
         "RUST_LOG",
@@ -25,9 +76,9 @@ where
     let dir = TempDir::new_in("target/tmp", "rej").unwrap();
     let path = dir.path().join("test-insert");
 
-    let db = Db::<N>::new(&path, Params::new_mock(true)).unwrap();
+    let db = Db::<N>::new_with(&path, Params::new_mock(true), options).unwrap();
     drop(db);
 
-    let db = Db::new(&path, Params::new_mock(false)).unwrap();
+    let db = Db::new_with(&path, Params::new_mock(false), options).unwrap();
     f(db, &mut rng)
 }
@@ -0,0 +1,1074 @@
+use std::{
+    fs, io,
+    sync::atomic::AtomicBool,
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use fs4::fs_std::FileExt;
+use tempdir::TempDir;
+
+use crate::{Db, DbError, DbStats, NodePage, Params, SizeHistogram, Value, WalError, wal::Wal};
+
+#[test]
+fn open_twice() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-open");
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(true)).unwrap();
+    let res = Db::<NodePage>::new(
+        dir.path().join(".").join("test-open"),
+        Params::new_mock(false),
+    );
+    assert!(matches!(res, Err(DbError::AlreadyOpen)));
+    drop(db);
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(false)).unwrap();
+    drop(db);
+
+    // another open file description behaves like another process
+    let file = fs::File::open(&path).unwrap();
+    file.lock_exclusive().unwrap();
+    let res = Db::<NodePage>::new(&path, Params::new_mock(false));
+    assert!(matches!(res, Err(DbError::Locked)));
+    FileExt::unlock(&file).unwrap();
+    drop(file);
+
+    Db::<NodePage>::new(&path, Params::new_mock(false)).unwrap();
+}
+
+#[test]
+fn in_memory() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-memory");
+
+    let db = Db::<NodePage>::in_memory().unwrap();
+    assert!(!db.direct());
+    for i in 0..1000u16 {
+        let key = format!("key {i:04}");
+        db.entry(key.as_bytes())
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap()
+            .write_at(0, &i.to_le_bytes())
+            .unwrap();
+    }
+    // syncing keeps the pages, there is nowhere to write them
+    db.sync().unwrap();
+    db.check().unwrap();
+
+    let res = db.backup(&path);
+    assert!(matches!(res, Err(DbError::Io(err)) if err.kind() == io::ErrorKind::Unsupported));
+    db.backup_to(&path, Params::new_mock(true)).unwrap();
+    drop(db);
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(false)).unwrap();
+    let value = db.get(b"key 0777").unwrap().unwrap();
+    assert_eq!(value.read_to_vec(0, 2).unwrap(), 777u16.to_le_bytes());
+}
+
+#[test]
+fn io_error() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("missing").join("test-io");
+
+    // a disk error is not reported as a cipher one, with or without the cipher
+    let res = Db::<NodePage>::new(&path, Params::new_mock(false));
+    assert!(matches!(res, Err(DbError::Io(err)) if err.kind() == io::ErrorKind::NotFound));
+    let res = Db::<NodePage>::new(&path, Params::new_mock(true));
+    assert!(matches!(res, Err(DbError::Io(err)) if err.kind() == io::ErrorKind::NotFound));
+}
+
+#[test]
+fn background_sync() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-background");
+    let crashed = dir.path().join("test-crashed");
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(true)).unwrap();
+    db.start_background_sync(Duration::from_millis(10));
+    for i in 0..100u16 {
+        let key = format!("key {i:04}");
+        let value = db.entry(key.as_bytes()).unwrap().vacant().unwrap().insert();
+        value.unwrap().write_at(0, &i.to_le_bytes()).unwrap();
+    }
+    // the pages are in the cache until the thread writes them
+    let writes = db.metrics().page_writes;
+    let start = Instant::now();
+    while db.metrics().page_writes == writes {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    // a copy of the file is what remains after a crash, nobody did call `sync`
+    fs::copy(&path, &crashed).unwrap();
+    let db_crashed = Db::<NodePage>::new(&crashed, Params::new_mock(false)).unwrap();
+    let value = db_crashed.get(b"key 0077").unwrap().unwrap();
+    assert_eq!(value.read_to_vec(0, 2).unwrap(), 77u16.to_le_bytes());
+
+    db.stop_background_sync();
+    db.start_background_sync(Duration::from_millis(1));
+    drop(db);
+    let db = Db::<NodePage>::new(&path, Params::new_mock(false)).unwrap();
+    let value = db.get(b"key 0099").unwrap().unwrap();
+    assert_eq!(value.read_to_vec(0, 2).unwrap(), 99u16.to_le_bytes());
+}
+
+#[test]
+fn sync_preallocated() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-sync-all");
+    let copy = dir.path().join("test-copy");
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(true)).unwrap();
+    for i in 0..100u16 {
+        let key = format!("key {i:04}");
+        let value = db.entry(key.as_bytes()).unwrap().vacant().unwrap().insert();
+        value.unwrap().write_at(0, &i.to_le_bytes()).unwrap();
+    }
+    db.sync_data().unwrap();
+    let total = db.stats().total;
+    db.preallocate(0x400).unwrap();
+    db.sync_all().unwrap();
+    let len = fs::metadata(&path).unwrap().len();
+
+    // the file holds everything while the database is still open, a copy of it opens
+    // with the grown length, it does not tell anything about a crash
+    fs::copy(&path, &copy).unwrap();
+    assert_eq!(fs::metadata(&copy).unwrap().len(), len);
+    let db_copy = Db::<NodePage>::new(&copy, Params::new_mock(false)).unwrap();
+    assert_eq!(db_copy.stats().total, total + 0x400);
+    assert_eq!(db_copy.stats().total, db.stats().total);
+    let value = db_copy.get(b"key 0077").unwrap().unwrap();
+    assert_eq!(value.read_to_vec(0, 2).unwrap(), 77u16.to_le_bytes());
+}
+
+#[test]
+fn open_or_create() {
+    use crate::IoOptions;
+
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-open-or-create");
+
+    let db = Db::<NodePage>::open_or_create(&path, Params::new_mock(true), IoOptions::default())
+        .unwrap();
+    db.entry(b"key")
+        .unwrap()
+        .vacant()
+        .unwrap()
+        .insert_small(b"value")
+        .unwrap();
+    drop(db);
+    let header = fs::read(&path).unwrap()[..0x1000].to_vec();
+
+    // the file exists, so it is opened with the same secret and its header is kept
+    let db = Db::<NodePage>::open_or_create(&path, Params::new_mock(true), IoOptions::default())
+        .unwrap();
+    let value = db.get(b"key").unwrap().unwrap();
+    assert_eq!(value.read_to_vec(0, 5).unwrap(), b"value");
+    assert_eq!(fs::read(&path).unwrap()[..0x1000], header);
+}
+
+#[test]
+fn clear() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-clear");
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(true)).unwrap();
+    for i in 0..1000u16 {
+        let key = format!("key {i:04}");
+        db.entry(key.as_bytes())
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert_with(&i.to_le_bytes())
+            .unwrap();
+        db.tree(1)
+            .entry(key)
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert_empty()
+            .unwrap();
+    }
+    db.sync().unwrap();
+    let total = db.stats().total;
+
+    db.clear().unwrap();
+    let mut it = db.iter_from(b"").unwrap();
+    assert!(db.next(&mut it).unwrap().is_none());
+    assert!(db.tree(1).get(b"key 0000").unwrap().is_none());
+    // the empty root, the pages of the old trees are free
+    assert_eq!(db.stats().used, 1);
+    db.check().unwrap();
+    drop(db);
+
+    // the same header, so the same secret opens it
+    let db = Db::<NodePage>::new(&path, Params::new_mock(false)).unwrap();
+    let mut it = db.iter_from(b"").unwrap();
+    assert!(db.next(&mut it).unwrap().is_none());
+    db.check().unwrap();
+    for i in 0..1000u16 {
+        let key = format!("key {i:04}");
+        db.entry(key).unwrap().vacant().unwrap().insert().unwrap();
+    }
+    // the freed pages are used again
+    assert_eq!(db.stats().total, total);
+}
+
+#[test]
+fn bad_head() {
+    use std::os::unix::fs::FileExt as _;
+
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-head");
+    let child_path = dir.path().join("test-child");
+    // the pages are patched in place, they must not be encrypted
+    #[cfg(feature = "cipher")]
+    let params = |create| Params::Bare { create };
+    #[cfg(not(feature = "cipher"))]
+    let params = Params::new_mock;
+
+    let db = Db::<NodePage>::new(&path, params(true)).unwrap();
+    for i in 0..1000u16 {
+        let key = format!("key {i:04}");
+        db.entry(key.as_bytes())
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap();
+    }
+    let head = u64::from(db.head()) * 0x1000;
+    drop(db);
+    fs::copy(&path, &child_path).unwrap();
+
+    // the root is garbage behind the database's back
+    let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+    file.write_all_at(&[0xff; 0x1000], head).unwrap();
+    drop(file);
+    let res = Db::<NodePage>::new(&path, params(false));
+    assert!(matches!(res, Err(DbError::WalError(WalError::BadHead))));
+
+    // the root is fine, but its first child is past the end of the file
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .open(&child_path)
+        .unwrap();
+    file.write_all_at(&[0xff; 4], head).unwrap();
+    drop(file);
+    let db = Db::<NodePage>::new(&child_path, params(false)).unwrap();
+    assert!(matches!(db.entry(b"key 0000"), Err(DbError::CorruptedFile)));
+    assert!(matches!(db.iter_from(b""), Err(DbError::CorruptedFile)));
+    assert!(db.get(b"key 0999").unwrap().is_some());
+}
+
+#[test]
+fn old_record() {
+    use std::os::unix::fs::FileExt as _;
+
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-old-record");
+    // the pages are patched in place, they must not be encrypted
+    #[cfg(feature = "cipher")]
+    let params = |create| Params::Bare { create };
+    #[cfg(not(feature = "cipher"))]
+    let params = Params::new_mock;
+    let key = |i: u16| format!("key {i:04}");
+
+    let db = Db::<NodePage>::new(&path, params(true)).unwrap();
+    for i in 0..1000 {
+        let value = db
+            .entry(key(i))
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap();
+        value.write_at(0, &i.to_le_bytes()).unwrap();
+    }
+    assert_eq!(db.stats().record_format, DbStats::RECORD_FORMAT);
+    drop(db);
+
+    // both copies of the last record as the first version did write them,
+    // with garbage where the orphan and the trees are now
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&path)
+        .unwrap();
+    let mut pages = vec![[0; 0x1000]; Wal::SIZE as usize];
+    for (n, page) in pages.iter_mut().enumerate() {
+        file.read_exact_at(page, n as u64 * 0x1000).unwrap();
+    }
+    let seq = |page: &[u8; 0x1000]| u64::from_ne_bytes(page[8..16].try_into().unwrap());
+    let last = pages.iter().map(seq).max().unwrap();
+    let mut copies = 0;
+    for (n, page) in pages.iter_mut().enumerate() {
+        if seq(page) == last {
+            Wal::downgrade_record(page, 0xff);
+            file.write_all_at(page, n as u64 * 0x1000).unwrap();
+            copies += 1;
+        }
+    }
+    assert_eq!(copies, 2);
+    drop(file);
+
+    // the fields the old layout did not have are at their defaults
+    let db = Db::<NodePage>::new(&path, params(false)).unwrap();
+    assert_eq!(db.stats().record_format, 0);
+    assert!(!db.last_recovery().unwrap().orphan_reclaimed);
+    assert!(db.tree(1).get(b"key 0000").unwrap().is_none());
+    db.check().unwrap();
+    for i in 0..1000 {
+        let value = db.get(key(i).as_bytes()).unwrap().unwrap();
+        assert_eq!(value.read_to_vec(0, 2).unwrap(), i.to_le_bytes());
+    }
+
+    // the first change writes the record in the current layout
+    db.entry(key(1000))
+        .unwrap()
+        .vacant()
+        .unwrap()
+        .insert()
+        .unwrap();
+    assert_eq!(db.stats().record_format, DbStats::RECORD_FORMAT);
+    drop(db);
+    let db = Db::<NodePage>::new(&path, params(false)).unwrap();
+    assert_eq!(db.stats().record_format, DbStats::RECORD_FORMAT);
+    db.check().unwrap();
+    assert!(db.get(key(1000).as_bytes()).unwrap().is_some());
+}
+
+#[test]
+fn old_values() {
+    use std::os::unix::fs::FileExt as _;
+
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-old-values");
+    #[cfg(feature = "cipher")]
+    let params = |create| Params::Bare { create };
+    #[cfg(not(feature = "cipher"))]
+    let params = Params::new_mock;
+    let key = |i: u16| format!("key {i:03}");
+
+    // the last 8 bytes of every page are not zero, as an older version did write them
+    let db = Db::<NodePage>::new(&path, params(true)).unwrap();
+    for i in 0..100 {
+        let vacant = db.entry(key(i)).unwrap().vacant().unwrap();
+        let value = vacant.insert_with_ttl(Duration::from_millis(1)).unwrap();
+        value.write_at(0, &[i as u8 + 1; Value::CAPACITY]).unwrap();
+    }
+    drop(db);
+
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&path)
+        .unwrap();
+    let mut pages = vec![[0; 0x1000]; Wal::SIZE as usize];
+    for (n, page) in pages.iter_mut().enumerate() {
+        file.read_exact_at(page, n as u64 * 0x1000).unwrap();
+    }
+    let seq = |page: &[u8; 0x1000]| u64::from_ne_bytes(page[8..16].try_into().unwrap());
+    let last = pages.iter().map(seq).max().unwrap();
+    for (n, page) in pages.iter_mut().enumerate() {
+        if seq(page) == last {
+            Wal::downgrade_record(page, 0xff);
+            file.write_all_at(page, n as u64 * 0x1000).unwrap();
+        }
+    }
+    drop(file);
+
+    // those bytes are a part of the value, it never expires
+    let far = SystemTime::now() + Duration::from_secs(1 << 30);
+    let db = Db::<NodePage>::new(&path, params(false)).unwrap();
+    assert_eq!(db.purge_expired_at(far).unwrap(), 0);
+    let value = db.get(key(7).as_bytes()).unwrap().unwrap();
+    assert_eq!(value.capacity(), 0x1000);
+    assert!(value.len().unwrap() > Value::CAPACITY);
+    assert_eq!(value.read_to_vec(0, 4).unwrap(), [8; 4]);
+    value.write_at(0x1000 - 2, b"ab").unwrap();
+    assert!(matches!(value.set_expiry(Some(1)), Err(DbError::NoExpiry)));
+    value.set_expiry(None).unwrap();
+    assert_eq!(value.read_to_vec(0x1000 - 2, 2).unwrap(), b"ab");
+    let histogram = db.size_histogram(&AtomicBool::new(false)).unwrap();
+    assert_eq!(histogram.values[SizeHistogram::bucket(0x1000)], 1);
+    let vacant = db.entry(key(100)).unwrap().vacant().unwrap();
+    assert!(matches!(
+        vacant.insert_with_ttl(Duration::from_secs(1)),
+        Err(DbError::NoExpiry)
+    ));
+    drop(value);
+    // the record is written in the current layout, still without the expiration
+    db.entry(key(100))
+        .unwrap()
+        .vacant()
+        .unwrap()
+        .insert()
+        .unwrap();
+
+    // a copy keeps the pages as they are, so it does not keep the expiration either
+    db.backup_to(dir.path().join("test-old-values-copy"), params(true))
+        .unwrap();
+    drop(db);
+    for name in ["test-old-values", "test-old-values-copy"] {
+        let db = Db::<NodePage>::new(dir.path().join(name), params(false)).unwrap();
+        assert_eq!(db.stats().record_format, DbStats::RECORD_FORMAT);
+        assert_eq!(db.purge_expired_at(far).unwrap(), 0);
+        let value = db.get(key(7).as_bytes()).unwrap().unwrap();
+        assert_eq!(value.read_to_vec(0x1000 - 2, 2).unwrap(), b"ab");
+        let value = db.get(key(99).as_bytes()).unwrap().unwrap();
+        assert!(value.len().unwrap() > Value::CAPACITY);
+    }
+}
+
+#[test]
+fn old_ring() {
+    use std::os::unix::fs::FileExt as _;
+
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-old-ring");
+    // the pages are moved in place, they must not be encrypted
+    #[cfg(feature = "cipher")]
+    let params = |create| Params::Bare { create };
+    #[cfg(not(feature = "cipher"))]
+    let params = Params::new_mock;
+    let key = |i: u16| format!("key {i:04}");
+
+    // the last record goes to a slot the current layout does not use for it,
+    // dropping the database writes one more
+    let db = Db::<NodePage>::new(&path, params(true)).unwrap();
+    let mut n = 0;
+    while n < 1000 || !(0x40..0x60).contains(&((db.stats().seq + 1) % 0x100)) {
+        let value = db
+            .entry(key(n))
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap();
+        value.write_at(0, &n.to_le_bytes()).unwrap();
+        n += 1;
+    }
+    drop(db);
+
+    // the ring as the first version did write it, each record once at `seq % 256`
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&path)
+        .unwrap();
+    let mut pages = vec![[0; 0x1000]; Wal::SIZE as usize];
+    for (n, page) in pages.iter_mut().enumerate() {
+        file.read_exact_at(page, n as u64 * 0x1000).unwrap();
+    }
+    let seq = |page: &[u8; 0x1000]| u64::from_ne_bytes(page[8..16].try_into().unwrap());
+    let last = pages.iter().map(seq).max().unwrap();
+    assert!((0x40..0x80).contains(&(last % 0x100)));
+    let mut ring = vec![[0; 0x1000]; Wal::SIZE as usize];
+    for page in &mut pages {
+        let seq = seq(page);
+        if seq + u64::from(Wal::SIZE) > last {
+            Wal::downgrade_record(page, 0xff);
+            ring[(seq % u64::from(Wal::SIZE)) as usize] = *page;
+        }
+    }
+    for (n, page) in ring.iter().enumerate() {
+        file.write_all_at(page, n as u64 * 0x1000).unwrap();
+    }
+    drop(file);
+
+    // the last record is found, not an older one at a slot of the current layout
+    let db = Db::<NodePage>::new(&path, params(false)).unwrap();
+    assert_eq!(db.stats().seq_at_open, last);
+    assert_eq!(db.last_recovery().unwrap().skipped_records, 0);
+    db.check().unwrap();
+    for i in 0..n {
+        let value = db.get(key(i).as_bytes()).unwrap().unwrap();
+        assert_eq!(value.read_to_vec(0, 2).unwrap(), i.to_le_bytes());
+    }
+
+    // the changes after it go to the current layout
+    for i in n..n + 300 {
+        db.entry(key(i))
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap();
+    }
+    drop(db);
+    let db = Db::<NodePage>::new(&path, params(false)).unwrap();
+    db.check().unwrap();
+    assert_eq!(db.stats().record_format, DbStats::RECORD_FORMAT);
+    for i in 0..n + 300 {
+        assert!(db.get(key(i).as_bytes()).unwrap().is_some());
+    }
+}
+
+// with the `small` feature both kinds of nodes have the same fanout
+#[test]
+fn corrupted_node() {
+    use std::os::unix::fs::FileExt as _;
+
+    use crate::node::Node;
+
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-node");
+    // the pages are patched in place, they must not be encrypted
+    #[cfg(feature = "cipher")]
+    let params = |create| Params::Bare { create };
+    #[cfg(not(feature = "cipher"))]
+    let params = Params::new_mock;
+
+    let db = Db::<NodePage>::new(&path, params(true)).unwrap();
+    for i in 0..1000u16 {
+        let key = format!("key {i:04}");
+        db.entry(key.as_bytes())
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap();
+    }
+    let head = u64::from(db.head()) * 0x1000;
+    drop(db);
+
+    // the first key of the first leaf is longer than its key pages
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&path)
+        .unwrap();
+    let mut child = [0; 4];
+    file.read_exact_at(&mut child, head).unwrap();
+    let leaf = u64::from(u32::from_ne_bytes(child)) * 0x1000;
+    let keys_len = leaf + <NodePage>::M as u64 * 4;
+    file.write_all_at(&0x7ffu16.to_ne_bytes(), keys_len)
+        .unwrap();
+    drop(file);
+
+    let db = Db::<NodePage>::new(&path, params(false)).unwrap();
+    assert!(matches!(db.get(b"key 0000"), Err(DbError::CorruptedFile)));
+    assert!(matches!(db.iter_from(b""), Err(DbError::CorruptedFile)));
+    assert!(db.get(b"key 0999").unwrap().is_some());
+}
+
+#[test]
+fn bad_wal() {
+    use std::os::unix::fs::FileExt as _;
+
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-small");
+    let torn_path = dir.path().join("test-torn");
+    // the pages are patched in place, they must not be encrypted
+    #[cfg(feature = "cipher")]
+    let params = |create| Params::Bare { create };
+    #[cfg(not(feature = "cipher"))]
+    let params = Params::new_mock;
+
+    let db = Db::<NodePage>::new(&path, params(true)).unwrap();
+    db.entry(b"key")
+        .unwrap()
+        .vacant()
+        .unwrap()
+        .insert()
+        .unwrap();
+    drop(db);
+    fs::copy(&path, &torn_path).unwrap();
+
+    let header = params(false).header_size() as u64;
+    let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+    file.set_len(header + 10 * 0x1000).unwrap();
+    drop(file);
+    let res = Db::<NodePage>::new(&path, params(false));
+    assert!(matches!(
+        res,
+        Err(DbError::WalError(WalError::TooSmall {
+            pages: 10,
+            min: 0x100
+        }))
+    ));
+
+    // every log page fails the checksum
+    let file = fs::OpenOptions::new().write(true).open(&torn_path).unwrap();
+    for n in 0..0x100 {
+        file.write_all_at(&[0xff; 0x1000], header + n * 0x1000)
+            .unwrap();
+    }
+    drop(file);
+    let res = Db::<NodePage>::new(&torn_path, params(false));
+    let Err(DbError::WalError(err @ WalError::NoRecord { .. })) = res else {
+        panic!("must be no record");
+    };
+    assert!(matches!(
+        err,
+        WalError::NoRecord {
+            slots: 0x100,
+            torn: 0x100,
+            best_seq: Some(u64::MAX),
+            wrong_secret: false,
+        }
+    ));
+    assert!(!err.to_string().contains("secret"));
+}
+
+#[cfg(feature = "cipher")]
+#[test]
+fn wrong_key_wal() {
+    use std::os::unix::fs::FileExt as _;
+
+    use crate::Secret;
+
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-one");
+    let other_path = dir.path().join("test-other");
+
+    for (path, seed) in [(&path, [1; 32]), (&other_path, [2; 32])] {
+        let params = Params::Create {
+            secret: Secret::Pw {
+                pw: "qwerty",
+                time: 1,
+                memory: 0x1000,
+            },
+            seed: &seed,
+        };
+        let db = Db::<NodePage>::new(path, params).unwrap();
+        db.entry(b"key")
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap();
+    }
+
+    // the header of the other file opens with the same password,
+    // but its key decrypts the pages of this one to garbage
+    let header_size = Params::new_mock(false).header_size();
+    let header = fs::read(&other_path).unwrap()[..header_size].to_vec();
+    let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+    file.write_all_at(&header, 0).unwrap();
+    drop(file);
+
+    let res = Db::<NodePage>::new(&path, Params::new_mock(false));
+    let Err(DbError::WalError(err @ WalError::NoRecord { .. })) = res else {
+        panic!("must be no record");
+    };
+    assert!(matches!(
+        err,
+        WalError::NoRecord {
+            torn: 1..,
+            wrong_secret: true,
+            ..
+        }
+    ));
+    assert!(err.to_string().contains("secret"));
+}
+
+#[cfg(not(feature = "small"))]
+#[test]
+fn fanout_mismatch() {
+    use crate::{NodeCPage, node::Node};
+
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-fanout");
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(true)).unwrap();
+    assert_eq!(db.fanout(), <NodePage>::M);
+    drop(db);
+
+    let res = Db::<NodeCPage>::new(&path, Params::new_mock(false));
+    let (stored, given) = (<NodePage>::M as u64, <NodeCPage>::M as u64);
+    assert!(matches!(
+        res,
+        Err(DbError::WalError(WalError::Fanout { stored: s, given: g })) if (s, g) == (stored, given)
+    ));
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(false)).unwrap();
+    assert_eq!(db.fanout(), <NodePage>::M);
+}
+
+#[cfg(feature = "cipher")]
+#[test]
+fn bare() {
+    use std::collections::BTreeMap;
+
+    use crate::CipherError;
+
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-encrypted");
+    let bare_path = dir.path().join("test-bare");
+    let back_path = dir.path().join("test-back");
+
+    let content = |db: &Db<NodePage>| {
+        let mut it = db.iter_from(b"").unwrap();
+        let mut content = BTreeMap::new();
+        while let Some((key, value)) = db.next(&mut it).unwrap() {
+            content.insert(key, value.unwrap().read_to_vec(0, 16).unwrap());
+        }
+        content
+    };
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(true)).unwrap();
+    for i in 0..1000u16 {
+        let key = format!("key {i:04}");
+        db.entry(key.as_bytes())
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap()
+            .write_at(0, format!("plaintext {i:04}").as_bytes())
+            .unwrap();
+    }
+    let expected = content(&db);
+    db.backup_to(&bare_path, Params::Bare { create: true })
+        .unwrap();
+    drop(db);
+
+    // the pages start at the beginning of the file and are not encrypted
+    let bytes = fs::read(&bare_path).unwrap();
+    assert_eq!(bytes.len() % 0x1000, 0);
+    assert!(bytes.windows(14).any(|w| w == b"plaintext 0777"));
+    let encrypted = fs::read(&path).unwrap();
+    assert!(!encrypted.windows(14).any(|w| w == b"plaintext 0777"));
+
+    let res = Db::<NodePage>::new(&bare_path, Params::new_mock(false));
+    assert!(res.is_err());
+    let db = Db::<NodePage>::new(&bare_path, Params::Bare { create: false }).unwrap();
+    assert_eq!(content(&db), expected);
+    // shredding has no header to destroy
+    assert!(matches!(
+        db.crypt_shred(&[1; 32]),
+        Err(DbError::Cipher(CipherError::NotEncrypted))
+    ));
+    db.backup_to(&back_path, Params::new_mock(true)).unwrap();
+    drop(db);
+
+    let db = Db::<NodePage>::new(&bare_path, Params::Bare { create: false }).unwrap();
+    assert_eq!(content(&db), expected);
+    drop(db);
+    let db = Db::<NodePage>::new(&back_path, Params::new_mock(false)).unwrap();
+    assert_eq!(content(&db), expected);
+}
+
+#[cfg(feature = "cipher")]
+#[test]
+fn stored_cost() {
+    use crate::{CipherError, Secret};
+
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-cost");
+    let key_path = dir.path().join("test-key");
+
+    let params = Params::Create {
+        secret: Secret::Pw {
+            pw: "qwerty",
+            time: 2,
+            memory: 0x2000,
+        },
+        seed: &[1; 32],
+    };
+    let db = Db::<NodePage>::new(&path, params).unwrap();
+    db.entry(b"key")
+        .unwrap()
+        .vacant()
+        .unwrap()
+        .insert()
+        .unwrap();
+    drop(db);
+
+    // the password alone opens it, a different cost given is ignored
+    let open = |secret| Db::<NodePage>::new(&path, Params::Open { secret });
+    let db = open(Secret::Password("qwerty")).unwrap();
+    assert!(db.get(b"key").unwrap().is_some());
+    drop(db);
+    let db = open(Secret::Pw {
+        pw: "qwerty",
+        time: 1,
+        memory: 0x1000,
+    })
+    .unwrap();
+    drop(db);
+    let res = open(Secret::Password("wrong"));
+    assert!(matches!(
+        res,
+        Err(DbError::Cipher(CipherError::WrongSecret))
+    ));
+
+    // a raw key has no cost, a password does not open it
+    let params = Params::Create {
+        secret: Secret::Key(&[7; 32]),
+        seed: &[2; 32],
+    };
+    Db::<NodePage>::new(&key_path, params).unwrap();
+    let res = Db::<NodePage>::new(
+        &key_path,
+        Params::Open {
+            secret: Secret::Password("qwerty"),
+        },
+    );
+    assert!(matches!(
+        res,
+        Err(DbError::Cipher(CipherError::WrongSecret))
+    ));
+    let params = Params::Open {
+        secret: Secret::Key(&[7; 32]),
+    };
+    Db::<NodePage>::new(&key_path, params).unwrap();
+}
+
+#[cfg(feature = "cipher")]
+#[test]
+fn detached_header() {
+    use crate::{CipherError, Secret};
+
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-detached");
+    let header = dir.path().join("test-detached-header");
+    let copy = dir.path().join("test-detached-copy");
+    let secret = || Secret::Pw {
+        pw: "qwerty",
+        time: 1,
+        memory: 0x1000,
+    };
+    let open = || {
+        let params = Params::OpenDetached {
+            secret: Secret::Password("qwerty"),
+            header: &header,
+        };
+        Db::<NodePage>::new(&path, params)
+    };
+
+    let params = Params::CreateDetached {
+        secret: secret(),
+        seed: &[1; 32],
+        header: &header,
+    };
+    let db = Db::<NodePage>::new(&path, params).unwrap();
+    for i in 0..100u16 {
+        let value = db.entry(format!("key {i:04}")).unwrap().vacant().unwrap();
+        let value = value.insert().unwrap();
+        value
+            .write_at(0, format!("plaintext {i:04}").as_bytes())
+            .unwrap();
+    }
+    // the copy of the file alone would have no header, `backup_to` makes a new one
+    let backup = dir.path().join("test-detached-backup");
+    let backup_header = dir.path().join("test-detached-backup-header");
+    let res = db.backup(&backup);
+    assert!(matches!(res, Err(DbError::Io(err)) if err.kind() == io::ErrorKind::Unsupported));
+    assert!(!backup.exists());
+    let params = Params::CreateDetached {
+        secret: secret(),
+        seed: &[2; 32],
+        header: &backup_header,
+    };
+    db.backup_to(&backup, params).unwrap();
+    drop(db);
+    let params = Params::OpenDetached {
+        secret: Secret::Password("qwerty"),
+        header: &backup_header,
+    };
+    let db = Db::<NodePage>::new(&backup, params).unwrap();
+    let value = db.get(b"key 0077").unwrap().unwrap();
+    assert_eq!(value.read_to_vec(0, 14).unwrap(), b"plaintext 0077");
+    drop(value);
+    drop(db);
+
+    // the pages start at the beginning of the file, but they are encrypted
+    let bytes = fs::read(&path).unwrap();
+    assert_eq!(bytes.len() % 0x1000, 0);
+    assert!(!bytes.windows(14).any(|w| w == b"plaintext 0077"));
+    assert_eq!(fs::metadata(&header).unwrap().len(), 1 << 20);
+    // the header is not in the file, nor is it created again over the old one
+    assert!(Db::<NodePage>::new(&path, Params::new_mock(false)).is_err());
+    let params = Params::CreateDetached {
+        secret: secret(),
+        seed: &[2; 32],
+        header: &header,
+    };
+    assert!(Db::<NodePage>::new(dir.path().join("test-other"), params).is_err());
+
+    // without the header nothing opens the data
+    fs::copy(&header, &copy).unwrap();
+    fs::remove_file(&header).unwrap();
+    assert!(open().is_err());
+    fs::rename(&copy, &header).unwrap();
+    let db = open().unwrap();
+    let value = db.get(b"key 0077").unwrap().unwrap();
+    assert_eq!(value.read_to_vec(0, 14).unwrap(), b"plaintext 0077");
+    drop(value);
+
+    // shredding overwrites only the header
+    db.crypt_shred(&[3; 32]).unwrap();
+    drop(db);
+    assert_eq!(fs::metadata(&path).unwrap().len(), bytes.len() as u64);
+    // random bytes, the stored cost of the password is gone as well
+    assert!(matches!(
+        open(),
+        Err(DbError::Cipher(CipherError::NoStoredCost))
+    ));
+}
+
+#[cfg(feature = "cipher")]
+#[test]
+fn key_blob_cleared() {
+    use crate::{Secret, cipher::Cipher};
+
+    let secret = |pw| Secret::Pw {
+        pw,
+        time: 1,
+        memory: 0x1000,
+    };
+    let (_, blob) = Cipher::setup(secret("qwerty"), &[1; 32]).unwrap();
+
+    // the salt and the tag stay, the decrypted key material is cleared
+    let mut buf = blob.to_vec();
+    Cipher::open(&mut buf, secret("qwerty")).unwrap();
+    assert_eq!(buf[..0x20], blob[..0x20]);
+    assert!(buf[0x20..].iter().all(|b| *b == 0));
+
+    // a wrong secret does not decrypt anything
+    let mut buf = blob.to_vec();
+    assert!(Cipher::open(&mut buf, secret("wrong")).is_err());
+    assert_eq!(buf[..], blob[..]);
+}
+
+#[cfg(feature = "cipher")]
+#[test]
+fn legacy_header() {
+    use aes::Aes256;
+    use argon2::{
+        password_hash::{PasswordHasher, SaltString},
+        Algorithm, Argon2, ParamsBuilder, Version,
+    };
+    use chacha20poly1305::{
+        aead::{generic_array::GenericArray, AeadInPlace},
+        ChaCha20Poly1305, KeyInit,
+    };
+    use chacha20::XChaCha12;
+    use hkdf::Hkdf;
+    use sha3::{
+        digest::{ExtendableOutput, Update, XofReader},
+        Sha3_256, Shake256,
+    };
+
+    use crate::{cipher::Cipher, CipherError, Secret};
+
+    // the header as it was written before the cost was stored: the salt, the tag,
+    // and the key material encrypted to the end, the password hashed as a hash string
+    let (pw, time, memory) = ("qwerty", 1, 0x1000);
+    let mut blob = vec![0; Params::new_mock(true).header_size()];
+    Shake256::default()
+        .chain([3; 32])
+        .finalize_xof()
+        .read(&mut blob);
+    let (salt, rest) = blob.split_first_chunk_mut::<0x10>().unwrap();
+    let (tag, material) = rest.split_first_chunk_mut::<0x10>().unwrap();
+    let mut main_key = [0; 32];
+    Hkdf::<Sha3_256>::new(Some(&*salt), &*material)
+        .expand(b"main_key", &mut main_key)
+        .unwrap();
+    let mut params = ParamsBuilder::new();
+    params.m_cost(memory);
+    params.t_cost(time);
+    let hash = Argon2::new(Algorithm::Argon2id, Version::V0x13, params.build().unwrap())
+        .hash_password(pw.as_bytes(), &SaltString::encode_b64(&*salt).unwrap())
+        .unwrap()
+        .hash
+        .unwrap();
+    *tag = ChaCha20Poly1305::new(GenericArray::from_slice(hash.as_bytes()))
+        .encrypt_in_place_detached(&GenericArray::default(), b"main_blob", material)
+        .unwrap()
+        .into();
+
+    // the cost is given, the pages are encrypted with the same key as before
+    let mut buf = blob.to_vec();
+    let cipher = Cipher::open(&mut buf, Secret::Pw { pw, time, memory }).unwrap();
+    let legacy = adiantum::Cipher::<XChaCha12, Aes256>::new(GenericArray::from_slice(&main_key));
+    let mut page = [0x5a; 0x1000];
+    let mut expected = page;
+    cipher.encrypt(&mut page, 7);
+    legacy.encrypt(&mut expected, &7_u32.to_le_bytes());
+    assert_eq!(page, expected);
+
+    // nothing in the header tells the cost, the password alone does not open it
+    let mut buf = blob.to_vec();
+    assert!(matches!(
+        Cipher::open(&mut buf, Secret::Password(pw)),
+        Err(CipherError::NoStoredCost)
+    ));
+    let mut buf = blob.to_vec();
+    let wrong = Secret::Pw {
+        pw: "wrong",
+        time,
+        memory,
+    };
+    assert!(matches!(
+        Cipher::open(&mut buf, wrong),
+        Err(CipherError::WrongSecret)
+    ));
+}
+
+#[test]
+fn fanout_generic() {
+    use crate::{node::Node, runtime::PlainData};
+
+    fn keys<N: Copy + PlainData + Node>(db: &Db<N>) -> Vec<Vec<u8>> {
+        let mut it = db.iter_from(b"").unwrap();
+        let mut keys = vec![];
+        while let Some((key, _)) = db.next(&mut it).unwrap() {
+            keys.push(key);
+        }
+        keys
+    }
+
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let narrow_path = dir.path().join("test-narrow");
+    let wide_path = dir.path().join("test-wide");
+
+    let narrow = Db::<NodePage<8>>::new(&narrow_path, Params::new_mock(true)).unwrap();
+    let wide = Db::<NodePage<0x100>>::new(&wide_path, Params::new_mock(true)).unwrap();
+    assert_eq!(narrow.fanout(), 8);
+    assert_eq!(wide.fanout(), <NodePage<0x100>>::M);
+
+    // the narrow tree is several levels deep, the wide one is a single leaf
+    let expected = (0..0x80_u32)
+        .map(|i| i.to_be_bytes().to_vec())
+        .collect::<Vec<_>>();
+    for key in &expected {
+        narrow
+            .entry(key)
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert_small(key)
+            .unwrap();
+        wide.entry(key)
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert_small(key)
+            .unwrap();
+    }
+    assert_eq!(keys(&narrow), expected);
+    assert_eq!(keys(&wide), expected);
+    narrow.check().unwrap();
+    wide.check().unwrap();
+    drop((narrow, wide));
+
+    let res = Db::<NodePage<0x100>>::new(&narrow_path, Params::new_mock(false));
+    assert!(matches!(
+        res,
+        Err(DbError::WalError(WalError::Fanout {
+            stored: 8,
+            given: 0x100
+        }))
+    ));
+    let narrow = Db::<NodePage<8>>::new(&narrow_path, Params::new_mock(false)).unwrap();
+    assert_eq!(keys(&narrow), expected);
+}
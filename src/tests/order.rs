@@ -0,0 +1,678 @@
+use std::{
+    cmp::Ordering,
+    collections::BTreeSet,
+    ops::{Bound, ControlFlow},
+    sync::Arc,
+};
+
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use tempdir::TempDir;
+
+use crate::{node::Node, Collation, Db, DbError, IoOptions, NodePage, Params, Value, WalError};
+
+use super::with_db;
+
+#[test]
+fn lexicographic() {
+    with_db::<_, _, NodePage>(0x123, |db, rng| {
+        // long runs of zeros make keys differ only in the padded tail of a chunk
+        let mut keys = (0..1000)
+            .map(|_| {
+                let mut key = vec![0; rng.gen_range(0..=20)];
+                key.extend((0..rng.gen_range(0..=20)).map(|_| rng.gen_range(0..=1u8)));
+                key
+            })
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys.dedup();
+        keys.shuffle(rng);
+
+        for key in &keys {
+            db.entry(key).unwrap().vacant().unwrap().insert().unwrap();
+        }
+        for key in keys.split_off(keys.len() / 2) {
+            db.entry(&key)
+                .unwrap()
+                .occupied()
+                .unwrap()
+                .remove()
+                .unwrap();
+        }
+        for key in &keys {
+            assert!(db.entry(key).unwrap().occupied().is_some());
+        }
+
+        keys.sort();
+        let mut it = db.entry(b"").unwrap().into_db_iter();
+        let mut actual = Vec::with_capacity(keys.len());
+        while let Some((key, _)) = db.next(&mut it).unwrap() {
+            actual.push(key);
+        }
+        assert_eq!(actual, keys);
+    })
+}
+
+#[test]
+fn empty_key() {
+    with_db::<_, _, NodePage>(0x123, |db, rng| {
+        // the empty key has no chunk, but the key pages of the node must shift anyway
+        let mut model = BTreeSet::new();
+        for step in 0..20000 {
+            let key = if rng.gen_ratio(1, 10) {
+                vec![]
+            } else {
+                let mut key = vec![0; rng.gen_range(0..=3)];
+                key.extend((0..rng.gen_range(0..=40)).map(|_| rng.gen_range(0..=2u8)));
+                key
+            };
+            if model.remove(&key) {
+                db.entry(&key)
+                    .unwrap()
+                    .occupied()
+                    .unwrap()
+                    .remove()
+                    .unwrap();
+            } else {
+                db.entry(&key).unwrap().vacant().unwrap().insert().unwrap();
+                model.insert(key);
+            }
+            if step % 1000 == 0 {
+                db.check().unwrap();
+                assert_eq!(db.get(b"").unwrap().is_some(), model.contains(&vec![]));
+                let mut it = db.iter_from(b"").unwrap();
+                let mut actual = vec![];
+                while let Some((key, _)) = db.next(&mut it).unwrap() {
+                    actual.push(key);
+                }
+                assert!(actual.iter().eq(&model), "step {step}");
+            }
+        }
+    })
+}
+
+#[test]
+fn seek() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        let key = |i: u32| format!("key {i:05}").into_bytes();
+        // every third key, so seeking between keys lands on the next one
+        for i in (0..3000).step_by(3) {
+            db.entry(key(i))
+                .unwrap()
+                .vacant()
+                .unwrap()
+                .insert()
+                .unwrap();
+        }
+
+        let mut it = db.entry(b"").unwrap().into_db_iter();
+        for i in (0..30).step_by(3) {
+            assert_eq!(db.next(&mut it).unwrap().unwrap().0, key(i));
+        }
+
+        // forward, across many leaves
+        db.seek(&mut it, &key(2000)).unwrap();
+        assert_eq!(db.next(&mut it).unwrap().unwrap().0, key(2001));
+        assert_eq!(db.next(&mut it).unwrap().unwrap().0, key(2004));
+
+        // backward
+        db.seek(&mut it, &key(10)).unwrap();
+        assert_eq!(db.next(&mut it).unwrap().unwrap().0, key(12));
+
+        // every key between two stored keys lands on the next one,
+        // even if it is the first key of the next leaf
+        for i in (0..2997).filter(|i| i % 3 != 0) {
+            db.seek(&mut it, &key(i)).unwrap();
+            let next = i.next_multiple_of(3);
+            assert_eq!(db.next(&mut it).unwrap().unwrap().0, key(next));
+        }
+
+        // past the end
+        db.seek(&mut it, &key(3000)).unwrap();
+        assert!(db.next(&mut it).unwrap().is_none());
+
+        // and back from the exhausted state
+        db.seek(&mut it, b"").unwrap();
+        assert_eq!(db.next(&mut it).unwrap().unwrap().0, key(0));
+    })
+}
+
+#[test]
+fn range_after() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        const PAGE: usize = 100;
+
+        let key = |i: u32| format!("key {i:05}").into_bytes();
+        for i in 0..1000 {
+            db.entry(key(i))
+                .unwrap()
+                .vacant()
+                .unwrap()
+                .insert()
+                .unwrap();
+        }
+
+        // pages of keys, each resumes after the last key of the previous one
+        let mut keys = (0..1000).map(key).collect::<BTreeSet<_>>();
+        let mut seen = vec![];
+        let mut unseen = BTreeSet::new();
+        let mut it = db.iter_from(b"").unwrap();
+        loop {
+            let mut page = vec![];
+            while let Some(k) = db.next_key(&mut it).unwrap() {
+                page.push(k);
+                if page.len() == PAGE {
+                    break;
+                }
+            }
+            let Some(last) = page.last().cloned() else {
+                break;
+            };
+            seen.extend(page);
+            // the last seen key and the one after it are gone before the next page
+            let after = keys.range(last.clone()..).nth(1).cloned();
+            unseen.extend(after.clone());
+            for k in Some(last.clone()).into_iter().chain(after) {
+                let occupied = db.entry(&k).unwrap().occupied().unwrap();
+                occupied.remove().unwrap();
+                keys.remove(&k);
+            }
+            it = db.range_after(&last).unwrap();
+        }
+        // every key is seen once, but those removed before their page
+        assert_eq!(unseen.len(), 1000 / (PAGE + 1));
+        let expected = (0..1000).map(key).filter(|k| !unseen.contains(k));
+        assert!(seen.into_iter().eq(expected));
+
+        // an existing key is skipped, a missing one lands on the next key
+        let mut it = db.range_after(&key(1)).unwrap();
+        assert_eq!(db.next_key(&mut it).unwrap().unwrap(), key(2));
+        let mut it = db.range_after(&key(99)).unwrap();
+        assert_eq!(db.next_key(&mut it).unwrap().unwrap(), key(101));
+        let mut it = db.range_after(b"key").unwrap();
+        assert_eq!(db.next_key(&mut it).unwrap().unwrap(), key(0));
+        let mut it = db.range_after(&key(999)).unwrap();
+        assert!(db.next_key(&mut it).unwrap().is_none());
+    })
+}
+
+#[test]
+fn next_key() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        // long keys span several key pages
+        let key = |i: u32| format!("key {i:05} with a long tail to spill into key pages");
+        for i in 0..2000 {
+            db.entry(key(i))
+                .unwrap()
+                .vacant()
+                .unwrap()
+                .insert()
+                .unwrap();
+        }
+
+        let reads = || {
+            let stats = db.stats();
+            stats.cache_hits + stats.cache_misses
+        };
+
+        let before = reads();
+        let mut it = db.entry(b"").unwrap().into_db_iter();
+        let mut i = 0;
+        while let Some(k) = db.next_key(&mut it).unwrap() {
+            assert_eq!(k, key(i).into_bytes());
+            i += 1;
+        }
+        assert_eq!(i, 2000);
+        let scan = reads() - before;
+
+        // reading each key on its own reads every key page for every key
+        let before = reads();
+        for i in 0..2000 {
+            db.entry(key(i)).unwrap().occupied().unwrap().key().unwrap();
+        }
+        let lookup = reads() - before;
+
+        assert!(scan * 10 < lookup, "{scan} {lookup}");
+    })
+}
+
+#[test]
+fn cursor_merge_join() {
+    with_db::<_, _, NodePage>(0x123, |a, _rng| {
+        let b = Db::<NodePage>::in_memory().unwrap();
+        let key = |i: u32| format!("key {i:05}").into_bytes();
+        for (db, step) in [(&a, 2), (&b, 3)] {
+            for i in (0..10_000).map(|i| i * step) {
+                let value = db.entry(key(i)).unwrap().vacant().unwrap().insert();
+                value.unwrap().write_at(0, &i.to_le_bytes()).unwrap();
+            }
+        }
+
+        let reads = || {
+            let (a, b) = (a.stats(), b.stats());
+            a.cache_hits + a.cache_misses + b.cache_hits + b.cache_misses
+        };
+
+        // leapfrog, each cursor seeks the key of the other
+        let before = reads();
+        let (mut left, mut right) = (a.cursor(), b.cursor());
+        let mut joined = vec![];
+        left.next().unwrap();
+        while let Some(k) = left.key().unwrap() {
+            if right.seek(&k).unwrap() {
+                let value = right.value().unwrap().unwrap();
+                joined.push(u32::from_le_bytes(
+                    value.read_to_vec(0, 4).unwrap().try_into().unwrap(),
+                ));
+                left.next().unwrap();
+            } else {
+                let Some(k) = right.key().unwrap() else {
+                    break;
+                };
+                left.seek(&k).unwrap();
+            }
+        }
+        let join = reads() - before;
+        assert_eq!(joined, (0..20_000).step_by(6).collect::<Vec<_>>());
+
+        // a lookup of every key of one side descends from the root each time
+        let before = reads();
+        let mut count = 0;
+        for i in (0..20_000).step_by(2) {
+            count += usize::from(b.get(&key(i)).unwrap().is_some());
+        }
+        let lookup = reads() - before;
+        assert_eq!(count, joined.len());
+        assert!(join * 3 < lookup, "{join} {lookup}");
+
+        // backward from off the tree, then forward again
+        let mut cursor = b.cursor();
+        for i in (0..10_000).rev() {
+            assert!(cursor.prev().unwrap());
+            assert_eq!(cursor.key().unwrap().unwrap(), key(i * 3));
+        }
+        assert!(!cursor.prev().unwrap());
+        assert!(cursor.next().unwrap());
+        assert_eq!(cursor.key().unwrap().unwrap(), key(0));
+
+        // far away, past the end, and after a change of the tree
+        assert!(!cursor.seek(&key(15_001)).unwrap());
+        assert_eq!(cursor.key().unwrap().unwrap(), key(15_003));
+        assert!(cursor.seek(&key(3)).unwrap());
+        assert!(!cursor.seek(&key(30_000)).unwrap());
+        assert!(cursor.key().unwrap().is_none());
+        assert!(cursor.value().unwrap().is_none());
+        b.entry(key(15_002))
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert_empty()
+            .unwrap();
+        assert!(!cursor.seek(&key(15_001)).unwrap());
+        assert_eq!(cursor.key().unwrap().unwrap(), key(15_002));
+        assert!(cursor.value().unwrap().is_none());
+        assert!(cursor.prev().unwrap());
+        assert_eq!(cursor.key().unwrap().unwrap(), key(15_000));
+    })
+}
+
+#[test]
+fn cursor_changed() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        let key = |i: u32| format!("key {i:05}").into_bytes();
+        let remove = |i: u32| {
+            db.entry(key(i))
+                .unwrap()
+                .occupied()
+                .unwrap()
+                .remove()
+                .unwrap()
+        };
+        for i in (0..3000).map(|i| i * 3) {
+            db.entry(key(i))
+                .unwrap()
+                .vacant()
+                .unwrap()
+                .insert()
+                .unwrap();
+        }
+
+        // the moves after a change go from the key, not from the old nodes
+        let mut cursor = db.cursor();
+        assert!(cursor.seek(&key(300)).unwrap());
+        db.entry(key(301))
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap();
+        assert!(cursor.next().unwrap());
+        assert_eq!(cursor.key().unwrap().unwrap(), key(301));
+
+        // the key at the position is gone, and so are many nodes after it
+        remove(301);
+        for i in (101..2000).map(|i| i * 3) {
+            remove(i);
+        }
+        assert!(cursor.next().unwrap());
+        assert_eq!(cursor.key().unwrap().unwrap(), key(6000));
+        remove(6000);
+        assert!(cursor.value().unwrap().is_none());
+        assert!(cursor.prev().unwrap());
+        assert_eq!(cursor.key().unwrap().unwrap(), key(300));
+        remove(300);
+        assert!(cursor.prev().unwrap());
+        assert_eq!(cursor.key().unwrap().unwrap(), key(297));
+    })
+}
+
+#[test]
+fn advance_by() {
+    with_db::<_, _, NodePage>(0x123, |db, rng| {
+        let key = |i: u32| format!("key {i:05}").into_bytes();
+        for i in 0..3000 {
+            db.entry(key(i))
+                .unwrap()
+                .vacant()
+                .unwrap()
+                .insert()
+                .unwrap();
+        }
+
+        let mut it = db.entry(b"").unwrap().into_db_iter();
+        let mut naive = db.entry(b"").unwrap().into_db_iter();
+        let mut pos = 0;
+        loop {
+            let n = rng.gen_range(0..300);
+            let skipped = db.advance_by(&mut it, n).unwrap();
+            for _ in 0..n {
+                if db.next_key(&mut naive).unwrap().is_none() {
+                    break;
+                }
+            }
+            assert_eq!(skipped, n.min(3000 - pos));
+            pos += skipped;
+
+            let actual = db.next_key(&mut it).unwrap();
+            assert_eq!(actual, db.next_key(&mut naive).unwrap());
+            let Some(actual) = actual else {
+                break;
+            };
+            assert_eq!(actual, key(pos as u32));
+            pos += 1;
+        }
+        assert_eq!(db.advance_by(&mut it, 10).unwrap(), 0);
+    })
+}
+
+#[test]
+fn fold_prefix() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        for i in 0..10_000u32 {
+            let key = format!("{} {i:05}", ["a", "b", "c"][i as usize % 3]);
+            db.entry(key)
+                .unwrap()
+                .vacant()
+                .unwrap()
+                .insert()
+                .unwrap()
+                .write_at(0, &i.to_le_bytes())
+                .unwrap();
+        }
+        // the empty cell has no value to fold
+        db.entry(b"b empty")
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert_empty()
+            .unwrap();
+
+        let sum = |acc: u64, key: &[u8], value: crate::Value<'_>| {
+            assert!(key.starts_with(b"b "));
+            let mut buf = [0; 4];
+            value.read(0, &mut buf).unwrap();
+            ControlFlow::Continue(acc + u64::from(u32::from_le_bytes(buf)))
+        };
+        let expected = (0..10_000u64).filter(|i| i % 3 == 1).sum::<u64>();
+        assert_eq!(db.fold_prefix(b"b ", 0, sum).unwrap(), expected);
+        assert_eq!(db.fold_prefix(b"d", 0, sum).unwrap(), 0);
+
+        let reads = || {
+            let stats = db.stats();
+            stats.cache_hits + stats.cache_misses
+        };
+
+        let before = reads();
+        let count = db
+            .fold_prefix(b"", 0, |n, _, _| ControlFlow::Continue(n + 1))
+            .unwrap();
+        assert_eq!(count, 10_000);
+        let full = reads() - before;
+
+        let before = reads();
+        let count = db
+            .fold_prefix(b"", 0, |n, _, _| {
+                if n + 1 == 10 {
+                    ControlFlow::Break(n + 1)
+                } else {
+                    ControlFlow::Continue(n + 1)
+                }
+            })
+            .unwrap();
+        assert_eq!(count, 10);
+        let early = reads() - before;
+
+        assert!(early * 10 < full, "{early} {full}");
+    })
+}
+
+#[test]
+fn approximate_count() {
+    with_db::<_, _, NodePage>(0x123, |db, rng| {
+        let key = |i: u32| format!("key {i:08}");
+
+        // a single leaf, the count is exact
+        let n = <NodePage>::M as u32 - 1;
+        for i in 0..n {
+            db.entry(key(i * 2))
+                .unwrap()
+                .vacant()
+                .unwrap()
+                .insert_empty()
+                .unwrap();
+        }
+        assert_eq!(db.approximate_count(b"", b"z").unwrap(), u64::from(n));
+        assert_eq!(
+            db.approximate_count(key(3).as_bytes(), key(9).as_bytes())
+                .unwrap(),
+            3
+        );
+        assert_eq!(db.approximate_count(b"z", b"").unwrap(), 0);
+
+        let mut keys = (n..50_000).collect::<Vec<u32>>();
+        keys.shuffle(rng);
+        for i in keys {
+            db.entry(key(i * 2))
+                .unwrap()
+                .vacant()
+                .unwrap()
+                .insert_empty()
+                .unwrap();
+        }
+        // the nodes of `small` are tiny, so the tree is deep and the error compounds
+        let bar = if cfg!(feature = "small") { 3 } else { 2 };
+        for _ in 0..200 {
+            let start = rng.gen_range(0..100_000);
+            let end = rng.gen_range(start..=100_000);
+            let count = db
+                .approximate_count(key(start).as_bytes(), key(end).as_bytes())
+                .unwrap();
+            let exact = u64::from(end.div_ceil(2) - start.div_ceil(2));
+            assert!(
+                count <= exact * bar && count * bar >= exact,
+                "{count} instead of {exact} from {start} to {end}"
+            );
+        }
+    })
+}
+
+#[test]
+fn multi_get() {
+    with_db::<_, _, NodePage>(0x123, |db, rng| {
+        let key = |i: u32| format!("key {i:05}").into_bytes();
+        for i in (0..3000).step_by(3) {
+            let vacant = db.entry(key(i)).unwrap().vacant().unwrap();
+            if i % 2 == 0 {
+                let value = vacant.insert().unwrap();
+                value.write_at(0, &i.to_le_bytes()).unwrap();
+            } else {
+                vacant.insert_empty().unwrap();
+            }
+        }
+
+        // unsorted, missing and repeated keys, the result follows the input
+        let keys = (0..1000)
+            .map(|_| key(rng.gen_range(0..3100)))
+            .collect::<Vec<_>>();
+        let keys = keys.iter().map(Vec::as_slice).collect::<Vec<_>>();
+        let read = |value: Option<Value>| value.map(|v| v.read_to_vec(0, 4).unwrap());
+        let values = db.multi_get(&keys).unwrap();
+        assert_eq!(values.len(), keys.len());
+        for (key, value) in keys.iter().zip(values) {
+            assert_eq!(read(value), read(db.get(key).unwrap()));
+        }
+
+        // keys before the first and past the last descend to the edges of every level
+        let edges = [
+            &b""[..],
+            b"a",
+            b"key",
+            b"key 99999",
+            b"\xff\xff",
+            b"key 00000",
+        ];
+        let mut values = db.multi_get(&edges).unwrap();
+        assert_eq!(
+            read(values.pop().unwrap()),
+            read(db.get(b"key 00000").unwrap())
+        );
+        assert!(values.iter().all(Option::is_none));
+
+        assert!(db.multi_get(&[]).unwrap().is_empty());
+        let absent = db.tree(1).multi_get(&keys[..10]).unwrap();
+        assert!(absent.iter().all(Option::is_none));
+    })
+}
+
+// decimal numbers, a shorter one is smaller
+struct Numeric;
+
+impl Collation for Numeric {
+    fn id(&self) -> u64 {
+        1
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+    }
+}
+
+#[test]
+fn collation() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-collation");
+    let collation = Arc::new(Numeric);
+    let options = IoOptions::default();
+
+    let db = Db::<NodePage>::new_with_collation(
+        &path,
+        Params::new_mock(true),
+        options,
+        collation.clone(),
+    )
+    .unwrap();
+    let mut numbers = (0..2000).collect::<Vec<u32>>();
+    numbers.shuffle(&mut StdRng::seed_from_u64(0x123));
+    for n in &numbers {
+        let vacant = db.entry(n.to_string()).unwrap().vacant().unwrap();
+        vacant
+            .insert()
+            .unwrap()
+            .write_at(0, &n.to_be_bytes())
+            .unwrap();
+    }
+    drop(db);
+
+    let res = Db::<NodePage>::new(&path, Params::new_mock(false));
+    assert!(matches!(
+        res,
+        Err(DbError::WalError(WalError::Collation {
+            stored: 1,
+            given: 0
+        }))
+    ));
+
+    let db = Db::<NodePage>::new_with_collation(&path, Params::new_mock(false), options, collation)
+        .unwrap();
+    let mut it = db.entry(b"").unwrap().into_db_iter();
+    let mut actual = vec![];
+    while let Some((key, _)) = db.next(&mut it).unwrap() {
+        actual.push(String::from_utf8(key).unwrap());
+    }
+    let expected = (0..2000).map(|n| n.to_string()).collect::<Vec<_>>();
+    assert_eq!(actual, expected);
+    assert!(actual.iter().position(|k| k == "9") < actual.iter().position(|k| k == "10"));
+
+    let value = db.entry(b"1234").unwrap().occupied().unwrap().into_value();
+    assert_eq!(value.read_to_vec(0, 4).unwrap(), 1234u32.to_be_bytes());
+    // exact only while the range is in a single leaf
+    let n = db.approximate_count(b"10", b"100").unwrap();
+    assert!((45..=180).contains(&n), "{n}");
+    // the end bound is compared by the collation too, bytewise "9" is past "10"
+    let range = (
+        Bound::Included(b"9".as_slice()),
+        Bound::Included(b"10".as_slice()),
+    );
+    let scanned = db
+        .scan_values(range)
+        .unwrap()
+        .map(|item| item.unwrap().0)
+        .collect::<Vec<_>>();
+    assert_eq!(scanned, [b"9".to_vec(), b"10".to_vec()]);
+    db.check().unwrap();
+}
+
+// ASCII letters in any case are the same key
+struct CaseInsensitive;
+
+impl Collation for CaseInsensitive {
+    fn id(&self) -> u64 {
+        2
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.to_ascii_lowercase().cmp(&b.to_ascii_lowercase())
+    }
+}
+
+#[test]
+fn collation_range_after() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-collation-range-after");
+    let db = Db::<NodePage>::new_with_collation(
+        &path,
+        Params::new_mock(true),
+        IoOptions::default(),
+        Arc::new(CaseInsensitive),
+    )
+    .unwrap();
+    for key in ["apple", "banana", "cherry"] {
+        db.entry(key).unwrap().vacant().unwrap().insert().unwrap();
+    }
+
+    // the key equal by the collation is skipped, though its bytes differ
+    let mut it = db.range_after(b"BANANA").unwrap();
+    assert_eq!(db.next_key(&mut it).unwrap().unwrap(), b"cherry");
+    let mut it = db.range_after(b"Apricot").unwrap();
+    assert_eq!(db.next_key(&mut it).unwrap().unwrap(), b"banana");
+    db.check().unwrap();
+}
@@ -2,42 +2,58 @@ use std::{fs, panic, path::Path};
 
 use tempdir::TempDir;
 
-use crate::{Db, DbError, DbStats, Params, NodePage};
+use crate::{Db, DbError, Entry, DbStats, Params, NodePage, RecoveryReport, MAIN_TREE};
+
+const KEYS: [&[u8]; 3] = [
+    b"some key 1, long",
+    b"some key 6, too                long",
+    b"some key 3",
+];
+
+fn data(s: u8) -> Vec<u8> {
+    128u64.to_le_bytes().into_iter().chain(s..128u8).collect()
+}
+
+fn old_data(i: usize) -> Vec<u8> {
+    data(10 * (i as u8 + 1))
+}
+
+// spans the same bytes as the old data, so a mix would be visible
+fn new_data(i: usize) -> Vec<u8> {
+    data(10 * (i as u8 + 1)).into_iter().map(|b| !b).collect()
+}
 
 fn populate(db: Db<NodePage>) -> Result<DbStats, DbError> {
-    let data = |s| {
-        128u64
-            .to_le_bytes()
-            .into_iter()
-            .chain(s..128u8)
-            .collect::<Vec<u8>>()
-    };
-    db.entry(b"some key 1, long")
-        .vacant()
-        .unwrap()
-        .insert()?
-        .write_at(0, &data(10))?;
-    db.entry(b"some key 6, too                long")
-        .vacant()
-        .unwrap()
-        .insert()?
-        .write_at(0, &data(20))?;
-    db.entry(b"some key 3")
-        .vacant()
-        .unwrap()
-        .insert()?
-        .write_at(0, &data(30))?;
+    for (i, key) in KEYS.into_iter().enumerate() {
+        db.entry(key)?
+            .vacant()
+            .unwrap()
+            .insert()?
+            .write_at(0, &old_data(i))?;
+    }
+    db.sync()?;
+
+    for (i, key) in KEYS.into_iter().enumerate() {
+        let value = db.entry(key)?.occupied().unwrap().into_value();
+        value.write_at(0, &new_data(i))?;
+        value.flush()?;
+    }
 
     Ok(db.stats())
 }
 
 // TODO: proper check
+#[allow(clippy::nonminimal_bool)]
 fn check(db: Db<NodePage>) -> bool {
     let stats = db.stats();
     db.print(|k| std::str::from_utf8(k).unwrap().to_owned());
-    let mut it = db.entry(b"").into_db_iter();
+    let mut it = db.entry(b"").unwrap().into_db_iter();
     let mut cnt = 0;
-    while db.next(&mut it).is_some() {
+    while let Some((key, value)) = db.next(&mut it).unwrap() {
+        let i = KEYS.iter().position(|k| *k == key).unwrap();
+        let value = value.unwrap().read_to_vec(0, old_data(i).len()).unwrap();
+        // either the old or the new content, never a mix
+        assert!(value == old_data(i) || value == new_data(i), "{key:?}");
         cnt += 1;
     }
     log::debug!("{cnt}, {stats:?}");
@@ -62,12 +78,16 @@ fn recovery_test<const MESS_PAGE: bool>() {
     let db = Db::new(&path, Params::new_mock(false)).unwrap();
     let stats = populate(db).unwrap();
 
-    for i in 0..(stats.writes - 1) {
-        crash_test(&path, i, MESS_PAGE);
+    let mut skipped = 0;
+    for i in 0..stats.writes {
+        skipped += crash_test(&path, i, MESS_PAGE);
     }
+    // some of the crashed writes are records
+    assert_eq!(skipped > 0, MESS_PAGE);
 }
 
-fn crash_test(path: &Path, crash_at: u32, mess_page: bool) {
+// returns how many records the crash did tear
+fn crash_test(path: &Path, crash_at: u32, mess_page: bool) -> u32 {
     fs::remove_file(path).unwrap_or_default();
     let db = Db::<NodePage>::new(path, Params::new_mock(true)).unwrap();
     drop(db);
@@ -84,7 +104,12 @@ fn crash_test(path: &Path, crash_at: u32, mess_page: bool) {
     assert_eq!(*err, "intentional panic for test");
 
     let db = Db::new(path, Params::new_mock(false)).unwrap();
+    let report = db.last_recovery().unwrap();
+    // only the page being written is messed
+    assert!(report.skipped_records <= u32::from(mess_page));
     assert!(check(db));
+
+    report.skipped_records
 }
 
 #[test]
@@ -97,3 +122,387 @@ fn recovery() {
 fn recovery_messed_page() {
     recovery_test::<true>();
 }
+
+#[test]
+fn clear() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let template = dir.path().join("test-clear-template");
+    let path = dir.path().join("test-clear");
+
+    let key = |i: u16| format!("key {i:04} with a long tail to spill into key pages");
+    let db = Db::<NodePage>::new(&template, Params::new_mock(true)).unwrap();
+    for i in 0..1000u16 {
+        let vacant = db.entry(key(i)).unwrap().vacant().unwrap();
+        vacant
+            .insert()
+            .unwrap()
+            .write_at(0, &i.to_le_bytes())
+            .unwrap();
+    }
+    db.close().unwrap();
+
+    fs::copy(&template, &path).unwrap();
+    let db = Db::<NodePage>::new(&path, Params::new_mock(false)).unwrap();
+    db.clear().unwrap();
+    db.sync().unwrap();
+    let writes = db.stats().writes;
+    drop(db);
+    assert!(writes > 32, "{writes}");
+
+    // the record comes early, after it the old pages are freed
+    for crash_at in (0..writes).filter(|i| *i < 32 || i % 16 == 0) {
+        fs::copy(&template, &path).unwrap();
+        let err = panic::catch_unwind(|| {
+            let db = Db::<NodePage>::new(&path, Params::new_mock(false))
+                .unwrap()
+                .with_simulator(crash_at, false);
+            db.clear().unwrap();
+            db.close().unwrap();
+        });
+        if err.is_ok() {
+            continue;
+        }
+
+        let db = Db::<NodePage>::new(&path, Params::new_mock(false)).unwrap();
+        let mut it = db.entry(b"").unwrap().into_db_iter();
+        let mut cnt = 0;
+        while let Some((k, value)) = db.next(&mut it).unwrap() {
+            let value = value.unwrap().read_to_vec(0, 2).unwrap();
+            assert_eq!(k, key(cnt).into_bytes());
+            assert_eq!(value, cnt.to_le_bytes());
+            cnt += 1;
+        }
+        // either intact or empty
+        assert!(
+            cnt == 0 || cnt == 1000,
+            "{cnt} keys after crash at {crash_at}"
+        );
+        if cnt == 1000 {
+            db.check().unwrap();
+        }
+    }
+}
+
+#[test]
+fn batch() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let template = dir.path().join("test-batch-template");
+    let path = dir.path().join("test-batch");
+
+    let key = |i: u16| format!("key {i:04} with a long tail to spill into key pages");
+    let index = |i: u16| format!("index {:04}", 9999 - i);
+    let db = Db::<NodePage>::new(&template, Params::new_mock(true)).unwrap();
+    for i in 0..200u16 {
+        db.entry(key(i))
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap();
+        let index = db.tree(1).entry(index(i)).unwrap();
+        index.vacant().unwrap().insert_empty().unwrap();
+    }
+    db.close().unwrap();
+
+    // each batch adds records with their index entries and removes older ones
+    let run = |db: &Db<NodePage>| {
+        for round in 0..3u16 {
+            let mut batch = db.batch();
+            for i in (200 + round * 50)..(250 + round * 50) {
+                batch.insert(MAIN_TREE, key(i).as_bytes()).unwrap();
+                batch.insert_empty(1, index(i).as_bytes()).unwrap();
+            }
+            for i in (round * 50..(round + 1) * 50).step_by(3) {
+                assert!(batch.remove(MAIN_TREE, key(i).as_bytes()).unwrap());
+                assert!(batch.remove(1, index(i).as_bytes()).unwrap());
+            }
+            batch.commit().unwrap();
+            db.sync().unwrap();
+        }
+    };
+
+    fs::copy(&template, &path).unwrap();
+    let db = Db::<NodePage>::new(&path, Params::new_mock(false)).unwrap();
+    run(&db);
+    let writes = db.stats().writes;
+    drop(db);
+    assert!(writes > 32, "{writes}");
+
+    for crash_at in (0..writes).step_by(writes as usize / 100) {
+        fs::copy(&template, &path).unwrap();
+        let err = panic::catch_unwind(|| {
+            let db = Db::<NodePage>::new(&path, Params::new_mock(false))
+                .unwrap()
+                .with_simulator(crash_at, false);
+            run(&db);
+            db.close().unwrap();
+        });
+        if err.is_ok() {
+            continue;
+        }
+
+        let db = Db::<NodePage>::new(&path, Params::new_mock(false)).unwrap();
+        db.check().unwrap();
+        // the record and its index entry are never seen apart
+        for i in 0..350u16 {
+            let record = db.get(key(i).as_bytes()).unwrap().is_some();
+            let indexed = db.tree(1).entry(index(i)).unwrap();
+            let indexed = matches!(indexed, Entry::Empty(_));
+            assert_eq!(record, indexed, "key {i} after crash at {crash_at}");
+        }
+    }
+}
+
+#[test]
+fn insert_with() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let template = dir.path().join("test-insert-with-template");
+    let path = dir.path().join("test-insert-with");
+
+    let key = |i: u16| format!("key {i:04}");
+    let value = |i: u16| format!("value of the key {i:04}").into_bytes();
+    Db::<NodePage>::new(&template, Params::new_mock(true))
+        .unwrap()
+        .close()
+        .unwrap();
+
+    let run = |db: &Db<NodePage>| {
+        for i in 0..300u16 {
+            let vacant = db.entry(key(i)).unwrap().vacant().unwrap();
+            vacant.insert_with(&value(i)).unwrap();
+            if i % 50 == 49 {
+                db.sync().unwrap();
+            }
+        }
+    };
+
+    fs::copy(&template, &path).unwrap();
+    let db = Db::<NodePage>::new(&path, Params::new_mock(false)).unwrap();
+    run(&db);
+    let writes = db.stats().writes;
+    drop(db);
+
+    for crash_at in (0..writes).step_by((writes as usize / 100).max(1)) {
+        fs::copy(&template, &path).unwrap();
+        let err = panic::catch_unwind(|| {
+            let db = Db::<NodePage>::new(&path, Params::new_mock(false))
+                .unwrap()
+                .with_simulator(crash_at, false);
+            run(&db);
+            db.close().unwrap();
+        });
+        if err.is_ok() {
+            continue;
+        }
+
+        let db = Db::<NodePage>::new(&path, Params::new_mock(false)).unwrap();
+        db.check().unwrap();
+        // a key is never there without its bytes
+        for i in 0..300u16 {
+            if let Some(v) = db.get(key(i).as_bytes()).unwrap() {
+                let bytes = v.read_to_vec(0, value(i).len()).unwrap();
+                assert_eq!(bytes, value(i), "key {i} after crash at {crash_at}");
+            }
+        }
+    }
+}
+
+#[test]
+fn checkpointer() {
+    use std::{
+        thread,
+        time::{Duration, Instant},
+    };
+
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let template = dir.path().join("test-checkpointer-template");
+    let path = dir.path().join("test-checkpointer");
+
+    let key = |i: u16| format!("key {i:04}");
+    let value = |i: u16| format!("value of the key {i:04}").into_bytes();
+    Db::<NodePage>::new(&template, Params::new_mock(true))
+        .unwrap()
+        .close()
+        .unwrap();
+
+    let run = |db: &Db<NodePage>, range: std::ops::Range<u16>| {
+        for i in range {
+            let vacant = db.entry(key(i)).unwrap().vacant().unwrap();
+            vacant.insert_with(&value(i)).unwrap();
+        }
+    };
+
+    // the first write after the first half crashes
+    fs::copy(&template, &path).unwrap();
+    let db = Db::<NodePage>::new(&path, Params::new_mock(false)).unwrap();
+    run(&db, 0..100);
+    let crash_at = db.stats().writes;
+    drop(db);
+
+    fs::copy(&template, &path).unwrap();
+    let err = panic::catch_unwind(|| {
+        let db = Db::<NodePage>::new(&path, Params::new_mock(false))
+            .unwrap()
+            .with_simulator(crash_at, false);
+        let _handle = db.start_checkpointer(Duration::from_millis(10));
+        run(&db, 0..100);
+        // the background sync does not count as a write, it never crashes
+        let page_writes = db.metrics().page_writes;
+        let start = Instant::now();
+        while db.metrics().page_writes == page_writes {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(1));
+        }
+        run(&db, 100..200);
+    });
+    assert!(err.is_err());
+
+    // nobody did call `sync`, the checkpoint did persist the first half
+    let db = Db::<NodePage>::new(&path, Params::new_mock(false)).unwrap();
+    db.check().unwrap();
+    for i in 0..100u16 {
+        let v = db.get(key(i).as_bytes()).unwrap().unwrap();
+        assert_eq!(v.read_to_vec(0, value(i).len()).unwrap(), value(i));
+    }
+    assert!(db.get(key(100).as_bytes()).unwrap().is_none());
+}
+
+#[test]
+fn grown_tail() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let template = dir.path().join("test-grown-template");
+    let path = dir.path().join("test-grown");
+
+    let key = |i: u16| format!("key {i:04}");
+    let db = Db::<NodePage>::new(&template, Params::new_mock(true)).unwrap();
+    // the length of the file past the pages
+    let header = fs::metadata(&template).unwrap().len() - u64::from(db.stats().total) * 0x1000;
+    db.close().unwrap();
+
+    // every value takes a page, the file grows many times between syncs
+    let run = |db: &Db<NodePage>, range: std::ops::Range<u16>| {
+        for i in range {
+            let vacant = db.entry(key(i)).unwrap().vacant().unwrap();
+            vacant.insert_with(&i.to_le_bytes()).unwrap();
+            if i % 100 == 99 {
+                db.sync().unwrap();
+            }
+        }
+    };
+
+    fs::copy(&template, &path).unwrap();
+    let db = Db::<NodePage>::new(&path, Params::new_mock(false)).unwrap();
+    run(&db, 0..500);
+    let writes = db.stats().writes;
+    drop(db);
+
+    let mut cut = 0;
+    for crash_at in (0..writes).step_by((writes as usize / 50).max(1)) {
+        fs::copy(&template, &path).unwrap();
+        let err = panic::catch_unwind(|| {
+            let db = Db::<NodePage>::new(&path, Params::new_mock(false))
+                .unwrap()
+                .with_simulator(crash_at, false);
+            run(&db, 0..500);
+            db.close().unwrap();
+        });
+        if err.is_ok() {
+            continue;
+        }
+        let crashed = fs::metadata(&path).unwrap().len();
+
+        let db = Db::<NodePage>::new(&path, Params::new_mock(false)).unwrap();
+        let len = fs::metadata(&path).unwrap().len();
+        assert_eq!(len, header + u64::from(db.stats().total) * 0x1000);
+        let report = db.last_recovery().unwrap();
+        assert!(len >= crashed || report.truncated_pages > 0);
+        assert_eq!(report.skipped_records, 0);
+        cut += usize::from(len < crashed);
+        db.check().unwrap();
+        let present = (0..500).filter(|i| db.get(key(*i).as_bytes()).unwrap().is_some());
+        let present = present.count() as u16;
+
+        // the pages past the record are used again
+        run(&db, present..600);
+        db.close().unwrap();
+        let db = Db::<NodePage>::new(&path, Params::new_mock(false)).unwrap();
+        assert!(db.last_recovery().unwrap().is_clean());
+        db.check().unwrap();
+        for i in 0..600u16 {
+            let value = db.get(key(i).as_bytes()).unwrap().unwrap();
+            assert_eq!(value.read_to_vec(0, 2).unwrap(), i.to_le_bytes());
+        }
+    }
+    assert!(cut > 0);
+}
+
+#[test]
+fn recovery_report() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-report");
+    let crashed = dir.path().join("test-report-crashed");
+
+    let key = |i: u16| format!("key {i:04}");
+    let db = Db::<NodePage>::new(&path, Params::new_mock(true)).unwrap();
+    assert_eq!(db.last_recovery(), None);
+    for i in 0..100u16 {
+        let vacant = db.entry(key(i)).unwrap().vacant().unwrap();
+        vacant.insert_with(&i.to_le_bytes()).unwrap();
+    }
+    // the removed value stays allocated for the caller
+    let entry = db.entry(key(7)).unwrap().occupied().unwrap();
+    let value = entry.remove().unwrap();
+    db.sync().unwrap();
+    let seq = db.stats().seq;
+    fs::copy(&path, &crashed).unwrap();
+    drop(value);
+    drop(db);
+
+    let db = Db::<NodePage>::new(&crashed, Params::new_mock(false)).unwrap();
+    let expected = RecoveryReport {
+        orphan_reclaimed: true,
+        ..RecoveryReport::default()
+    };
+    assert_eq!(db.last_recovery(), Some(expected));
+    assert_eq!(db.stats().seq_at_open, seq);
+    db.insert_tombstone(b"other").unwrap();
+    let stats = db.stats();
+    assert!(stats.seq > seq);
+    assert_eq!(stats.seq_at_open, seq);
+    db.check().unwrap();
+    drop(db);
+
+    let db = Db::<NodePage>::new(&crashed, Params::new_mock(false)).unwrap();
+    assert!(db.last_recovery().unwrap().is_clean());
+}
+
+#[test]
+fn unwritten_slots() {
+    use rand::RngCore;
+
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-unwritten-slots");
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(true)).unwrap();
+    db.insert_tombstone(b"key").unwrap();
+    drop(db);
+
+    // the log slots never written are zeros, even if encrypted, they are not torn
+    let db = Db::<NodePage>::new(&path, Params::new_mock(false)).unwrap();
+    assert!(db.last_recovery().unwrap().is_clean());
+    drop(db);
+
+    let header = Params::new_mock(false).header_size();
+    let mut bytes = fs::read(&path).unwrap();
+    let slot = (0..256)
+        .map(|n| header + n * 0x1000)
+        .rfind(|o| bytes[*o..][..0x1000].iter().all(|b| *b == 0))
+        .unwrap();
+    rand::thread_rng().fill_bytes(&mut bytes[slot..][..0x1000]);
+    fs::write(&path, bytes).unwrap();
+
+    // garbage is
+    let db = Db::<NodePage>::new(&path, Params::new_mock(false)).unwrap();
+    assert_eq!(db.last_recovery().unwrap().skipped_records, 1);
+    assert!(db.entry(b"key").unwrap().empty().is_some());
+}
@@ -0,0 +1,129 @@
+use rand::Rng;
+use tempdir::TempDir;
+
+use crate::{ChangeSet, Db, DbError, Entry, NodePage, Params, WalError};
+
+type Content = Vec<(Vec<u8>, Option<Vec<u8>>)>;
+
+fn content(db: &Db<NodePage>) -> Content {
+    let mut it = db.iter_from(b"").unwrap();
+    let mut content = vec![];
+    while let Some((key, value)) = db.next(&mut it).unwrap() {
+        let value = value.map(|value| value.read_to_vec(0, 8).unwrap());
+        content.push((key, value));
+    }
+    content
+}
+
+// through the bytes, as it goes over the network
+fn ship(primary: &Db<NodePage>, replica: &Db<NodePage>) -> ChangeSet {
+    let changes = primary.export_changes(replica.version()).unwrap();
+    let mut bytes = vec![];
+    changes.write_to(&mut bytes).unwrap();
+    let changes = ChangeSet::read_from(bytes.as_slice()).unwrap();
+    replica.apply_changes(&changes).unwrap();
+    assert_eq!(replica.version(), primary.version());
+    changes
+}
+
+#[test]
+fn replica() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-primary");
+    let replica_path = dir.path().join("test-replica");
+    let mut rng = rand::thread_rng();
+
+    let primary = Db::<NodePage>::new(&path, Params::new_mock(true)).unwrap();
+    for i in 0..300u16 {
+        let key = format!("old {i:04}");
+        primary
+            .entry(key)
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap();
+    }
+
+    // the replica starts with the whole database
+    let replica = Db::<NodePage>::new(&replica_path, Params::new_mock(true)).unwrap();
+    let changes = primary.export_changes(0).unwrap();
+    assert!(changes.is_full());
+    replica.apply_changes(&changes).unwrap();
+    assert_eq!(content(&replica), content(&primary));
+
+    for round in 0..10 {
+        for _ in 0..200 {
+            let key = format!("key {:03}", rng.gen_range(0..500u16));
+            match primary.entry(key.as_bytes()).unwrap() {
+                Entry::Vacant(v) if rng.gen() => {
+                    let value = v.insert().unwrap();
+                    value.write_at(0, &rng.gen::<u64>().to_le_bytes()).unwrap();
+                }
+                Entry::Vacant(v) => v.insert_empty().unwrap(),
+                Entry::Occupied(v) if rng.gen() => {
+                    let value = v.into_value();
+                    value.write_at(0, &rng.gen::<u64>().to_le_bytes()).unwrap();
+                }
+                Entry::Occupied(v) => drop(v.remove().unwrap()),
+                Entry::Empty(v) => v.remove().unwrap(),
+            }
+        }
+        if round == 5 {
+            primary.compact().unwrap();
+        }
+        let changes = ship(&primary, &replica);
+        assert!(!changes.is_full());
+        assert_eq!(content(&replica), content(&primary));
+        replica.check().unwrap();
+    }
+
+    // values written in place are shipped again until a record follows them
+    primary
+        .entry(b"last")
+        .unwrap()
+        .vacant()
+        .unwrap()
+        .insert_empty()
+        .unwrap();
+    ship(&primary, &replica);
+
+    // nothing did change, still a record to follow
+    let changes = ship(&primary, &replica);
+    assert!(changes.is_empty());
+
+    // a broken stream is rejected
+    let changes = primary.export_changes(replica.version()).unwrap();
+    let mut bytes = vec![];
+    changes.write_to(&mut bytes).unwrap();
+    bytes[100] ^= 1;
+    assert!(matches!(
+        ChangeSet::read_from(bytes.as_slice()),
+        Err(DbError::BadChangeSet)
+    ));
+
+    // the reopened replica did write its own records
+    replica.close().unwrap();
+    let replica = Db::<NodePage>::new(&replica_path, Params::new_mock(false)).unwrap();
+    primary
+        .entry(b"new")
+        .unwrap()
+        .vacant()
+        .unwrap()
+        .insert()
+        .unwrap();
+    let changes = primary.export_changes(primary.version() - 1).unwrap();
+    assert!(matches!(
+        replica.apply_changes(&changes),
+        Err(DbError::WalError(WalError::Diverged))
+    ));
+    replica
+        .apply_changes(&primary.export_changes(0).unwrap())
+        .unwrap();
+    assert_eq!(content(&replica), content(&primary));
+    replica.close().unwrap();
+
+    let replica = Db::<NodePage>::new(&replica_path, Params::new_mock(false)).unwrap();
+    replica.check().unwrap();
+    assert_eq!(content(&replica), content(&primary));
+}
@@ -0,0 +1,567 @@
+use std::iter;
+
+use rand::{rngs::StdRng, seq::SliceRandom};
+use tempdir::TempDir;
+
+use crate::{Db, DbError, NodePage, Params, SizeHistogram, MAIN_TREE, node::Node};
+
+use super::with_db;
+
+#[test]
+fn cache_hits() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        // the root is pinned, the path to a key must be longer to miss
+        for i in 0..1000u16 {
+            let key = format!("key {i:04}");
+            db.entry(key.as_bytes())
+                .unwrap()
+                .vacant()
+                .unwrap()
+                .insert()
+                .unwrap();
+        }
+        let before = db.stats();
+        assert!(db.entry(b"key").unwrap().vacant().is_some());
+        let first = db.stats();
+        assert!(db.entry(b"key").unwrap().vacant().is_some());
+        let second = db.stats();
+
+        assert!(first.cache_misses > before.cache_misses);
+        assert_eq!(second.cache_misses, first.cache_misses);
+        assert!(second.cache_hits > first.cache_hits);
+    })
+}
+
+#[test]
+fn fragmentation() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        for i in 0..1000u16 {
+            let key = format!("key {i:04}");
+            db.entry(key.as_bytes())
+                .unwrap()
+                .vacant()
+                .unwrap()
+                .insert()
+                .unwrap();
+        }
+        let before = db.stats();
+        for i in (0..1000u16).filter(|i| i % 10 != 0) {
+            let key = format!("key {i:04}");
+            db.entry(key.as_bytes())
+                .unwrap()
+                .occupied()
+                .unwrap()
+                .remove()
+                .unwrap();
+        }
+        let after = db.stats();
+        let fast = db.stats_fast();
+
+        // released pages go to the freelist with the next change
+        assert_eq!(after.garbage, 0);
+        assert!(after.freelist_len >= before.freelist_len + 800);
+        assert_eq!(after.free, after.freelist_len);
+        assert_eq!(fast.freelist_len, after.freelist_len);
+        assert!(after.fragmentation > 0.5 && before.fragmentation < 0.1);
+
+        db.compact().unwrap();
+        assert!(db.stats().fragmentation < 0.1);
+    })
+}
+
+#[test]
+fn version() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-version");
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(true)).unwrap();
+    let mut version = db.version();
+    for i in 0..100u16 {
+        let key = format!("key {i:03}");
+        db.entry(key).unwrap().vacant().unwrap().insert().unwrap();
+        assert!(db.version() > version);
+        version = db.version();
+        // reading does not change it
+        db.get(b"key 000").unwrap().unwrap();
+        assert_eq!(db.version(), version);
+    }
+    db.close().unwrap();
+
+    // the final record of close, opening writes nothing
+    for _ in 0..2 {
+        let db = Db::<NodePage>::new(&path, Params::new_mock(false)).unwrap();
+        assert_eq!(db.version(), version + 1);
+        version = db.version();
+        db.close().unwrap();
+    }
+}
+
+#[test]
+fn changes_since() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-changes");
+    let key = |i: u16| format!("key {i:04}").into_bytes();
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(true)).unwrap();
+    for i in 0..100 {
+        db.entry(key(i))
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap();
+    }
+    let version = db.version();
+    let (keys, current) = db.changes_since(version).unwrap();
+    assert!(keys.is_empty());
+    assert_eq!(current, version);
+
+    for i in (100..150).rev() {
+        let vacant = db.entry(key(i)).unwrap().vacant().unwrap();
+        vacant.insert_empty().unwrap();
+    }
+    for i in 0..10 {
+        let occupied = db.entry(key(i)).unwrap().occupied().unwrap();
+        occupied.remove().unwrap();
+    }
+    // another tree is not in the feed, neither is writing a value
+    let index = db.tree(1).entry(key(0)).unwrap().vacant().unwrap();
+    index.insert_empty().unwrap();
+    db.get(&key(50))
+        .unwrap()
+        .unwrap()
+        .write_at(0, b"value")
+        .unwrap();
+    let mut batch = db.batch();
+    batch.insert(MAIN_TREE, &key(150)).unwrap();
+    batch.insert_empty(1, &key(1)).unwrap();
+    batch.commit().unwrap();
+
+    let (keys, current) = db.changes_since(version).unwrap();
+    let expected = (0..10).chain(100..151).map(key).collect::<Vec<_>>();
+    assert_eq!(keys, expected);
+    assert_eq!(current, db.version());
+    let (keys, _) = db.changes_since(current).unwrap();
+    assert!(keys.is_empty());
+
+    // the oldest changes are forgotten
+    for i in 0..5000 {
+        let key = format!("more {i:04}");
+        db.entry(key)
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert_empty()
+            .unwrap();
+    }
+    assert!(matches!(
+        db.changes_since(version),
+        Err(DbError::VersionTooOld { version: v, .. }) if v == version
+    ));
+    let recent = db.version();
+    db.entry(key(0))
+        .unwrap()
+        .vacant()
+        .unwrap()
+        .insert_empty()
+        .unwrap();
+    assert_eq!(db.changes_since(recent).unwrap().0, [key(0)]);
+
+    db.clear().unwrap();
+    assert!(db.changes_since(recent).is_err());
+    let cleared = db.version();
+    db.entry(key(1))
+        .unwrap()
+        .vacant()
+        .unwrap()
+        .insert_empty()
+        .unwrap();
+    assert_eq!(db.changes_since(cleared).unwrap().0, [key(1)]);
+    db.close().unwrap();
+
+    // the feed starts anew
+    let db = Db::<NodePage>::new(&path, Params::new_mock(false)).unwrap();
+    assert!(db.changes_since(cleared).is_err());
+    assert!(db.changes_since(db.version()).unwrap().0.is_empty());
+}
+
+#[test]
+fn metrics() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-metrics");
+    let key = |i: u16| format!("key {i:04}").into_bytes();
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(true)).unwrap();
+    let start = db.metrics();
+    assert_eq!(start.lookups, 0);
+    assert!(start.page_writes > 0);
+
+    for i in 0..100 {
+        db.entry(key(i))
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap();
+    }
+    for i in 0..10 {
+        db.entry(key(i))
+            .unwrap()
+            .occupied()
+            .unwrap()
+            .remove()
+            .unwrap();
+    }
+    let mut batch = db.batch();
+    batch.insert(MAIN_TREE, &key(100)).unwrap();
+    batch.insert_empty(MAIN_TREE, &key(101)).unwrap();
+    batch.remove(MAIN_TREE, &key(10)).unwrap();
+    batch.commit().unwrap();
+    // a dropped batch changes nothing
+    let mut batch = db.batch();
+    batch.remove(MAIN_TREE, &key(11)).unwrap();
+    drop(batch);
+    assert!(db.get(&key(11)).unwrap().is_some());
+    db.multi_get(&[&key(20), &key(30), &key(1000)]).unwrap();
+
+    let changed = db.metrics();
+    assert_eq!(changed.lookups, 110 + 1 + 3);
+    assert_eq!(changed.inserts, 102);
+    assert_eq!(changed.removes, 11);
+    assert!(changed.cache_hits > start.cache_hits);
+    // the released pages are held until the log is on the disk,
+    // so the changes sync on their own once too many are held
+    assert!(changed.page_writes > start.page_writes);
+
+    db.sync().unwrap();
+    let synced = db.metrics();
+    assert!(synced.page_writes > changed.page_writes);
+    assert_eq!(synced.page_reads, changed.page_reads);
+
+    // the cache is empty after the sync, the descent reads the file
+    db.get(&key(50)).unwrap().unwrap();
+    let read = db.metrics();
+    assert!(read.cache_misses > synced.cache_misses);
+    assert_eq!(
+        read.page_reads - synced.page_reads,
+        read.cache_misses - synced.cache_misses
+    );
+    assert_eq!(read.lookups, synced.lookups + 1);
+}
+
+#[test]
+fn release_cache() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-release");
+    let key = |i: u16| format!("key {i:04}").into_bytes();
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(true)).unwrap();
+    for i in 0..2000 {
+        let value = db
+            .entry(key(i))
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap();
+        value.write_at(0, &i.to_le_bytes()).unwrap();
+    }
+    db.sync().unwrap();
+
+    // a big scan leaves the pages in the cache
+    let mut it = db.iter_from(b"").unwrap();
+    while let Some((_, value)) = db.next(&mut it).unwrap() {
+        value.unwrap().read_to_vec(0, 2).unwrap();
+    }
+    // not written yet, dropping it would lose the change
+    db.get(&key(7))
+        .unwrap()
+        .unwrap()
+        .write_at(0, b"xx")
+        .unwrap();
+    let scanned = db.stats_fast().cache_pages;
+    assert!(scanned > 2000);
+
+    assert_eq!(db.release_cache().unwrap(), scanned);
+    assert_eq!(db.stats_fast().cache_pages, 0);
+    assert_eq!(db.release_cache().unwrap(), 0);
+
+    let value = db.get(&key(1999)).unwrap().unwrap();
+    assert_eq!(value.read_to_vec(0, 2).unwrap(), 1999u16.to_le_bytes());
+    let value = db.get(&key(7)).unwrap().unwrap();
+    assert_eq!(value.read_to_vec(0, 2).unwrap(), b"xx");
+    // two descents, a node and its key and prefix pages on each level, and two values
+    let depth = iter::successors(Some(2000_usize), |n| {
+        (*n > 1).then(|| n.div_ceil(<NodePage>::M / 2))
+    })
+    .count() as u32;
+    assert!(db.stats_fast().cache_pages <= 2 * (3 * depth + 1));
+
+    // in memory the cache is the only copy
+    let db = Db::<NodePage>::in_memory().unwrap();
+    db.entry(key(0))
+        .unwrap()
+        .vacant()
+        .unwrap()
+        .insert()
+        .unwrap();
+    assert_eq!(db.release_cache().unwrap(), 0);
+    assert!(db.get(&key(0)).unwrap().is_some());
+}
+
+#[test]
+fn pinned() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-pinned");
+    let key = |i: u16| format!("key {i:04}").into_bytes();
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(true)).unwrap();
+    for i in 0..2000 {
+        let value = db
+            .entry(key(i))
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap();
+        value.write_at(0, &i.to_le_bytes()).unwrap();
+    }
+    assert!(db.pin(&key(1000)).unwrap());
+    assert!(!db.pin(&key(2000)).unwrap());
+    db.sync().unwrap();
+    // the root, the trees page is not there yet, and the metadata page
+    assert_eq!(db.stats_fast().pinned_pages, 2);
+
+    let misses = |i: u16| {
+        let before = db.stats_fast().cache_misses;
+        let value = db.get(&key(i)).unwrap().unwrap();
+        assert_eq!(value.read_to_vec(0, 2).unwrap(), i.to_le_bytes());
+        db.stats_fast().cache_misses - before
+    };
+    for _ in 0..3 {
+        // the scan fills the cache, dropping it all does not touch the pinned pages
+        let mut it = db.iter_from(b"").unwrap();
+        while let Some((_, value)) = db.next(&mut it).unwrap() {
+            value.unwrap().read_to_vec(0, 2).unwrap();
+        }
+        let scanned = db.stats_fast().cache_pages;
+        assert_eq!(db.release_cache().unwrap(), scanned);
+        assert_eq!(db.stats_fast().cache_pages, 0);
+        let pinned = misses(1000);
+        db.release_cache().unwrap();
+        // the neighbour is on the same path, but its metadata page is read again
+        assert_eq!(misses(1001), pinned + 1);
+    }
+
+    assert!(db.unpin(&key(1000)).unwrap());
+    assert!(!db.unpin(&key(1000)).unwrap());
+    db.release_cache().unwrap();
+    assert_eq!(db.stats_fast().pinned_pages, 1);
+    let unpinned = misses(1000);
+    db.release_cache().unwrap();
+    assert_eq!(misses(1001), unpinned);
+
+    // the page of a removed value is unpinned once it is freed, the next remove frees it
+    assert!(db.pin(&key(500)).unwrap());
+    assert_eq!(db.stats_fast().pinned_pages, 2);
+    for i in [500, 501] {
+        let occupied = db.entry(key(i)).unwrap().occupied().unwrap();
+        occupied.remove().unwrap();
+    }
+    db.sync().unwrap();
+    assert_eq!(db.stats_fast().pinned_pages, 1);
+
+    // clearing frees every value at once
+    assert!(db.pin(&key(600)).unwrap());
+    db.clear().unwrap();
+    db.sync().unwrap();
+    assert_eq!(db.stats_fast().pinned_pages, 1);
+}
+
+#[test]
+fn key_prefix() {
+    let keys = |shared: bool| {
+        (0..10_000u16).map(move |i| {
+            let (prefix, suffix) = ([b'p'; 200], format!("{i:05}"));
+            if shared {
+                [&prefix[..], suffix.as_bytes()].concat()
+            } else {
+                [suffix.as_bytes(), &prefix[..]].concat()
+            }
+        })
+    };
+    let fill = |db: &Db<NodePage>, rng: &mut StdRng, shared: bool| {
+        let mut keys = keys(shared).collect::<Vec<_>>();
+        keys.shuffle(rng);
+        for key in &keys {
+            let vacant = db.entry(key).unwrap().vacant().unwrap();
+            vacant.insert_empty().unwrap();
+        }
+        db.stats().used
+    };
+
+    let plain = with_db(0x123, |db, rng| fill(&db, rng, false));
+    with_db(0x123, |db, rng| {
+        let used = fill(&db, rng, true);
+        log::info!("used {used}, without the common prefix {plain}");
+        // the leaf keeps the prefix once instead of a chunk of it for each key
+        assert!(used * 3 < plain * 2);
+        db.check().unwrap();
+
+        let mut it = db.iter_from(b"").unwrap();
+        for key in keys(true) {
+            assert_eq!(db.next_key(&mut it).unwrap().unwrap(), key);
+        }
+        assert!(db.next_key(&mut it).unwrap().is_none());
+
+        // a key without the prefix goes in the first leaf, then half of the others go away
+        db.insert_tombstone(b"other").unwrap();
+        for key in keys(true).step_by(2) {
+            let empty = db.entry(&key).unwrap().empty().unwrap();
+            empty.into_vacant().unwrap();
+        }
+        db.check().unwrap();
+        let mut it = db.iter_from(b"").unwrap();
+        assert_eq!(db.next_key(&mut it).unwrap().unwrap(), b"other");
+        for key in keys(true).skip(1).step_by(2) {
+            assert_eq!(db.next_key(&mut it).unwrap().unwrap(), key);
+            assert!(db.entry(&key).unwrap().empty().is_some());
+        }
+        assert!(db.next_key(&mut it).unwrap().is_none());
+    })
+}
+
+#[test]
+fn size_histogram() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        // short keys with short values, long keys with long values
+        for i in 0..1000u32 {
+            let key = format!("s {i:04}");
+            db.entry(key)
+                .unwrap()
+                .vacant()
+                .unwrap()
+                .insert_small(&[1; 10])
+                .unwrap();
+        }
+        for i in 0..300u32 {
+            let key = format!("long key {i:04} {}", "x".repeat(86));
+            let value = db.entry(key).unwrap().vacant().unwrap().insert().unwrap();
+            value.write_at(0, &[2; 300]).unwrap();
+        }
+        db.insert_tombstone(b"empty").unwrap();
+
+        let stop = AtomicBool::new(false);
+        let histogram = db.size_histogram(&stop).unwrap();
+        let mut keys = [0; SizeHistogram::BUCKETS];
+        keys[SizeHistogram::bucket(6)] = 1000;
+        keys[SizeHistogram::bucket(100)] = 300;
+        keys[SizeHistogram::bucket(5)] += 1;
+        let mut values = [0; SizeHistogram::BUCKETS];
+        values[SizeHistogram::bucket(10)] = 1000;
+        values[SizeHistogram::bucket(300)] = 300;
+        let expected = SizeHistogram {
+            keys,
+            values,
+            key_bytes: 1000 * 6 + 300 * 100 + 5,
+            value_bytes: 1000 * 10 + 300 * 300,
+            records: 1301,
+            complete: true,
+        };
+        assert_eq!(histogram, expected);
+        assert_eq!(SizeHistogram::bucket(0), 0);
+        assert_eq!(SizeHistogram::bucket(1024), 11);
+        assert!(SizeHistogram::bucket(0x1000) < SizeHistogram::BUCKETS);
+
+        stop.store(true, Ordering::Relaxed);
+        let histogram = db.size_histogram(&stop).unwrap();
+        assert_eq!(histogram, SizeHistogram::default());
+    })
+}
+
+#[test]
+fn write_amplification() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        // pages written by the insert and whether it did split a node
+        let insert = |i: u32| {
+            let used = db.stats_fast().used;
+            let key = format!("key {i:05}");
+            let vacant = db.entry(key).unwrap().vacant().unwrap();
+            vacant.insert_small(&[1; 10]).unwrap();
+            let stats = db.stats_fast();
+            (stats.pages_written_last_op, stats.used > used)
+        };
+
+        // the first insert into the new file sets up the root
+        insert(0);
+        // the root is the only leaf
+        let (leaf_only, split) = insert(1);
+        assert!(!split);
+        let mut i = 2;
+        let split_writes = loop {
+            match insert(i) {
+                (writes, true) => break writes,
+                (writes, false) => assert_eq!(writes, leaf_only),
+            }
+            i += 1;
+        };
+        assert!(split_writes > leaf_only + 1);
+
+        // one more level, one more page on the path
+        let (writes, split) = insert(i + 1);
+        assert!(!split);
+        assert_eq!(writes, leaf_only + 1);
+
+        // the path again, and the page the removed inline value is returned in
+        db.entry(format!("key {:05}", i + 1))
+            .unwrap()
+            .occupied()
+            .unwrap()
+            .remove()
+            .unwrap();
+        assert!(db.stats_fast().pages_written_last_op > leaf_only + 1);
+    })
+}
+
+#[test]
+fn page_kinds() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        const N: u32 = 500;
+        const M: usize = 100;
+
+        for i in 0..N {
+            let vacant = db.entry(format!("key {i:04}")).unwrap().vacant().unwrap();
+            vacant.insert_with(&[1; M]).unwrap();
+        }
+        let stats = db.stats_full();
+        assert_eq!(stats.data_pages, N);
+        assert_eq!(stats.tree_pages + stats.data_pages, stats.used);
+        assert!(stats.tree_pages > 0);
+        // the other stats do not walk the trees
+        assert_eq!(db.stats().data_pages, 0);
+
+        // the inline values live in the leaves
+        for i in 0..N {
+            let vacant = db
+                .entry(format!("inline {i:04}"))
+                .unwrap()
+                .vacant()
+                .unwrap();
+            vacant.insert_small(&[1; 10]).unwrap();
+        }
+        let inline = db.stats_full();
+        assert_eq!(inline.data_pages, N);
+        assert!(inline.tree_pages > stats.tree_pages);
+        assert_eq!(inline.tree_pages + inline.data_pages, inline.used);
+
+        // so do the pages of the other trees
+        let tree = db.tree(1);
+        let vacant = tree.entry(b"key").unwrap().vacant().unwrap();
+        vacant.insert_with(&[1; M]).unwrap();
+        let trees = db.stats_full();
+        assert_eq!(trees.data_pages, N + 1);
+        assert_eq!(trees.tree_pages + trees.data_pages, trees.used);
+    })
+}
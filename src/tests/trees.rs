@@ -0,0 +1,171 @@
+use tempdir::TempDir;
+
+use crate::{Db, NodePage, Params, MAIN_TREE};
+
+fn key(i: u16) -> String {
+    format!("key {i:04} with a long tail to spill into key pages")
+}
+
+fn index(i: u16) -> String {
+    format!("index {:04}", 9999 - i)
+}
+
+// every key of the tree in order
+fn keys(db: &Db<NodePage>, tree: u8) -> Vec<Vec<u8>> {
+    let mut it = db.tree(tree).iter_from(b"").unwrap();
+    let mut keys = vec![];
+    while let Some(key) = db.next_key(&mut it).unwrap() {
+        keys.push(key);
+    }
+    keys
+}
+
+#[test]
+fn trees() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-trees");
+    let dest = dir.path().join("test-trees-copy");
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(true)).unwrap();
+    assert!(db.tree(1).get(b"key").unwrap().is_none());
+    assert!(keys(&db, 1).is_empty());
+
+    // a lookup in a tree that does not exist writes nothing
+    let seq = db.stats_fast().seq;
+    let vacant = db.tree(1).entry(b"key").unwrap().vacant().unwrap();
+    assert_eq!(vacant.insertion_point_key().unwrap(), None);
+    drop(vacant);
+    let mut it = db.tree(1).entry(b"key").unwrap().into_db_iter();
+    assert_eq!(db.next_key(&mut it).unwrap(), None);
+    assert_eq!(db.stats_fast().seq, seq);
+
+    for i in 0..1000u16 {
+        let tree = db.tree((i % 3) as u8);
+        let vacant = tree.entry(key(i)).unwrap().vacant().unwrap();
+        vacant
+            .insert()
+            .unwrap()
+            .write_at(0, &i.to_le_bytes())
+            .unwrap();
+    }
+    for i in (0..1000u16).filter(|i| i % 5 == 0) {
+        let tree = db.tree((i % 3) as u8);
+        let occupied = tree.entry(key(i)).unwrap().occupied().unwrap();
+        occupied.remove().unwrap();
+    }
+    db.check().unwrap();
+
+    let check = |db: &Db<NodePage>| {
+        for id in 0..3 {
+            let expected = (0..1000u16)
+                .filter(|i| i % 3 == u16::from(id) && i % 5 != 0)
+                .collect::<Vec<_>>();
+            let expected_keys = expected.iter().map(|i| key(*i).into_bytes());
+            assert_eq!(keys(db, id), expected_keys.collect::<Vec<_>>());
+            for i in expected {
+                let value = db.tree(id).get(key(i).as_bytes()).unwrap().unwrap();
+                assert_eq!(value.read_to_vec(0, 2).unwrap(), i.to_le_bytes());
+            }
+        }
+        assert!(keys(db, 3).is_empty());
+    };
+    check(&db);
+    // the main tree is the one of `Db::entry`
+    assert_eq!(keys(&db, MAIN_TREE), {
+        let mut it = db.iter_from(b"").unwrap();
+        let mut keys = vec![];
+        while let Some(key) = db.next_key(&mut it).unwrap() {
+            keys.push(key);
+        }
+        keys
+    });
+
+    db.backup_to(&dest, Params::new_mock(true)).unwrap();
+    db.compact().unwrap();
+    db.check().unwrap();
+    check(&db);
+    db.close().unwrap();
+
+    for path in [&path, &dest] {
+        let db = Db::<NodePage>::new(path, Params::new_mock(false)).unwrap();
+        db.check().unwrap();
+        check(&db);
+    }
+}
+
+#[test]
+fn batch() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-batch");
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(true)).unwrap();
+    let empty = db.stats().used;
+
+    // a record and its index entry at once
+    for i in 0..100u16 {
+        let mut batch = db.batch();
+        let value = batch.insert(MAIN_TREE, key(i).as_bytes()).unwrap();
+        value.write_at(0, &i.to_le_bytes()).unwrap();
+        batch.insert_empty(1, index(i).as_bytes()).unwrap();
+        batch.commit().unwrap();
+    }
+    db.check().unwrap();
+
+    // dropped without commit, nothing changes and no page is lost
+    let stats = db.stats();
+    let mut batch = db.batch();
+    for i in 100..1000u16 {
+        batch.insert(MAIN_TREE, key(i).as_bytes()).unwrap();
+        batch.insert_empty(1, index(i).as_bytes()).unwrap();
+    }
+    assert!(batch.remove(MAIN_TREE, key(0).as_bytes()).unwrap());
+    assert!(batch.remove(1, index(0).as_bytes()).unwrap());
+    drop(batch);
+    db.check().unwrap();
+    assert_eq!(db.stats().used, stats.used);
+    assert_eq!(keys(&db, MAIN_TREE).len(), 100);
+    assert_eq!(keys(&db, 1).len(), 100);
+
+    // big enough to refill the cache many times
+    let mut batch = db.batch();
+    for i in 100..3000u16 {
+        batch.insert(MAIN_TREE, key(i).as_bytes()).unwrap();
+        batch.insert_empty(1, index(i).as_bytes()).unwrap();
+    }
+    for i in (0..3000u16).step_by(2) {
+        assert!(batch.remove(MAIN_TREE, key(i).as_bytes()).unwrap());
+        assert!(batch.remove(1, index(i).as_bytes()).unwrap());
+    }
+    assert!(!batch.remove(1, index(0).as_bytes()).unwrap());
+    batch.commit().unwrap();
+    db.check().unwrap();
+    assert_eq!(keys(&db, MAIN_TREE).len(), 1500);
+    assert_eq!(keys(&db, 1).len(), 1500);
+
+    let value = db.get(key(99).as_bytes()).unwrap().unwrap();
+    assert_eq!(value.read_to_vec(0, 2).unwrap(), 99u16.to_le_bytes());
+    drop(value);
+
+    // an existing value is returned as is
+    let used = db.stats().used;
+    let mut batch = db.batch();
+    let value = batch.insert(MAIN_TREE, key(99).as_bytes()).unwrap();
+    assert_eq!(value.read_to_vec(0, 2).unwrap(), 99u16.to_le_bytes());
+    drop(value);
+    for i in 0..3000u16 {
+        batch.remove(MAIN_TREE, key(i).as_bytes()).unwrap();
+        batch.remove(1, index(i).as_bytes()).unwrap();
+    }
+    batch.commit().unwrap();
+    db.reclaim().unwrap();
+    db.check().unwrap();
+    // only the emptied branches of both trees and the page of the trees are left
+    assert!(db.stats().used < empty + 10);
+    assert!(db.stats().used < used);
+    db.close().unwrap();
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(false)).unwrap();
+    db.check().unwrap();
+    assert!(keys(&db, MAIN_TREE).is_empty());
+    assert!(keys(&db, 1).is_empty());
+}
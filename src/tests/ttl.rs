@@ -0,0 +1,169 @@
+use std::{
+    ops::ControlFlow,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use crate::NodePage;
+
+use super::with_db;
+
+static NOW: AtomicU64 = AtomicU64::new(1_000_000);
+
+fn clock() -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_millis(NOW.load(Ordering::SeqCst))
+}
+
+#[test]
+fn expire() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        let db = db.with_clock(clock);
+        for i in 0..300u16 {
+            let key = format!("key {i:03}");
+            let vacant = db.entry(key.as_bytes()).unwrap().vacant().unwrap();
+            let value = if i % 3 == 0 {
+                vacant.insert_with_ttl(Duration::from_secs(10)).unwrap()
+            } else {
+                vacant.insert().unwrap()
+            };
+            value.write_at(0, &i.to_le_bytes()).unwrap();
+        }
+        let mut occupied = db.entry(b"key 001").unwrap().occupied().unwrap();
+        occupied.set_ttl(Some(Duration::from_secs(5))).unwrap();
+        drop(occupied);
+        let mut occupied = db.entry(b"key 003").unwrap().occupied().unwrap();
+        occupied.set_ttl(None).unwrap();
+        drop(occupied);
+
+        assert!(db.get(b"key 000").unwrap().is_some());
+        NOW.fetch_add(20_000, Ordering::SeqCst);
+        assert!(db.get(b"key 000").unwrap().is_none());
+        assert!(db.get(b"key 001").unwrap().is_none());
+        assert!(db.get(b"key 003").unwrap().is_some());
+        let occupied = db.entry(b"key 000").unwrap().occupied().unwrap();
+        assert!(occupied.is_expired().unwrap());
+        drop(occupied);
+
+        assert_eq!(db.purge_expired().unwrap(), 100);
+        assert_eq!(db.purge_expired().unwrap(), 0);
+        for i in 0..300u16 {
+            let key = format!("key {i:03}");
+            let expired = (i % 3 == 0 && i != 3) || i == 1;
+            let value = db.get(key.as_bytes()).unwrap();
+            assert_eq!(value.is_none(), expired, "{key}");
+            assert_eq!(
+                db.entry(key.as_bytes()).unwrap().vacant().is_some(),
+                expired
+            );
+            if let Some(value) = value {
+                assert_eq!(value.read_to_vec(0, 2).unwrap(), i.to_le_bytes());
+            }
+        }
+    })
+}
+
+#[test]
+fn expiry() {
+    with_db::<_, _, NodePage>(0x124, |db, _rng| {
+        let secs = |s: u64| SystemTime::UNIX_EPOCH + Duration::from_secs(s);
+        let db = db.with_clock(|| SystemTime::UNIX_EPOCH + Duration::from_secs(1_000));
+        for i in 0..100u16 {
+            let key = format!("key {i:03}");
+            let value = db
+                .entry(key.as_bytes())
+                .unwrap()
+                .vacant()
+                .unwrap()
+                .insert()
+                .unwrap();
+            value.write_at(0, &i.to_le_bytes()).unwrap();
+            if i % 2 == 0 {
+                value.set_expiry(Some(1_000 + u64::from(i))).unwrap();
+            }
+        }
+        // the inline value moves to a page to keep its expiry
+        let key = b"key inline";
+        db.try_insert(key, b"small").unwrap();
+        db.get(key)
+            .unwrap()
+            .unwrap()
+            .set_expiry(Some(1_050))
+            .unwrap();
+        db.get(b"key 002")
+            .unwrap()
+            .unwrap()
+            .set_expiry(None)
+            .unwrap();
+
+        // the clock of the database does not reach any expiry yet, `key 000` expires at it
+        assert!(db.get(b"key 000").unwrap().is_none());
+        assert!(db.get(b"key 004").unwrap().is_some());
+        assert_eq!(db.purge_expired_at(secs(999)).unwrap(), 0);
+
+        // just before and just after the boundary of `key inline`
+        assert_eq!(db.purge_expired_at(secs(1_049)).unwrap(), 24);
+        assert!(db.get(key).unwrap().is_some());
+        assert_eq!(db.purge_expired_at(secs(1_050)).unwrap(), 2);
+        assert!(db.get(key).unwrap().is_none());
+        assert!(db.entry(key).unwrap().vacant().is_some());
+
+        assert_eq!(db.purge_expired_at(secs(2_000)).unwrap(), 24);
+        for i in 0..100u16 {
+            let key = format!("key {i:03}");
+            let value = db.get(key.as_bytes()).unwrap();
+            assert_eq!(value.is_none(), i % 2 == 0 && i != 2, "{key}");
+        }
+    })
+}
+
+#[test]
+fn iterate_expired() {
+    with_db::<_, _, NodePage>(0x125, |db, _rng| {
+        let db = db.with_clock(|| SystemTime::UNIX_EPOCH + Duration::from_secs(1_000));
+        for i in 0..60u16 {
+            let key = format!("key {i:03}");
+            let value = db
+                .entry(key.as_bytes())
+                .unwrap()
+                .vacant()
+                .unwrap()
+                .insert()
+                .unwrap();
+            value.write_at(0, &i.to_le_bytes()).unwrap();
+            // every third value is expired by the clock already
+            if i % 3 == 0 {
+                value.set_expiry(Some(999)).unwrap();
+            }
+        }
+        let live = (0..60u16).filter(|i| i % 3 != 0).collect::<Vec<_>>();
+        let key = |i: u16| format!("key {i:03}").into_bytes();
+
+        let mut it = db.iter_from(b"").unwrap();
+        let mut found = vec![];
+        while let Some((k, value)) = db.next(&mut it).unwrap() {
+            if value.is_some() {
+                found.push(k);
+            }
+        }
+        assert_eq!(found, live.iter().map(|i| key(*i)).collect::<Vec<_>>());
+
+        let scanned = db.scan_values(..).unwrap();
+        let scanned = scanned.map(|item| item.unwrap().0).collect::<Vec<_>>();
+        assert_eq!(scanned, found);
+
+        let count = db
+            .fold_prefix(b"key 0", 0, |n, _, _| ControlFlow::Continue(n + 1))
+            .unwrap();
+        assert_eq!(count, live.len());
+
+        let mut cursor = db.cursor();
+        assert!(cursor.seek(b"key 003").unwrap());
+        assert!(cursor.value().unwrap().is_none());
+        assert!(cursor.next().unwrap());
+        assert!(cursor.value().unwrap().is_some());
+
+        // the entry still sees the value, it tells that it is expired
+        let occupied = db.entry(key(3)).unwrap().occupied().unwrap();
+        assert!(occupied.is_expired().unwrap());
+    })
+}
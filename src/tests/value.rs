@@ -0,0 +1,673 @@
+use std::{mem, sync::Barrier, thread, time::Duration};
+
+use tempdir::TempDir;
+
+use crate::{Db, DbError, NodePage, Params, Value, WalError};
+
+use super::with_db;
+
+#[test]
+fn truncate() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        let value = db
+            .entry(b"key")
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap();
+        value.write_at(0, b"some value").unwrap();
+        value.truncate(4).unwrap();
+        assert_eq!(value.read_to_vec(0, 10).unwrap(), b"some\0\0\0\0\0\0");
+        assert!(matches!(value.truncate(0x1001), Err(DbError::OutOfBounds)));
+    })
+}
+
+#[test]
+fn write_past_end() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        let insert = |key: &[u8]| db.entry(key).unwrap().vacant().unwrap().insert().unwrap();
+
+        // the page of a removed value may be reused, it is zeroed first
+        let value = insert(b"old");
+        value.write_at(0, &[0xff; Value::CAPACITY]).unwrap();
+        drop(value);
+        db.entry(b"old")
+            .unwrap()
+            .occupied()
+            .unwrap()
+            .remove()
+            .unwrap();
+        db.sync().unwrap();
+
+        for offset in [10, 1000, Value::CAPACITY - 4] {
+            let key = format!("key {offset}");
+            let value = insert(key.as_bytes());
+            assert_eq!(value.len().unwrap(), 0);
+            value.write_at(offset, b"tail").unwrap();
+            assert_eq!(value.len().unwrap(), offset + 4);
+            let expected = [vec![0; offset], b"tail".to_vec()].concat();
+            assert_eq!(value.read_to_vec(0, offset + 4).unwrap(), expected);
+            // only the gap, not the bytes around it
+            value.write_at(0, b"head").unwrap();
+            assert_eq!(
+                value.read_to_vec(4, offset - 4).unwrap(),
+                vec![0; offset - 4]
+            );
+        }
+        let value = insert(b"past");
+        let res = value.write_at(Value::CAPACITY - 3, b"tail");
+        assert!(matches!(res, Err(DbError::OutOfBounds)));
+        assert_eq!(value.len().unwrap(), 0);
+
+        // an inline value moves to a page, the gap is zeroed as well
+        let vacant = db.entry(b"small").unwrap().vacant().unwrap();
+        vacant.insert_small(b"head").unwrap();
+        let value = db.get(b"small").unwrap().unwrap();
+        value.write_at(2000, b"tail").unwrap();
+        assert_eq!(value.len().unwrap(), 2004);
+        assert_eq!(value.read_to_vec(4, 1996).unwrap(), vec![0; 1996]);
+        db.check().unwrap();
+    })
+}
+
+#[test]
+fn as_slice() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        let value = db
+            .entry(b"key")
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap();
+        value.write_at(0, b"old").unwrap();
+
+        let view = value.as_slice().unwrap();
+        thread::scope(|s| {
+            let writer = s.spawn(|| {
+                let value = db.entry(b"key").unwrap().occupied().unwrap().into_value();
+                value.write_at(0, b"new").unwrap();
+            });
+            thread::sleep(Duration::from_millis(50));
+            assert_eq!(&view[..3], b"old");
+            drop(view);
+            writer.join().unwrap();
+        });
+        assert_eq!(&value.as_slice().unwrap()[..3], b"new");
+    })
+}
+
+#[test]
+fn read_aligned() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        let value = db
+            .entry(b"key")
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap();
+        value.write_at(0, b"page aligned").unwrap();
+        assert!(db.try_insert(b"inline", b"small").unwrap());
+        let inline = db.get(b"inline").unwrap().unwrap();
+
+        for (value, offset, expected) in [(value, 5, b"aligned".as_slice()), (inline, 2, b"all")] {
+            let buf = value.read_aligned(offset, <[u8]>::to_vec).unwrap();
+            value
+                .read_aligned(offset, |page| {
+                    assert_eq!(page.as_ptr() as usize % 0x1000, 0);
+                    assert_eq!(page.len(), 0x1000);
+                })
+                .unwrap();
+            assert_eq!(&buf[..expected.len()], expected);
+            assert!(buf[expected.len()..].iter().all(|b| *b == 0));
+        }
+        assert!(matches!(
+            db.get(b"key")
+                .unwrap()
+                .unwrap()
+                .read_aligned(Value::CAPACITY + 1, |_| ()),
+            Err(DbError::OutOfBounds)
+        ));
+    })
+}
+
+#[test]
+fn allocate() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-allocate");
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(true)).unwrap();
+    let used = db.stats().used;
+
+    let value = db.allocate().unwrap();
+    assert_eq!(db.stats().used, used + 1);
+    assert!(matches!(
+        db.allocate(),
+        Err(DbError::WalError(WalError::Allocated))
+    ));
+    // dropping returns the page
+    drop(value);
+    assert_eq!(db.stats().used, used);
+
+    // dropping while an entry holds the lock does not wait for it,
+    // the next allocation returns the page
+    let value = db.allocate().unwrap();
+    let entry = db.entry(b"key").unwrap();
+    drop(value);
+    drop(entry);
+    assert_eq!(db.stats().used, used + 1);
+    drop(db.allocate().unwrap());
+    assert_eq!(db.stats().used, used);
+
+    let value = db.allocate().unwrap();
+    value.write_at(0, b"value").unwrap();
+    let value = db
+        .entry(b"key")
+        .unwrap()
+        .vacant()
+        .unwrap()
+        .insert_value(value)
+        .unwrap();
+    drop(value);
+    let value = db.entry(b"key").unwrap().occupied().unwrap().into_value();
+    assert_eq!(value.read_to_vec(0, 5).unwrap(), b"value");
+
+    // a value that is not allocated cannot be inserted twice
+    assert!(matches!(
+        db.entry(b"other key")
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert_value(value),
+        Err(DbError::NotAllocated)
+    ));
+
+    // the process crashes before the value is inserted
+    let used = db.stats().used;
+    mem::forget(db.allocate().unwrap());
+    db.sync().unwrap();
+    drop(db);
+
+    let db = Db::<NodePage>::new(&path, Params::new_mock(false)).unwrap();
+    assert_eq!(db.stats().used, used);
+    assert!(db.entry(b"other key").unwrap().vacant().is_some());
+}
+
+#[test]
+fn compare_and_swap() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        let value = db
+            .entry(b"key")
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap();
+        assert!(!value.compare_and_swap(0, b"a", b"b").unwrap());
+        assert!(matches!(
+            value.compare_and_swap(Value::CAPACITY, b"", b"b"),
+            Err(DbError::OutOfBounds)
+        ));
+
+        // two handles race, only one of them wins
+        let barrier = Barrier::new(2);
+        let winners = thread::scope(|s| {
+            let handles = [1u8, 2].map(|id| {
+                let barrier = &barrier;
+                let db = &db;
+                s.spawn(move || {
+                    let value = db.get(b"key").unwrap().unwrap();
+                    barrier.wait();
+                    value.compare_and_swap(0, &[0], &[id]).unwrap()
+                })
+            });
+            handles.map(|h| h.join().unwrap())
+        });
+        assert_eq!(winners.iter().filter(|won| **won).count(), 1);
+        let id = if winners[0] { 1 } else { 2 };
+        assert_eq!(value.read_to_vec(0, 1).unwrap(), [id]);
+
+        // a counter incremented concurrently
+        value.write_at(0, &0u64.to_le_bytes()).unwrap();
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let value = db.get(b"key").unwrap().unwrap();
+                    for _ in 0..100 {
+                        loop {
+                            let old = value.read_to_vec(0, 8).unwrap();
+                            let new = (u64::from_le_bytes(old.clone().try_into().unwrap()) + 1)
+                                .to_le_bytes();
+                            if value.compare_and_swap(0, &old, &new).unwrap() {
+                                break;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(value.read_to_vec(0, 8).unwrap(), 400u64.to_le_bytes());
+    })
+}
+
+#[test]
+fn inline() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        let key = |i: u16| format!("key {i:04}");
+        let used = db.stats().used;
+        for i in 0..1000 {
+            assert!(db.try_insert(key(i).as_bytes(), &[i as u8]).unwrap());
+        }
+        let inline = db.stats().used - used;
+        // the same in another tree, but a page per value
+        let used = db.stats().used;
+        let tree = db.tree(1);
+        for i in 0..1000 {
+            let value = tree.entry(key(i)).unwrap().vacant().unwrap().insert();
+            value.unwrap().write_at(0, &[i as u8]).unwrap();
+        }
+        assert!(db.stats().used - used >= inline + 1000);
+        db.check().unwrap();
+
+        let value = db.get(key(7).as_bytes()).unwrap().unwrap();
+        assert_eq!(value.read_to_vec(0, 2).unwrap(), [7, 0]);
+        value.write_at(1, &[1; 7]).unwrap();
+        assert!(value.compare_and_swap(0, &[7], &[8]).unwrap());
+        assert!(!value.compare_and_swap(0, &[7], &[9]).unwrap());
+        let other = db.get(key(7).as_bytes()).unwrap().unwrap();
+        assert_eq!(
+            other.read_to_vec(0, 9).unwrap(),
+            [8, 1, 1, 1, 1, 1, 1, 1, 0]
+        );
+        let used = db.stats().used;
+
+        // past the inline bytes the value moves to a page
+        value.write_at(Value::INLINE, b"spill").unwrap();
+        assert_eq!(db.stats().used, used + 1);
+        let spilled = value.read_to_vec(Value::INLINE - 2, 7).unwrap();
+        assert_eq!(spilled, b"\0\0spill");
+        // the other handle sees the page
+        other.truncate(1).unwrap();
+        assert_eq!(
+            value.read_to_vec(0, 9).unwrap(),
+            [8, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+        db.check().unwrap();
+
+        // the removed value is returned on a page of its own
+        let removed = db
+            .entry(key(3).as_bytes())
+            .unwrap()
+            .occupied()
+            .unwrap()
+            .remove()
+            .unwrap();
+        assert_eq!(removed.read_to_vec(0, 1).unwrap(), [3]);
+        let value = db.get(key(4).as_bytes()).unwrap().unwrap();
+        db.entry(key(4).as_bytes())
+            .unwrap()
+            .occupied()
+            .unwrap()
+            .into_empty()
+            .unwrap();
+        assert!(matches!(value.write_at(0, &[1]), Err(DbError::KeyNotFound)));
+        db.check().unwrap();
+    })
+}
+
+#[test]
+fn insert_small() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        let key = |i: u32| format!("key {i:05}");
+        let small = |i: u32| [i.to_le_bytes(); 4].concat();
+        let used = db.stats().used;
+        for i in 0..10_000 {
+            let vacant = db.entry(key(i)).unwrap().vacant().unwrap();
+            vacant.insert_small(&small(i)).unwrap();
+        }
+        let inline = db.stats().used - used;
+        // the same in another tree, but a page per value
+        let used = db.stats().used;
+        let tree = db.tree(1);
+        for i in 0..10_000 {
+            let value = tree.entry(key(i)).unwrap().vacant().unwrap().insert();
+            value.unwrap().write_at(0, &small(i)).unwrap();
+        }
+        // the tail pages of a leaf are shared by its values, at least half of the pages is saved
+        assert!(db.stats().used - used >= inline + 5_000);
+        db.check().unwrap();
+
+        // the tail pages follow the values through splits and merges
+        for i in (0..10_000).step_by(3) {
+            let occupied = db.entry(key(i)).unwrap().occupied().unwrap();
+            assert_eq!(
+                occupied.remove().unwrap().read_to_vec(0, 16).unwrap(),
+                small(i)
+            );
+        }
+        let long = [0xab; Value::INLINE];
+        db.entry(key(3))
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert_small(&long)
+            .unwrap();
+        db.compact().unwrap();
+        db.check().unwrap();
+        for i in 0..10_000 {
+            let value = db.get(key(i).as_bytes()).unwrap();
+            match i {
+                3 => assert_eq!(value.unwrap().read_to_vec(0, 0x40).unwrap(), long),
+                _ if i % 3 == 0 => assert!(value.is_none()),
+                _ => assert_eq!(
+                    value.unwrap().read_to_vec(0, 0x20).unwrap(),
+                    [small(i), vec![0; 16]].concat()
+                ),
+            }
+        }
+
+        let vacant = db.entry(b"long").unwrap().vacant().unwrap();
+        let res = vacant.insert_small(&[1; Value::INLINE + 1]);
+        assert!(matches!(res, Err(DbError::OutOfBounds)));
+    })
+}
+
+#[test]
+fn stale() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        let value = db
+            .entry(b"key")
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap();
+        value.write_at(0, b"old").unwrap();
+        let value = db.get(b"key").unwrap().unwrap();
+        assert_eq!(value.read_to_vec(0, 3).unwrap(), b"old");
+
+        // the removed value is kept until the next removal
+        db.entry(b"next")
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap();
+        for key in [b"key".as_slice(), b"next"] {
+            let occupied = db.entry(key).unwrap().occupied().unwrap();
+            occupied.remove().unwrap();
+        }
+        // one of them reuses the page of the removed value
+        for i in 0..10u8 {
+            let value = db.entry([i]).unwrap().vacant().unwrap().insert().unwrap();
+            value.write_at(0, b"new").unwrap();
+        }
+
+        assert!(matches!(value.read_to_vec(0, 3), Err(DbError::Stale)));
+        assert!(matches!(value.write_at(0, b"bad"), Err(DbError::Stale)));
+        assert!(matches!(value.as_slice(), Err(DbError::Stale)));
+        for i in 0..10u8 {
+            let value = db.get(&[i]).unwrap().unwrap();
+            assert_eq!(value.read_to_vec(0, 3).unwrap(), b"new");
+        }
+    })
+}
+
+#[test]
+fn removed() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        for key in [b"first".as_slice(), b"second"] {
+            let value = db.entry(key).unwrap().vacant().unwrap().insert().unwrap();
+            value.write_at(0, key).unwrap();
+        }
+        // the inline value gets a page when it is removed
+        db.entry(b"small")
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert_small(b"small")
+            .unwrap();
+
+        let first = db.entry(b"first").unwrap().occupied().unwrap();
+        let first = first.remove().unwrap();
+        assert!(!first.is_stale());
+        let copy = first.to_vec().unwrap();
+        assert_eq!(copy, b"first");
+
+        // an insert frees no value page, the removed one is still held
+        let value = db
+            .entry(b"third")
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap();
+        value.write_at(0, b"third").unwrap();
+        assert_eq!(first.read_to_vec(0, 5).unwrap(), b"first");
+        assert_eq!(first.len().unwrap(), 5);
+
+        // the next removal takes the orphan slot and frees the page
+        let second = db.entry(b"second").unwrap().occupied().unwrap();
+        let second = second.remove().unwrap();
+        assert!(first.is_stale());
+        assert!(matches!(first.read_to_vec(0, 5), Err(DbError::Stale)));
+        assert!(matches!(first.to_vec(), Err(DbError::Stale)));
+        assert_eq!(copy, b"first");
+        assert_eq!(second.to_vec().unwrap(), b"second");
+
+        let small = db.entry(b"small").unwrap().occupied().unwrap();
+        let small = small.remove().unwrap();
+        assert!(second.is_stale());
+        assert_eq!(small.to_vec().unwrap(), b"small");
+
+        // so does reclaiming
+        db.reclaim().unwrap();
+        assert!(matches!(small.read_to_vec(0, 5), Err(DbError::Stale)));
+    })
+}
+
+#[test]
+fn read_chunks() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        let bytes = (0..Value::CAPACITY)
+            .map(|i| (i % 251) as u8 + 1)
+            .collect::<Vec<_>>();
+        let value = db
+            .entry(b"page")
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert_with(&bytes);
+        let value = value.unwrap();
+        db.entry(b"inline")
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert_small(&bytes[..Value::INLINE])
+            .unwrap();
+        let inline = db.get(b"inline").unwrap().unwrap();
+
+        let concat = |value: &Value<'_>, offset, len, chunk| {
+            let mut out = vec![];
+            value
+                .read_chunks(offset, len, chunk, |piece| {
+                    assert!(piece.len() <= chunk);
+                    out.extend_from_slice(piece);
+                    Ok(())
+                })
+                .unwrap();
+            out
+        };
+        // chunks that do not divide the length, and ones longer than it
+        for chunk in [1, 7, 0x100, 1000, 0x1000] {
+            for (offset, len) in [(0, Value::CAPACITY), (13, 3000), (100, 0)] {
+                let expected = value.read_to_vec(offset, len).unwrap();
+                assert_eq!(concat(&value, offset, len, chunk), expected);
+            }
+            let expected = inline.read_to_vec(10, 100).unwrap();
+            assert_eq!(concat(&inline, 10, 100, chunk), expected);
+        }
+
+        let mut calls = 0;
+        let res = value.read_chunks(0, 100, 10, |_| {
+            calls += 1;
+            if calls == 3 {
+                return Err(DbError::OutOfBounds);
+            }
+            Ok(())
+        });
+        assert!(matches!(res, Err(DbError::OutOfBounds)));
+        assert_eq!(calls, 3);
+        let res = value.read_chunks(1, Value::CAPACITY, 10, |_| unreachable!());
+        assert!(matches!(res, Err(DbError::OutOfBounds)));
+        let res = value.read_chunks(0, 100, 0, |_| unreachable!());
+        assert!(matches!(res, Err(DbError::OutOfBounds)));
+    })
+}
+
+#[test]
+fn scan_values() {
+    use std::{collections::BTreeMap, ops::Bound};
+
+    use rand::Rng;
+
+    with_db::<_, _, NodePage>(0x123, |db, rng| {
+        let mut expected = BTreeMap::new();
+        for i in 0..500u32 {
+            let key = format!("key {i:04}").into_bytes();
+            // inline values, page values and values ending with zeros
+            let len = rng.gen_range(1..200);
+            let mut bytes = (0..len)
+                .map(|_| rng.gen_range(1..=255))
+                .collect::<Vec<u8>>();
+            bytes.extend_from_slice(&[0; 3][..i as usize % 4]);
+            let vacant = db.entry(&key).unwrap().vacant().unwrap();
+            if bytes.len() <= Value::INLINE {
+                vacant.insert_small(&bytes).unwrap();
+            } else {
+                vacant.insert_with(&bytes).unwrap();
+            }
+            bytes.truncate(len);
+            expected.insert(key, bytes);
+        }
+        db.insert_tombstone(b"key 0100 without value").unwrap();
+
+        // owned, so they outlive the borrow of the database
+        let scan = |range: (Bound<&[u8]>, Bound<&[u8]>)| {
+            let items = db.scan_values(range).unwrap();
+            items.collect::<Result<BTreeMap<_, _>, _>>().unwrap()
+        };
+        let all = thread::scope(|s| {
+            s.spawn(|| scan((Bound::Unbounded, Bound::Unbounded)))
+                .join()
+        });
+        assert_eq!(all.unwrap(), expected);
+
+        let (start, end) = (b"key 0100".as_slice(), b"key 0200".as_slice());
+        let part = scan((Bound::Included(start), Bound::Excluded(end)));
+        let expected_part = expected.range(start.to_vec()..end.to_vec());
+        assert!(part.iter().eq(expected_part));
+        let part = scan((Bound::Excluded(start), Bound::Included(end)));
+        let expected_part = expected.range((
+            Bound::Excluded(start.to_vec()),
+            Bound::Included(end.to_vec()),
+        ));
+        assert!(part.iter().eq(expected_part));
+        assert!(scan((Bound::Included(b"z"), Bound::Unbounded)).is_empty());
+    })
+}
+
+#[test]
+fn batch_read_back() {
+    use crate::{node::Node, MAIN_TREE};
+
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        const N: u32 = <NodePage>::M as u32 * 3;
+
+        // long keys, so the nodes have key pages and prefixes to copy
+        let key = |i: u32| format!("a long common prefix of the keys {i:05}").into_bytes();
+        db.entry(key(0))
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert_small(b"inline")
+            .unwrap();
+
+        let mut batch = db.batch();
+        // the inline value moves to a page, the bytes go along
+        let value = batch.insert(MAIN_TREE, &key(0)).unwrap();
+        assert_eq!(value.read_to_vec(0, 6).unwrap(), b"inline");
+        for i in 1..N {
+            let value = batch.insert(MAIN_TREE, &key(i)).unwrap();
+            value.write_at(0, &i.to_le_bytes()).unwrap();
+            // the tree of the batch is split as it grows, the earlier values are found
+            let j = i / 2 + 1;
+            let value = batch.insert(MAIN_TREE, &key(j)).unwrap();
+            assert_eq!(value.read_to_vec(0, 4).unwrap(), j.to_le_bytes());
+        }
+        // and merged as it shrinks
+        for i in (1..N).step_by(2) {
+            assert!(batch.remove(MAIN_TREE, &key(i)).unwrap());
+        }
+        for i in (2..N).step_by(2) {
+            let value = batch.insert(MAIN_TREE, &key(i)).unwrap();
+            assert_eq!(value.read_to_vec(0, 4).unwrap(), i.to_le_bytes());
+        }
+        batch.commit().unwrap();
+
+        db.check().unwrap();
+        for i in 1..N {
+            let value = db.get(&key(i)).unwrap();
+            assert_eq!(value.is_some(), i % 2 == 0);
+        }
+        let value = db.get(&key(N - 2)).unwrap().unwrap();
+        assert_eq!(value.read_to_vec(0, 4).unwrap(), (N - 2).to_le_bytes());
+        let value = db.get(&key(0)).unwrap().unwrap();
+        assert_eq!(value.read_to_vec(0, 6).unwrap(), b"inline");
+    })
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn read_async() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        let key = |i: u8| format!("key {i:03}").into_bytes();
+        for i in 0..100 {
+            let vacant = db.entry(key(i)).unwrap().vacant().unwrap();
+            vacant.insert_with(&[i + 1; 100]).unwrap();
+        }
+        // every value page is read from the disk
+        db.release_cache().unwrap();
+
+        let read = |i: u8| {
+            let db = &db;
+            async move {
+                let value = db.get_async(&key(i)).await.unwrap().unwrap();
+                let mut buf = [0; 100];
+                value.read_async(0, &mut buf).await.unwrap();
+                buf
+            }
+        };
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let reads = db.metrics().page_reads;
+            // in flight at once
+            let (a, b, c) = tokio::join!(read(1), read(50), read(99));
+            assert_eq!((a, b, c), ([2; 100], [51; 100], [100; 100]));
+            assert!(db.metrics().page_reads >= reads + 3);
+            assert!(db.get_async(b"missing").await.unwrap().is_none());
+
+            // a change in the cache is seen before it is on the disk
+            db.get(&key(1))
+                .unwrap()
+                .unwrap()
+                .write_at(0, b"new")
+                .unwrap();
+            assert_eq!(&read(1).await[..4], b"new\x02");
+        });
+    })
+}
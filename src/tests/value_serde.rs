@@ -0,0 +1,70 @@
+use serde::{Serialize, Deserialize};
+
+use crate::{DbError, NodePage};
+
+use super::with_db;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Inner {
+    name: String,
+    tags: Vec<u16>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Outer {
+    id: u64,
+    inner: Inner,
+    parent: Option<Box<Outer>>,
+}
+
+#[test]
+fn round_trip() {
+    with_db::<_, _, NodePage>(0x123, |db, _rng| {
+        let v = Outer {
+            id: 1,
+            inner: Inner {
+                name: "child".to_owned(),
+                tags: vec![1, 2, 300],
+            },
+            parent: Some(Box::new(Outer {
+                id: 0,
+                inner: Inner {
+                    name: "root".to_owned(),
+                    tags: vec![],
+                },
+                parent: None,
+            })),
+        };
+
+        let value = db
+            .entry(b"key")
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap();
+        // garbage after the serialized data is ignored
+        value.write_at(0, &[0xff; 0x100]).unwrap();
+        value.write_serde(&v).unwrap();
+        assert_eq!(value.read_serde::<Outer>().unwrap(), v);
+
+        value.truncate(8).unwrap();
+        assert!(matches!(
+            value.read_serde::<Outer>(),
+            Err(DbError::Serde(_))
+        ));
+
+        // nothing is written
+        let value = db
+            .entry(b"other")
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap();
+        assert!(matches!(
+            value.read_serde::<Inner>(),
+            Err(DbError::Serde(_))
+        ));
+    })
+}
@@ -1,4 +1,8 @@
-use std::{fs, io, path::Path};
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::Path,
+};
 
 #[cfg(unix)]
 pub fn m_lock<T>(p: &T) -> bool {
@@ -48,6 +52,35 @@ pub fn read_at(file: &fs::File, buf: &mut [u8], offset: u64) -> io::Result<()> {
     file.read_exact_at(buf, offset)
 }
 
+#[cfg(windows)]
+pub fn read_at(file: &fs::File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+
+    while !buf.is_empty() {
+        let len = file.seek_read(buf, offset)?;
+        if len == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf = &mut buf[len..];
+        offset += len as u64;
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+pub fn is_regular_file(file: &fs::File) -> io::Result<bool> {
+    use std::os::unix::fs::FileTypeExt;
+
+    Ok(!file.metadata()?.file_type().is_block_device())
+}
+
+#[cfg(windows)]
+pub fn is_regular_file(file: &fs::File) -> io::Result<bool> {
+    let _ = file;
+    Ok(true)
+}
+
 #[cfg(unix)]
 pub fn open_file(path: impl AsRef<Path>, direct_write: bool) -> io::Result<fs::File> {
     use std::os::unix::fs::OpenOptionsExt;
@@ -70,12 +103,59 @@ pub fn open_file(path: impl AsRef<Path>, direct_write: bool) -> io::Result<fs::F
 }
 
 #[cfg(windows)]
-pub fn open_file(path: impl AsRef<Path>, create: bool, direct_write: bool) -> io::Result<fs::File> {
+pub fn open_file(path: impl AsRef<Path>, direct_write: bool) -> io::Result<fs::File> {
     let mut open_options = fs::OpenOptions::new();
     let _ = direct_write;
     open_options.write(true).read(true);
-    if create {
+    if !path.as_ref().exists() {
         open_options.create_new(true);
     }
     open_options.open(path)
 }
+
+pub fn read_array<const N: usize>(r: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut array = [0; N];
+    r.read_exact(&mut array)?;
+
+    Ok(array)
+}
+
+// computes the checksum of the bytes passing through
+pub struct Checked<T> {
+    pub inner: T,
+    pub crc: u64,
+}
+
+impl<T> Checked<T> {
+    pub fn new(inner: T) -> Self {
+        Checked { inner, crc: 0 }
+    }
+}
+
+impl<W> Write for Checked<W>
+where
+    W: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.crc = crc64::crc64(self.crc, &buf[..len]);
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R> Read for Checked<R>
+where
+    R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.crc = crc64::crc64(self.crc, &buf[..len]);
+
+        Ok(len)
+    }
+}
@@ -1,19 +1,83 @@
-use super::{page::PAGE_SIZE, runtime::PlainData};
+use std::time::{Duration, SystemTime};
 
+use super::{
+    page::PAGE_SIZE,
+    runtime::{PlainData, PageKind},
+};
+
+// the value takes the whole page, unless the database keeps the expiration,
+// then it is in the last 8 bytes, see `FileIo::expiry`
 #[repr(C, align(0x1000))]
 #[derive(Clone, Copy)]
 pub struct MetadataPage {
-    plain: [u8; PAGE_SIZE as usize],
+    bytes: [u8; PAGE_SIZE as usize],
 }
 
 impl MetadataPage {
+    pub const CAPACITY: usize = PAGE_SIZE as usize - 8;
+
     pub const fn empty() -> Self {
         MetadataPage {
-            plain: [0; PAGE_SIZE as _],
+            bytes: [0; PAGE_SIZE as usize],
+        }
+    }
+
+    /// The bytes of the value, `expiry` is `FileIo::expiry`
+    pub const fn capacity(expiry: bool) -> usize {
+        if expiry {
+            Self::CAPACITY
+        } else {
+            PAGE_SIZE as usize
         }
     }
+
+    /// Never expires, `plain` must fit in `CAPACITY`
+    pub fn new(plain: &[u8]) -> Self {
+        let mut page = Self::empty();
+        page.bytes[..plain.len()].clone_from_slice(plain);
+        page
+    }
+
+    pub fn plain(&self, expiry: bool) -> &[u8] {
+        &self.bytes[..Self::capacity(expiry)]
+    }
+
+    pub fn plain_mut(&mut self, expiry: bool) -> &mut [u8] {
+        &mut self.bytes[..Self::capacity(expiry)]
+    }
+
+    /// The rest is zeroed, the expiration stays, `plain` must fit in `capacity`
+    pub fn set_plain(&mut self, plain: &[u8], expiry: bool) {
+        let bytes = self.plain_mut(expiry);
+        bytes[..plain.len()].clone_from_slice(plain);
+        bytes[plain.len()..].fill(0);
+    }
+
+    /// Always `None` unless the page keeps the expiration
+    pub fn expires(&self, expiry: bool) -> Option<SystemTime> {
+        let ms = u64::from_ne_bytes(self.bytes[Self::CAPACITY..].try_into().ok()?);
+        (expiry && ms != 0).then(|| SystemTime::UNIX_EPOCH + Duration::from_millis(ms))
+    }
+
+    /// Overwrites the last 8 bytes, only if the page keeps the expiration
+    pub fn set_expires(&mut self, time: Option<SystemTime>) {
+        let ms = time.map_or(0, |time| {
+            let ms = time
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis();
+            // zero is reserved for never
+            (ms as u64).max(1)
+        });
+        self.bytes[Self::CAPACITY..].clone_from_slice(&ms.to_ne_bytes());
+    }
+
+    pub fn is_expired(&self, expiry: bool, now: SystemTime) -> bool {
+        self.expires(expiry).is_some_and(|time| time <= now)
+    }
 }
 
 unsafe impl PlainData for MetadataPage {
     const NAME: &str = "Metadata";
+    const KIND: PageKind = PageKind::Data;
 }
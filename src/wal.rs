@@ -1,14 +1,20 @@
 use std::{
-    io,
-    ops::DerefMut,
-    sync::{Mutex, MutexGuard},
+    collections::{BTreeSet, VecDeque},
+    io, iter, mem,
+    ops::{Deref, Range},
+    sync::{
+        atomic::{self, AtomicBool, AtomicUsize, Ordering},
+        Condvar, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError,
+    },
+    thread::{self, ThreadId},
+    time::{Duration, Instant},
 };
 
 use thiserror::Error;
 
 use super::{
-    page::{PagePtr, RawPtr},
-    runtime::{Alloc, Free, PlainData, AbstractIo, PageKind},
+    page::{PagePtr, RawPtr, PAGE_SIZE},
+    runtime::{Alloc, Free, PlainData, AbstractIo, PageKind, PBox},
     file::FileIo,
 };
 
@@ -18,6 +24,55 @@ pub enum WalError {
     Io(#[from] io::Error),
     #[error("bad write-ahead log")]
     BadWal,
+    #[error(
+        "no valid record among {slots} log pages, {torn} fail the checksum{}{}",
+        best_seq.map(|seq| format!(", the highest seq among them is {seq}")).unwrap_or_default(),
+        if *wrong_secret { ", the secret is likely wrong or the header is of another file" } else { "" },
+    )]
+    NoRecord {
+        slots: u32,
+        /// Pages that are not zeros, but fail the checksum
+        torn: u32,
+        /// Read from the torn pages as is, it may be garbage too
+        best_seq: Option<u64>,
+        /// Every page written is garbage, which is what a wrong key does
+        wrong_secret: bool,
+    },
+    #[error("the file has {pages} pages, the log alone takes {min}")]
+    TooSmall { pages: u32, min: u32 },
+    #[error("the head of the tree is not a node, the file is truncated or corrupted")]
+    BadHead,
+    #[error("a snapshot is pinned")]
+    Pinned,
+    #[error("a value is allocated, but not inserted yet")]
+    Allocated,
+    #[error("inconsistent pages: {total} in total, {used} used, {free} free")]
+    Inconsistent { total: u32, used: u32, free: u32 },
+    #[error("the changes do not follow the last record, the database did change meanwhile")]
+    Diverged,
+    #[error("the database is created with the collation {stored}, not {given}")]
+    Collation { stored: u64, given: u64 },
+    #[error("the database is created with the node fanout {stored}, not {given}")]
+    Fanout { stored: u64, given: u64 },
+}
+
+/// A page of the freelist is out of the file, or the freelist loops back to it,
+/// the file is corrupted
+#[derive(Debug, Error)]
+#[error("bad free page {0}, it is out of the file or the freelist loops")]
+pub struct BadFreelist(pub u32);
+
+impl BadFreelist {
+    /// Whether `err` is made of `BadFreelist`
+    pub fn is(err: &io::Error) -> bool {
+        err.get_ref().is_some_and(|inner| inner.is::<Self>())
+    }
+}
+
+impl From<BadFreelist> for io::Error {
+    fn from(err: BadFreelist) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
 }
 
 #[derive(Debug)]
@@ -28,14 +83,256 @@ pub struct DbStats {
     pub used: u32,
     pub seq: u64,
     pub writes: u32,
+    /// Page reads served from the cache, monotonic since the database is open
+    pub cache_hits: u64,
+    /// Page reads that went to the file, monotonic since the database is open
+    pub cache_misses: u64,
+    /// Pages in the persistent freelist
+    pub freelist_len: u32,
+    /// Pages released by the last change, they go to the freelist with the next one
+    pub garbage: u32,
+    /// `free / total`, the share of the file compaction could give back
+    pub fragmentation: f64,
+    /// Pages held in memory by the page cache, `Db::release_cache` drops them.
+    /// The pinned pages are not counted, see `pinned_pages`.
+    pub cache_pages: u32,
+    /// `seq` of the record the database is opened at, it only grows while it is open
+    pub seq_at_open: u64,
+    /// Pages written by the last insert or remove, the path from the root to the leaf,
+    /// the nodes of a split or a merge, the value and the record.
+    /// A value written concurrently by another thread counts too.
+    pub pages_written_last_op: u32,
+    /// Used pages of the trees: nodes, their key pages and the trees page.
+    /// Only `Db::stats_full` walks the trees to count them, otherwise zero.
+    pub tree_pages: u32,
+    /// Used pages of the values, `used` is `tree_pages + data_pages`
+    /// unless a snapshot is pinned. Zero unless counted by `Db::stats_full`.
+    pub data_pages: u32,
+    /// Pages kept in the cache over a sync: the roots and the ones pinned by `Db::pin`
+    pub pinned_pages: u32,
+    /// The layout of the last log record, `DbStats::RECORD_FORMAT` unless the database
+    /// is opened with a record written by an older version and not changed yet
+    pub record_format: u64,
+    /// Pages the file can still grow by, the next growth past them is `DbError::DatabaseFull`
+    pub capacity_left: u32,
+}
+
+impl DbStats {
+    /// The layout of the log record written now, each older one is read as well
+    pub const RECORD_FORMAT: u64 = RecordSeq::FORMAT;
 }
 
-pub struct Wal(Mutex<RecordSeq>);
+/// What opening the database did find after the last run, see `Db::last_recovery`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// Log pages that fail the checksum, a torn write of a record
+    pub skipped_records: u32,
+    /// A value was allocated, but its key was not inserted, the page is free again
+    pub orphan_reclaimed: bool,
+    /// Pages the file did grow by after the last record, they are cut off
+    pub truncated_pages: u32,
+    /// Pages released while `Db::backup_to` was running, they are free again
+    pub lost_reclaimed: u32,
+}
+
+impl RecoveryReport {
+    /// Nothing is discarded, the last run did close the database
+    pub fn is_clean(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+pub struct Wal {
+    state: RwLock<WalState>,
+    // the thread holding the write lock, locking again it would wait for itself
+    owner: Mutex<Option<ThreadId>>,
+    // notified with `owner` when the lock is released while someone is in `lock_timeout`
+    released: Condvar,
+    waiting: AtomicUsize,
+    // the allocated value is dropped while the lock was busy,
+    // the next `allocate` or `reclaim` frees it
+    dropped: AtomicBool,
+}
+
+pub struct WalState {
+    record: RecordSeq,
+    // number of pinned snapshots
+    pinned: u32,
+    // pages released while a snapshot is pinned
+    deferred: Vec<(PageKind, PagePtr<FreePage>)>,
+    // the last run did crash while a snapshot was pinned, the pages it did hold are not free
+    lost: bool,
+    // the orphan is a value allocated, but not inserted yet
+    allocated: bool,
+    // the final record is written
+    closed: bool,
+    changes: ChangeLog,
+    pages: PageLog,
+    fresh: FreshPages,
+    synced: Synced,
+    // a batch is open, a record on the disk would lose the pages it did allocate
+    batch: bool,
+    // pages known to be free, checks every allocation and release
+    free: FreeSet,
+    // the state before the change in progress, restored if its record is not written
+    undo: Option<Undo>,
+    seq_at_open: u64,
+    // `None` if the database is created
+    recovery: Option<RecoveryReport>,
+    last_op_writes: u32,
+    // values in the garbage, those of the record read at open are taken for tree pages
+    data_garbage: BTreeSet<u32>,
+}
+
+struct Undo {
+    record: RecordSeq,
+    last: RecordPage,
+    deferred: Vec<(PageKind, PagePtr<FreePage>)>,
+    fresh: FreshPages,
+    synced: Synced,
+    free: FreeSet,
+}
+
+impl WalState {
+    fn new(record: RecordSeq) -> Self {
+        WalState {
+            record,
+            pinned: 0,
+            deferred: vec![],
+            lost: false,
+            allocated: false,
+            closed: false,
+            changes: ChangeLog {
+                oldest: record.seq,
+                keys: VecDeque::new(),
+            },
+            pages: PageLog::new(record),
+            fresh: FreshPages::default(),
+            synced: Synced {
+                seq: record.seq,
+                ..Synced::default()
+            },
+            batch: false,
+            free: FreeSet::new(record.cache.iter().chain(record.garbage.iter())),
+            undo: None,
+            seq_at_open: record.seq,
+            recovery: None,
+            last_op_writes: 0,
+            data_garbage: BTreeSet::new(),
+        }
+    }
+}
+
+/// What is known to be on the disk. A torn write must not hit a page
+/// the last record on the disk still refers to, so the pages released since
+/// are held at the bottom of the freelist cache and taken last.
+#[derive(Default, Clone, Copy)]
+struct Synced {
+    // `FileIo::syncs` after the last record is written
+    record: u64,
+    // the last record on the disk
+    seq: u64,
+    // `FileIo::syncs` after the held pages are counted
+    pages: u64,
+    held: u32,
+}
+
+/// Pages preallocated in a row, each one points to the one before in the freelist,
+/// the first one to the `tail`. They are taken from the top without reading them.
+#[derive(Default, Clone)]
+struct FreshPages {
+    range: Range<u32>,
+    tail: Option<PagePtr<FreePage>>,
+}
+
+impl FreshPages {
+    // the next page of the freelist if `ptr` is the last fresh one
+    fn take(&mut self, ptr: PagePtr<FreePage>) -> Option<Option<PagePtr<FreePage>>> {
+        let n = ptr.raw_number();
+        if self.range.is_empty() || n + 1 != self.range.end {
+            return None;
+        }
+        self.range.end = n;
+        if self.range.is_empty() {
+            Some(self.tail)
+        } else {
+            Some(PagePtr::from_raw_number(n - 1))
+        }
+    }
+}
+
+/// Keys of the main tree changed since the database is open, each with the sequence
+/// number of the record after the change. Only the last `ChangeLog::LEN` are kept.
+struct ChangeLog {
+    // the changes after this version are all here
+    oldest: u64,
+    keys: VecDeque<(u64, Vec<u8>)>,
+}
+
+impl ChangeLog {
+    const LEN: usize = 0x1000;
+
+    fn push(&mut self, seq: u64, key: Vec<u8>) {
+        if self.keys.len() == Self::LEN {
+            if let Some((seq, _)) = self.keys.pop_front() {
+                self.oldest = seq;
+            }
+        }
+        self.keys.push_back((seq, key));
+    }
+}
+
+/// The records written since the database is open, each with the checksum
+/// and the pages written before it. Only the last `PageLog::LEN` are kept.
+struct PageLog {
+    records: VecDeque<(u64, u64, BTreeSet<u32>)>,
+    // the record in memory changes before it is written again
+    last: RecordPage,
+}
+
+impl PageLog {
+    const LEN: usize = 0x1000;
+
+    fn new(record: RecordSeq) -> Self {
+        let last = RecordPage::new(record);
+        PageLog {
+            records: [(record.seq, last.checksum, BTreeSet::new())].into(),
+            last,
+        }
+    }
+
+    fn push(&mut self, last: RecordPage, pages: BTreeSet<u32>) {
+        if self.records.len() == Self::LEN {
+            self.records.pop_front();
+        }
+        self.records
+            .push_back((last.inner.seq, last.checksum, pages));
+        self.last = last;
+    }
+}
 
 impl Wal {
-    const SIZE: u32 = 0x100;
+    pub const SIZE: u32 = 0x100;
+    /// Records kept in the log, each takes two pages
+    pub const RECORDS: u32 = Self::SIZE / 2;
+    /// The tree the log record points to, the others are in `TreesPage`
+    pub const MAIN: u8 = 0;
+    /// Makes the record page look as the first layout did write it,
+    /// the orphan and the trees are `junk`, the fields past them are zero
+    #[cfg(test)]
+    pub fn downgrade_record(page: &mut [u8], junk: u8) {
+        let (checksum, inner) = page.split_at_mut(8);
+        let inner = &mut inner[..mem::size_of::<RecordSeq>()];
+        let len = RecordSeq::checked_len(0);
+        let zero = mem::offset_of!(RecordSeq, collation);
+        inner[len..zero].fill(junk);
+        inner[zero..].fill(0);
+        checksum.copy_from_slice(&crc64::crc64(0, &inner[..len]).to_ne_bytes());
+    }
 
-    pub fn new(create: bool, file: &FileIo) -> Result<Self, WalError> {
+    /// `fanout` is `Node::M` of the tree, the database must be opened with the same one
+    pub fn new(create: bool, file: &FileIo, fanout: usize) -> Result<Self, WalError> {
+        let fanout = fanout as u64;
         if create {
             let head = PagePtr::from_raw_number(Self::SIZE)
                 .ok_or(io::Error::from(io::ErrorKind::UnexpectedEof))?;
@@ -45,10 +342,16 @@ impl Wal {
                     garbage: FreelistCache::empty(),
                     cache: FreelistCache::empty(),
                     size: Self::SIZE + 1,
-                    __padding: 0,
+                    freelist_len: 0,
                     freelist: None,
                     head,
                     orphan: None,
+                    trees: None,
+                    collation: file.collation_id(),
+                    fanout,
+                    format: RecordSeq::FORMAT,
+                    pinned: 0,
+                    expiry: file.expiry().into(),
                 };
                 let page = RecordPage::new(inner);
                 let ptr = file.grow(pos, 1)?;
@@ -57,38 +360,105 @@ impl Wal {
             }
             let head = file.grow(Self::SIZE, 1)?.expect("must yield some");
 
-            let s = Self(Mutex::new(RecordSeq {
+            let s = Self::from_state(WalState::new(RecordSeq {
                 seq: (Self::SIZE - 1).into(),
                 garbage: FreelistCache::empty(),
                 cache: FreelistCache::empty(),
                 size: Self::SIZE + 1,
-                __padding: 0,
+                freelist_len: 0,
                 freelist: None,
                 head,
                 orphan: None,
+                trees: None,
+                collation: file.collation_id(),
+                fanout,
+                format: RecordSeq::FORMAT,
+                pinned: 0,
+                expiry: file.expiry().into(),
             }));
-            s.lock().fill_cache(file, None)?;
+            let mut lock = s.lock();
+            lock.fill_cache(file, None)?;
+            lock.reset_logs(file);
+            drop(lock);
             file.sync()?;
 
             log::info!("did initialize empty database");
 
             Ok(s)
         } else {
-            let it = (0..Self::SIZE)
-                .map(PagePtr::<RecordPage>::from_raw_number)
-                .map(|ptr| file.read(ptr))
-                .filter_map(|p| p.check().copied());
+            if let Some(pages) = file.disk_pages()?.filter(|pages| *pages < Self::SIZE) {
+                return Err(WalError::TooSmall {
+                    pages,
+                    min: Self::SIZE,
+                });
+            }
+            let mut records = Vec::with_capacity(Self::SIZE as usize);
+            let mut skipped_records = 0;
+            let mut best_seq = None;
+            for n in 0..Self::SIZE {
+                let page = file.read_page(n)?;
+                match RecordPage::parse(&page) {
+                    Some(record) => records.push(record),
+                    // a page never written is zeros, otherwise its write is torn
+                    None if page.iter().any(|b| *b != 0) => {
+                        skipped_records += 1;
+                        best_seq = best_seq.max(Some(RecordPage::raw_seq(&page)));
+                    }
+                    None => {}
+                }
+            }
+            let it = records.into_iter();
 
             let inner = it.max_by(|a, b| a.seq.cmp(&b.seq));
 
-            let wal = inner.map(Mutex::new).map(Self).ok_or(WalError::BadWal)?;
+            let wal = inner
+                .map(WalState::new)
+                .map(Self::from_state)
+                .ok_or_else(|| WalError::NoRecord {
+                    slots: Self::SIZE,
+                    torn: skipped_records,
+                    best_seq,
+                    wrong_secret: skipped_records > 0 && file.encrypted(),
+                })?;
 
             let mut lock = wal.lock();
-            let stats = lock.stats(file);
-            log::info!("did open database, will unroll log, stats: {stats:?}");
-            lock.unroll(file)?;
+            let (stored, given) = (lock.0.record.collation, file.collation_id());
+            if stored != given {
+                return Err(WalError::Collation { stored, given });
+            }
+            let truncated_pages = lock.unroll(file)?;
+            // an older version did not keep the expiration, its values take the whole page
+            file.set_expiry(lock.0.record.expiry != 0);
+            // the length is trusted, but the first layouts have zero padding in its place,
+            // nor do they keep the fanout
+            if lock.0.record.format < 2 {
+                lock.0.record.freelist_len = lock.freelist_size(file)?;
+            }
+            match lock.0.record.fanout {
+                0 => lock.0.record.fanout = fanout,
+                stored if stored != fanout => {
+                    return Err(WalError::Fanout {
+                        stored,
+                        given: fanout,
+                    });
+                }
+                _ => {}
+            }
+            let stats = lock.stats_fast(file);
+            log::info!("did open database, stats: {stats:?}");
             let orphan = lock.orphan_mut().take();
+            let report = RecoveryReport {
+                skipped_records,
+                orphan_reclaimed: orphan.is_some(),
+                truncated_pages,
+                lost_reclaimed: 0,
+            };
+            log::info!("did recover: {report:?}");
+            lock.0.seq_at_open = lock.0.record.seq;
+            lock.0.recovery = Some(report);
+            lock.0.lost = lock.0.record.pinned > 0;
             lock.fill_cache(file, orphan)?;
+            lock.reset_logs(file);
             drop(lock);
             log::info!("did unroll log");
 
@@ -96,20 +466,187 @@ impl Wal {
         }
     }
 
+    fn from_state(state: WalState) -> Self {
+        Wal {
+            state: RwLock::new(state),
+            owner: Mutex::new(None),
+            released: Condvar::new(),
+            waiting: AtomicUsize::new(0),
+            dropped: AtomicBool::new(false),
+        }
+    }
+
+    fn locked<'a>(&'a self, guard: RwLockWriteGuard<'a, WalState>) -> WalLock<'a> {
+        *self.owner.lock().expect("poisoned") = Some(thread::current().id());
+        WalLock(guard, Release(self))
+    }
+
+    /// Whether the current thread holds the write lock, `lock` would never return then
+    pub fn is_locked_here(&self) -> bool {
+        *self.owner.lock().expect("poisoned") == Some(thread::current().id())
+    }
+
+    /// Frees the garbage and the orphan, writes the final record
+    /// and waits until the file is on the disk.
+    /// Does nothing if it is closed already or a change did panic.
+    pub fn close(&self, file: &FileIo) -> Result<(), WalError> {
+        let Ok(guard) = self.state.write() else {
+            return Ok(());
+        };
+        let mut lock = self.locked(guard);
+        if lock.0.closed {
+            return Ok(());
+        }
+        lock.reclaim(file)?;
+        lock.write(file)?;
+        file.sync_all()?;
+        lock.0.closed = true;
+
+        Ok(())
+    }
+
+    /// Exclusive access to change the tree
     pub fn lock(&self) -> WalLock<'_> {
-        WalLock(self.0.lock().expect("poisoned"))
+        self.locked(self.state.write().expect("poisoned"))
+    }
+
+    /// Shared access, readers do not wait for each other, only for a change
+    pub fn read(&self) -> WalReadLock<'_> {
+        WalReadLock {
+            guard: self.state.read().expect("poisoned"),
+            _release: Release(self),
+        }
+    }
+
+    /// Frees the allocated value without waiting for the lock, if it is busy,
+    /// the page stays in the orphan slot until the next holder frees it
+    pub fn release_allocated(&self, file: &FileIo, ptr: PagePtr<()>) -> Result<(), WalError> {
+        match self.state.try_write() {
+            Ok(guard) => self.locked(guard).release(file, ptr),
+            Err(TryLockError::WouldBlock) => {
+                self.dropped.store(true, Ordering::Release);
+                Ok(())
+            }
+            Err(TryLockError::Poisoned(_)) => Ok(()),
+        }
+    }
+
+    /// `None` if the lock is held by someone else
+    pub fn try_lock(&self) -> Option<WalLock<'_>> {
+        match self.state.try_write() {
+            Ok(guard) => Some(self.locked(guard)),
+            Err(TryLockError::WouldBlock) => None,
+            Err(TryLockError::Poisoned(_)) => panic!("poisoned"),
+        }
+    }
+
+    /// `None` if the lock is still held by someone else after `timeout`,
+    /// sleeps until it is released rather than polling
+    pub fn lock_timeout(&self, timeout: Duration) -> Option<WalLock<'_>> {
+        let start = Instant::now();
+        let mut owner = self.owner.lock().expect("poisoned");
+        self.waiting.fetch_add(1, Ordering::Relaxed);
+        // pairs with the fence in `Release::drop`, either the lock is seen free
+        // or the releaser sees the waiter and notifies it
+        atomic::fence(Ordering::SeqCst);
+        let lock = loop {
+            match self.state.try_write() {
+                Ok(guard) => {
+                    *owner = Some(thread::current().id());
+                    break Some(WalLock(guard, Release(self)));
+                }
+                Err(TryLockError::WouldBlock) => {}
+                Err(TryLockError::Poisoned(_)) => panic!("poisoned"),
+            }
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                break None;
+            }
+            owner = self
+                .released
+                .wait_timeout(owner, timeout - elapsed)
+                .expect("poisoned")
+                .0;
+        };
+        self.waiting.fetch_sub(1, Ordering::Relaxed);
+
+        lock
     }
 }
 
-pub struct WalLock<'a>(MutexGuard<'a, RecordSeq>);
+// wakes `lock_timeout` once the guard before it is dropped
+struct Release<'a>(&'a Wal);
 
-impl WalLock<'_> {
+impl Drop for Release<'_> {
+    fn drop(&mut self) {
+        atomic::fence(Ordering::SeqCst);
+        if self.0.waiting.load(Ordering::Relaxed) != 0 {
+            // the waiter holds `owner` until it sleeps, so it cannot miss this
+            drop(self.0.owner.lock());
+            self.0.released.notify_all();
+        }
+    }
+}
+
+/// Exclusive access, needed to change the tree
+pub struct WalLock<'a>(RwLockWriteGuard<'a, WalState>, Release<'a>);
+
+impl Drop for WalLock<'_> {
+    fn drop(&mut self) {
+        // a panic while the lock is held poisons it anyway
+        if let Ok(mut owner) = self.1 .0.owner.lock() {
+            *owner = None;
+        }
+    }
+}
+
+/// Shared access, enough to read the tree
+pub struct WalReadLock<'a> {
+    guard: RwLockReadGuard<'a, WalState>,
+    // dropped after the guard
+    _release: Release<'a>,
+}
+
+impl Deref for WalLock<'_> {
+    type Target = WalState;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Deref for WalReadLock<'_> {
+    type Target = WalState;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl WalState {
+    /// Walks the persistent freelist to count its pages,
+    /// falls back to the length from the log record if the walk fails
     pub fn stats(&self, file: &FileIo) -> DbStats {
-        let total = self.0.size - Wal::SIZE;
-        let cached = self.0.cache.len();
-        let free = self.freelist_size(file) + self.0.garbage.len();
+        let freelist_len = self.freelist_size(file).unwrap_or_else(|err| {
+            log::error!("failed to read the freelist: {err}");
+            self.record.freelist_len
+        });
+        self.stats_inner(file, freelist_len)
+    }
+
+    /// Takes the length of the freelist from the log record
+    pub fn stats_fast(&self, file: &FileIo) -> DbStats {
+        self.stats_inner(file, self.record.freelist_len)
+    }
+
+    fn stats_inner(&self, file: &FileIo, freelist_len: u32) -> DbStats {
+        let total = self.record.size - Wal::SIZE;
+        let cached = self.record.cache.len();
+        let garbage = self.record.garbage.len();
+        let free = freelist_len + garbage;
         let used = total - cached - free;
-        let seq = self.0.seq;
+        let seq = self.record.seq;
+        let (cache_hits, cache_misses) = file.cache_stats();
 
         DbStats {
             total,
@@ -118,48 +655,299 @@ impl WalLock<'_> {
             used,
             seq,
             writes: file.writes(),
+            cache_hits,
+            cache_misses,
+            freelist_len,
+            garbage,
+            fragmentation: f64::from(free) / f64::from(total),
+            cache_pages: file.cache_len(),
+            seq_at_open: self.seq_at_open,
+            pages_written_last_op: self.last_op_writes,
+            tree_pages: 0,
+            data_pages: 0,
+            pinned_pages: file.pinned_len(),
+            record_format: self.record.format,
+            capacity_left: file.max_pages().saturating_sub(self.record.size),
         }
     }
 
-    fn ptr(&self) -> Option<PagePtr<RecordPage>> {
-        Self::seq_to_ptr(self.0.seq)
+    pub fn free_pages(&self, file: &FileIo) -> io::Result<Vec<u32>> {
+        let mut pages = self
+            .record
+            .cache
+            .iter()
+            .chain(self.record.garbage.iter())
+            .map(PagePtr::raw_number)
+            .collect::<Vec<_>>();
+        pages.extend(self.freelist_pages(file)?);
+
+        Ok(pages)
     }
 
-    fn seq_to_ptr(seq: u64) -> Option<PagePtr<RecordPage>> {
-        let pos = (seq % u64::from(Wal::SIZE)) as u32;
-        PagePtr::<RecordPage>::from_raw_number(pos)
+    /// Number of pages in the file, excluding the crypto header
+    pub fn size(&self) -> u32 {
+        self.record.size
+    }
+
+    pub fn is_pinned(&self) -> bool {
+        self.pinned > 0
+    }
+
+    /// The last run did crash while a snapshot was pinned, some pages are lost
+    pub fn was_pinned(&self) -> bool {
+        self.lost
+    }
+
+    pub fn is_allocated(&self) -> bool {
+        self.allocated
+    }
+
+    /// The page allocated, but not inserted yet
+    pub fn allocated<T>(&self) -> Option<PagePtr<T>> {
+        self.allocated
+            .then_some(self.record.orphan)
+            .flatten()
+            .map(PagePtr::cast)
+    }
+
+    pub fn current_head<T>(&self) -> PagePtr<T> {
+        self.record.head.cast()
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.record.seq
+    }
+
+    pub fn recovery(&self) -> Option<RecoveryReport> {
+        self.recovery
+    }
+
+    /// `Node::M` of the tree the database is created with
+    pub fn fanout(&self) -> usize {
+        self.record.fanout as usize
+    }
+
+    /// Checksum of the record `seq` and the pages written after it,
+    /// `None` if the record is not kept
+    pub fn pages_since(&self, seq: u64) -> Option<(u64, BTreeSet<u32>)> {
+        let records = &self.pages.records;
+        let pos = records.iter().position(|(s, ..)| *s == seq)?;
+        let pages = records.iter().skip(pos + 1).flat_map(|(_, _, pages)| pages);
+
+        Some((records[pos].1, pages.copied().collect()))
+    }
+
+    /// Number of pages the last record written to the log has
+    pub fn written_size(&self) -> u32 {
+        self.pages.last.inner.size
+    }
+
+    /// Image of the last record written to the log
+    pub fn record_page(&self) -> PBox {
+        let mut page = PBox::new(4096, [0; PAGE_SIZE as usize]);
+        let bytes = self.pages.last.as_bytes();
+        page[..bytes.len()].clone_from_slice(bytes);
+
+        page
+    }
+
+    /// Sorted keys changed after `version`, `Err` with the oldest version
+    /// the changes are kept for if `version` is older
+    pub fn changes_since(&self, version: u64) -> Result<Vec<Vec<u8>>, u64> {
+        let log = &self.changes;
+        if version < log.oldest {
+            return Err(log.oldest);
+        }
+        let changed = log.keys.iter().filter(|(seq, _)| *seq > version);
+        let mut keys = changed.map(|(_, key)| key.clone()).collect::<Vec<_>>();
+        keys.sort();
+        keys.dedup();
+
+        Ok(keys)
+    }
+
+    pub fn orphan(&self) -> Option<PagePtr<()>> {
+        self.record.orphan
+    }
+
+    pub fn trees(&self) -> Option<PagePtr<TreesPage>> {
+        self.record.trees
+    }
+
+    /// The root of the tree, `None` if it is not created yet
+    pub fn tree_head<T>(&self, file: &FileIo, id: u8) -> io::Result<Option<PagePtr<T>>> {
+        if id == Wal::MAIN {
+            return Ok(Some(self.current_head()));
+        }
+        let Some(ptr) = self.record.trees else {
+            return Ok(None);
+        };
+        let trees = file.read(ptr)?;
+
+        Ok(trees.roots[usize::from(id)].map(PagePtr::cast))
+    }
+
+    fn freelist_size(&self, file: &FileIo) -> io::Result<u32> {
+        Ok(self.freelist_pages(file)?.len() as u32)
+    }
+
+    /// The pages of the persistent freelist in order, fails with `BadFreelist`
+    /// on a page out of the file or on a page met twice, rather than looping
+    pub fn freelist_pages(&self, file: &FileIo) -> io::Result<Vec<u32>> {
+        let mut pages = vec![];
+        let mut visited = BTreeSet::new();
+        let mut freelist = self.record.freelist;
+
+        while let Some(ptr) = freelist {
+            let n = ptr.raw_number();
+            if n < Wal::SIZE || n >= self.record.size || !visited.insert(n) {
+                return Err(BadFreelist(n).into());
+            }
+            pages.push(n);
+            freelist = file.read(ptr)?.next;
+        }
+        Ok(pages)
+    }
+}
+
+impl WalLock<'_> {
+    // each record is written twice, a torn write spoils only one copy
+    fn seq_to_ptrs(seq: u64) -> [Option<PagePtr<RecordPage>>; 2] {
+        let pos = (seq % u64::from(Wal::RECORDS)) as u32 * 2;
+        [pos, pos + 1].map(PagePtr::from_raw_number)
     }
 
     fn next(&mut self) {
-        self.0.seq = self.0.seq.wrapping_add(1);
+        self.0.record.seq = self.0.record.seq.wrapping_add(1);
     }
 
     fn write(&mut self, file: &FileIo) -> Result<(), WalError> {
+        if file.syncs() != self.0.synced.record {
+            self.0.synced.seq = self.0.record.seq;
+        }
+        // the copies of the last record on the disk are next to be overwritten
+        let distance = self.0.record.seq.wrapping_sub(self.0.synced.seq);
+        if distance + 1 >= u64::from(Wal::RECORDS) {
+            file.sync()?;
+            self.0.synced.seq = self.0.record.seq;
+        }
         self.next();
-        let page = RecordPage::new(*self.0);
-        file.write(self.ptr(), PageKind::Log, page)?;
+        // a record read in an older layout is migrated by the first write
+        self.0.record.format = RecordSeq::FORMAT;
+        self.0.record.pinned = u64::from(self.0.pinned) + u64::from(self.0.lost);
+        let page = RecordPage::new(self.0.record);
+        for ptr in Self::seq_to_ptrs(self.0.record.seq) {
+            file.write(ptr, PageKind::Log, page)?;
+        }
+        self.0.synced.record = file.syncs();
+        self.0.pages.push(page, file.take_written());
+        self.pin_roots(file);
 
         Ok(())
     }
 
-    fn unroll(&mut self, file: &FileIo) -> Result<(), WalError> {
-        let mut reverse = self.0.seq;
+    // remembers the state before a change, unless the change did begin already
+    fn begin(&mut self) {
+        if self.0.undo.is_none() {
+            self.0.undo = Some(Undo {
+                record: self.0.record,
+                last: self.0.pages.last,
+                deferred: self.0.deferred.clone(),
+                fresh: self.0.fresh.clone(),
+                synced: self.0.synced,
+                free: self.0.free.clone(),
+            });
+        }
+    }
 
-        loop {
-            let page = file.read(Self::seq_to_ptr(reverse));
-            if let Some(inner) = page.check() {
-                *self.0 = *inner;
-                break;
-            } else {
-                reverse = reverse.wrapping_sub(1);
+    // the record of the change is not on the disk, it is as if the change did not happen:
+    // the pages it did allocate are free again, the last record is in the log slots again
+    fn rollback(&mut self, file: &FileIo, undo: Undo) {
+        let seq = undo.last.inner.seq;
+        while self.0.pages.records.len() > 1 {
+            match self.0.pages.records.pop_back() {
+                Some((s, _, pages)) if s != seq => file.restore_written(pages),
+                Some(entry) => {
+                    self.0.pages.records.push_back(entry);
+                    break;
+                }
+                None => break,
             }
         }
+        self.0.pages.last = undo.last;
+        if self.0.record.seq != undo.record.seq {
+            // replaces the copies of the newer record in the cache
+            for ptr in Self::seq_to_ptrs(seq) {
+                if let Err(err) = file.write(ptr, PageKind::Log, undo.last) {
+                    log::error!("failed to restore the record: {err}");
+                }
+            }
+        }
+        self.0.record = undo.record;
+        self.0.deferred = undo.deferred;
+        self.0.fresh = undo.fresh;
+        self.0.synced = Synced {
+            record: file.syncs(),
+            ..undo.synced
+        };
+        self.0.free = undo.free;
+    }
 
-        file.set_pages(self.0.size)?;
+    // the logs start at the current record, what was written before is forgotten
+    fn reset_logs(&mut self, file: &FileIo) {
+        file.take_written();
+        self.0.pages = PageLog::new(self.0.record);
+        self.0.changes.keys.clear();
+        self.0.changes.oldest = self.0.record.seq;
+        self.pin_roots(file);
+    }
 
-        Ok(())
+    fn pin_roots(&self, file: &FileIo) {
+        let trees = self.0.record.trees.map(PagePtr::raw_number);
+        file.pin_roots(self.0.record.head.raw_number(), trees);
     }
 
+    // returns how many pages the file is cut by
+    fn unroll(&mut self, file: &FileIo) -> Result<u32, WalError> {
+        let seq = self.0.record.seq;
+
+        // either copy, if intact, the newest one read is kept otherwise
+        'records: for reverse in (0..u64::from(Wal::RECORDS)).map(|i| seq.wrapping_sub(i)) {
+            for ptr in Self::seq_to_ptrs(reverse) {
+                if let Some(inner) = RecordPage::read(file, ptr)?.filter(|r| r.seq == reverse) {
+                    self.0.record = inner;
+                    break 'records;
+                }
+            }
+            // older versions did write each record once at `seq % 256`, the page
+            // goes as is to both slots of the current layout before anything else
+            let old = (reverse % u64::from(Wal::SIZE)) as u32;
+            let page = file.read_page(old)?;
+            if let Some(inner) = RecordPage::parse(&page).filter(|r| r.seq == reverse) {
+                for ptr in Self::seq_to_ptrs(reverse).into_iter().flatten() {
+                    file.write_page(ptr.raw_number(), PageKind::Log, page.clone())?;
+                }
+                file.sync()?;
+                self.0.record = inner;
+                break 'records;
+            }
+        }
+
+        // pages grown after the record was written are cut off, the file is shorter
+        // than before the crash, a later grow reads zeros there
+        let pages = file.disk_pages()?;
+        file.set_pages(self.0.record.size)?;
+
+        Ok(pages.map_or(0, |n| n.saturating_sub(self.0.record.size)))
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            skip_all,
+            fields(recycled = tracing::field::Empty, grown = tracing::field::Empty)
+        )
+    )]
     fn fill_cache(&mut self, file: &FileIo, orphan: Option<PagePtr<()>>) -> Result<(), WalError> {
         struct FreelistCacheIter<'a>(&'a mut FreelistCache);
 
@@ -171,54 +959,128 @@ impl WalLock<'_> {
             }
         }
 
-        let mut freelist = self.0.freelist;
-        let (cache, garbage) = self.cache_mut();
-        let garbage = FreelistCacheIter(garbage);
+        if let Some(ptr) = orphan {
+            file.value_freed(ptr.raw_number());
+            self.0.free.free(ptr);
+        }
+        let state = &mut *self.0;
+        let garbage = FreelistCacheIter(&mut state.record.garbage);
         let orphan = orphan.map(|ptr| (PageKind::Data, ptr.cast()));
-        let mut iter = garbage.map(|ptr| (PageKind::Tree, ptr)).chain(orphan);
+        let data = &mut state.data_garbage;
+        let mut released = garbage
+            .map(|ptr| (garbage_kind(data, ptr), ptr))
+            .chain(orphan)
+            .collect::<Vec<_>>();
+        // pages of a pinned snapshot must not be reused
+        if state.pinned > 0 {
+            state.deferred.append(&mut released);
+        } else {
+            released.append(&mut state.deferred);
+        }
 
-        loop {
-            if !cache.is_full() {
-                if let Some((_, ptr)) = iter.next() {
-                    cache.put(ptr);
-                    continue;
-                }
-            }
+        self.recycle(file, released)
+    }
 
-            break;
+    // puts the pages in the cache, the rest in the freelist, then fills the cache;
+    // the pages the last record on the disk may refer to go under the others
+    fn recycle(
+        &mut self,
+        file: &FileIo,
+        mut released: Vec<(PageKind, PagePtr<FreePage>)>,
+    ) -> Result<(), WalError> {
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record("recycled", released.len());
+        if file.syncs() != self.0.synced.pages {
+            self.0.synced.held = 0;
         }
-
-        while let Some((kind, ptr)) = iter.next() {
+        let mut held = self.0.synced.held.min(self.0.record.cache.len());
+        let mut freelist = self.0.record.freelist;
+        let mut freelist_len = self.0.record.freelist_len;
+        let state = &mut *self.0;
+        let cache = &mut state.record.cache;
+        let mut push = |kind, ptr| {
             let page = FreePage { next: freelist };
             file.write(ptr, kind, page)?;
             freelist = Some(ptr);
-        }
+            freelist_len += 1;
+            io::Result::Ok(())
+        };
 
-        while !self.0.cache.is_full() {
-            if let Some(ptr) = freelist {
-                self.0.cache.put(ptr);
-                freelist = file.read(ptr).next;
-            } else {
-                break;
+        // make room moving the pages free on the disk to the freelist
+        while (cache.capacity() as usize) < released.len() && cache.len() > held {
+            push(PageKind::Tree, cache.take().expect("must not be empty"))?;
+        }
+        if (cache.capacity() as usize) < released.len() {
+            // the records releasing the pages are written, nothing is held anymore
+            if !state.batch {
+                file.sync()?;
+                held = 0;
             }
+            let rest = released.split_off(cache.capacity() as usize);
+            for (_, ptr) in released {
+                cache.put(ptr);
+            }
+            for (kind, ptr) in rest {
+                push(kind, ptr)?;
+            }
+        } else {
+            held += released.len() as u32;
+            cache.put_under(released.into_iter().map(|(_, ptr)| ptr));
         }
-        let freelist_change = self.0.freelist != freelist;
-        self.0.freelist = freelist;
 
-        let resize = !self.0.cache.is_full();
+        // the freelist of the last record on the disk may go through these pages
+        let mut taken = vec![];
+        while cache.len() + (taken.len() as u32) < FreelistCache::SIZE {
+            let Some(ptr) = freelist else {
+                break;
+            };
+            // the change is already written, the file grows instead
+            let next = match state.fresh.take(ptr) {
+                Some(next) => next,
+                None => match file.read(ptr) {
+                    Ok(page) => page.next,
+                    Err(err) => {
+                        log::error!("failed to read the freelist: {err}");
+                        break;
+                    }
+                },
+            };
+            taken.push(ptr);
+            freelist = next;
+            freelist_len -= 1;
+        }
+        held += taken.len() as u32;
+        state.free.add(taken.iter().copied());
+        cache.put_under(taken);
+        let freelist_change = self.0.record.freelist != freelist;
+        self.0.record.freelist = freelist;
+        self.0.record.freelist_len = freelist_len;
+
+        let resize = !self.0.record.cache.is_full();
         if resize {
+            #[cfg(feature = "tracing")]
+            tracing::Span::current().record("grown", self.0.record.cache.capacity());
             let ptr = file
-                .grow(self.0.size, self.0.cache.capacity())?
+                .grow(self.0.record.size, self.0.record.cache.capacity())?
                 .expect("grow must yield value");
-            self.0.size += self.0.cache.capacity();
-            for i in 0..self.0.cache.capacity() {
-                self.0.cache.put(ptr.add(i));
+            self.0.record.size += self.0.record.cache.capacity();
+            for i in 0..self.0.record.cache.capacity() {
+                self.0.free.add([ptr.add(i)]);
+                self.0.record.cache.put(ptr.add(i));
             }
         }
 
         if freelist_change || resize {
             self.write(file)?;
         }
+        // a change takes the pages from the top, they must be enough
+        let ready = self.0.record.cache.len().saturating_sub(held);
+        if held > 0 && ready < FreelistCache::SIZE / 2 && !self.0.batch {
+            file.sync()?;
+            held = 0;
+        }
+        self.0.synced.pages = file.syncs();
+        self.0.synced.held = held;
 
         Ok(())
     }
@@ -229,38 +1091,593 @@ impl WalLock<'_> {
         head: PagePtr<T>,
         orphan: Option<PagePtr<()>>,
     ) -> Result<(), WalError> {
-        self.0.head = head.cast();
-        self.write(file)?;
+        self.begin();
+        self.0.record.head = head.cast();
+        let mut res = self.write(file);
+        let syncs = file.syncs();
+        if res.is_ok() {
+            res = self.fill_cache(file, orphan);
+        }
+        let undo = self.0.undo.take().expect("must begin");
+        // once a sync is done, the record is on the disk and the change stays
+        if res.is_err() && file.syncs() == syncs {
+            self.rollback(file, undo);
+        }
+
+        res
+    }
+
+    /// Same as `new_head`, but for the tree `id`. The trees page is copied,
+    /// so the root of the tree and the record change at once.
+    pub fn new_tree_head<T>(
+        &mut self,
+        file: &FileIo,
+        id: u8,
+        root: PagePtr<T>,
+        orphan: Option<PagePtr<()>>,
+    ) -> Result<(), WalError> {
+        if id == Wal::MAIN {
+            return self.new_head(file, root, orphan);
+        }
+        let mut trees = match self.0.record.trees {
+            Some(ptr) => file.read(ptr)?,
+            None => TreesPage::empty(),
+        };
+        trees.set_root(id, root);
+        self.begin();
+        let ptr = self.0.record.cache.alloc();
+        self.0.free.alloc(ptr);
+        if let Err(err) = file.write(ptr, PageKind::Tree, trees) {
+            let undo = self.0.undo.take().expect("must begin");
+            self.rollback(file, undo);
+            return Err(err.into());
+        }
+        if let Some(old) = self.0.record.trees.replace(ptr) {
+            self.0.free.free(old);
+            self.0.record.garbage.free(old);
+        }
+        self.new_head(file, self.current_head::<()>(), orphan)
+    }
+
+    /// Remembers how many pages the last insert or remove did write
+    pub fn op_written(&mut self, writes: u32) {
+        self.0.last_op_writes = writes;
+    }
+
+    /// Remembers the key of the main tree changed by the last record
+    pub fn touch(&mut self, key: Vec<u8>) {
+        let seq = self.0.record.seq;
+        self.0.changes.push(seq, key);
+    }
+
+    /// Makes an empty leaf the root of the tree `id`
+    pub fn create_tree<T>(&mut self, file: &FileIo, id: u8) -> Result<PagePtr<T>, WalError> {
+        // a zeroed node is an empty leaf
+        let root = self.0.record.cache.alloc::<FreePage>();
+        self.0.free.alloc(root);
+        let page = PBox::new(4096, [0; PAGE_SIZE as usize]);
+        file.write_page(root.raw_number(), PageKind::Tree, page)?;
+        self.new_tree_head(file, id, root, None)?;
+
+        Ok(root.cast())
+    }
+
+    /// Frees the deferred garbage and the orphan, returns the number of pages.
+    /// An allocated value stays in the orphan slot.
+    pub fn reclaim(&mut self, file: &FileIo) -> Result<u32, WalError> {
+        self.release_dropped(file)?;
+        let orphan = if self.0.allocated {
+            None
+        } else {
+            self.0.record.orphan.take()
+        };
+        let n = self.0.record.garbage.len() + u32::from(orphan.is_some());
         self.fill_cache(file, orphan)?;
+        // the held pages are free on the disk too after the sync
+        if self.0.synced.held > 0 {
+            file.sync()?;
+            self.0.synced.held = 0;
+        }
+
+        Ok(n)
+    }
+
+    /// Grows the file by `n` pages at once and puts them in the persistent freelist.
+    /// The pages are chained and written in batches bypassing the cache,
+    /// then a single record takes the new size. A crash before the record
+    /// leaves the old state, the file is truncated on open.
+    pub fn preallocate(&mut self, file: &FileIo, n: u32) -> Result<(), WalError> {
+        const BATCH: usize = 0x100;
+
+        if n == 0 {
+            return Ok(());
+        }
+        let old = self.0.record.size;
+        let size = old
+            .checked_add(n)
+            .ok_or(io::Error::from(io::ErrorKind::InvalidInput))?;
+        file.set_pages(size)?;
+
+        let mut freelist = self.0.record.freelist;
+        let mut pages = Vec::with_capacity(BATCH);
+        for ptr in (old..size).filter_map(PagePtr::<FreePage>::from_raw_number) {
+            let free = FreePage { next: freelist };
+            let mut page = PBox::new(4096, [0; PAGE_SIZE as usize]);
+            page[..mem::size_of::<FreePage>()].clone_from_slice(free.as_bytes());
+            pages.push((ptr.raw_number(), page));
+            freelist = Some(ptr);
+            if pages.len() == BATCH {
+                file.write_batch(PageKind::Tree, mem::take(&mut pages))?;
+            }
+        }
+        file.write_batch(PageKind::Tree, pages)?;
+
+        self.0.fresh = FreshPages {
+            range: old..size,
+            tail: self.0.record.freelist,
+        };
+        self.0.record.size = size;
+        self.0.record.freelist = freelist;
+        self.0.record.freelist_len += n;
+        self.write(file)?;
+        file.sync()?;
 
         Ok(())
     }
 
-    pub fn current_head<T>(&self) -> PagePtr<T> {
-        self.0.head.cast()
+    /// Pins the current head, pages reachable from it will not be reused
+    /// until `unpin`. The pages released meanwhile are kept only in memory.
+    pub fn pin<T>(&mut self) -> PagePtr<T> {
+        self.0.pinned += 1;
+        self.current_head()
+    }
+
+    pub fn unpin(&mut self, file: &FileIo) -> Result<(), WalError> {
+        self.0.pinned -= 1;
+        if self.0.pinned == 0 && !self.0.deferred.is_empty() {
+            self.fill_cache(file, None)?;
+        }
+
+        Ok(())
+    }
+
+    /// Frees the pages a crash did lose while a snapshot was pinned,
+    /// `lost` are neither used nor free
+    pub fn free_lost(&mut self, file: &FileIo, lost: Vec<u32>) -> Result<(), WalError> {
+        let lost = lost
+            .into_iter()
+            .filter_map(PagePtr::<FreePage>::from_raw_number)
+            .collect::<Vec<_>>();
+        if let Some(report) = &mut self.0.recovery {
+            report.lost_reclaimed = lost.len() as u32;
+            log::info!("did reclaim pages of a pinned snapshot: {}", lost.len());
+        }
+        self.0.free.add(lost.iter().copied());
+        self.recycle(
+            file,
+            lost.into_iter().map(|ptr| (PageKind::Tree, ptr)).collect(),
+        )?;
+        // the record forgets the snapshot
+        self.0.lost = false;
+        self.write(file)?;
+        file.sync()?;
+
+        Ok(())
+    }
+
+    /// Sets whether the value pages keep the expiration, only while there are no values,
+    /// e.g. for a copy of a database created by an older version
+    pub fn set_expiry(&mut self, file: &FileIo, expiry: bool) -> Result<(), WalError> {
+        self.0.record.expiry = expiry.into();
+        file.set_expiry(expiry);
+        self.write(file)
+    }
+
+    /// Forgets the persistent freelist, so its pages can be overwritten.
+    /// They stay lost if the process crashes before `install`.
+    pub fn detach_freelist(&mut self, file: &FileIo) -> Result<(), WalError> {
+        self.0.record.freelist = None;
+        self.0.record.freelist_len = 0;
+        self.0.fresh = FreshPages::default();
+        self.write(file)
+    }
+
+    /// Replaces the head, the size of the file and all free pages at once,
+    /// then truncates the file. The record must be on disk before the truncation.
+    pub fn install<T>(
+        &mut self,
+        file: &FileIo,
+        head: PagePtr<T>,
+        trees: Option<PagePtr<TreesPage>>,
+        size: u32,
+        free: impl IntoIterator<Item = u32>,
+    ) -> Result<(), WalError> {
+        self.0.fresh = FreshPages::default();
+        self.0.synced.held = 0;
+        let state = &mut *self.0;
+        let record = &mut state.record;
+        record.head = head.cast();
+        record.trees = trees;
+        record.size = size;
+        record.garbage = FreelistCache::empty();
+        state.data_garbage.clear();
+        record.cache = FreelistCache::empty();
+        record.orphan = None;
+        record.freelist = None;
+        record.freelist_len = 0;
+        state.free = FreeSet::new::<FreePage>([]);
+        for ptr in free.into_iter().filter_map(PagePtr::from_raw_number) {
+            state.free.add([ptr]);
+            if !record.cache.is_full() {
+                record.cache.put(ptr);
+            } else {
+                let page = FreePage {
+                    next: record.freelist,
+                };
+                file.write(ptr, PageKind::Tree, page)?;
+                record.freelist = Some(ptr);
+                record.freelist_len += 1;
+            }
+        }
+        self.write(file)?;
+        file.sync()?;
+        file.set_pages(size)?;
+        // grows the file if there were too few free pages for the cache
+        self.fill_cache(file, None)
     }
 
-    pub fn cache_mut(&mut self) -> (&mut FreelistCache, &mut FreelistCache) {
-        let inner = self.0.deref_mut();
-        (&mut inner.cache, &mut inner.garbage)
+    /// Replaces the tree with an empty leaf and forgets the other trees, the record is on disk
+    /// before the pages of the old trees are freed, so a crash leaves either state.
+    /// If the process crashes meanwhile, the old pages are lost.
+    pub fn clear(
+        &mut self,
+        file: &FileIo,
+        old: impl IntoIterator<Item = (PageKind, u32)>,
+    ) -> Result<(), WalError> {
+        // a zeroed node is an empty leaf
+        let head = self.0.record.cache.alloc::<FreePage>();
+        self.0.free.alloc(head);
+        let page = PBox::new(4096, [0; PAGE_SIZE as usize]);
+        file.write_page(head.raw_number(), PageKind::Tree, page)?;
+        self.0.record.head = head.cast();
+        self.0.record.trees = None;
+        self.write(file)?;
+        file.sync()?;
+        // every key is gone, the changes before are meaningless,
+        // the pages are still shipped as usual
+        self.0.changes.keys.clear();
+        self.0.changes.oldest = self.0.record.seq;
+
+        // deferred if pinned, otherwise freed right away
+        file.values_moved();
+        let old = old
+            .into_iter()
+            .filter_map(|(kind, n)| Some((kind, PagePtr::from_raw_number(n)?)));
+        for (kind, ptr) in old {
+            self.0.free.free(ptr);
+            self.0.deferred.push((kind, ptr));
+        }
+        self.fill_cache(file, None)
     }
 
+    /// Takes the pages and the record of another database with the same layout.
+    /// Unless `base` is `None`, it is the checksum of the record the changes follow,
+    /// it must be the current one. Otherwise the pages are the whole database,
+    /// the other records of the log are wiped, so an older one never wins on open.
+    /// The pages go to the disk before the record, a crash leaves the old state.
+    pub fn apply(
+        &mut self,
+        file: &FileIo,
+        base: Option<u64>,
+        record: &PBox,
+        pages: impl IntoIterator<Item = (u32, PBox)>,
+    ) -> Result<(), WalError> {
+        if self.0.pinned > 0 {
+            return Err(WalError::Pinned);
+        }
+        if self.0.allocated {
+            return Err(WalError::Allocated);
+        }
+        if base.is_some_and(|base| base != self.0.pages.last.checksum) {
+            return Err(WalError::Diverged);
+        }
+        let inner = RecordPage::parse(record).ok_or(WalError::BadWal)?;
+        let (stored, given) = (inner.collation, self.0.record.collation);
+        if stored != given {
+            return Err(WalError::Collation { stored, given });
+        }
+        let (stored, given) = (inner.fanout, self.0.record.fanout);
+        if stored != 0 && stored != given {
+            return Err(WalError::Fanout { stored, given });
+        }
+
+        file.set_pages(inner.size)?;
+        file.values_moved();
+        for (n, page) in pages {
+            if n < Wal::SIZE || n >= inner.size {
+                return Err(WalError::BadWal);
+            }
+            file.write_page(n, PageKind::Data, page)?;
+        }
+        file.sync()?;
+        let ptrs = Self::seq_to_ptrs(inner.seq);
+        if base.is_none() {
+            // the cache keeps one record of the log, each page is synced on its own
+            let copies = ptrs.map(|ptr| ptr.map_or(0, PagePtr::raw_number));
+            for pos in (0..Wal::SIZE).filter(|pos| !copies.contains(pos)) {
+                let page = PBox::new(4096, [0; PAGE_SIZE as usize]);
+                file.write_page(pos, PageKind::Log, page)?;
+                file.sync()?;
+            }
+        }
+        self.0.record = inner;
+        // the pages are the primary's, so is their layout
+        file.set_expiry(inner.expiry != 0);
+        self.0.free = FreeSet::new(inner.cache.iter().chain(inner.garbage.iter()));
+        self.0.deferred.clear();
+        self.0.fresh = FreshPages::default();
+        self.0.synced.held = 0;
+        for ptr in ptrs {
+            file.write(ptr, PageKind::Log, RecordPage::new(inner))?;
+        }
+        file.sync()?;
+        self.0.synced.seq = inner.seq;
+        self.reset_logs(file);
+
+        Ok(())
+    }
+
+    pub fn begin_batch(&mut self) -> BatchPages {
+        self.0.batch = true;
+        BatchPages {
+            allocated: BTreeSet::new(),
+            released: vec![],
+            pos: self.0.record.cache.pos,
+        }
+    }
+
+    /// Takes account of the pages a change of the batch did allocate and release.
+    /// The released pages are kept until the commit, unless the batch did allocate
+    /// them. The record is written only if the cache is refilled, it still refers
+    /// to the committed trees.
+    pub fn batch_step(
+        &mut self,
+        file: &FileIo,
+        pages: &mut BatchPages,
+        value: Option<PagePtr<()>>,
+    ) -> Result<(), WalError> {
+        // the batch is undone by `abort_batch`
+        self.0.undo = None;
+        let cache = &self.0.record.cache;
+        let allocated = &cache.pages[cache.pos as usize..pages.pos as usize];
+        pages
+            .allocated
+            .extend(allocated.iter().flatten().map(|ptr| ptr.raw_number()));
+
+        if let Some(ptr) = value {
+            file.value_freed(ptr.raw_number());
+            self.0.free.free(ptr);
+        }
+        let state = &mut *self.0;
+        let garbage = iter::from_fn(|| state.record.garbage.take());
+        let data = &mut state.data_garbage;
+        let released = garbage
+            .map(|ptr| (garbage_kind(data, ptr), ptr))
+            .chain(value.map(|ptr| (PageKind::Data, ptr.cast())))
+            .collect::<Vec<_>>();
+        let mut free = vec![];
+        for (kind, ptr) in released {
+            if pages.allocated.remove(&ptr.raw_number()) {
+                free.push((kind, ptr));
+            } else {
+                pages.released.push((kind, ptr));
+            }
+        }
+        self.recycle(file, free)?;
+        pages.pos = self.0.record.cache.pos;
+
+        Ok(())
+    }
+
+    /// Writes the record with the new roots of the batch, `head` is the main one.
+    /// The pages released by the batch are in the garbage of the record,
+    /// if the process crashes, those that do not fit are lost until compaction.
+    pub fn commit_batch<T>(
+        &mut self,
+        file: &FileIo,
+        pages: BatchPages,
+        head: PagePtr<T>,
+        roots: &[(u8, PagePtr<T>)],
+    ) -> Result<(), WalError> {
+        self.0.batch = false;
+        let mut released = pages.released;
+        if !roots.is_empty() {
+            let mut trees = match self.0.record.trees {
+                Some(ptr) => file.read(ptr)?,
+                None => TreesPage::empty(),
+            };
+            for &(id, root) in roots {
+                trees.set_root(id, root);
+            }
+            self.begin();
+            let ptr = self.0.record.cache.alloc();
+            self.0.free.alloc(ptr);
+            if let Err(err) = file.write(ptr, PageKind::Tree, trees) {
+                let undo = self.0.undo.take().expect("must begin");
+                self.rollback(file, undo);
+                return Err(err.into());
+            }
+            if let Some(old) = self.0.record.trees.replace(ptr) {
+                self.0.free.free(old);
+                released.push((PageKind::Tree, old.cast()));
+            }
+        }
+        self.begin();
+        for (kind, ptr) in released {
+            if self.0.record.garbage.is_full() {
+                self.0.deferred.push((kind, ptr));
+            } else {
+                self.0.record.garbage.put(ptr);
+            }
+        }
+
+        self.new_head(file, head, None)
+    }
+
+    /// Frees the pages the batch did allocate, the committed trees stay as they are
+    pub fn abort_batch(&mut self, file: &FileIo, mut pages: BatchPages) -> Result<(), WalError> {
+        // a change may be interrupted, its pages count as well, the garbage
+        // holds either those or the pages of the committed trees
+        let cache = &self.0.record.cache;
+        let allocated = cache.pages.get(cache.pos as usize..pages.pos as usize);
+        pages.allocated.extend(
+            allocated
+                .into_iter()
+                .flatten()
+                .flatten()
+                .map(|ptr| ptr.raw_number()),
+        );
+        let state = &mut *self.0;
+        // the released pages are still in the committed trees
+        let released = pages.released.iter().map(|(_, ptr)| *ptr);
+        state
+            .free
+            .forget(released.chain(state.record.garbage.iter()));
+        state.record.garbage = FreelistCache::empty();
+        state.data_garbage.clear();
+        let free = pages
+            .allocated
+            .into_iter()
+            .filter_map(PagePtr::from_raw_number)
+            .map(|ptr| (PageKind::Tree, ptr))
+            .collect::<Vec<_>>();
+        state.free.add(free.iter().map(|(_, ptr)| *ptr));
+        let res = self.recycle(file, free);
+        self.0.batch = false;
+        res
+    }
+
+    /// Runs a change with the caches, restores them if the change fails,
+    /// so the pages allocated or freed meanwhile are as before
+    pub fn transaction<T, E>(
+        &mut self,
+        f: impl FnOnce(&mut FreelistCache, &mut Garbage) -> Result<T, E>,
+    ) -> Result<T, E> {
+        self.0.undo = None;
+        self.begin();
+        let RecordSeq { cache, garbage, .. } = self.0.record;
+        let state = &mut *self.0;
+        let inner = &mut state.record;
+        let mut new_garbage = Garbage {
+            pages: garbage,
+            data: vec![],
+        };
+        let res = f(&mut inner.cache, &mut new_garbage);
+        if res.is_err() {
+            inner.cache = cache;
+            state.undo = None;
+        } else {
+            inner.garbage = new_garbage.pages;
+            state.data_garbage.extend(new_garbage.data);
+            // the change takes from the top of the cache and puts on the top of the garbage
+            let taken = cache
+                .pages
+                .get(inner.cache.pos as usize..cache.pos as usize);
+            for ptr in taken.into_iter().flatten().flatten() {
+                state.free.alloc(*ptr);
+            }
+            let freed = inner
+                .garbage
+                .pages
+                .get(garbage.pos as usize..inner.garbage.pos as usize);
+            for ptr in freed.into_iter().flatten().flatten() {
+                state.free.free(*ptr);
+            }
+        }
+
+        res
+    }
+
+    /// The orphan is the page of the value removed last, or the value allocated,
+    /// it is in the record, so it is freed after a crash. The removed value is readable
+    /// until the next removal replaces it, `reclaim`, `allocate` or closing frees it.
     pub fn orphan_mut(&mut self) -> &mut Option<PagePtr<()>> {
-        &mut self.0.orphan
+        &mut self.0.record.orphan
     }
 
-    fn freelist_size(&self, file: &FileIo) -> u32 {
-        let mut x = 0;
-        let mut freelist = self.0.freelist;
+    /// Puts the page in the orphan slot, returns the page to free.
+    /// While a value is allocated, the slot is busy and the page itself is returned.
+    pub fn replace_orphan(&mut self, ptr: PagePtr<()>) -> Option<PagePtr<()>> {
+        if self.0.allocated {
+            Some(ptr)
+        } else {
+            self.0.record.orphan.replace(ptr)
+        }
+    }
 
-        while freelist.is_some() {
-            x += 1;
-            freelist = file.read(freelist).next;
+    /// Takes a page from the cache and keeps it in the orphan slot,
+    /// so it is freed after a crash. Only one page can be allocated at a time.
+    pub fn allocate<T>(&mut self, file: &FileIo) -> Result<PagePtr<T>, WalError>
+    where
+        T: PlainData,
+    {
+        self.release_dropped(file)?;
+        if self.0.allocated {
+            return Err(WalError::Allocated);
         }
-        x
+        let ptr = self.0.record.cache.alloc::<T>();
+        self.0.free.alloc(ptr);
+        let old = self.0.record.orphan.replace(ptr.cast());
+        self.0.allocated = true;
+        self.write(file)?;
+        self.fill_cache(file, old)?;
+
+        Ok(ptr)
+    }
+
+    /// Takes the allocated page out of the orphan slot,
+    /// the next record must refer to it. Returns `false` if the page is not allocated.
+    pub fn publish<T>(&mut self, ptr: PagePtr<T>) -> bool {
+        let allocated = self.0.allocated && self.0.record.orphan == Some(ptr.cast());
+        if allocated {
+            self.0.allocated = false;
+            self.0.record.orphan = None;
+        }
+        allocated
+    }
+
+    /// Frees the allocated page, does nothing if the page is not allocated
+    pub fn release<T>(&mut self, file: &FileIo, ptr: PagePtr<T>) -> Result<(), WalError> {
+        if self.publish(ptr) {
+            self.write(file)?;
+            self.fill_cache(file, Some(ptr.cast()))?;
+        }
+
+        Ok(())
+    }
+
+    // frees the value dropped while the lock was busy, see `Wal::release_allocated`
+    fn release_dropped(&mut self, file: &FileIo) -> Result<(), WalError> {
+        if self.1 .0.dropped.swap(false, Ordering::AcqRel) {
+            if let Some(ptr) = self.allocated::<()>() {
+                self.release(file, ptr)?;
+            }
+        }
+
+        Ok(())
     }
 }
 
+/// Pages of a batch of changes that is not committed yet
+pub struct BatchPages {
+    // allocated by the batch, free again if the batch is dropped
+    allocated: BTreeSet<u32>,
+    // released by the batch, but still in the committed trees
+    released: Vec<(PageKind, PagePtr<FreePage>)>,
+    // position of the cache after the last change
+    pos: u32,
+}
+
 #[repr(C, align(0x1000))]
 #[derive(Clone, Copy)]
 struct RecordPage {
@@ -274,15 +1691,43 @@ impl RecordPage {
         RecordPage { checksum, inner }
     }
 
-    fn check_old(&self) -> Option<&RecordSeq> {
-        let l = 0xc98;
-        (self.checksum == crc64::crc64(0, &self.inner.as_bytes()[..l])).then_some(&self.inner)
+    // the checksum is verified on raw bytes, a page never written
+    // is not a valid `RecordSeq`, the head pointer is zero there
+    fn read(file: &FileIo, ptr: Option<PagePtr<Self>>) -> io::Result<Option<RecordSeq>> {
+        let page = file.read_page(ptr.map_or(0, PagePtr::raw_number))?;
+        Ok(Self::parse(&page))
     }
 
-    fn check(&self) -> Option<&RecordSeq> {
-        (self.checksum == crc64::crc64(0, self.inner.as_bytes()))
-            .then_some(&self.inner)
-            .or_else(|| self.check_old())
+    // the sequence number of the page, even if it fails the checksum
+    fn raw_seq(page: &[u8; PAGE_SIZE as usize]) -> u64 {
+        let seq = page[8..16].try_into().expect("must be 8 bytes");
+        u64::from_ne_bytes(seq)
+    }
+
+    // `RecordSeq::format` of the result is the layout the page is written in,
+    // the fields an older layout did not have get their defaults
+    fn parse(page: &[u8; PAGE_SIZE as usize]) -> Option<RecordSeq> {
+        let (checksum, inner) = page.split_at(8);
+        let checksum = u64::from_ne_bytes(checksum.try_into().expect("must be 8 bytes"));
+        let inner = &inner[..mem::size_of::<RecordSeq>()];
+        // the fields older layouts did not have are zero, except in the first one,
+        // it left whatever did follow in the place of the orphan and the trees
+        let format = (0..=RecordSeq::FORMAT).rev().find(|format| {
+            let len = RecordSeq::checked_len(*format);
+            let zero = match format {
+                0 => mem::offset_of!(RecordSeq, collation),
+                _ => len,
+            };
+            checksum == crc64::crc64(0, &inner[..len]) && inner[zero..].iter().all(|b| *b == 0)
+        })?;
+
+        let mut record = *RecordSeq::as_this(inner);
+        if format == 0 {
+            record.orphan = None;
+            record.trees = None;
+        }
+        record.format = format;
+        Some(record)
     }
 }
 
@@ -293,10 +1738,46 @@ struct RecordSeq {
     garbage: FreelistCache,
     cache: FreelistCache,
     size: u32,
-    __padding: u32,
+    // number of pages in the persistent freelist
+    freelist_len: u32,
     freelist: Option<PagePtr<FreePage>>,
     head: PagePtr<()>,
     orphan: Option<PagePtr<()>>,
+    // roots of the trees other than the main one
+    trees: Option<PagePtr<TreesPage>>,
+    // `Collation::id` of the key order
+    collation: u64,
+    // `Node::M` of the tree, zero if written by an older version
+    fanout: u64,
+    // `RecordSeq::FORMAT` of the layout, zero if written by an older version,
+    // in memory it is the layout the record is read in
+    format: u64,
+    // nonzero while a snapshot is pinned or the pages it did hold are not free again,
+    // they are lost if the process crashes
+    pinned: u64,
+    // nonzero if the value pages keep the expiration in their last 8 bytes,
+    // zero if created by an older version, its values take the whole page
+    expiry: u64,
+}
+
+impl RecordSeq {
+    const FORMAT: u64 = 7;
+
+    // the bytes the checksum of a layout covers, each one did add fields at the end
+    fn checked_len(format: u64) -> usize {
+        match format {
+            // neither the orphan nor the trees
+            0 => mem::offset_of!(RecordSeq, orphan),
+            1 => mem::offset_of!(RecordSeq, trees),
+            // no collation, that one is bytewise
+            2 => mem::offset_of!(RecordSeq, collation),
+            3 => mem::offset_of!(RecordSeq, fanout),
+            4 => mem::offset_of!(RecordSeq, format),
+            5 => mem::offset_of!(RecordSeq, pinned),
+            6 => mem::offset_of!(RecordSeq, expiry),
+            _ => mem::size_of::<RecordSeq>(),
+        }
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -317,10 +1798,41 @@ impl Alloc for FreelistCache {
     {
         let ptr = self
             .take()
-            .expect("BUG: must be big enough, increase size of freelist cache")
-            .cast();
+            .expect("BUG: must be big enough, increase size of freelist cache");
+        #[cfg(feature = "debug_checks")]
+        if self.iter().any(|other| other == ptr) {
+            panic!("BUG: page {ptr:?} is twice in the freelist cache");
+        }
         log::debug!("alloc {}, {ptr:?}", T::NAME);
-        ptr
+        ptr.cast()
+    }
+}
+
+/// The garbage of a change in progress, it remembers which of the pages are values,
+/// so they are recycled as `PageKind::Data`
+pub struct Garbage {
+    pages: FreelistCache,
+    data: Vec<u32>,
+}
+
+impl Free for Garbage {
+    fn free<T>(&mut self, ptr: PagePtr<T>)
+    where
+        T: PlainData,
+    {
+        if T::KIND == PageKind::Data {
+            self.data.push(ptr.raw_number());
+        }
+        self.pages.free(ptr);
+    }
+}
+
+// a page taken from the garbage is a value if it is remembered as one
+fn garbage_kind(data: &mut BTreeSet<u32>, ptr: PagePtr<FreePage>) -> PageKind {
+    if data.remove(&ptr.raw_number()) {
+        PageKind::Data
+    } else {
+        PageKind::Tree
     }
 }
 
@@ -332,11 +1844,72 @@ impl Free for FreelistCache {
         if self.is_full() {
             panic!("BUG: must have enough space, increase size of freelist cache");
         }
+        #[cfg(feature = "debug_checks")]
+        if self.iter().any(|other| other == ptr.cast()) {
+            panic!("BUG: page {ptr:?} is freed twice");
+        }
         log::debug!("free {} {:?}", T::NAME, ptr);
         self.put(ptr.cast());
     }
 }
 
+/// The pages known to be free: in the caches, the deferred garbage
+/// or taken from the freelist. With the `debug_checks` feature an allocation
+/// of a page that is not free, or a release of a free page, panics.
+#[cfg(feature = "debug_checks")]
+#[derive(Clone)]
+struct FreeSet(BTreeSet<u32>);
+
+#[cfg(feature = "debug_checks")]
+impl FreeSet {
+    fn new<T>(ptrs: impl IntoIterator<Item = PagePtr<T>>) -> Self {
+        FreeSet(ptrs.into_iter().map(|ptr| ptr.raw_number()).collect())
+    }
+
+    fn alloc<T>(&mut self, ptr: PagePtr<T>) {
+        if !self.0.remove(&ptr.raw_number()) {
+            panic!("BUG: page {ptr:?} is allocated, but it is not free");
+        }
+    }
+
+    fn free<T>(&mut self, ptr: PagePtr<T>) {
+        if !self.0.insert(ptr.raw_number()) {
+            panic!("BUG: page {ptr:?} is freed twice");
+        }
+    }
+
+    // the pages become free without an owner that frees them
+    fn add<T>(&mut self, ptrs: impl IntoIterator<Item = PagePtr<T>>) {
+        self.0.extend(ptrs.into_iter().map(|ptr| ptr.raw_number()));
+    }
+
+    // the pages turn out to be still in use
+    fn forget<T>(&mut self, ptrs: impl IntoIterator<Item = PagePtr<T>>) {
+        for ptr in ptrs {
+            self.0.remove(&ptr.raw_number());
+        }
+    }
+}
+
+#[cfg(not(feature = "debug_checks"))]
+#[derive(Clone)]
+struct FreeSet;
+
+#[cfg(not(feature = "debug_checks"))]
+impl FreeSet {
+    fn new<T>(_: impl IntoIterator<Item = PagePtr<T>>) -> Self {
+        FreeSet
+    }
+
+    fn alloc<T>(&mut self, _: PagePtr<T>) {}
+
+    fn free<T>(&mut self, _: PagePtr<T>) {}
+
+    fn add<T>(&mut self, _: impl IntoIterator<Item = PagePtr<T>>) {}
+
+    fn forget<T>(&mut self, _: impl IntoIterator<Item = PagePtr<T>>) {}
+}
+
 impl FreelistCache {
     pub const SIZE: u32 = CACHE_SIZE as u32;
 
@@ -363,11 +1936,24 @@ impl FreelistCache {
         self.pos
     }
 
+    fn iter(&self) -> impl Iterator<Item = PagePtr<FreePage>> + '_ {
+        self.pages[..self.pos as usize].iter().flatten().copied()
+    }
+
     fn put(&mut self, ptr: PagePtr<FreePage>) {
         self.pages[self.pos as usize] = Some(ptr);
         self.pos += 1;
     }
 
+    // puts the pages at the bottom, they are taken after the others
+    fn put_under(&mut self, ptrs: impl IntoIterator<Item = PagePtr<FreePage>>) {
+        for ptr in ptrs {
+            self.pages.copy_within(..self.pos as usize, 1);
+            self.pages[0] = Some(ptr);
+            self.pos += 1;
+        }
+    }
+
     fn take(&mut self) -> Option<PagePtr<FreePage>> {
         if self.is_empty() {
             None
@@ -395,3 +1981,38 @@ struct FreePage {
 unsafe impl PlainData for FreePage {
     const NAME: &str = "Free";
 }
+
+/// Roots of the trees by id, the main tree is in the log record instead
+#[repr(C, align(0x1000))]
+#[derive(Clone, Copy)]
+pub struct TreesPage {
+    roots: [Option<PagePtr<()>>; 0x100],
+}
+
+impl TreesPage {
+    const fn empty() -> Self {
+        TreesPage {
+            roots: [None; 0x100],
+        }
+    }
+
+    pub fn roots(&self) -> impl Iterator<Item = (u8, PagePtr<()>)> + '_ {
+        (0..=u8::MAX).filter_map(|id| Some((id, self.roots[usize::from(id)]?)))
+    }
+
+    pub fn set_root<T>(&mut self, id: u8, root: PagePtr<T>) {
+        self.roots[usize::from(id)] = Some(root.cast());
+    }
+
+    pub fn relocate(&mut self, mut f: impl FnMut(u32) -> u32) {
+        for root in &mut self.roots {
+            if let Some(old) = *root {
+                *root = PagePtr::from_raw_number(f(old.raw_number()));
+            }
+        }
+    }
+}
+
+unsafe impl PlainData for TreesPage {
+    const NAME: &str = "Trees";
+}
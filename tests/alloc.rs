@@ -0,0 +1,88 @@
+//! Counts the page buffers the database allocates, it has a binary of its own,
+//! because the counting allocator is global
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+};
+
+use tempdir::TempDir;
+
+use rej::{Db, NodePage, Params};
+
+#[cfg(feature = "cipher")]
+use rej::Secret;
+
+const PAGE_SIZE: usize = 0x1000;
+
+// counts the page buffers allocated by the current thread
+struct Counting;
+
+thread_local! {
+    static PAGES: Cell<u64> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if layout.size() == PAGE_SIZE && layout.align() == PAGE_SIZE {
+            PAGES.try_with(|n| n.set(n.get() + 1)).unwrap_or_default();
+        }
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOC: Counting = Counting;
+
+#[test]
+fn page_buffers() {
+    let dir = TempDir::new_in("target/tmp", "rej").unwrap();
+    let path = dir.path().join("test-alloc");
+
+    #[cfg(feature = "cipher")]
+    let params = Params::Create {
+        secret: Secret::Pw {
+            pw: "qwerty",
+            time: 1,
+            memory: 0x100,
+        },
+        seed: [1; 32].as_slice(),
+    };
+
+    #[cfg(not(feature = "cipher"))]
+    let params = Params::Create;
+
+    let db = Db::<NodePage>::new(&path, params).unwrap();
+
+    let key = |i: u32| format!("key {i:08}");
+    let insert = |i: u32| {
+        let value = db
+            .entry(key(i))
+            .unwrap()
+            .vacant()
+            .unwrap()
+            .insert()
+            .unwrap();
+        value.write_at(0, &i.to_le_bytes()).unwrap();
+        value.read_to_vec(0, 4).unwrap();
+    };
+    for i in 0..100 {
+        insert(i);
+    }
+    db.sync().unwrap();
+
+    let before = PAGES.with(Cell::get);
+    for i in 100..1100 {
+        insert(i);
+        if i % 100 == 0 {
+            db.sync().unwrap();
+        }
+    }
+    // without reuse an insert takes about fifteen buffers
+    let allocated = PAGES.with(Cell::get) - before;
+    assert!(allocated < 2 * 1000, "{allocated}");
+}